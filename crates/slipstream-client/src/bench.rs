@@ -0,0 +1,407 @@
+//! `slipstream-client bench`: measures a live tunnel against the server's built-in sink,
+//! source and echo targets (`slipstream-server --bench`), so no external target is needed.
+//!
+//! The tunnel runs in-process; each bench stream is a loopback TCP pair whose accepted end is
+//! handed to the tunnel like a client connection, so the measured path is the real one.
+
+use crate::error::ClientError;
+use crate::runtime::{map_io, run_tunnel, PathSample, TunnelHooks};
+use crate::streams::{Command, LocalStream};
+use serde::Serialize;
+use slipstream_core::stream_header::{encode_stream_header, StreamTarget};
+use slipstream_ffi::ClientConfig;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinSet;
+
+const WRITE_CHUNK_BYTES: usize = 16 * 1024;
+/// Samples shown in the text report's cwnd history, one per second.
+const CWND_HISTORY_POINTS: usize = 60;
+const SAMPLES_PER_SECOND: usize = 4;
+
+pub(crate) struct BenchPlan {
+    /// Parallel streams per phase.
+    pub(crate) streams: usize,
+    /// Bytes each stream uploads and downloads.
+    pub(crate) bytes: u64,
+    /// Echo round trips per stream.
+    pub(crate) pings: usize,
+    pub(crate) ping_size: usize,
+    pub(crate) timeout: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct BenchReport {
+    congestion_control: Option<String>,
+    resolvers: usize,
+    upload: Throughput,
+    download: Throughput,
+    latency: Latency,
+    paths: Vec<PathReport>,
+}
+
+#[derive(Debug, Serialize)]
+struct Throughput {
+    streams: usize,
+    bytes: u64,
+    elapsed_ms: u64,
+    mbit_per_s: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct Latency {
+    samples: usize,
+    min_ms: f64,
+    median_ms: f64,
+    p90_ms: f64,
+    max_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct PathReport {
+    resolver: String,
+    mode: String,
+    sent: u64,
+    lost: u64,
+    history: Vec<PathPoint>,
+}
+
+/// One sample of a path; `pacing_*` come from the poll pacer's `PacingBudgetSnapshot`, which
+/// only authoritative paths have.
+#[derive(Debug, Serialize)]
+struct PathPoint {
+    t_ms: u64,
+    cwnd: u64,
+    rtt_us: u64,
+    bytes_in_transit: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pacing_rate: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pacing_qps: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pacing_gain: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target_inflight: Option<usize>,
+}
+
+/// Opens tunnel streams for the bench: each one is a loopback TCP pair whose accepted end
+/// goes to the tunnel.
+struct StreamOpener {
+    listener: TokioTcpListener,
+    command_tx: mpsc::UnboundedSender<Command>,
+}
+
+impl StreamOpener {
+    async fn new(command_tx: mpsc::UnboundedSender<Command>) -> Result<Self, ClientError> {
+        let listener = TokioTcpListener::bind(("127.0.0.1", 0))
+            .await
+            .map_err(map_io)?;
+        Ok(Self {
+            listener,
+            command_tx,
+        })
+    }
+
+    async fn open(&self, target: StreamTarget) -> Result<TokioTcpStream, ClientError> {
+        let addr = self.listener.local_addr().map_err(map_io)?;
+        let (mut stream, (accepted, _)) =
+            tokio::try_join!(TokioTcpStream::connect(addr), self.listener.accept())
+                .map_err(map_io)?;
+        self.command_tx
+            .send(Command::NewStream(LocalStream::tcp(accepted)))
+            .map_err(|_| ClientError::new("Tunnel closed"))?;
+        let _ = stream.set_nodelay(true);
+        stream
+            .write_all(&encode_stream_header(&target))
+            .await
+            .map_err(map_io)?;
+        Ok(stream)
+    }
+}
+
+pub(crate) async fn run_bench(
+    config: &ClientConfig<'_>,
+    plan: &BenchPlan,
+) -> Result<BenchReport, ClientError> {
+    let (ready_tx, ready_rx) = oneshot::channel();
+    let (sample_tx, mut sample_rx) = mpsc::unbounded_channel();
+    let (stop_tx, stop_rx) = watch::channel(false);
+    let hooks = TunnelHooks {
+        ready_tx: Some(ready_tx),
+        sample_tx,
+        stop_rx,
+    };
+    let tunnel = run_tunnel(config, Some(hooks));
+    tokio::pin!(tunnel);
+    let phases = async {
+        let command_tx = ready_rx
+            .await
+            .map_err(|_| ClientError::new("Tunnel closed before the connection was ready"))?;
+        let opener = StreamOpener::new(command_tx).await?;
+        let upload = measure_upload(&opener, plan).await?;
+        let download = measure_download(&opener, plan).await?;
+        let latency = measure_latency(&opener, plan).await?;
+        Ok::<_, ClientError>((upload, download, latency))
+    };
+    let (upload, download, latency) = tokio::select! {
+        result = &mut tunnel => {
+            return Err(result
+                .err()
+                .unwrap_or_else(|| ClientError::new("Tunnel closed during the bench")));
+        }
+        phases = tokio::time::timeout(plan.timeout, phases) => {
+            phases.map_err(|_| ClientError::new("Bench timed out"))??
+        }
+    };
+    let _ = stop_tx.send(true);
+    tunnel.await?;
+
+    let mut samples = Vec::new();
+    while let Ok(sample) = sample_rx.try_recv() {
+        samples.push(sample);
+    }
+    Ok(BenchReport {
+        congestion_control: config.congestion_control.map(str::to_string),
+        resolvers: config.resolvers.len(),
+        upload,
+        download,
+        latency,
+        paths: path_reports(&samples),
+    })
+}
+
+async fn measure_upload(
+    opener: &StreamOpener,
+    plan: &BenchPlan,
+) -> Result<Throughput, ClientError> {
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for _ in 0..plan.streams {
+        let mut stream = opener.open(StreamTarget::Sink).await?;
+        let bytes = plan.bytes;
+        tasks.spawn(async move {
+            let chunk = vec![0u8; WRITE_CHUNK_BYTES];
+            let mut left = bytes;
+            while left > 0 {
+                let len = left.min(chunk.len() as u64) as usize;
+                stream.write_all(&chunk[..len]).await?;
+                left -= len as u64;
+            }
+            stream.shutdown().await?;
+            // The sink answers our FIN with what it received, so this covers delivery.
+            let received = stream.read_u64().await?;
+            Ok::<_, std::io::Error>(received)
+        });
+    }
+    let mut total = 0;
+    while let Some(result) = tasks.join_next().await {
+        let received = result
+            .map_err(|err| ClientError::new(err.to_string()))?
+            .map_err(map_io)?;
+        if received != plan.bytes {
+            return Err(ClientError::new(format!(
+                "Sink received {} of {} bytes",
+                received, plan.bytes
+            )));
+        }
+        total += received;
+    }
+    Ok(throughput(plan.streams, total, started.elapsed()))
+}
+
+async fn measure_download(
+    opener: &StreamOpener,
+    plan: &BenchPlan,
+) -> Result<Throughput, ClientError> {
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+    for _ in 0..plan.streams {
+        let mut stream = opener.open(StreamTarget::Source(plan.bytes)).await?;
+        tasks.spawn(async move {
+            stream.shutdown().await?;
+            let mut buf = vec![0u8; WRITE_CHUNK_BYTES];
+            let mut received = 0u64;
+            loop {
+                let len = stream.read(&mut buf).await?;
+                if len == 0 {
+                    return Ok::<_, std::io::Error>(received);
+                }
+                received += len as u64;
+            }
+        });
+    }
+    let mut total = 0;
+    while let Some(result) = tasks.join_next().await {
+        let received = result
+            .map_err(|err| ClientError::new(err.to_string()))?
+            .map_err(map_io)?;
+        if received != plan.bytes {
+            return Err(ClientError::new(format!(
+                "Source sent {} of {} bytes",
+                received, plan.bytes
+            )));
+        }
+        total += received;
+    }
+    Ok(throughput(plan.streams, total, started.elapsed()))
+}
+
+async fn measure_latency(opener: &StreamOpener, plan: &BenchPlan) -> Result<Latency, ClientError> {
+    let mut tasks = JoinSet::new();
+    for _ in 0..plan.streams {
+        let mut stream = opener.open(StreamTarget::Echo).await?;
+        let pings = plan.pings;
+        let ping = vec![0x5au8; plan.ping_size];
+        tasks.spawn(async move {
+            let mut reply = vec![0u8; ping.len()];
+            let mut rtts = Vec::with_capacity(pings);
+            for _ in 0..pings {
+                let sent_at = Instant::now();
+                stream.write_all(&ping).await?;
+                stream.read_exact(&mut reply).await?;
+                rtts.push(sent_at.elapsed());
+            }
+            stream.shutdown().await?;
+            Ok::<_, std::io::Error>(rtts)
+        });
+    }
+    let mut rtts = Vec::new();
+    while let Some(result) = tasks.join_next().await {
+        rtts.extend(
+            result
+                .map_err(|err| ClientError::new(err.to_string()))?
+                .map_err(map_io)?,
+        );
+    }
+    if rtts.is_empty() {
+        return Err(ClientError::new("No echo round trips completed"));
+    }
+    rtts.sort();
+    let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+    Ok(Latency {
+        samples: rtts.len(),
+        min_ms: ms(rtts[0]),
+        median_ms: ms(rtts[rtts.len() / 2]),
+        p90_ms: ms(rtts[(rtts.len() * 9 / 10).min(rtts.len() - 1)]),
+        max_ms: ms(rtts[rtts.len() - 1]),
+    })
+}
+
+fn throughput(streams: usize, bytes: u64, elapsed: Duration) -> Throughput {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    Throughput {
+        streams,
+        bytes,
+        elapsed_ms: elapsed.as_millis() as u64,
+        mbit_per_s: bytes as f64 * 8.0 / seconds / 1_000_000.0,
+    }
+}
+
+fn path_reports(samples: &[PathSample]) -> Vec<PathReport> {
+    let start_us = samples.first().map_or(0, |sample| sample.at_us);
+    let mut paths: BTreeMap<String, PathReport> = BTreeMap::new();
+    for sample in samples {
+        let report = paths
+            .entry(sample.resolver.to_string())
+            .or_insert_with(|| PathReport {
+                resolver: sample.resolver.to_string(),
+                mode: format!("{:?}", sample.mode),
+                sent: 0,
+                lost: 0,
+                history: Vec::new(),
+            });
+        report.sent = sample.sent;
+        report.lost = sample.lost;
+        report.history.push(PathPoint {
+            t_ms: sample.at_us.saturating_sub(start_us) / 1000,
+            cwnd: sample.cwnd,
+            rtt_us: sample.rtt_us,
+            bytes_in_transit: sample.bytes_in_transit,
+            pacing_rate: sample.pacing.map(|pacing| pacing.pacing_rate),
+            pacing_qps: sample.pacing.map(|pacing| pacing.qps),
+            pacing_gain: sample.pacing.map(|pacing| pacing.gain),
+            target_inflight: sample.pacing.map(|pacing| pacing.target_inflight),
+        });
+    }
+    paths.into_values().collect()
+}
+
+impl BenchReport {
+    pub(crate) fn to_text(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "Bench over {} resolver(s), congestion control {}",
+            self.resolvers,
+            self.congestion_control.as_deref().unwrap_or("default")
+        );
+        for (name, phase) in [("upload", &self.upload), ("download", &self.download)] {
+            let _ = writeln!(
+                out,
+                "  {:<9} {} streams, {} bytes in {:.2} s: {:.3} Mbit/s",
+                name,
+                phase.streams,
+                phase.bytes,
+                phase.elapsed_ms as f64 / 1000.0,
+                phase.mbit_per_s
+            );
+        }
+        let _ = writeln!(
+            out,
+            "  {:<9} {} round trips: min {:.1} ms, median {:.1} ms, p90 {:.1} ms, max {:.1} ms",
+            "latency",
+            self.latency.samples,
+            self.latency.min_ms,
+            self.latency.median_ms,
+            self.latency.p90_ms,
+            self.latency.max_ms
+        );
+        for path in &self.paths {
+            let mut cwnds: Vec<u64> = path.history.iter().map(|point| point.cwnd).collect();
+            cwnds.sort_unstable();
+            let mut rtts: Vec<u64> = path.history.iter().map(|point| point.rtt_us).collect();
+            rtts.sort_unstable();
+            let median = |values: &[u64]| values.get(values.len() / 2).copied().unwrap_or(0);
+            let _ = writeln!(
+                out,
+                "  path {} ({}): cwnd min/median/max {}/{}/{} bytes, rtt median {:.1} ms, {} of {} packets lost",
+                path.resolver,
+                path.mode,
+                cwnds.first().copied().unwrap_or(0),
+                median(&cwnds),
+                cwnds.last().copied().unwrap_or(0),
+                median(&rtts) as f64 / 1000.0,
+                path.lost,
+                path.sent
+            );
+            if let Some(point) = path
+                .history
+                .iter()
+                .rev()
+                .find(|point| point.pacing_rate.is_some())
+            {
+                let _ = writeln!(
+                    out,
+                    "    pacing: rate {} B/s, {:.1} qps, gain {:.2}, target inflight {}",
+                    point.pacing_rate.unwrap_or(0),
+                    point.pacing_qps.unwrap_or(0.0),
+                    point.pacing_gain.unwrap_or(0.0),
+                    point.target_inflight.unwrap_or(0)
+                );
+            }
+            let history: Vec<String> = path
+                .history
+                .iter()
+                .step_by(SAMPLES_PER_SECOND)
+                .take(CWND_HISTORY_POINTS)
+                .map(|point| point.cwnd.to_string())
+                .collect();
+            let _ = writeln!(out, "    cwnd per second: {}", history.join(" "));
+        }
+        out
+    }
+}
@@ -1,14 +1,24 @@
 mod debug;
+mod encoding;
+mod health;
+mod mtu;
 mod path;
 mod poll;
+mod query;
 mod resolver;
 mod response;
 
 pub(crate) use debug::maybe_report_debug;
+pub(crate) use encoding::send_encoding_probe;
+pub(crate) use health::{evict_unusable_resolvers, update_resolver_health};
+pub(crate) use mtu::{measure_path_limits, send_mtu_probe, MtuOutcome, PathLimits};
 pub(crate) use path::{add_paths, refresh_resolver_path, resolver_mode_to_c};
-pub(crate) use poll::{expire_inflight_polls, send_poll_queries};
+pub(crate) use poll::{
+    expire_inflight_polls, flush_deferred_queries, next_query_id, send_dns_query, send_poll_queries,
+};
+pub(crate) use query::QueryEncoder;
 pub(crate) use resolver::{
-    normalize_dual_stack_addr, reset_resolver_path, resolve_resolvers,
-    sockaddr_storage_to_socket_addr, ResolverState,
+    apply_resolver_list, attach_transports, normalize_dual_stack_addr, reset_resolver_path,
+    resolve_resolvers, sockaddr_storage_to_socket_addr, ResolvedResolver, ResolverState,
 };
 pub(crate) use response::{handle_dns_response, DnsResponseContext};
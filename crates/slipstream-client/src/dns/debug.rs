@@ -15,6 +15,9 @@ pub(crate) struct DebugMetrics {
     pub(crate) send_packets: u64,
     pub(crate) send_bytes: u64,
     pub(crate) polls_sent: u64,
+    pub(crate) rate_limited: u64,
+    pub(crate) rate_limit_drops: u64,
+    pub(crate) case_mismatches: u64,
     pub(crate) last_enqueue_at: u64,
     pub(crate) last_report_dns: u64,
     pub(crate) last_report_zero: u64,
@@ -23,6 +26,9 @@ pub(crate) struct DebugMetrics {
     pub(crate) last_report_send_packets: u64,
     pub(crate) last_report_send_bytes: u64,
     pub(crate) last_report_polls: u64,
+    pub(crate) last_report_rate_limited: u64,
+    pub(crate) last_report_rate_limit_drops: u64,
+    pub(crate) last_report_case_mismatches: u64,
 }
 
 impl DebugMetrics {
@@ -37,6 +43,9 @@ impl DebugMetrics {
             send_packets: 0,
             send_bytes: 0,
             polls_sent: 0,
+            rate_limited: 0,
+            rate_limit_drops: 0,
+            case_mismatches: 0,
             last_enqueue_at: 0,
             last_report_dns: 0,
             last_report_zero: 0,
@@ -45,6 +54,9 @@ impl DebugMetrics {
             last_report_send_packets: 0,
             last_report_send_bytes: 0,
             last_report_polls: 0,
+            last_report_rate_limited: 0,
+            last_report_rate_limit_drops: 0,
+            last_report_case_mismatches: 0,
         }
     }
 }
@@ -58,6 +70,9 @@ pub(crate) fn maybe_report_debug(
     pacing_snapshot: Option<PacingBudgetSnapshot>,
 ) {
     let label = resolver.label();
+    let health = resolver.health.summary();
+    let deferred = resolver.deferred_queries.len();
+    let rate_limited = resolver.rate_limiter.is_some();
     let debug = &mut resolver.debug;
     if !debug.enabled {
         return;
@@ -90,6 +105,29 @@ pub(crate) fn maybe_report_debug(
     } else {
         now.saturating_sub(debug.last_enqueue_at) / 1_000
     };
+    // Shows when the per-resolver QPS limit, not the network, is what holds throughput back.
+    let rate_summary = if rate_limited {
+        format!(
+            " rate_limited+={} rate_drops+={} deferred={}",
+            debug
+                .rate_limited
+                .saturating_sub(debug.last_report_rate_limited),
+            debug
+                .rate_limit_drops
+                .saturating_sub(debug.last_report_rate_limit_drops),
+            deferred
+        )
+    } else {
+        String::new()
+    };
+    let case_delta = debug
+        .case_mismatches
+        .saturating_sub(debug.last_report_case_mismatches);
+    let case_summary = if case_delta > 0 {
+        format!(" case_mismatch+={}", case_delta)
+    } else {
+        String::new()
+    };
     let pacing_summary = if let Some(snapshot) = pacing_snapshot {
         format!(
             " pacing_rate={} qps_target={:.2} target_inflight={} gain={:.2}",
@@ -99,7 +137,7 @@ pub(crate) fn maybe_report_debug(
         String::new()
     };
     debug!(
-        "debug: {} dns+={} send_pkts+={} send_bytes+={} polls+={} zero_send+={} zero_send_streams+={} streams={} enqueued+={} last_enqueue_ms={} pending_polls={} inflight_polls={}{}",
+        "debug: {} dns+={} send_pkts+={} send_bytes+={} polls+={} zero_send+={} zero_send_streams+={} streams={} enqueued+={} last_enqueue_ms={} pending_polls={} inflight_polls={} {}{}{}{}",
         label,
         dns_delta,
         send_pkt_delta,
@@ -112,6 +150,9 @@ pub(crate) fn maybe_report_debug(
         enqueue_ms,
         pending_polls,
         inflight_polls,
+        health,
+        rate_summary,
+        case_summary,
         pacing_summary
     );
     debug.last_report_at = now;
@@ -122,4 +163,7 @@ pub(crate) fn maybe_report_debug(
     debug.last_report_send_packets = debug.send_packets;
     debug.last_report_send_bytes = debug.send_bytes;
     debug.last_report_polls = debug.polls_sent;
+    debug.last_report_rate_limited = debug.rate_limited;
+    debug.last_report_rate_limit_drops = debug.rate_limit_drops;
+    debug.last_report_case_mismatches = debug.case_mismatches;
 }
@@ -0,0 +1,217 @@
+use crate::error::ClientError;
+use crate::transport::UdpSocketPool;
+use rand::RngCore;
+use slipstream_dns::{decode_response, probe_answer, QnameEncoding, QueryControl};
+use std::collections::VecDeque;
+use tracing::info;
+
+use super::poll::{next_query_id, send_dns_query};
+use super::query::QueryEncoder;
+use super::resolver::ResolverState;
+
+const ENCODING_PROBE_TIMEOUT_US: u64 = 2_000_000;
+const ENCODING_PROBE_ATTEMPTS: u32 = 3;
+
+/// Finds the densest QNAME encoding a resolver path delivers intact. Each candidate is
+/// probed with a query of random bytes as long as the path carries, which the server
+/// answers with a digest of what it decoded; folded case, mangled bytes or a server that
+/// does not know the encoding all rule it out. Paths keep base32 until a candidate is
+/// accepted.
+pub(crate) struct EncodingNegotiation {
+    pending: VecDeque<QnameEncoding>,
+    attempts: u32,
+    outstanding: Option<EncodingProbe>,
+}
+
+struct EncodingProbe {
+    id: u16,
+    encoding: QnameEncoding,
+    payload: Vec<u8>,
+    sent_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProbeOutcome {
+    Accepted(QnameEncoding),
+    Rejected(QnameEncoding),
+}
+
+impl EncodingNegotiation {
+    fn new(candidates: &[QnameEncoding]) -> Self {
+        Self {
+            pending: candidates.iter().copied().collect(),
+            attempts: 0,
+            outstanding: None,
+        }
+    }
+
+    /// The encoding to probe now: nothing is in flight, or the last probe timed out.
+    fn next_probe(&mut self, now: u64) -> Option<QnameEncoding> {
+        if let Some(probe) = &self.outstanding {
+            if now < probe.sent_at.saturating_add(ENCODING_PROBE_TIMEOUT_US) {
+                return None;
+            }
+            self.outstanding = None;
+            if self.attempts >= ENCODING_PROBE_ATTEMPTS {
+                self.pending.pop_front();
+                self.attempts = 0;
+            }
+        }
+        self.pending.front().copied()
+    }
+
+    fn note_sent(&mut self, probe: EncodingProbe) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.outstanding = Some(probe);
+    }
+
+    /// Settles the probe in flight if `id` is its answer; `answer` is the answer's payload.
+    fn handle_response(&mut self, id: u16, answer: Option<&[u8]>) -> Option<ProbeOutcome> {
+        if self.outstanding.as_ref().is_none_or(|probe| probe.id != id) {
+            return None;
+        }
+        let probe = self.outstanding.take()?;
+        self.attempts = 0;
+        if answer.is_some_and(|answer| answer == probe_answer(&probe.payload, answer.len())) {
+            self.pending.clear();
+            return Some(ProbeOutcome::Accepted(probe.encoding));
+        }
+        self.pending.pop_front();
+        Some(ProbeOutcome::Rejected(probe.encoding))
+    }
+
+    /// Microseconds until the probe in flight times out.
+    pub(crate) fn wait_us(&self, now: u64) -> Option<u64> {
+        self.outstanding.as_ref().map(|probe| {
+            probe
+                .sent_at
+                .saturating_add(ENCODING_PROBE_TIMEOUT_US)
+                .saturating_sub(now)
+        })
+    }
+}
+
+/// Sends the resolver's next encoding probe if one is due.
+pub(crate) async fn send_encoding_probe(
+    udp: &UdpSocketPool,
+    encoder: &QueryEncoder<'_>,
+    resolver: &mut ResolverState,
+    now: u64,
+) -> Result<(), ClientError> {
+    if encoder.candidates().is_empty() || resolver.transport.is_none() || resolver.unusable {
+        return Ok(());
+    }
+    // Probes are sized to the path, so wait until it has been measured.
+    let Some(limits) = resolver.path_limits else {
+        return Ok(());
+    };
+    let Some(encoding) = resolver
+        .encoding_negotiation
+        .get_or_insert_with(|| EncodingNegotiation::new(encoder.candidates()))
+        .next_probe(now)
+    else {
+        return Ok(());
+    };
+    if !resolver.admit_query(now) {
+        return Ok(());
+    }
+    let payload_len = encoder.max_payload(encoding, QueryControl::Probe(0), limits.qname_len)?;
+    let mut payload = vec![0u8; payload_len];
+    rand::thread_rng().fill_bytes(&mut payload);
+    let id = next_query_id(&resolver.inflight_poll_ids);
+    let (packet, _) = encoder.encode_probe(id, &payload, encoding, 0)?;
+    send_dns_query(udp, resolver.transport.as_deref(), resolver.addr, packet).await?;
+    if let Some(negotiation) = resolver.encoding_negotiation.as_mut() {
+        negotiation.note_sent(EncodingProbe {
+            id,
+            encoding,
+            payload,
+            sent_at: now,
+        });
+    }
+    Ok(())
+}
+
+/// Consumes `response` if it answers the resolver's encoding probe, switching the path to
+/// the encoding once it is accepted.
+pub(crate) fn handle_encoding_probe_response(
+    resolver: &mut ResolverState,
+    encoder: &QueryEncoder<'_>,
+    id: u16,
+    response: &[u8],
+) -> Result<bool, ClientError> {
+    let Some(negotiation) = resolver.encoding_negotiation.as_mut() else {
+        return Ok(false);
+    };
+    let answer = decode_response(response);
+    match negotiation.handle_response(id, answer.as_deref()) {
+        None => Ok(false),
+        Some(ProbeOutcome::Accepted(encoding)) => {
+            resolver.qname_encoding = encoding;
+            resolver.update_upstream_mtu(encoder)?;
+            info!(
+                "Resolver {} carries {} QNAMEs; upstream MTU {}",
+                resolver.addr, encoding, resolver.upstream_mtu
+            );
+            Ok(true)
+        }
+        Some(ProbeOutcome::Rejected(encoding)) => {
+            info!(
+                "Resolver {} does not carry {} QNAMEs intact",
+                resolver.addr, encoding
+            );
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EncodingNegotiation, EncodingProbe, ProbeOutcome};
+    use slipstream_dns::{probe_answer, QnameEncoding};
+
+    fn probe(id: u16, encoding: QnameEncoding, sent_at: u64) -> EncodingProbe {
+        EncodingProbe {
+            id,
+            encoding,
+            payload: vec![1, 2, 3],
+            sent_at,
+        }
+    }
+
+    #[test]
+    fn falls_back_until_an_encoding_is_verified() {
+        let answer = probe_answer(&[1, 2, 3], 8);
+        let mut negotiation =
+            EncodingNegotiation::new(&[QnameEncoding::Raw, QnameEncoding::Base64]);
+        assert_eq!(negotiation.next_probe(0), Some(QnameEncoding::Raw));
+        negotiation.note_sent(probe(1, QnameEncoding::Raw, 0));
+        assert_eq!(negotiation.next_probe(1_000), None);
+        assert_eq!(negotiation.handle_response(2, Some(&answer)), None);
+        assert_eq!(
+            negotiation.handle_response(1, Some(&probe_answer(&[1, 2, 4], 8))),
+            Some(ProbeOutcome::Rejected(QnameEncoding::Raw))
+        );
+
+        assert_eq!(negotiation.next_probe(2_000), Some(QnameEncoding::Base64));
+        negotiation.note_sent(probe(3, QnameEncoding::Base64, 2_000));
+        assert_eq!(
+            negotiation.handle_response(3, Some(&answer)),
+            Some(ProbeOutcome::Accepted(QnameEncoding::Base64))
+        );
+        assert_eq!(negotiation.next_probe(3_000), None);
+    }
+
+    #[test]
+    fn gives_up_on_an_encoding_after_repeated_timeouts() {
+        let mut negotiation = EncodingNegotiation::new(&[QnameEncoding::Raw]);
+        let mut now = 0;
+        for id in 0..3 {
+            assert_eq!(negotiation.next_probe(now), Some(QnameEncoding::Raw));
+            negotiation.note_sent(probe(id, QnameEncoding::Raw, now));
+            now += 2_000_000;
+        }
+        assert_eq!(negotiation.next_probe(now), None);
+        assert_eq!(negotiation.wait_us(now), None);
+    }
+}
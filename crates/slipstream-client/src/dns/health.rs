@@ -0,0 +1,414 @@
+use slipstream_ffi::picoquic::{
+    picoquic_abandon_path, picoquic_cnx_t, picoquic_get_path_quality, picoquic_path_quality_t,
+    picoquic_path_status_enum, picoquic_set_path_status,
+};
+use tracing::{info, warn};
+
+use super::path::path_probe_backoff;
+use super::resolver::ResolverState;
+
+const HEALTH_WINDOW_US: u64 = 5_000_000;
+// Fewer queries than this in a window says nothing about the resolver.
+const HEALTH_MIN_SAMPLES: u64 = 8;
+const HEALTH_SMOOTHING: f64 = 0.5;
+const HEALTH_DEMOTE_BELOW: f64 = 0.6;
+const HEALTH_RESTORE_ABOVE: f64 = 0.8;
+const HEALTH_EVICT_BELOW: f64 = 0.3;
+const HEALTH_EVICT_WINDOWS: u32 = 2;
+// Re-admitted resolvers start demoted and have to earn their share back.
+const HEALTH_READMIT_SCORE: f64 = 0.7;
+// RTTs within this factor of the fastest resolver are not penalised.
+const HEALTH_RTT_TOLERANCE: u64 = 4;
+const HEALTH_RTT_FLOOR_US: u64 = 500_000;
+// First eviction backs off 2s, doubling up to the path probe cap.
+const HEALTH_EVICT_PROBE_ATTEMPTS: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum HealthState {
+    Healthy,
+    Demoted,
+    Evicted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HealthTransition {
+    Demote,
+    Restore,
+    Evict,
+}
+
+pub(crate) struct ResolverHealth {
+    pub(crate) state: HealthState,
+    pub(crate) score: f64,
+    pub(crate) evictions: u32,
+    window_start: u64,
+    window_sent: u64,
+    window_responses: u64,
+    window_errors: u64,
+    last_error_rcode: Option<u8>,
+    bad_windows: u32,
+}
+
+impl ResolverHealth {
+    pub(crate) fn new() -> Self {
+        Self {
+            state: HealthState::Healthy,
+            score: 1.0,
+            evictions: 0,
+            window_start: 0,
+            window_sent: 0,
+            window_responses: 0,
+            window_errors: 0,
+            last_error_rcode: None,
+            bad_windows: 0,
+        }
+    }
+
+    pub(crate) fn is_demoted(&self) -> bool {
+        self.state == HealthState::Demoted
+    }
+
+    pub(crate) fn is_evicted(&self) -> bool {
+        self.state == HealthState::Evicted
+    }
+
+    pub(crate) fn note_query(&mut self) {
+        self.window_sent = self.window_sent.saturating_add(1);
+    }
+
+    pub(crate) fn note_response(&mut self) {
+        self.window_responses = self.window_responses.saturating_add(1);
+    }
+
+    pub(crate) fn note_rcode_error(&mut self, rcode: u8) {
+        self.window_responses = self.window_responses.saturating_add(1);
+        self.window_errors = self.window_errors.saturating_add(1);
+        self.last_error_rcode = Some(rcode);
+    }
+
+    /// Called once the evicted resolver's path has been probed again.
+    pub(crate) fn readmit(&mut self, now: u64) {
+        self.state = HealthState::Demoted;
+        self.score = HEALTH_READMIT_SCORE;
+        self.bad_windows = 0;
+        self.reset_window(now);
+    }
+
+    pub(crate) fn summary(&self) -> String {
+        format!("health={:.2} state={:?}", self.score, self.state)
+    }
+
+    fn reset_window(&mut self, now: u64) {
+        self.window_start = now;
+        self.window_sent = 0;
+        self.window_responses = 0;
+        self.window_errors = 0;
+        self.last_error_rcode = None;
+    }
+
+    fn window_score(&self, rtt_us: u64, best_rtt_us: u64) -> Option<f64> {
+        if self.window_sent < HEALTH_MIN_SAMPLES {
+            return None;
+        }
+        let response_ratio = (self.window_responses as f64 / self.window_sent as f64).min(1.0);
+        let error_ratio = if self.window_responses == 0 {
+            0.0
+        } else {
+            self.window_errors as f64 / self.window_responses as f64
+        };
+        let rtt_limit = best_rtt_us
+            .saturating_mul(HEALTH_RTT_TOLERANCE)
+            .max(HEALTH_RTT_FLOOR_US);
+        let rtt_factor = if rtt_us > rtt_limit {
+            rtt_limit as f64 / rtt_us as f64
+        } else {
+            1.0
+        };
+        Some(response_ratio * (1.0 - error_ratio) * rtt_factor)
+    }
+
+    fn evaluate(&mut self, now: u64, rtt_us: u64, best_rtt_us: u64) -> Option<HealthTransition> {
+        if self.window_start == 0 {
+            self.window_start = now;
+            return None;
+        }
+        if now.saturating_sub(self.window_start) < HEALTH_WINDOW_US {
+            return None;
+        }
+        let window_score = self.window_score(rtt_us, best_rtt_us);
+        self.reset_window(now);
+        let window_score = window_score?;
+        self.score = self.score * (1.0 - HEALTH_SMOOTHING) + window_score * HEALTH_SMOOTHING;
+        if self.score < HEALTH_EVICT_BELOW {
+            self.bad_windows = self.bad_windows.saturating_add(1);
+        } else {
+            self.bad_windows = 0;
+        }
+        match self.state {
+            HealthState::Evicted => None,
+            _ if self.bad_windows >= HEALTH_EVICT_WINDOWS => Some(HealthTransition::Evict),
+            HealthState::Healthy if self.score < HEALTH_DEMOTE_BELOW => {
+                Some(HealthTransition::Demote)
+            }
+            HealthState::Demoted if self.score >= HEALTH_RESTORE_ABOVE => {
+                Some(HealthTransition::Restore)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Scores every active resolver and demotes, restores or evicts its path.
+pub(crate) fn update_resolver_health(
+    cnx: *mut picoquic_cnx_t,
+    resolvers: &mut [ResolverState],
+    now: u64,
+) {
+    let rtts: Vec<Option<u64>> = resolvers
+        .iter()
+        .map(|resolver| path_rtt(cnx, resolver))
+        .collect();
+    let best_rtt_us = resolvers
+        .iter()
+        .zip(rtts.iter())
+        .filter(|(resolver, _)| resolver.health.state == HealthState::Healthy)
+        .filter_map(|(_, rtt)| *rtt)
+        .min()
+        .unwrap_or(0);
+
+    for index in 0..resolvers.len() {
+        let Some(rtt_us) = rtts[index] else {
+            continue;
+        };
+        let last_error_rcode = resolvers[index].health.last_error_rcode;
+        let Some(transition) = resolvers[index].health.evaluate(now, rtt_us, best_rtt_us) else {
+            continue;
+        };
+        match transition {
+            HealthTransition::Demote => {
+                let resolver = &mut resolvers[index];
+                resolver.health.state = HealthState::Demoted;
+                set_path_status(
+                    cnx,
+                    resolver,
+                    picoquic_path_status_enum::picoquic_path_status_standby,
+                );
+                warn!(
+                    "Demoting resolver {} ({} rtt_ms={} last_rcode={:?})",
+                    resolver.addr,
+                    resolver.health.summary(),
+                    rtt_us / 1000,
+                    last_error_rcode
+                );
+            }
+            HealthTransition::Restore => {
+                let resolver = &mut resolvers[index];
+                resolver.health.state = HealthState::Healthy;
+                set_path_status(
+                    cnx,
+                    resolver,
+                    picoquic_path_status_enum::picoquic_path_status_available,
+                );
+                info!(
+                    "Resolver {} recovered ({})",
+                    resolver.addr,
+                    resolver.health.summary()
+                );
+            }
+            HealthTransition::Evict => {
+                // Never abandon the last usable path; a demoted path still beats none. Path 0
+                // cannot be abandoned at all (see `apply_resolver_list`), so it is only demoted.
+                let has_other = resolvers.iter().enumerate().any(|(other, resolver)| {
+                    other != index
+                        && resolver.added
+                        && resolver.unique_path_id.is_some()
+                        && resolver.health.state == HealthState::Healthy
+                });
+                if index != 0 && has_other {
+                    evict_resolver(cnx, &mut resolvers[index], now, last_error_rcode);
+                } else if resolvers[index].health.state == HealthState::Healthy {
+                    resolvers[index].health.state = HealthState::Demoted;
+                }
+            }
+        }
+    }
+}
+
+/// Evicts the paths of resolvers that path MTU discovery found unusable, keeping at least
+/// one path and never path 0, which already gets no tunnel traffic while unusable. They stay
+/// out until a later round finds them usable again.
+pub(crate) fn evict_unusable_resolvers(
+    cnx: *mut picoquic_cnx_t,
+    resolvers: &mut [ResolverState],
+    now: u64,
+) {
+    for index in 0..resolvers.len() {
+        let resolver = &resolvers[index];
+        if index == 0 || !resolver.unusable || !resolver.added || resolver.health.is_evicted() {
+            continue;
+        }
+        let has_other = resolvers.iter().enumerate().any(|(other, resolver)| {
+            other != index
+                && resolver.added
+                && resolver.unique_path_id.is_some()
+                && !resolver.unusable
+                && !resolver.health.is_evicted()
+        });
+        if has_other {
+            evict_resolver(cnx, &mut resolvers[index], now, None);
+        }
+    }
+}
+
+fn evict_resolver(
+    cnx: *mut picoquic_cnx_t,
+    resolver: &mut ResolverState,
+    now: u64,
+    last_error_rcode: Option<u8>,
+) {
+    if let Some(unique_path_id) = resolver.unique_path_id {
+        unsafe {
+            let _ = picoquic_abandon_path(cnx, unique_path_id, 0, std::ptr::null(), now);
+        }
+    }
+    resolver.health.state = HealthState::Evicted;
+    resolver.health.evictions = resolver.health.evictions.saturating_add(1);
+    resolver.added = false;
+    resolver.path_id = -1;
+    resolver.unique_path_id = None;
+    resolver.local_addr_storage = None;
+    resolver.pending_polls = 0;
+    resolver.inflight_poll_ids.clear();
+    resolver.last_pacing_snapshot = None;
+    resolver.probe_attempts =
+        HEALTH_EVICT_PROBE_ATTEMPTS.saturating_add(resolver.health.evictions - 1);
+    let delay = path_probe_backoff(resolver.probe_attempts);
+    resolver.next_probe_at = now.saturating_add(delay);
+    warn!(
+        "Evicting resolver {} ({} last_rcode={:?}), re-probing in {}ms",
+        resolver.addr,
+        resolver.health.summary(),
+        last_error_rcode,
+        delay / 1000
+    );
+}
+
+fn set_path_status(
+    cnx: *mut picoquic_cnx_t,
+    resolver: &ResolverState,
+    status: picoquic_path_status_enum,
+) {
+    if let Some(unique_path_id) = resolver.unique_path_id {
+        unsafe {
+            let _ = picoquic_set_path_status(cnx, unique_path_id, status);
+        }
+    }
+}
+
+fn path_rtt(cnx: *mut picoquic_cnx_t, resolver: &ResolverState) -> Option<u64> {
+    if !resolver.added || resolver.health.is_evicted() {
+        return None;
+    }
+    let unique_path_id = resolver.unique_path_id?;
+    let mut quality = picoquic_path_quality_t::default();
+    let ret = unsafe { picoquic_get_path_quality(cnx, unique_path_id, &mut quality) };
+    if ret != 0 {
+        return None;
+    }
+    Some(quality.rtt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HealthState, HealthTransition, ResolverHealth, HEALTH_WINDOW_US};
+
+    fn run_window(
+        health: &mut ResolverHealth,
+        now: &mut u64,
+        sent: u64,
+        responses: u64,
+        errors: u64,
+        rtt_us: u64,
+    ) -> Option<HealthTransition> {
+        for _ in 0..sent {
+            health.note_query();
+        }
+        for _ in 0..responses {
+            health.note_response();
+        }
+        for _ in 0..errors {
+            health.note_rcode_error(2);
+        }
+        *now += HEALTH_WINDOW_US;
+        health.evaluate(*now, rtt_us, 50_000)
+    }
+
+    #[test]
+    fn healthy_resolver_stays_healthy() {
+        let mut health = ResolverHealth::new();
+        let mut now = 1;
+        assert_eq!(health.evaluate(now, 50_000, 50_000), None);
+        for _ in 0..5 {
+            assert_eq!(run_window(&mut health, &mut now, 40, 40, 0, 60_000), None);
+        }
+        assert_eq!(health.state, HealthState::Healthy);
+        assert!(health.score > 0.99);
+    }
+
+    #[test]
+    fn servfail_demotes_then_evicts() {
+        let mut health = ResolverHealth::new();
+        let mut now = 1;
+        health.evaluate(now, 50_000, 50_000);
+        assert_eq!(
+            run_window(&mut health, &mut now, 40, 0, 40, 60_000),
+            Some(HealthTransition::Demote)
+        );
+        health.state = HealthState::Demoted;
+        assert_eq!(run_window(&mut health, &mut now, 40, 0, 40, 60_000), None);
+        assert_eq!(
+            run_window(&mut health, &mut now, 40, 0, 40, 60_000),
+            Some(HealthTransition::Evict)
+        );
+    }
+
+    #[test]
+    fn idle_windows_do_not_change_score() {
+        let mut health = ResolverHealth::new();
+        let mut now = 1;
+        health.evaluate(now, 50_000, 50_000);
+        assert_eq!(run_window(&mut health, &mut now, 2, 0, 0, 60_000), None);
+        assert_eq!(health.score, 1.0);
+    }
+
+    #[test]
+    fn slow_resolver_is_demoted_and_recovers() {
+        let mut health = ResolverHealth::new();
+        let mut now = 1;
+        health.evaluate(now, 50_000, 50_000);
+        assert_eq!(
+            run_window(&mut health, &mut now, 40, 40, 0, 4_000_000),
+            Some(HealthTransition::Demote)
+        );
+        health.state = HealthState::Demoted;
+        let mut restored = false;
+        for _ in 0..4 {
+            if run_window(&mut health, &mut now, 40, 40, 0, 60_000)
+                == Some(HealthTransition::Restore)
+            {
+                restored = true;
+                break;
+            }
+        }
+        assert!(restored);
+    }
+
+    #[test]
+    fn readmitted_resolver_starts_demoted() {
+        let mut health = ResolverHealth::new();
+        health.state = HealthState::Evicted;
+        health.readmit(10);
+        assert_eq!(health.state, HealthState::Demoted);
+        assert!(health.score < super::HEALTH_RESTORE_ABOVE);
+    }
+}
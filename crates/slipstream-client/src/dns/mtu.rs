@@ -0,0 +1,498 @@
+use crate::error::ClientError;
+use crate::transport::UdpSocketPool;
+use rand::RngCore;
+use slipstream_dns::{
+    decode_response, probe_answer, QnameEncoding, QueryControl, EDNS_UDP_PAYLOAD,
+};
+use std::fmt;
+use std::future::Future;
+use tracing::{info, warn};
+
+use super::poll::{next_query_id, send_dns_query};
+use super::query::QueryEncoder;
+use super::resolver::ResolverState;
+
+const MTU_PROBE_TIMEOUT_US: u64 = 2_000_000;
+const MTU_PROBE_ATTEMPTS: u32 = 2;
+// Resolver addresses are often anycast, so what sits behind them can change.
+const MTU_REDISCOVERY_INTERVAL_US: u64 = 600_000_000;
+/// Smallest QUIC packet a path must carry upstream in a base32 QNAME to be usable.
+const MIN_UPSTREAM_MTU: usize = 64;
+/// QNAME lengths probed once the minimum got through.
+const QNAME_LEN_STEPS: [usize; 4] = [160, 192, 224, 253];
+/// Answer sizes probed, smallest first. The first is the least a usable path must deliver;
+/// the last is what queries advertise with EDNS, so resolvers never send more.
+const ANSWER_SIZE_STEPS: [u16; 5] = [256, 512, 768, 1024, EDNS_UDP_PAYLOAD];
+/// Answer probes carry a few bytes so the answer still proves which query it belongs to.
+const ANSWER_PROBE_PAYLOAD: usize = 8;
+
+/// What a resolver path was measured to carry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PathLimits {
+    /// Longest QNAME, in characters, that reached the server intact.
+    pub(crate) qname_len: usize,
+    /// Largest DNS answer message that came back intact.
+    pub(crate) answer_size: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MtuStep {
+    Qname(usize),
+    Answer(u16),
+}
+
+impl fmt::Display for MtuStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MtuStep::Qname(len) => write!(f, "a {}-char QNAME", len),
+            MtuStep::Answer(size) => write!(f, "a {}-byte answer", size),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MtuOutcome {
+    Measured(PathLimits),
+    /// The smallest query or answer did not get through.
+    Unusable(MtuStep),
+}
+
+/// Measures a resolver path by sending probes of growing size: first longer QNAMEs with a
+/// minimal answer, then short QNAMEs asking for larger answers. Each ladder stops at the
+/// first size that is mangled, refused or lost on every attempt, and the measurement is
+/// repeated periodically.
+pub(crate) struct MtuDiscovery {
+    qname_steps: Vec<usize>,
+    step: Option<MtuStep>,
+    attempts: u32,
+    outstanding: Option<MtuProbe>,
+    measured: PathLimits,
+    next_round_at: u64,
+}
+
+struct MtuProbe {
+    id: u16,
+    payload: Vec<u8>,
+    answer_len: usize,
+    sent_at: u64,
+}
+
+impl MtuDiscovery {
+    fn new(min_qname_len: usize) -> Self {
+        let qname_steps = std::iter::once(min_qname_len)
+            .chain(
+                QNAME_LEN_STEPS
+                    .into_iter()
+                    .filter(|len| *len > min_qname_len),
+            )
+            .collect();
+        Self {
+            qname_steps,
+            step: None,
+            attempts: 0,
+            outstanding: None,
+            measured: PathLimits {
+                qname_len: 0,
+                answer_size: 0,
+            },
+            next_round_at: 0,
+        }
+    }
+
+    /// The step to probe now: nothing is in flight, or the last probe timed out. Starts a
+    /// new round when one is due.
+    fn next_probe(&mut self, now: u64) -> (Option<MtuStep>, Option<MtuOutcome>) {
+        if let Some(probe) = &self.outstanding {
+            if now < probe.sent_at.saturating_add(MTU_PROBE_TIMEOUT_US) {
+                return (None, None);
+            }
+            self.outstanding = None;
+            if self.attempts >= MTU_PROBE_ATTEMPTS {
+                let outcome = self.fail_step(now);
+                return (self.step, outcome);
+            }
+        }
+        if self.step.is_none() && now >= self.next_round_at {
+            self.step = Some(MtuStep::Qname(self.qname_steps[0]));
+            self.attempts = 0;
+        }
+        (self.step, None)
+    }
+
+    fn note_sent(&mut self, probe: MtuProbe) {
+        self.attempts = self.attempts.saturating_add(1);
+        self.outstanding = Some(probe);
+    }
+
+    /// Settles the probe in flight if `id` is its answer, returning `None` otherwise and the
+    /// round's outcome once it is complete. `answer` is the answer's payload and
+    /// `response_len` the size of the whole DNS message.
+    fn handle_response(
+        &mut self,
+        id: u16,
+        answer: Option<&[u8]>,
+        response_len: usize,
+        now: u64,
+    ) -> Option<Option<MtuOutcome>> {
+        if self.outstanding.as_ref().is_none_or(|probe| probe.id != id) {
+            return None;
+        }
+        let probe = self.outstanding.take()?;
+        let intact =
+            answer.is_some_and(|answer| answer == probe_answer(&probe.payload, answer.len()));
+        if !intact {
+            return Some(self.fail_step(now));
+        }
+        self.attempts = 0;
+        let answer_len = answer.map_or(0, <[u8]>::len);
+        Some(match self.step? {
+            MtuStep::Qname(len) => {
+                self.measured.qname_len = len;
+                let next = self.qname_steps.iter().copied().find(|step| *step > len);
+                self.step =
+                    Some(next.map_or(MtuStep::Answer(ANSWER_SIZE_STEPS[0]), MtuStep::Qname));
+                None
+            }
+            MtuStep::Answer(size) => {
+                // A short answer means the server would not go larger; that is the limit.
+                let capped = answer_len < probe.answer_len;
+                self.measured.answer_size = if capped {
+                    response_len.min(size as usize) as u16
+                } else {
+                    size
+                };
+                let next = ANSWER_SIZE_STEPS.into_iter().find(|step| *step > size);
+                match next {
+                    Some(next) if !capped => {
+                        self.step = Some(MtuStep::Answer(next));
+                        None
+                    }
+                    _ => self.finish_round(now),
+                }
+            }
+        })
+    }
+
+    fn fail_step(&mut self, now: u64) -> Option<MtuOutcome> {
+        self.attempts = 0;
+        match self.step? {
+            MtuStep::Qname(len) if len == self.qname_steps[0] => {
+                self.step = None;
+                self.next_round_at = now.saturating_add(MTU_REDISCOVERY_INTERVAL_US);
+                Some(MtuOutcome::Unusable(MtuStep::Qname(len)))
+            }
+            MtuStep::Qname(_) => {
+                self.step = Some(MtuStep::Answer(ANSWER_SIZE_STEPS[0]));
+                None
+            }
+            MtuStep::Answer(size) if size == ANSWER_SIZE_STEPS[0] => {
+                self.step = None;
+                self.next_round_at = now.saturating_add(MTU_REDISCOVERY_INTERVAL_US);
+                Some(MtuOutcome::Unusable(MtuStep::Answer(size)))
+            }
+            MtuStep::Answer(_) => self.finish_round(now),
+        }
+    }
+
+    fn finish_round(&mut self, now: u64) -> Option<MtuOutcome> {
+        self.step = None;
+        self.next_round_at = now.saturating_add(MTU_REDISCOVERY_INTERVAL_US);
+        Some(MtuOutcome::Measured(self.measured))
+    }
+
+    /// Microseconds until the probe in flight times out or the next round starts.
+    pub(crate) fn wait_us(&self, now: u64) -> Option<u64> {
+        match (&self.outstanding, self.step) {
+            (Some(probe), _) => Some(
+                probe
+                    .sent_at
+                    .saturating_add(MTU_PROBE_TIMEOUT_US)
+                    .saturating_sub(now),
+            ),
+            (None, None) => Some(self.next_round_at.saturating_sub(now)),
+            // A probe is due but waiting on the rate limit.
+            (None, Some(_)) => None,
+        }
+    }
+}
+
+/// Sends the resolver's next path MTU probe if one is due.
+pub(crate) async fn send_mtu_probe(
+    udp: &UdpSocketPool,
+    encoder: &QueryEncoder<'_>,
+    resolver: &mut ResolverState,
+    now: u64,
+) -> Result<(), ClientError> {
+    if resolver.transport.is_none() {
+        return Ok(());
+    }
+    if resolver.mtu_discovery.is_none() {
+        resolver.mtu_discovery = Some(MtuDiscovery::new(min_qname_len(encoder)?));
+    }
+    let Some(discovery) = resolver.mtu_discovery.as_mut() else {
+        return Ok(());
+    };
+    let (step, outcome) = discovery.next_probe(now);
+    if let Some(outcome) = outcome {
+        apply_outcome(resolver, encoder, outcome)?;
+    }
+    let Some(step) = step else {
+        return Ok(());
+    };
+    if !resolver.admit_query(now) {
+        return Ok(());
+    }
+    let id = next_query_id(&resolver.inflight_poll_ids);
+    let (packet, probe) = build_probe(encoder, step, id, now)?;
+    send_dns_query(udp, resolver.transport.as_deref(), resolver.addr, packet).await?;
+    if let Some(discovery) = resolver.mtu_discovery.as_mut() {
+        discovery.note_sent(probe);
+    }
+    Ok(())
+}
+
+/// Runs one discovery round, waiting on each probe in turn. `exchange` sends a query and
+/// returns the response with its ID, or `None` once the probe is given up on.
+pub(crate) async fn measure_path_limits<F, Fut>(
+    encoder: &QueryEncoder<'_>,
+    mut exchange: F,
+) -> Result<MtuOutcome, ClientError>
+where
+    F: FnMut(u16, Vec<u8>) -> Fut,
+    Fut: Future<Output = Result<Option<Vec<u8>>, ClientError>>,
+{
+    let mut discovery = MtuDiscovery::new(min_qname_len(encoder)?);
+    // Only lost probes move this clock, so each one times out as soon as `exchange` says.
+    let mut now = 0;
+    let mut id: u16 = rand::random();
+    loop {
+        let (step, outcome) = discovery.next_probe(now);
+        if let Some(outcome) = outcome {
+            return Ok(outcome);
+        }
+        let Some(step) = step else {
+            return Err(ClientError::new(
+                "Path MTU discovery stopped without a result",
+            ));
+        };
+        id = id.wrapping_add(1);
+        let (packet, probe) = build_probe(encoder, step, id, now)?;
+        discovery.note_sent(probe);
+        match exchange(id, packet).await? {
+            Some(response) => {
+                let answer = decode_response(&response);
+                if let Some(Some(outcome)) =
+                    discovery.handle_response(id, answer.as_deref(), response.len(), now)
+                {
+                    return Ok(outcome);
+                }
+            }
+            None => now += MTU_PROBE_TIMEOUT_US,
+        }
+    }
+}
+
+fn build_probe(
+    encoder: &QueryEncoder<'_>,
+    step: MtuStep,
+    id: u16,
+    now: u64,
+) -> Result<(Vec<u8>, MtuProbe), ClientError> {
+    let (payload_len, answer_size) = match step {
+        MtuStep::Qname(len) => (probe_payload_len(encoder, len)?, 0),
+        MtuStep::Answer(size) => (ANSWER_PROBE_PAYLOAD, size),
+    };
+    let mut payload = vec![0u8; payload_len];
+    rand::thread_rng().fill_bytes(&mut payload);
+    let (packet, answer_len) =
+        encoder.encode_probe(id, &payload, QnameEncoding::Base32, answer_size)?;
+    let probe = MtuProbe {
+        id,
+        payload,
+        answer_len,
+        sent_at: now,
+    };
+    Ok((packet, probe))
+}
+
+/// Consumes `response` if it answers the resolver's path MTU probe, applying the limits
+/// once a round is complete.
+pub(crate) fn handle_mtu_probe_response(
+    resolver: &mut ResolverState,
+    encoder: &QueryEncoder<'_>,
+    id: u16,
+    response: &[u8],
+    now: u64,
+) -> Result<bool, ClientError> {
+    let Some(discovery) = resolver.mtu_discovery.as_mut() else {
+        return Ok(false);
+    };
+    let answer = decode_response(response);
+    match discovery.handle_response(id, answer.as_deref(), response.len(), now) {
+        None => Ok(false),
+        Some(outcome) => {
+            if let Some(outcome) = outcome {
+                apply_outcome(resolver, encoder, outcome)?;
+            }
+            Ok(true)
+        }
+    }
+}
+
+fn apply_outcome(
+    resolver: &mut ResolverState,
+    encoder: &QueryEncoder<'_>,
+    outcome: MtuOutcome,
+) -> Result<(), ClientError> {
+    match outcome {
+        MtuOutcome::Measured(limits) => {
+            let changed = resolver.path_limits != Some(limits) || resolver.unusable;
+            resolver.path_limits = Some(limits);
+            resolver.unusable = false;
+            resolver.update_upstream_mtu(encoder)?;
+            if changed {
+                info!(
+                    "Resolver {} carries QNAMEs up to {} chars and answers up to {} bytes; upstream MTU {}",
+                    resolver.addr, limits.qname_len, limits.answer_size, resolver.upstream_mtu
+                );
+            }
+        }
+        MtuOutcome::Unusable(step) => {
+            if !resolver.unusable {
+                warn!(
+                    "Resolver {} cannot carry {}, the minimum; marking it unusable",
+                    resolver.addr, step
+                );
+            }
+            resolver.unusable = true;
+        }
+    }
+    Ok(())
+}
+
+/// Base32 payload of a QNAME probe that makes the name `qname_len` characters long.
+fn probe_payload_len(encoder: &QueryEncoder<'_>, qname_len: usize) -> Result<usize, ClientError> {
+    encoder.max_payload(QnameEncoding::Base32, QueryControl::Probe(0), qname_len)
+}
+
+/// Shortest QNAME whose probe carries `MIN_UPSTREAM_MTU` bytes, or the longest possible if
+/// the domain leaves no room for that.
+fn min_qname_len(encoder: &QueryEncoder<'_>) -> Result<usize, ClientError> {
+    let longest = QNAME_LEN_STEPS[QNAME_LEN_STEPS.len() - 1];
+    for len in 1..longest {
+        if probe_payload_len(encoder, len)? >= MIN_UPSTREAM_MTU {
+            return Ok(len);
+        }
+    }
+    Ok(longest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        MtuDiscovery, MtuOutcome, MtuProbe, MtuStep, PathLimits, ANSWER_SIZE_STEPS,
+        MTU_PROBE_TIMEOUT_US,
+    };
+    use slipstream_dns::probe_answer;
+
+    fn send(discovery: &mut MtuDiscovery, id: u16, answer_len: usize, now: u64) {
+        discovery.note_sent(MtuProbe {
+            id,
+            payload: vec![id as u8; 4],
+            answer_len,
+            sent_at: now,
+        });
+    }
+
+    fn answer(id: u16, len: usize) -> Vec<u8> {
+        probe_answer(&[id as u8; 4], len)
+    }
+
+    #[test]
+    fn climbs_both_ladders_until_a_step_fails() {
+        let mut discovery = MtuDiscovery::new(120);
+        let mut now = 1;
+        let mut id = 0;
+        let mut qname_lens = Vec::new();
+        loop {
+            let (step, outcome) = discovery.next_probe(now);
+            assert_eq!(outcome, None);
+            let Some(MtuStep::Qname(len)) = step else {
+                break;
+            };
+            qname_lens.push(len);
+            id += 1;
+            send(&mut discovery, id, 8, now);
+            // The resolver truncates anything past 200 characters.
+            let reply = (len <= 200).then(|| answer(id, 8));
+            assert_eq!(
+                discovery.handle_response(id, reply.as_deref(), 100, now),
+                Some(None)
+            );
+            now += 1;
+        }
+        assert_eq!(qname_lens, vec![120, 160, 192, 224]);
+
+        let mut outcome = None;
+        while outcome.is_none() {
+            let (step, _) = discovery.next_probe(now);
+            let Some(MtuStep::Answer(size)) = step else {
+                panic!("expected an answer step");
+            };
+            id += 1;
+            send(&mut discovery, id, size as usize - 60, now);
+            if size > 512 {
+                // Larger answers never arrive.
+                now += MTU_PROBE_TIMEOUT_US;
+                let (retry, _) = discovery.next_probe(now);
+                assert_eq!(retry, Some(MtuStep::Answer(size)));
+                send(&mut discovery, id + 1, size as usize - 60, now);
+                now += MTU_PROBE_TIMEOUT_US;
+                outcome = discovery.next_probe(now).1;
+            } else {
+                let reply = answer(id, size as usize - 60);
+                outcome = discovery
+                    .handle_response(id, Some(&reply), size as usize, now)
+                    .expect("consumed");
+            }
+        }
+        assert_eq!(
+            outcome,
+            Some(MtuOutcome::Measured(PathLimits {
+                qname_len: 192,
+                answer_size: 512,
+            }))
+        );
+        assert_eq!(discovery.next_probe(now + 1), (None, None));
+    }
+
+    #[test]
+    fn reports_paths_that_fail_the_minimum() {
+        let mut discovery = MtuDiscovery::new(120);
+        let (step, _) = discovery.next_probe(1);
+        assert_eq!(step, Some(MtuStep::Qname(120)));
+        send(&mut discovery, 1, 8, 1);
+        assert_eq!(
+            discovery.handle_response(1, Some(&[0u8; 8]), 60, 1),
+            Some(Some(MtuOutcome::Unusable(MtuStep::Qname(120))))
+        );
+
+        let mut discovery = MtuDiscovery::new(253);
+        let (step, _) = discovery.next_probe(1);
+        send(&mut discovery, 1, 8, 1);
+        assert_eq!(step, Some(MtuStep::Qname(253)));
+        assert_eq!(
+            discovery.handle_response(1, Some(&answer(1, 8)), 60, 1),
+            Some(None)
+        );
+        let (step, _) = discovery.next_probe(2);
+        assert_eq!(step, Some(MtuStep::Answer(ANSWER_SIZE_STEPS[0])));
+        send(&mut discovery, 2, 150, 2);
+        assert_eq!(
+            discovery.handle_response(2, None, 60, 2),
+            Some(Some(MtuOutcome::Unusable(MtuStep::Answer(
+                ANSWER_SIZE_STEPS[0]
+            ))))
+        );
+    }
+}
@@ -17,6 +17,9 @@ pub(crate) fn refresh_resolver_path(
     cnx: *mut picoquic_cnx_t,
     resolver: &mut ResolverState,
 ) -> bool {
+    if resolver.health.is_evicted() {
+        return false;
+    }
     if let Some(unique_path_id) = resolver.unique_path_id {
         let path_id = unsafe { slipstream_get_path_id_from_unique(cnx, unique_path_id) };
         if path_id >= 0 {
@@ -52,17 +55,26 @@ pub(crate) fn add_paths(
         return Ok(());
     }
 
+    // Path 0 may have been abandoned after an eviction; any live path has the local address.
     let mut local_storage: sockaddr_storage = unsafe { std::mem::zeroed() };
-    let ret = unsafe { picoquic_get_path_addr(cnx, 0, 1, &mut local_storage) };
-    if ret != 0 {
+    let mut unique_path_ids = std::iter::once(0).chain(
+        resolvers
+            .iter()
+            .filter_map(|resolver| resolver.unique_path_id)
+            .filter(|unique_path_id| *unique_path_id != 0),
+    );
+    let found = unique_path_ids.any(|unique_path_id| unsafe {
+        picoquic_get_path_addr(cnx, unique_path_id, 1, &mut local_storage) == 0
+    });
+    if !found {
         return Ok(());
     }
     let now = unsafe { picoquic_current_time() };
     let primary_mode = resolvers[0].mode;
     let mut default_mode = primary_mode;
 
-    for resolver in resolvers.iter_mut().skip(1) {
-        if resolver.added {
+    for resolver in resolvers.iter_mut() {
+        if resolver.added || resolver.unusable {
             continue;
         }
         if resolver.next_probe_at > now {
@@ -87,7 +99,14 @@ pub(crate) fn add_paths(
         if ret == 0 && path_id >= 0 {
             resolver.added = true;
             resolver.path_id = path_id;
-            info!("Added path {}", resolver.addr);
+            if resolver.health.is_evicted() {
+                resolver.health.readmit(now);
+                resolver.probe_attempts = 0;
+                resolver.next_probe_at = 0;
+                info!("Re-admitted path {} on probation", resolver.addr);
+            } else {
+                info!("Added path {}", resolver.addr);
+            }
             continue;
         }
         resolver.probe_attempts = resolver.probe_attempts.saturating_add(1);
@@ -115,7 +134,7 @@ pub(crate) fn resolver_mode_to_c(mode: ResolverMode) -> libc::c_int {
     }
 }
 
-fn path_probe_backoff(attempts: u32) -> u64 {
+pub(super) fn path_probe_backoff(attempts: u32) -> u64 {
     let shift = attempts.saturating_sub(1).min(6);
     let delay = PATH_PROBE_INITIAL_DELAY_US.saturating_mul(1u64 << shift);
     delay.min(PATH_PROBE_MAX_DELAY_US)
@@ -1,18 +1,31 @@
 use crate::error::ClientError;
-use slipstream_dns::{build_qname, encode_query, QueryParams, CLASS_IN, RR_TXT};
+use crate::transport::{DnsTransport, UdpSocketPool};
+use rand::Rng;
 use slipstream_ffi::picoquic::{
     picoquic_cnx_t, picoquic_current_time, picoquic_prepare_packet_ex, slipstream_request_poll,
 };
 use slipstream_ffi::runtime::sockaddr_storage;
-use slipstream_ffi::{ClientConfig, ResolverMode};
+use slipstream_ffi::ResolverMode;
 use std::collections::HashMap;
-use tokio::net::UdpSocket as TokioUdpSocket;
+use std::net::SocketAddr;
 
 use super::path::refresh_resolver_path;
+use super::query::QueryEncoder;
 use super::resolver::{normalize_dual_stack_addr, sockaddr_storage_to_socket_addr, ResolverState};
 
 const AUTHORITATIVE_POLL_TIMEOUT_US: u64 = 5_000_000;
 
+/// Picks a random query ID that is not already awaiting a response from this resolver.
+pub(crate) fn next_query_id(inflight_poll_ids: &HashMap<u16, u64>) -> u16 {
+    let mut rng = rand::thread_rng();
+    loop {
+        let id = rng.gen();
+        if !inflight_poll_ids.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
 pub(crate) fn expire_inflight_polls(inflight_poll_ids: &mut HashMap<u16, u64>, now: u64) {
     if inflight_poll_ids.is_empty() {
         return;
@@ -32,10 +45,9 @@ pub(crate) fn expire_inflight_polls(inflight_poll_ids: &mut HashMap<u16, u64>, n
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn send_poll_queries(
     cnx: *mut picoquic_cnx_t,
-    udp: &TokioUdpSocket,
-    config: &ClientConfig<'_>,
+    udp: &UdpSocketPool,
+    encoder: &QueryEncoder<'_>,
     local_addr_storage: &mut sockaddr_storage,
-    dns_id: &mut u16,
     resolver: &mut ResolverState,
     remaining: &mut usize,
     send_buf: &mut [u8],
@@ -48,6 +60,10 @@ pub(crate) async fn send_poll_queries(
 
     while remaining_count > 0 {
         let current_time = unsafe { picoquic_current_time() };
+        if resolver.must_defer(current_time) {
+            *remaining = remaining_count;
+            break;
+        }
         unsafe {
             slipstream_request_poll(cnx);
         }
@@ -84,28 +100,14 @@ pub(crate) async fn send_poll_queries(
         resolver.debug.send_packets = resolver.debug.send_packets.saturating_add(1);
         resolver.debug.send_bytes = resolver.debug.send_bytes.saturating_add(send_length as u64);
         resolver.debug.polls_sent = resolver.debug.polls_sent.saturating_add(1);
+        resolver.health.note_query();
 
-        let poll_id = *dns_id;
-        let qname = build_qname(&send_buf[..send_length], config.domain)
-            .map_err(|err| ClientError::new(err.to_string()))?;
-        let params = QueryParams {
-            id: poll_id,
-            qname: &qname,
-            qtype: RR_TXT,
-            qclass: CLASS_IN,
-            rd: true,
-            cd: false,
-            qdcount: 1,
-            is_query: true,
-        };
-        *dns_id = dns_id.wrapping_add(1);
-        let packet = encode_query(&params).map_err(|err| ClientError::new(err.to_string()))?;
+        let poll_id = next_query_id(&resolver.inflight_poll_ids);
+        let packet = resolver.encode_query(encoder, poll_id, &send_buf[..send_length])?;
 
         let dest = sockaddr_storage_to_socket_addr(&addr_to)?;
         let dest = normalize_dual_stack_addr(dest);
-        udp.send_to(&packet, dest)
-            .await
-            .map_err(|err| ClientError::new(err.to_string()))?;
+        send_dns_query(udp, resolver.transport.as_deref(), dest, packet).await?;
         if resolver.mode == ResolverMode::Authoritative {
             resolver.inflight_poll_ids.insert(poll_id, current_time);
         }
@@ -113,3 +115,35 @@ pub(crate) async fn send_poll_queries(
 
     Ok(())
 }
+
+/// Sends queries held back by the resolver's rate limit as its tokens come back.
+pub(crate) async fn flush_deferred_queries(
+    udp: &UdpSocketPool,
+    resolver: &mut ResolverState,
+    now: u64,
+) -> Result<(), ClientError> {
+    while !resolver.deferred_queries.is_empty() && resolver.admit_query(now) {
+        let Some(packet) = resolver.deferred_queries.pop_front() else {
+            break;
+        };
+        send_dns_query(udp, resolver.transport.as_deref(), resolver.addr, packet).await?;
+    }
+    Ok(())
+}
+
+/// Sends an encoded query over the resolver's transport, or straight to `dest` over UDP
+/// when the destination is not a known resolver.
+pub(crate) async fn send_dns_query(
+    udp: &UdpSocketPool,
+    transport: Option<&dyn DnsTransport>,
+    dest: SocketAddr,
+    packet: Vec<u8>,
+) -> Result<(), ClientError> {
+    if let Some(transport) = transport {
+        return transport.send(packet).await;
+    }
+    udp.send_to(&packet, dest)
+        .await
+        .map_err(|err| ClientError::new(err.to_string()))?;
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use crate::error::ClientError;
+use slipstream_dns::{
+    build_encoded_qname, encode_query, max_payload_len_within, max_response_payload_len,
+    response_question_name, QnameEncoding, QueryControl, QueryParams, Question, CLASS_IN, RR_TXT,
+};
+use slipstream_ffi::QnameEncodingPreference;
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+
+/// Turns QUIC packets into DNS queries for the tunnel domain.
+pub(crate) struct QueryEncoder<'a> {
+    domain: &'a str,
+    case: Option<CaseRandomizer>,
+    /// Encodings worth probing for on each path, densest first.
+    candidates: Vec<QnameEncoding>,
+}
+
+impl<'a> QueryEncoder<'a> {
+    pub(crate) fn new(
+        domain: &'a str,
+        randomize_case: bool,
+        preference: QnameEncodingPreference,
+    ) -> Self {
+        let candidates = match preference {
+            QnameEncodingPreference::Base32 => Vec::new(),
+            QnameEncodingPreference::Base64 => vec![QnameEncoding::Base64],
+            QnameEncodingPreference::Raw => vec![QnameEncoding::Raw],
+            QnameEncodingPreference::Auto => vec![QnameEncoding::Raw, QnameEncoding::Base64],
+        };
+        Self {
+            domain,
+            case: randomize_case.then(CaseRandomizer::new),
+            candidates,
+        }
+    }
+
+    pub(crate) fn candidates(&self) -> &[QnameEncoding] {
+        &self.candidates
+    }
+
+    /// Largest QUIC packet that fits in one query with `encoding` behind `control`'s label,
+    /// in a QNAME of at most `max_name_len` characters.
+    pub(crate) fn max_payload(
+        &self,
+        encoding: QnameEncoding,
+        control: QueryControl,
+        max_name_len: usize,
+    ) -> Result<usize, ClientError> {
+        max_payload_len_within(self.domain, encoding, control, max_name_len)
+            .map_err(|err| ClientError::new(err.to_string()))
+    }
+
+    pub(crate) fn encode(
+        &self,
+        id: u16,
+        payload: &[u8],
+        encoding: QnameEncoding,
+        control: QueryControl,
+    ) -> Result<Vec<u8>, ClientError> {
+        let qname = build_encoded_qname(payload, self.domain, encoding, control)
+            .map_err(|err| ClientError::new(err.to_string()))?;
+        self.encode_qname(id, qname)
+    }
+
+    /// A path probe asking for an answer of about `answer_size` bytes. Also returns the
+    /// answer payload length the server should send back.
+    pub(crate) fn encode_probe(
+        &self,
+        id: u16,
+        payload: &[u8],
+        encoding: QnameEncoding,
+        answer_size: u16,
+    ) -> Result<(Vec<u8>, usize), ClientError> {
+        let qname = build_encoded_qname(
+            payload,
+            self.domain,
+            encoding,
+            QueryControl::Probe(answer_size),
+        )
+        .map_err(|err| ClientError::new(err.to_string()))?;
+        let question = Question {
+            name: qname.clone(),
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+        };
+        let answer_len = max_response_payload_len(&question, answer_size);
+        Ok((self.encode_qname(id, qname)?, answer_len))
+    }
+
+    fn encode_qname(&self, id: u16, mut qname: String) -> Result<Vec<u8>, ClientError> {
+        if let Some(case) = &self.case {
+            qname = case.apply(id, &qname, self.case_start(&qname));
+        }
+        let params = QueryParams {
+            id,
+            qname: &qname,
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+            rd: true,
+            cd: false,
+            qdcount: 1,
+            is_query: true,
+        };
+        encode_query(&params).map_err(|err| ClientError::new(err.to_string()))
+    }
+
+    /// With 0x20 enabled, a response is only trusted if its question echoes the exact
+    /// letter case we sent.
+    pub(crate) fn response_case_matches(&self, response: &[u8]) -> bool {
+        let Some(case) = &self.case else {
+            return true;
+        };
+        if response.len() < 2 {
+            return false;
+        }
+        let id = u16::from_be_bytes([response[0], response[1]]);
+        response_question_name(response)
+            .is_some_and(|qname| case.apply(id, &qname, self.case_start(&qname)) == qname)
+    }
+
+    /// Case-sensitive encodings carry payload in the letter case, so only the tunnel
+    /// domain gets randomised for them.
+    fn case_start(&self, qname: &str) -> usize {
+        if !QnameEncoding::from_qname(qname).is_case_sensitive() {
+            return 0;
+        }
+        let suffix_len = self.domain.trim_end_matches('.').len() + 1;
+        qname.len().saturating_sub(suffix_len)
+    }
+}
+
+/// DNS 0x20 letter casing. The case of each letter comes from a keyed hash of the query ID
+/// and the name under a per-process secret, so responses can be checked without
+/// remembering what was sent.
+struct CaseRandomizer {
+    key: RandomState,
+}
+
+impl CaseRandomizer {
+    fn new() -> Self {
+        Self {
+            key: RandomState::new(),
+        }
+    }
+
+    /// Re-cases the letters from byte `start` on; earlier bytes are kept as they are.
+    fn apply(&self, id: u16, qname: &str, start: usize) -> String {
+        let lower = qname.to_ascii_lowercase();
+        let mut out = Vec::with_capacity(lower.len());
+        let mut mask = 0u64;
+        for (index, byte) in lower.bytes().enumerate() {
+            if index % 64 == 0 {
+                mask = self.key.hash_one((id, lower.as_str(), index / 64));
+            }
+            if index < start {
+                out.push(qname.as_bytes()[index]);
+                continue;
+            }
+            let upper = (mask >> (index % 64)) & 1 == 1;
+            out.push(if upper {
+                byte.to_ascii_uppercase()
+            } else {
+                byte
+            });
+        }
+        // Only ASCII letters change case, so the bytes are still valid UTF-8.
+        String::from_utf8(out).unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueryEncoder;
+    use slipstream_dns::{
+        encode_response, QnameEncoding, QueryControl, Question, Rcode, ResponseParams, CLASS_IN,
+        RR_TXT,
+    };
+    use slipstream_ffi::QnameEncodingPreference;
+
+    fn echo(query: &[u8], qname: &str) -> Vec<u8> {
+        let id = u16::from_be_bytes([query[0], query[1]]);
+        let question = Question {
+            name: qname.to_string(),
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+        };
+        encode_response(&ResponseParams {
+            id,
+            rd: true,
+            cd: false,
+            question: &question,
+            payload: Some(b"ok"),
+            rcode: Some(Rcode::Ok),
+        })
+        .expect("response")
+    }
+
+    #[test]
+    fn verifies_echoed_case() {
+        let encoder =
+            QueryEncoder::new("tunnel.example.com", true, QnameEncodingPreference::Base32);
+        let query = encoder
+            .encode(
+                0x1234,
+                &[0xab; 40],
+                QnameEncoding::Base32,
+                QueryControl::None,
+            )
+            .expect("query");
+        let sent = slipstream_dns::decode_query(&query, "tunnel.example.com")
+            .expect("server decodes mixed case")
+            .question
+            .name;
+        assert_ne!(sent, sent.to_ascii_lowercase());
+        assert!(encoder.response_case_matches(&echo(&query, &sent)));
+        assert!(!encoder.response_case_matches(&echo(&query, &sent.to_ascii_lowercase())));
+    }
+
+    #[test]
+    fn accepts_any_case_when_disabled() {
+        let encoder =
+            QueryEncoder::new("tunnel.example.com", false, QnameEncodingPreference::Base32);
+        let query = encoder
+            .encode(7, &[1, 2, 3], QnameEncoding::Base32, QueryControl::None)
+            .expect("query");
+        assert!(encoder.response_case_matches(&echo(&query, "whatever.example.com.")));
+    }
+
+    #[test]
+    fn keeps_case_sensitive_payload_intact() {
+        let domain = "tunnel.example.com";
+        let encoder = QueryEncoder::new(domain, true, QnameEncodingPreference::Auto);
+        let payload: Vec<u8> = (0..64).collect();
+        let query = encoder
+            .encode(
+                0x4321,
+                &payload,
+                QnameEncoding::Base64,
+                QueryControl::AnswerLimit(512),
+            )
+            .expect("query");
+        let decoded = slipstream_dns::decode_query(&query, domain).expect("decodes");
+        assert_eq!(decoded.payload, payload);
+        assert_eq!(decoded.control, QueryControl::AnswerLimit(512));
+        assert!(encoder.response_case_matches(&echo(&query, &decoded.question.name)));
+    }
+}
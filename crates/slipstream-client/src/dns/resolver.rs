@@ -1,19 +1,58 @@
 use crate::error::ClientError;
 use crate::pacing::{PacingBudgetSnapshot, PacingPollBudget};
+use crate::rate_limit::{QueryRateLimit, QueryRateLimiter};
+use crate::transport::{open_transport, path_peer_addr, DnsTransport, TransportContext};
 use slipstream_core::resolve_host_port;
+use slipstream_dns::{QnameEncoding, QueryControl, EDNS_UDP_PAYLOAD};
+use slipstream_ffi::picoquic::{picoquic_abandon_path, picoquic_cnx_t, picoquic_current_time};
 use slipstream_ffi::runtime::sockaddr_storage;
-use slipstream_ffi::{socket_addr_to_storage, ResolverMode, ResolverSpec};
-use std::collections::HashMap;
+use slipstream_ffi::{socket_addr_to_storage, ResolverMode, ResolverSpec, ResolverTransport};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{SocketAddr, SocketAddrV6};
-use tracing::warn;
+use tracing::{info, warn};
 
 use super::debug::DebugMetrics;
+use super::encoding::EncodingNegotiation;
+use super::health::ResolverHealth;
+use super::mtu::{MtuDiscovery, PathLimits};
+use super::query::QueryEncoder;
+
+const DEFERRED_QUERY_MAX: usize = 64;
+
+/// A resolver entry after name resolution; `addr` identifies its QUIC path and
+/// `connect_addr` is where its queries are actually sent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct ResolvedResolver {
+    pub(crate) addr: SocketAddr,
+    pub(crate) connect_addr: SocketAddr,
+    pub(crate) mode: ResolverMode,
+    pub(crate) transport: ResolverTransport,
+}
+
+impl ResolvedResolver {
+    pub(crate) fn new(
+        connect_addr: SocketAddr,
+        mode: ResolverMode,
+        transport: ResolverTransport,
+    ) -> Self {
+        Self {
+            addr: path_peer_addr(&transport, connect_addr),
+            connect_addr,
+            mode,
+            transport,
+        }
+    }
+}
 
 pub(crate) struct ResolverState {
     pub(crate) addr: SocketAddr,
+    pub(crate) connect_addr: SocketAddr,
     pub(crate) storage: sockaddr_storage,
     pub(crate) local_addr_storage: Option<sockaddr_storage>,
     pub(crate) mode: ResolverMode,
+    pub(crate) transport_kind: ResolverTransport,
+    /// Opened by `attach_transports` once the shared UDP socket exists.
+    pub(crate) transport: Option<Box<dyn DnsTransport>>,
     pub(crate) added: bool,
     pub(crate) path_id: libc::c_int,
     pub(crate) unique_path_id: Option<u64>,
@@ -23,14 +62,165 @@ pub(crate) struct ResolverState {
     pub(crate) inflight_poll_ids: HashMap<u16, u64>,
     pub(crate) pacing_budget: Option<PacingPollBudget>,
     pub(crate) last_pacing_snapshot: Option<PacingBudgetSnapshot>,
+    pub(crate) rate_limiter: Option<QueryRateLimiter>,
+    /// Queries picoquic produced while the rate limit was exhausted, sent as tokens return.
+    pub(crate) deferred_queries: VecDeque<Vec<u8>>,
+    /// QNAME encoding in use; base32 until a denser one is negotiated.
+    pub(crate) qname_encoding: QnameEncoding,
+    /// Largest QUIC packet this path's queries can carry with `qname_encoding`.
+    pub(crate) upstream_mtu: u32,
+    pub(crate) encoding_negotiation: Option<EncodingNegotiation>,
+    /// What path MTU discovery last measured; `None` until its first round completes.
+    pub(crate) path_limits: Option<PathLimits>,
+    pub(crate) mtu_discovery: Option<MtuDiscovery>,
+    /// The path failed to carry even the smallest probe; it gets no tunnel traffic.
+    pub(crate) unusable: bool,
     pub(crate) debug: DebugMetrics,
+    pub(crate) health: ResolverHealth,
 }
 
 impl ResolverState {
+    fn new(
+        resolved: &ResolvedResolver,
+        mtu: u32,
+        debug_poll: bool,
+        rate_limit: Option<QueryRateLimit>,
+        is_primary: bool,
+    ) -> Self {
+        let addr = resolved.addr;
+        let mode = resolved.mode;
+        Self {
+            addr,
+            connect_addr: resolved.connect_addr,
+            storage: socket_addr_to_storage(addr),
+            local_addr_storage: None,
+            mode,
+            transport_kind: resolved.transport.clone(),
+            transport: None,
+            added: is_primary,
+            path_id: if is_primary { 0 } else { -1 },
+            unique_path_id: if is_primary { Some(0) } else { None },
+            probe_attempts: 0,
+            next_probe_at: 0,
+            pending_polls: 0,
+            inflight_poll_ids: HashMap::new(),
+            pacing_budget: match mode {
+                ResolverMode::Authoritative => Some(PacingPollBudget::new(mtu)),
+                ResolverMode::Recursive => None,
+            },
+            last_pacing_snapshot: None,
+            // Authoritative resolvers are our own server; only shared resolvers are limited.
+            rate_limiter: match mode {
+                ResolverMode::Authoritative => None,
+                ResolverMode::Recursive => rate_limit.map(QueryRateLimiter::new),
+            },
+            deferred_queries: VecDeque::new(),
+            qname_encoding: QnameEncoding::Base32,
+            upstream_mtu: mtu,
+            encoding_negotiation: None,
+            path_limits: None,
+            mtu_discovery: None,
+            unusable: false,
+            debug: DebugMetrics::new(debug_poll),
+            health: ResolverHealth::new(),
+        }
+    }
+
+    /// The control label this path's queries carry: it asks the server to coalesce packets
+    /// into answers within the answer size the path was found to deliver, or within what
+    /// queries advertise with EDNS. Recursive resolvers advertise their own EDNS size to the
+    /// server, which may be more than they can relay back to us, so the size is always sent.
+    pub(crate) fn query_control(&self) -> QueryControl {
+        let answer_size = self
+            .path_limits
+            .map_or(EDNS_UDP_PAYLOAD, |limits| limits.answer_size)
+            .min(EDNS_UDP_PAYLOAD);
+        QueryControl::Coalesce(answer_size)
+    }
+
+    /// Recomputes `upstream_mtu` from the encoding, control label and measured QNAME limit.
+    pub(crate) fn update_upstream_mtu(
+        &mut self,
+        encoder: &QueryEncoder<'_>,
+    ) -> Result<(), ClientError> {
+        let qname_len = self
+            .path_limits
+            .map_or(usize::MAX, |limits| limits.qname_len);
+        self.upstream_mtu =
+            encoder.max_payload(self.qname_encoding, self.query_control(), qname_len)? as u32;
+        Ok(())
+    }
+
+    /// Encodes a QUIC packet as a query on this path. A packet sized before the path's limits
+    /// tightened may not fit behind the control label; it goes out without it and gets a
+    /// single-packet answer.
+    pub(crate) fn encode_query(
+        &self,
+        encoder: &QueryEncoder<'_>,
+        id: u16,
+        payload: &[u8],
+    ) -> Result<Vec<u8>, ClientError> {
+        let control = if payload.len() > self.upstream_mtu as usize {
+            QueryControl::None
+        } else {
+            self.query_control()
+        };
+        encoder.encode(id, payload, self.qname_encoding, control)
+    }
+
+    /// Takes a slot from the rate limit, counting the refusal in the debug metrics.
+    pub(crate) fn admit_query(&mut self, now: u64) -> bool {
+        let Some(limiter) = self.rate_limiter.as_mut() else {
+            return true;
+        };
+        if limiter.try_acquire(now) {
+            return true;
+        }
+        self.debug.rate_limited = self.debug.rate_limited.saturating_add(1);
+        false
+    }
+
+    /// Whether a fresh query has to wait behind the rate limit; queued ones go first.
+    pub(crate) fn must_defer(&mut self, now: u64) -> bool {
+        !self.deferred_queries.is_empty() || !self.admit_query(now)
+    }
+
+    /// Queues a query for later; returns false (dropping it) once the queue is full.
+    pub(crate) fn defer_query(&mut self, packet: Vec<u8>) -> bool {
+        if self.deferred_queries.len() >= DEFERRED_QUERY_MAX {
+            self.debug.rate_limit_drops = self.debug.rate_limit_drops.saturating_add(1);
+            return false;
+        }
+        self.deferred_queries.push_back(packet);
+        true
+    }
+
+    /// Microseconds until the rate limit lets queued or pending work through, if it is
+    /// what is holding it back.
+    pub(crate) fn rate_limit_wait_us(&mut self, now: u64) -> Option<u64> {
+        if self.deferred_queries.is_empty() && self.pending_polls == 0 {
+            return None;
+        }
+        self.rate_limiter
+            .as_mut()
+            .map(|limiter| limiter.wait_us(now))
+    }
+
     pub(crate) fn label(&self) -> String {
         format!(
-            "path_id={} unique_id={:?} resolver={} mode={:?}",
-            self.path_id, self.unique_path_id, self.addr, self.mode
+            "path_id={} unique_id={:?} resolver={} mode={:?}{}",
+            self.path_id,
+            self.unique_path_id,
+            self.addr,
+            self.mode,
+            match &self.transport_kind {
+                ResolverTransport::Udp => String::new(),
+                ResolverTransport::Doh { url } => format!(" doh={}", url),
+                ResolverTransport::Dot { server_name } => {
+                    format!(" dot={}@{}", server_name, self.connect_addr)
+                }
+                ResolverTransport::Tcp => format!(" tcp={}", self.connect_addr),
+            }
         )
     }
 }
@@ -39,6 +229,7 @@ pub(crate) fn resolve_resolvers(
     resolvers: &[ResolverSpec],
     mtu: u32,
     debug_poll: bool,
+    rate_limit: Option<QueryRateLimit>,
 ) -> Result<Vec<ResolverState>, ClientError> {
     let mut resolved = Vec::with_capacity(resolvers.len());
     let mut seen = HashMap::new();
@@ -46,37 +237,139 @@ pub(crate) fn resolve_resolvers(
         let addr = resolve_host_port(&resolver.resolver)
             .map_err(|err| ClientError::new(err.to_string()))?;
         let addr = normalize_dual_stack_addr(addr);
-        if let Some(existing_mode) = seen.get(&addr) {
+        let entry = ResolvedResolver::new(addr, resolver.mode, resolver.transport.clone());
+        if let Some(existing_mode) = seen.get(&entry.addr) {
             return Err(ClientError::new(format!(
                 "Duplicate resolver address {} (modes: {:?} and {:?})",
                 addr, existing_mode, resolver.mode
             )));
         }
-        seen.insert(addr, resolver.mode);
-        let is_primary = idx == 0;
-        resolved.push(ResolverState {
-            addr,
-            storage: socket_addr_to_storage(addr),
-            local_addr_storage: None,
-            mode: resolver.mode,
-            added: is_primary,
-            path_id: if is_primary { 0 } else { -1 },
-            unique_path_id: if is_primary { Some(0) } else { None },
-            probe_attempts: 0,
-            next_probe_at: 0,
-            pending_polls: 0,
-            inflight_poll_ids: HashMap::new(),
-            pacing_budget: match resolver.mode {
-                ResolverMode::Authoritative => Some(PacingPollBudget::new(mtu)),
-                ResolverMode::Recursive => None,
-            },
-            last_pacing_snapshot: None,
-            debug: DebugMetrics::new(debug_poll),
-        });
+        seen.insert(entry.addr, resolver.mode);
+        resolved.push(ResolverState::new(
+            &entry,
+            mtu,
+            debug_poll,
+            rate_limit,
+            idx == 0,
+        ));
     }
     Ok(resolved)
 }
 
+/// Reconciles the live resolver set with a freshly resolved list: new addresses are queued
+/// for `add_paths`, vanished ones have their paths abandoned. The first resolver carries path 0,
+/// which the connection cannot abandon and the rest of the client treats as the primary, so it
+/// stays even when the list no longer names it. Returns true if anything changed.
+pub(crate) fn apply_resolver_list(
+    cnx: *mut picoquic_cnx_t,
+    resolvers: &mut Vec<ResolverState>,
+    resolved: &[ResolvedResolver],
+    mtu: u32,
+    debug_poll: bool,
+    rate_limit: Option<QueryRateLimit>,
+) -> bool {
+    let current: Vec<ResolvedResolver> = resolvers
+        .iter()
+        .map(|resolver| ResolvedResolver {
+            addr: resolver.addr,
+            connect_addr: resolver.connect_addr,
+            mode: resolver.mode,
+            transport: resolver.transport_kind.clone(),
+        })
+        .collect();
+    let changes = plan_resolver_list(&current, resolved);
+    if let Some(entry) = &changes.primary_changed {
+        warn!(
+            "Resolver {} is now listed as {:?}; the change takes a restart because it carries \
+             the connection's first path",
+            entry.connect_addr, entry.mode
+        );
+    }
+    let now = unsafe { picoquic_current_time() };
+    for &index in &changes.removed {
+        let resolver = resolvers.remove(index);
+        if let Some(unique_path_id) = resolver.unique_path_id {
+            unsafe {
+                let _ = picoquic_abandon_path(cnx, unique_path_id, 0, std::ptr::null(), now);
+            }
+        }
+        info!(
+            "Removing resolver {} ({:?}) from the active set",
+            resolver.connect_addr, resolver.mode
+        );
+    }
+    for entry in &changes.added {
+        info!("Adding resolver {} ({:?})", entry.connect_addr, entry.mode);
+        resolvers.push(ResolverState::new(
+            entry, mtu, debug_poll, rate_limit, false,
+        ));
+    }
+    !changes.removed.is_empty() || !changes.added.is_empty()
+}
+
+/// What reconciling the live resolvers with a refreshed list changes.
+#[derive(Debug, Default)]
+struct ResolverListChanges {
+    /// Indices of live resolvers whose paths go away, highest first.
+    removed: Vec<usize>,
+    added: Vec<ResolvedResolver>,
+    /// The path-0 resolver's address is listed with another mode or transport.
+    primary_changed: Option<ResolvedResolver>,
+}
+
+/// Matches whole entries, so a resolver whose mode or transport changed is removed and added
+/// again with the new one. Path 0 is never removed, so a change to it is only reported.
+fn plan_resolver_list(
+    current: &[ResolvedResolver],
+    resolved: &[ResolvedResolver],
+) -> ResolverListChanges {
+    let wanted: HashSet<&ResolvedResolver> = resolved.iter().collect();
+    let mut changes = ResolverListChanges::default();
+    for (index, entry) in current.iter().enumerate() {
+        if wanted.contains(entry) {
+            continue;
+        }
+        if index == 0 {
+            changes.primary_changed = resolved
+                .iter()
+                .find(|listed| listed.addr == entry.addr)
+                .cloned();
+            continue;
+        }
+        changes.removed.push(index);
+    }
+    changes.removed.reverse();
+    changes.added = resolved
+        .iter()
+        .filter(|listed| {
+            !current.iter().enumerate().any(|(index, entry)| {
+                entry.addr == listed.addr && !changes.removed.contains(&index)
+            })
+        })
+        .cloned()
+        .collect();
+    changes
+}
+
+/// Opens the transport of every resolver that does not have one yet.
+pub(crate) fn attach_transports(
+    resolvers: &mut [ResolverState],
+    ctx: &TransportContext,
+) -> Result<(), ClientError> {
+    for resolver in resolvers.iter_mut() {
+        if resolver.transport.is_some() {
+            continue;
+        }
+        resolver.transport = Some(open_transport(
+            &resolver.transport_kind,
+            resolver.connect_addr,
+            resolver.addr,
+            ctx,
+        )?);
+    }
+    Ok(())
+}
+
 pub(crate) fn reset_resolver_path(resolver: &mut ResolverState) {
     warn!(
         "Path for resolver {} became unavailable; resetting state",
@@ -88,9 +381,13 @@ pub(crate) fn reset_resolver_path(resolver: &mut ResolverState) {
     resolver.local_addr_storage = None;
     resolver.pending_polls = 0;
     resolver.inflight_poll_ids.clear();
+    resolver.deferred_queries.clear();
     resolver.last_pacing_snapshot = None;
-    resolver.probe_attempts = 0;
-    resolver.next_probe_at = 0;
+    // Evicted resolvers keep their re-probe backoff.
+    if !resolver.health.is_evicted() {
+        resolver.probe_attempts = 0;
+        resolver.next_probe_at = 0;
+    }
 }
 
 pub(crate) fn normalize_dual_stack_addr(addr: SocketAddr) -> SocketAddr {
@@ -110,9 +407,9 @@ pub(crate) fn sockaddr_storage_to_socket_addr(
 
 #[cfg(test)]
 mod tests {
-    use super::resolve_resolvers;
+    use super::{plan_resolver_list, resolve_resolvers, ResolvedResolver};
     use slipstream_core::{AddressFamily, HostPort};
-    use slipstream_ffi::{ResolverMode, ResolverSpec};
+    use slipstream_ffi::{ResolverMode, ResolverSpec, ResolverTransport};
 
     #[test]
     fn rejects_duplicate_resolver_addr() {
@@ -124,6 +421,7 @@ mod tests {
                     family: AddressFamily::V4,
                 },
                 mode: ResolverMode::Recursive,
+                transport: ResolverTransport::Udp,
             },
             ResolverSpec {
                 resolver: HostPort {
@@ -132,12 +430,39 @@ mod tests {
                     family: AddressFamily::V4,
                 },
                 mode: ResolverMode::Authoritative,
+                transport: ResolverTransport::Udp,
             },
         ];
 
-        match resolve_resolvers(&resolvers, 900, false) {
+        match resolve_resolvers(&resolvers, 900, false, None) {
             Ok(_) => panic!("expected duplicate resolver error"),
             Err(err) => assert!(err.to_string().contains("Duplicate resolver address")),
         }
     }
+
+    #[test]
+    fn refresh_re_adds_resolvers_whose_mode_changed() {
+        let entry = |addr: &str, mode| {
+            ResolvedResolver::new(addr.parse().expect("addr"), mode, ResolverTransport::Udp)
+        };
+        let current = [
+            entry("192.0.2.1:53", ResolverMode::Recursive),
+            entry("192.0.2.2:53", ResolverMode::Recursive),
+            entry("192.0.2.3:53", ResolverMode::Recursive),
+        ];
+        let resolved = [
+            entry("192.0.2.1:53", ResolverMode::Authoritative),
+            entry("192.0.2.2:53", ResolverMode::Authoritative),
+            entry("192.0.2.3:53", ResolverMode::Recursive),
+        ];
+        let changes = plan_resolver_list(&current, &resolved);
+        assert_eq!(changes.removed, vec![1]);
+        assert_eq!(changes.added, vec![resolved[1].clone()]);
+        // Path 0 stays; its new mode is reported instead of dropped.
+        assert_eq!(changes.primary_changed, Some(resolved[0].clone()));
+
+        let unchanged = plan_resolver_list(&current, &current);
+        assert!(unchanged.removed.is_empty() && unchanged.added.is_empty());
+        assert!(unchanged.primary_changed.is_none());
+    }
 }
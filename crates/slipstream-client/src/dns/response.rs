@@ -1,5 +1,5 @@
 use crate::error::ClientError;
-use slipstream_dns::decode_response;
+use slipstream_dns::{decode_response_payloads, response_rcode};
 use slipstream_ffi::picoquic::{
     picoquic_cnx_t, picoquic_current_time, picoquic_incoming_packet_ex, picoquic_quic_t, sockaddr,
     PICOQUIC_PACKET_LOOP_RECV_MAX,
@@ -7,7 +7,11 @@ use slipstream_ffi::picoquic::{
 use slipstream_ffi::runtime::sockaddr_storage;
 use slipstream_ffi::{socket_addr_to_storage, ResolverMode};
 use std::net::SocketAddr;
+use tracing::warn;
 
+use super::encoding::handle_encoding_probe_response;
+use super::mtu::handle_mtu_probe_response;
+use super::query::QueryEncoder;
 use super::resolver::{normalize_dual_stack_addr, ResolverState};
 
 const MAX_POLL_BURST: usize = PICOQUIC_PACKET_LOOP_RECV_MAX;
@@ -16,6 +20,7 @@ pub(crate) struct DnsResponseContext<'a> {
     pub(crate) quic: *mut picoquic_quic_t,
     pub(crate) local_addr_storage: &'a sockaddr_storage,
     pub(crate) resolvers: &'a mut [ResolverState],
+    pub(crate) encoder: &'a QueryEncoder<'a>,
 }
 
 pub(crate) fn handle_dns_response(
@@ -24,8 +29,30 @@ pub(crate) fn handle_dns_response(
     ctx: &mut DnsResponseContext<'_>,
 ) -> Result<(), ClientError> {
     let peer = normalize_dual_stack_addr(peer);
+    if !ctx.encoder.response_case_matches(buf) {
+        if let Some(resolver) = find_resolver_by_addr(ctx.resolvers, peer) {
+            if resolver.debug.case_mismatches == 0 {
+                warn!(
+                    "Resolver {} does not echo 0x20 query case; dropping its responses",
+                    resolver.addr
+                );
+            }
+            resolver.debug.case_mismatches = resolver.debug.case_mismatches.saturating_add(1);
+        }
+        return Ok(());
+    }
     let response_id = dns_response_id(buf);
-    if let Some(payload) = decode_response(buf) {
+    if let Some(response_id) = response_id {
+        if let Some(resolver) = find_resolver_by_addr(ctx.resolvers, peer) {
+            let now = unsafe { picoquic_current_time() };
+            if handle_mtu_probe_response(resolver, ctx.encoder, response_id, buf, now)?
+                || handle_encoding_probe_response(resolver, ctx.encoder, response_id, buf)?
+            {
+                return Ok(());
+            }
+        }
+    }
+    if let Some(packets) = decode_response_payloads(buf) {
         let resolver_index = ctx
             .resolvers
             .iter()
@@ -40,25 +67,32 @@ pub(crate) fn handle_dns_response(
         } else {
             unsafe { std::ptr::read(ctx.local_addr_storage) }
         };
-        let mut first_cnx: *mut picoquic_cnx_t = std::ptr::null_mut();
+        // The server may coalesce several QUIC packets into one answer, one per record.
         let mut first_path: libc::c_int = -1;
-        let current_time = unsafe { picoquic_current_time() };
-        let ret = unsafe {
-            picoquic_incoming_packet_ex(
-                ctx.quic,
-                payload.as_ptr() as *mut u8,
-                payload.len(),
-                &mut peer_storage as *mut _ as *mut sockaddr,
-                &mut local_storage as *mut _ as *mut sockaddr,
-                0,
-                0,
-                &mut first_cnx,
-                &mut first_path,
-                current_time,
-            )
-        };
-        if ret < 0 {
-            return Err(ClientError::new("Failed processing inbound QUIC packet"));
+        for payload in &packets {
+            let mut packet_cnx: *mut picoquic_cnx_t = std::ptr::null_mut();
+            let mut packet_path: libc::c_int = -1;
+            let current_time = unsafe { picoquic_current_time() };
+            let ret = unsafe {
+                picoquic_incoming_packet_ex(
+                    ctx.quic,
+                    payload.as_ptr() as *mut u8,
+                    payload.len(),
+                    &mut peer_storage as *mut _ as *mut sockaddr,
+                    &mut local_storage as *mut _ as *mut sockaddr,
+                    0,
+                    0,
+                    &mut packet_cnx,
+                    &mut packet_path,
+                    current_time,
+                )
+            };
+            if ret < 0 {
+                return Err(ClientError::new("Failed processing inbound QUIC packet"));
+            }
+            if first_path < 0 {
+                first_path = packet_path;
+            }
         }
         let resolver = if let Some(resolver) = find_resolver_by_path_id(ctx.resolvers, first_path) {
             Some(resolver)
@@ -71,6 +105,7 @@ pub(crate) fn handle_dns_response(
                 resolver.added = true;
             }
             resolver.debug.dns_responses = resolver.debug.dns_responses.saturating_add(1);
+            resolver.health.note_response();
             if let Some(response_id) = response_id {
                 if resolver.mode == ResolverMode::Authoritative {
                     resolver.inflight_poll_ids.remove(&response_id);
@@ -84,6 +119,10 @@ pub(crate) fn handle_dns_response(
     } else if let Some(response_id) = response_id {
         if let Some(resolver) = find_resolver_by_addr(ctx.resolvers, peer) {
             resolver.debug.dns_responses = resolver.debug.dns_responses.saturating_add(1);
+            match response_rcode(buf) {
+                Some(rcode) if rcode != 0 => resolver.health.note_rcode_error(rcode),
+                _ => resolver.health.note_response(),
+            }
             if resolver.mode == ResolverMode::Authoritative {
                 resolver.inflight_poll_ids.remove(&response_id);
             }
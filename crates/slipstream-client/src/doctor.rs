@@ -0,0 +1,621 @@
+//! `slipstream-client doctor`: checks a resolver path end to end and suggests client flags.
+//!
+//! The checks speak plain DNS over UDP using the same probes the client sends during path
+//! discovery, then attempt a QUIC handshake through the resolver.
+
+use crate::dns::{measure_path_limits, MtuOutcome, PathLimits, QueryEncoder};
+use crate::error::ClientError;
+use crate::runtime::{map_io, run_client};
+use rand::RngCore;
+use slipstream_core::resolve_host_port;
+use slipstream_dns::{
+    decode_query, decode_response, encode_query, probe_answer, response_rcode, QnameEncoding,
+    QueryControl, QueryDiagnostic, QueryParams, CLASS_IN, DNS_UDP_PAYLOAD_MIN, RR_TXT,
+};
+use slipstream_ffi::{ClientConfig, QnameEncodingPreference, ResolverMode, ResolverSpec};
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket as TokioUdpSocket;
+
+const PROBE_ATTEMPTS: u32 = 2;
+/// Payload of the small probes used for reachability, case and latency checks.
+const SMALL_PROBE_PAYLOAD: usize = 8;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+/// Loss above this share of latency samples is worth a warning.
+const LOSS_WARN_PERCENT: usize = 10;
+const RCODE_NAME_ERROR: u8 = 3;
+const FLAG_RECURSION_AVAILABLE: u8 = 0x80;
+
+pub(crate) struct DoctorConfig<'a> {
+    pub(crate) domain: &'a str,
+    pub(crate) resolver: ResolverSpec,
+    pub(crate) cert: Option<&'a str>,
+    /// How long to wait for each response.
+    pub(crate) timeout: Duration,
+    /// Probes sent to measure latency and loss.
+    pub(crate) samples: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    statuses: Vec<Status>,
+}
+
+impl Report {
+    fn record(&mut self, name: &str, status: Status, detail: impl fmt::Display) -> Status {
+        println!("  [{}] {:<12} {}", status, name, detail);
+        self.statuses.push(status);
+        status
+    }
+
+    fn count(&self, status: Status) -> usize {
+        self.statuses
+            .iter()
+            .filter(|recorded| **recorded == status)
+            .count()
+    }
+}
+
+/// What the checks learned that shapes the suggested flags.
+struct Findings {
+    /// The resolver address answered as the server itself, without recursion.
+    direct: bool,
+    case_preserved: bool,
+    limits: Option<PathLimits>,
+    encoding: QnameEncoding,
+}
+
+enum ProbeReply {
+    Intact(Duration),
+    /// A response came back, but without the expected answer; carries its RCODE.
+    Mangled(u8),
+    Lost,
+}
+
+struct Prober<'a> {
+    socket: TokioUdpSocket,
+    encoder: QueryEncoder<'a>,
+    timeout: Duration,
+}
+
+impl Prober<'_> {
+    /// Sends `query` and waits for the response carrying `id`, or `None` on timeout.
+    async fn exchange(
+        &self,
+        id: u16,
+        query: Vec<u8>,
+    ) -> Result<Option<(Vec<u8>, Duration)>, ClientError> {
+        let sent_at = Instant::now();
+        self.socket.send(&query).await.map_err(map_io)?;
+        let deadline = tokio::time::Instant::from_std(sent_at + self.timeout);
+        let mut buf = vec![0u8; 4096];
+        loop {
+            let len = match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                Ok(Ok(len)) => len,
+                // ICMP errors surface here; to the checks they are just lost queries.
+                Ok(Err(_)) | Err(_) => return Ok(None),
+            };
+            if len >= 2 && u16::from_be_bytes([buf[0], buf[1]]) == id {
+                return Ok(Some((buf[..len].to_vec(), sent_at.elapsed())));
+            }
+        }
+    }
+
+    /// Sends a probe carrying `payload` up to `attempts` times and checks that the answer
+    /// proves it reached the server intact.
+    async fn probe(
+        &self,
+        payload: &[u8],
+        encoding: QnameEncoding,
+        attempts: u32,
+    ) -> Result<ProbeReply, ClientError> {
+        for _ in 0..attempts {
+            let id = rand::random();
+            let (query, _) = self.encoder.encode_probe(id, payload, encoding, 0)?;
+            let Some((response, rtt)) = self.exchange(id, query).await? else {
+                continue;
+            };
+            return Ok(match decode_response(&response) {
+                Some(answer) if answer == probe_answer(payload, answer.len()) => {
+                    ProbeReply::Intact(rtt)
+                }
+                _ => ProbeReply::Mangled(response_rcode(&response).unwrap_or(0)),
+            });
+        }
+        Ok(ProbeReply::Lost)
+    }
+}
+
+/// Runs every check, printing the report as it goes. Returns whether none failed.
+pub(crate) async fn run_doctor(config: &DoctorConfig<'_>) -> Result<bool, ClientError> {
+    let addr = resolve_host_port(&config.resolver.resolver)
+        .map_err(|err| ClientError::new(err.to_string()))?;
+    let bind_addr = match addr {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = TokioUdpSocket::bind(bind_addr).await.map_err(map_io)?;
+    socket.connect(addr).await.map_err(map_io)?;
+    let prober = Prober {
+        socket,
+        encoder: QueryEncoder::new(config.domain, false, QnameEncodingPreference::Base32),
+        timeout: config.timeout,
+    };
+
+    let mode = match config.resolver.mode {
+        ResolverMode::Recursive => "recursive",
+        ResolverMode::Authoritative => "authoritative",
+    };
+    println!(
+        "Checking {} ({}) for {}",
+        addr,
+        mode,
+        config.domain.trim_end_matches('.')
+    );
+    let mut report = Report::default();
+    if let Some(findings) = run_dns_checks(config, &prober, &mut report).await? {
+        check_handshake(config, &mut report).await;
+        print_suggestions(config, &prober.encoder, &findings)?;
+    }
+    let failed = report.count(Status::Fail);
+    let warned = report.count(Status::Warn);
+    println!(
+        "Result: {} ({} failed, {} warnings)",
+        if failed == 0 { "PASS" } else { "FAIL" },
+        failed,
+        warned
+    );
+    Ok(failed == 0)
+}
+
+/// The DNS checks, in the order they depend on each other. Returns `None` if a check the
+/// rest rely on failed.
+async fn run_dns_checks(
+    config: &DoctorConfig<'_>,
+    prober: &Prober<'_>,
+    report: &mut Report,
+) -> Result<Option<Findings>, ClientError> {
+    if check_reachability(config, prober, report).await? == Status::Fail {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0u8; SMALL_PROBE_PAYLOAD];
+    rand::thread_rng().fill_bytes(&mut payload);
+    let status = match prober
+        .probe(&payload, QnameEncoding::Base32, PROBE_ATTEMPTS)
+        .await?
+    {
+        ProbeReply::Intact(rtt) => report.record(
+            "server",
+            Status::Pass,
+            format!("a slipstream server answered in {} ms", rtt.as_millis()),
+        ),
+        ProbeReply::Mangled(RCODE_NAME_ERROR) => report.record(
+            "server",
+            Status::Fail,
+            "NXDOMAIN for tunnel queries: the zone is not delegated to the server, or the server predates path probes",
+        ),
+        ProbeReply::Mangled(rcode) => report.record(
+            "server",
+            Status::Fail,
+            format!("tunnel queries came back without the expected answer (RCODE {})", rcode),
+        ),
+        ProbeReply::Lost => report.record(
+            "server",
+            Status::Fail,
+            "tunnel queries went unanswered; check the NS delegation and that the server is running",
+        ),
+    };
+    if status == Status::Fail {
+        return Ok(None);
+    }
+
+    let (direct, case_preserved) = check_diagnostic(config, prober, report).await?;
+
+    let exchange = |id, query| async move {
+        Ok(prober
+            .exchange(id, query)
+            .await?
+            .map(|(response, _)| response))
+    };
+    let limits = match measure_path_limits(&prober.encoder, exchange).await? {
+        MtuOutcome::Measured(limits) => {
+            report.record(
+                "qname size",
+                Status::Pass,
+                format!("QNAMEs up to {} chars arrive intact", limits.qname_len),
+            );
+            let status = if (limits.answer_size as usize) < 512 {
+                Status::Warn
+            } else {
+                Status::Pass
+            };
+            report.record(
+                "answer size",
+                status,
+                format!(
+                    "answers up to {} bytes come back intact",
+                    limits.answer_size
+                ),
+            );
+            Some(limits)
+        }
+        MtuOutcome::Unusable(step) => {
+            report.record(
+                "path size",
+                Status::Fail,
+                format!("the path cannot carry {}, the minimum", step),
+            );
+            None
+        }
+    };
+
+    let encoding = match limits {
+        Some(limits) => check_encodings(prober, limits, report).await?,
+        None => QnameEncoding::Base32,
+    };
+    check_latency(config, prober, report).await?;
+
+    Ok(Some(Findings {
+        direct,
+        case_preserved,
+        limits,
+        encoding,
+    }))
+}
+
+async fn check_reachability(
+    config: &DoctorConfig<'_>,
+    prober: &Prober<'_>,
+    report: &mut Report,
+) -> Result<Status, ClientError> {
+    // Any response will do, even an error for the bare tunnel domain.
+    let qname = format!("{}.", config.domain.trim_end_matches('.'));
+    for _ in 0..PROBE_ATTEMPTS {
+        let id = rand::random();
+        let query = encode_query(&QueryParams {
+            id,
+            qname: &qname,
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+            rd: true,
+            cd: false,
+            qdcount: 1,
+            is_query: true,
+        })
+        .map_err(|err| ClientError::new(err.to_string()))?;
+        if let Some((_, rtt)) = prober.exchange(id, query).await? {
+            return Ok(report.record(
+                "reachable",
+                Status::Pass,
+                format!("the resolver answered in {} ms", rtt.as_millis()),
+            ));
+        }
+    }
+    Ok(report.record(
+        "reachable",
+        Status::Fail,
+        format!(
+            "no response after {} queries; check the address and that UDP/53 is not filtered",
+            PROBE_ATTEMPTS
+        ),
+    ))
+}
+
+/// Asks the server what it received: the QNAME's letter case and the resolver's EDNS
+/// size. Returns whether the address is the server itself and whether case survives.
+async fn check_diagnostic(
+    config: &DoctorConfig<'_>,
+    prober: &Prober<'_>,
+    report: &mut Report,
+) -> Result<(bool, bool), ClientError> {
+    let cased = QueryEncoder::new(config.domain, true, QnameEncodingPreference::Base32);
+    let mut payload = vec![0u8; SMALL_PROBE_PAYLOAD];
+    rand::thread_rng().fill_bytes(&mut payload);
+    for _ in 0..PROBE_ATTEMPTS {
+        let id = rand::random();
+        let query = cased.encode(
+            id,
+            &payload,
+            QnameEncoding::Base32,
+            QueryControl::Diagnostic,
+        )?;
+        let sent_qname = decode_query(&query, config.domain)
+            .ok()
+            .map(|query| query.question.name);
+        let Some((response, _)) = prober.exchange(id, query).await? else {
+            continue;
+        };
+        let Some(diagnostic) = decode_response(&response)
+            .as_deref()
+            .and_then(QueryDiagnostic::decode)
+        else {
+            report.record(
+                "diagnostic",
+                Status::Warn,
+                "the server did not understand the diagnostic query; it predates this client",
+            );
+            return Ok((false, false));
+        };
+        let direct = response
+            .get(3)
+            .is_some_and(|flags| flags & FLAG_RECURSION_AVAILABLE == 0);
+        report.record(
+            "peer",
+            Status::Pass,
+            if direct {
+                format!("the server answered directly; it saw {}", diagnostic.peer)
+            } else {
+                format!("queries reach the server from {}", diagnostic.peer)
+            },
+        );
+
+        let server_case = sent_qname.as_deref() == Some(diagnostic.qname.as_str());
+        let echoed_case = cased.response_case_matches(&response);
+        let case_preserved = server_case && echoed_case;
+        match (server_case, echoed_case) {
+            (true, true) => {
+                report.record("case", Status::Pass, "QNAME letter case survives both ways")
+            }
+            (true, false) => report.record(
+                "case",
+                Status::Warn,
+                "the resolver does not echo QNAME letter case; leave --dns-0x20 off",
+            ),
+            (false, _) => report.record(
+                "case",
+                Status::Warn,
+                "the resolver rewrites QNAME letter case; case-sensitive encodings will not work",
+            ),
+        };
+
+        match diagnostic.edns_udp_payload {
+            Some(size) if size as usize >= 1232 => report.record(
+                "edns",
+                Status::Pass,
+                format!("queries reach the server advertising {} bytes", size),
+            ),
+            Some(size) => report.record(
+                "edns",
+                Status::Warn,
+                format!(
+                    "queries reach the server advertising only {} bytes, limiting answers",
+                    size
+                ),
+            ),
+            None => report.record(
+                "edns",
+                Status::Warn,
+                format!(
+                    "the resolver drops EDNS, so answers are limited to {} bytes",
+                    DNS_UDP_PAYLOAD_MIN
+                ),
+            ),
+        };
+        return Ok((direct, case_preserved));
+    }
+    report.record(
+        "diagnostic",
+        Status::Warn,
+        "diagnostic queries went unanswered",
+    );
+    Ok((false, false))
+}
+
+/// Probes the denser QNAME encodings at the measured name length; returns the densest that
+/// arrives intact.
+async fn check_encodings(
+    prober: &Prober<'_>,
+    limits: PathLimits,
+    report: &mut Report,
+) -> Result<QnameEncoding, ClientError> {
+    let mut rejected = Vec::new();
+    for encoding in [QnameEncoding::Raw, QnameEncoding::Base64] {
+        let payload_len =
+            prober
+                .encoder
+                .max_payload(encoding, QueryControl::Probe(0), limits.qname_len)?;
+        let mut payload = vec![0u8; payload_len];
+        rand::thread_rng().fill_bytes(&mut payload);
+        if let ProbeReply::Intact(_) = prober.probe(&payload, encoding, PROBE_ATTEMPTS).await? {
+            report.record(
+                "encoding",
+                Status::Pass,
+                format!("{} QNAMEs arrive intact", encoding),
+            );
+            return Ok(encoding);
+        }
+        rejected.push(encoding.to_string());
+    }
+    report.record(
+        "encoding",
+        Status::Pass,
+        format!(
+            "only base32 QNAMEs arrive intact ({} were mangled)",
+            rejected.join(" and ")
+        ),
+    );
+    Ok(QnameEncoding::Base32)
+}
+
+async fn check_latency(
+    config: &DoctorConfig<'_>,
+    prober: &Prober<'_>,
+    report: &mut Report,
+) -> Result<(), ClientError> {
+    let mut rtts = Vec::with_capacity(config.samples);
+    for _ in 0..config.samples {
+        let mut payload = vec![0u8; SMALL_PROBE_PAYLOAD];
+        rand::thread_rng().fill_bytes(&mut payload);
+        if let ProbeReply::Intact(rtt) = prober.probe(&payload, QnameEncoding::Base32, 1).await? {
+            rtts.push(rtt);
+        }
+    }
+    let lost = config.samples - rtts.len();
+    let loss_percent = lost * 100 / config.samples.max(1);
+    if rtts.is_empty() {
+        report.record("latency", Status::Fail, "every latency probe was lost");
+        return Ok(());
+    }
+    rtts.sort();
+    let median = rtts[rtts.len() / 2];
+    let p90 = rtts[(rtts.len() * 9 / 10).min(rtts.len() - 1)];
+    let status = if loss_percent > LOSS_WARN_PERCENT {
+        Status::Warn
+    } else {
+        Status::Pass
+    };
+    report.record(
+        "latency",
+        status,
+        format!(
+            "median {} ms, p90 {} ms, {}/{} lost ({}%)",
+            median.as_millis(),
+            p90.as_millis(),
+            lost,
+            config.samples,
+            loss_percent
+        ),
+    );
+    Ok(())
+}
+
+async fn check_handshake(config: &DoctorConfig<'_>, report: &mut Report) {
+    let resolvers = [config.resolver.clone()];
+    let client_config = ClientConfig {
+        tcp_listen_port: None,
+        unix_listen_path: None,
+        local_forwards: &[],
+        remote_forwards: &[],
+        udp_forwards: &[],
+        udp_idle_timeout: 0,
+        tun: None,
+        transparent: false,
+        resolvers: &resolvers,
+        resolvers_file: None,
+        system_resolvers: false,
+        resolver_refresh_interval: 0,
+        domain: config.domain,
+        cert: config.cert,
+        congestion_control: None,
+        gso: false,
+        keep_alive_interval: 0,
+        debug_poll: false,
+        doh_get: false,
+        max_qps: 0,
+        qps_burst: 0,
+        query_jitter_ms: 0,
+        udp_sockets: 1,
+        dns_0x20: false,
+        qname_encoding: QnameEncodingPreference::Base32,
+        debug_streams: false,
+        handshake_only: true,
+        stdio: false,
+    };
+    let started = Instant::now();
+    let pinned = if config.cert.is_some() {
+        " and the certificate pin matched"
+    } else {
+        ""
+    };
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, run_client(&client_config)).await {
+        Ok(Ok(_)) => report.record(
+            "handshake",
+            Status::Pass,
+            format!(
+                "QUIC handshake completed in {} ms{}",
+                started.elapsed().as_millis(),
+                pinned
+            ),
+        ),
+        Ok(Err(err)) if config.cert.is_some() => report.record(
+            "handshake",
+            Status::Fail,
+            format!("{}; check that --cert is the server's certificate", err),
+        ),
+        Ok(Err(err)) => report.record("handshake", Status::Fail, err.to_string()),
+        Err(_) => report.record(
+            "handshake",
+            Status::Fail,
+            format!("no QUIC handshake within {} s", HANDSHAKE_TIMEOUT.as_secs()),
+        ),
+    };
+}
+
+/// `host:port` as the resolver flags parse it back, with IPv6 hosts in brackets.
+fn resolver_arg(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn print_suggestions(
+    config: &DoctorConfig<'_>,
+    encoder: &QueryEncoder<'_>,
+    findings: &Findings,
+) -> Result<(), ClientError> {
+    let resolver = &config.resolver.resolver;
+    let mut flags = vec![
+        format!("--domain {}", config.domain.trim_end_matches('.')),
+        if findings.direct {
+            format!(
+                "--authoritative {}",
+                resolver_arg(&resolver.host, resolver.port)
+            )
+        } else {
+            format!("--resolver {}", resolver_arg(&resolver.host, resolver.port))
+        },
+    ];
+    if findings.encoding != QnameEncoding::Base32 {
+        flags.push(format!("--qname-encoding {}", findings.encoding));
+    }
+    if findings.case_preserved {
+        flags.push("--dns-0x20".to_string());
+    }
+    if let Some(cert) = config.cert {
+        flags.push(format!("--cert {}", cert));
+    }
+    println!("Suggested client flags:");
+    println!("  slipstream-client {}", flags.join(" "));
+    if let Some(limits) = findings.limits {
+        let upstream =
+            encoder.max_payload(findings.encoding, QueryControl::None, limits.qname_len)?;
+        println!(
+            "Expected path MTU: {} bytes up and answers of {} bytes down per query; the client measures and applies this itself",
+            upstream, limits.answer_size
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolver_arg;
+
+    #[test]
+    fn resolver_arg_brackets_ipv6_hosts() {
+        assert_eq!(resolver_arg("::1", 53), "[::1]:53");
+        assert_eq!(resolver_arg("1.1.1.1", 53), "1.1.1.1:53");
+        assert_eq!(resolver_arg("ns.example.com", 5353), "ns.example.com:5353");
+    }
+}
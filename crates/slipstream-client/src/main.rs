@@ -1,32 +1,99 @@
+mod bench;
 mod dns;
+mod doctor;
 mod error;
 mod pacing;
 mod pinning;
+mod rate_limit;
+mod resolver_list;
 mod runtime;
 mod streams;
+mod transport;
+mod tun;
+mod udp;
+mod unix;
 
+use clap::parser::ValueSource;
 use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser};
-use slipstream_core::{normalize_domain, parse_host_port, AddressKind, HostPort};
-use slipstream_ffi::{ClientConfig, ResolverMode, ResolverSpec};
-use tokio::runtime::Builder;
+use slipstream_core::forward::{parse_forward_spec, ForwardSpec};
+use slipstream_core::{normalize_domain, parse_host_port, parse_unix_path, AddressKind, HostPort};
+use slipstream_ffi::{
+    ClientConfig, QnameEncodingPreference, ResolverMode, ResolverSpec, ResolverTransport,
+};
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
 use tracing_subscriber::EnvFilter;
 
+use bench::{run_bench, BenchPlan};
+use doctor::{run_doctor, DoctorConfig};
 use runtime::run_client;
 
 #[derive(Parser, Debug)]
 #[command(
     name = "slipstream-client",
     about = "slipstream-client - A high-performance covert channel over DNS (client)",
+    after_help = "Run `slipstream-client doctor --help` to check a resolver path before use, \
+                  or `slipstream-client bench --help` to measure a tunnel.",
     group(
         ArgGroup::new("resolvers")
             .required(true)
             .multiple(true)
-            .args(["resolver", "authoritative"])
+            .args([
+                "resolver",
+                "authoritative",
+                "doh",
+                "dot",
+                "tcp_resolver",
+                "resolvers_file",
+                "system_resolvers",
+            ])
     )
 )]
 struct Args {
-    #[arg(long = "tcp-listen-port", short = 'l', default_value_t = 5201)]
-    tcp_listen_port: u16,
+    /// Listen port, or unix:/path, for the server's default target; with forwards or --tun it
+    /// only listens when given, and with --transparent it takes redirected connections.
+    #[arg(
+        long = "tcp-listen-port",
+        short = 'l',
+        value_name = "PORT|unix:PATH",
+        default_value = "5201",
+        value_parser = parse_listen_addr
+    )]
+    tcp_listen_port: ListenAddr,
+    /// Forward [bind:]port to host:hostport through the server (repeatable).
+    #[arg(
+        long = "local-forward",
+        short = 'L',
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = parse_forward
+    )]
+    local_forwards: Vec<ForwardSpec>,
+    /// Have the server listen on [bind:]port and forward to host:hostport here (repeatable).
+    #[arg(
+        long = "remote-forward",
+        short = 'R',
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = parse_forward
+    )]
+    remote_forwards: Vec<ForwardSpec>,
+    /// Relay UDP on [bind:]port to host:hostport through the server (repeatable).
+    #[arg(
+        long = "udp-forward",
+        short = 'U',
+        value_name = "[BIND:]PORT:HOST:HOSTPORT",
+        value_parser = parse_forward
+    )]
+    udp_forwards: Vec<ForwardSpec>,
+    /// Seconds a UDP flow may stay silent before it is closed.
+    #[arg(long = "udp-idle-timeout", value_name = "SECS", default_value_t = 60)]
+    udp_idle_timeout: u64,
+    /// Carry every IP packet of this TUN device to the server's (Linux only).
+    #[arg(long = "tun", value_name = "NAME")]
+    tun: Option<String>,
+    /// Send each connection on the listen port to where it was headed before an iptables
+    /// REDIRECT (Linux only).
+    #[arg(long = "transparent")]
+    transparent: bool,
     #[arg(long = "resolver", short = 'r', value_parser = parse_resolver)]
     resolver: Vec<HostPort>,
     #[arg(
@@ -37,6 +104,46 @@ struct Args {
     congestion_control: Option<String>,
     #[arg(long = "authoritative", value_parser = parse_resolver)]
     authoritative: Vec<HostPort>,
+    #[arg(long = "doh", value_name = "URL", value_parser = parse_doh)]
+    doh: Vec<String>,
+    #[arg(long = "doh-method", value_enum, default_value = "post")]
+    doh_method: transport::DohMethod,
+    #[arg(long = "dot", value_name = "HOST[:PORT]", value_parser = parse_dot)]
+    dot: Vec<HostPort>,
+    #[arg(long = "tcp-resolver", value_name = "HOST[:PORT]", value_parser = parse_resolver)]
+    tcp_resolver: Vec<HostPort>,
+    #[arg(long = "resolvers-file", value_name = "PATH")]
+    resolvers_file: Option<String>,
+    #[arg(long = "system-resolvers")]
+    system_resolvers: bool,
+    #[arg(
+        long = "resolver-refresh-interval",
+        value_name = "SECS",
+        default_value_t = 300
+    )]
+    resolver_refresh_interval: u64,
+    #[arg(long = "max-qps", value_name = "QPS", default_value_t = 0)]
+    max_qps: u32,
+    #[arg(long = "qps-burst", value_name = "N", default_value_t = 10)]
+    qps_burst: u32,
+    #[arg(long = "query-jitter-ms", value_name = "MS", default_value_t = 0)]
+    query_jitter_ms: u64,
+    #[arg(
+        long = "udp-sockets",
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u16).range(1..=64)
+    )]
+    udp_sockets: u16,
+    #[arg(long = "dns-0x20")]
+    dns_0x20: bool,
+    #[arg(
+        long = "qname-encoding",
+        value_name = "ENCODING",
+        default_value = "base32",
+        value_parser = parse_qname_encoding
+    )]
+    qname_encoding: QnameEncodingPreference,
     #[arg(
         short = 'g',
         long = "gso",
@@ -55,52 +162,278 @@ struct Args {
     debug_poll: bool,
     #[arg(long = "debug-streams")]
     debug_streams: bool,
+    /// Bridge one stream to stdin/stdout and exit when it closes, e.g. for SSH ProxyCommand.
+    #[arg(
+        long = "stdio",
+        conflicts_with_all = [
+            "tcp_listen_port",
+            "local_forwards",
+            "remote_forwards",
+            "udp_forwards",
+            "tun",
+            "transparent"
+        ]
+    )]
+    stdio: bool,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "slipstream-client doctor",
+    about = "Check that a resolver path can carry the tunnel and suggest client flags",
+    group(
+        ArgGroup::new("resolvers")
+            .required(true)
+            .args(["resolver", "authoritative"])
+    )
+)]
+struct DoctorArgs {
+    #[arg(long = "domain", short = 'd', value_parser = parse_domain)]
+    domain: String,
+    #[arg(long = "resolver", short = 'r', value_parser = parse_resolver)]
+    resolver: Option<HostPort>,
+    #[arg(long = "authoritative", value_parser = parse_resolver)]
+    authoritative: Option<HostPort>,
+    #[arg(long = "cert", value_name = "PATH")]
+    cert: Option<String>,
+    #[arg(long = "timeout-ms", value_name = "MS", default_value_t = 2000)]
+    timeout_ms: u64,
+    #[arg(
+        long = "samples",
+        value_name = "N",
+        default_value_t = 20,
+        value_parser = clap::value_parser!(u16).range(1..=1000)
+    )]
+    samples: u16,
+}
+
+#[derive(Parser, Debug)]
+#[command(
+    name = "slipstream-client bench",
+    about = "Measure upload, download and latency through a tunnel to a server run with --bench"
+)]
+struct BenchArgs {
+    #[command(flatten)]
+    client: Args,
+    #[arg(
+        long = "streams",
+        value_name = "N",
+        default_value_t = 4,
+        value_parser = clap::value_parser!(u16).range(1..=256)
+    )]
+    streams: u16,
+    #[arg(long = "bytes", value_name = "BYTES", default_value_t = 1 << 20)]
+    bytes: u64,
+    #[arg(long = "pings", value_name = "N", default_value_t = 20)]
+    pings: u16,
+    #[arg(
+        long = "ping-size",
+        value_name = "BYTES",
+        default_value_t = 64,
+        value_parser = clap::value_parser!(u16).range(1..)
+    )]
+    ping_size: u16,
+    #[arg(long = "timeout", value_name = "SECS", default_value_t = 300)]
+    timeout: u64,
+    #[arg(long = "json")]
+    json: bool,
 }
 
 fn main() {
-    init_logging();
+    match std::env::args_os().nth(1) {
+        Some(arg) if arg == "doctor" => {
+            init_logging("warn");
+            let args = DoctorArgs::parse_from(std::env::args_os().skip(1));
+            std::process::exit(run_doctor_command(args));
+        }
+        Some(arg) if arg == "bench" => {
+            init_logging("warn");
+            let matches = BenchArgs::command().get_matches_from(std::env::args_os().skip(1));
+            let args = BenchArgs::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+            std::process::exit(run_bench_command(args, &matches));
+        }
+        _ => {}
+    }
     let matches = Args::command().get_matches();
     let args = Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    // Keep stdio sessions quiet; their stderr usually lands in the user's terminal.
+    init_logging(if args.stdio { "warn" } else { "info" });
     let resolvers = build_resolvers(&matches).unwrap_or_else(|err| {
         tracing::error!("Resolver error: {}", err);
         std::process::exit(2);
     });
 
-    let config = ClientConfig {
-        tcp_listen_port: args.tcp_listen_port,
-        resolvers: &resolvers,
+    if args.transparent && matches!(args.tcp_listen_port, ListenAddr::Unix(_)) {
+        tracing::error!("--transparent needs a TCP listen port");
+        std::process::exit(2);
+    }
+    let mut config = client_config(&args, &resolvers);
+    let forwards = !args.local_forwards.is_empty()
+        || !args.remote_forwards.is_empty()
+        || !args.udp_forwards.is_empty()
+        || args.tun.is_some();
+    if forwards
+        && !args.transparent
+        && matches.value_source("tcp_listen_port") != Some(ValueSource::CommandLine)
+    {
+        config.tcp_listen_port = None;
+        config.unix_listen_path = None;
+    }
+    match build_runtime().block_on(run_client(&config)) {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            tracing::error!("Client error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn client_config<'a>(args: &'a Args, resolvers: &'a [ResolverSpec]) -> ClientConfig<'a> {
+    ClientConfig {
+        tcp_listen_port: match args.tcp_listen_port {
+            ListenAddr::Tcp(port) => Some(port),
+            ListenAddr::Unix(_) => None,
+        },
+        unix_listen_path: match &args.tcp_listen_port {
+            ListenAddr::Tcp(_) => None,
+            ListenAddr::Unix(path) => Some(path.as_str()),
+        },
+        local_forwards: &args.local_forwards,
+        remote_forwards: &args.remote_forwards,
+        udp_forwards: &args.udp_forwards,
+        udp_idle_timeout: args.udp_idle_timeout,
+        tun: args.tun.as_deref(),
+        transparent: args.transparent,
+        resolvers,
+        resolvers_file: args.resolvers_file.as_deref(),
+        system_resolvers: args.system_resolvers,
+        resolver_refresh_interval: args.resolver_refresh_interval,
         congestion_control: args.congestion_control.as_deref(),
         gso: args.gso,
         domain: &args.domain,
         cert: args.cert.as_deref(),
         keep_alive_interval: args.keep_alive_interval as usize,
         debug_poll: args.debug_poll,
+        doh_get: args.doh_method == transport::DohMethod::Get,
+        max_qps: args.max_qps,
+        qps_burst: args.qps_burst,
+        query_jitter_ms: args.query_jitter_ms,
+        udp_sockets: args.udp_sockets as usize,
+        dns_0x20: args.dns_0x20,
+        qname_encoding: args.qname_encoding,
         debug_streams: args.debug_streams,
+        handshake_only: false,
+        stdio: args.stdio,
+    }
+}
+
+fn run_bench_command(args: BenchArgs, matches: &clap::ArgMatches) -> i32 {
+    if args.client.stdio {
+        tracing::error!("--stdio cannot be combined with bench");
+        return 2;
+    }
+    let resolvers = match build_resolvers(matches) {
+        Ok(resolvers) => resolvers,
+        Err(err) => {
+            tracing::error!("Resolver error: {}", err);
+            return 2;
+        }
     };
+    let config = client_config(&args.client, &resolvers);
+    let plan = BenchPlan {
+        streams: args.streams as usize,
+        bytes: args.bytes,
+        pings: args.pings as usize,
+        ping_size: args.ping_size as usize,
+        timeout: Duration::from_secs(args.timeout),
+    };
+    match build_runtime().block_on(run_bench(&config, &plan)) {
+        Ok(report) => {
+            if args.json {
+                match serde_json::to_string_pretty(&report) {
+                    Ok(json) => println!("{}", json),
+                    Err(err) => {
+                        tracing::error!("Bench error: {}", err);
+                        return 1;
+                    }
+                }
+            } else {
+                print!("{}", report.to_text());
+            }
+            0
+        }
+        Err(err) => {
+            tracing::error!("Bench error: {}", err);
+            1
+        }
+    }
+}
 
-    let runtime = Builder::new_current_thread()
-        .enable_io()
-        .enable_time()
-        .build()
-        .expect("Failed to build Tokio runtime");
-    match runtime.block_on(run_client(&config)) {
-        Ok(code) => std::process::exit(code),
+fn run_doctor_command(args: DoctorArgs) -> i32 {
+    let (resolver, mode) = match (args.resolver, args.authoritative) {
+        (Some(resolver), _) => (resolver, ResolverMode::Recursive),
+        (None, Some(resolver)) => (resolver, ResolverMode::Authoritative),
+        (None, None) => return 2,
+    };
+    let config = DoctorConfig {
+        domain: &args.domain,
+        resolver: ResolverSpec {
+            resolver,
+            mode,
+            transport: ResolverTransport::Udp,
+        },
+        cert: args.cert.as_deref(),
+        timeout: Duration::from_millis(args.timeout_ms),
+        samples: args.samples as usize,
+    };
+    match build_runtime().block_on(run_doctor(&config)) {
+        Ok(true) => 0,
+        Ok(false) => 1,
         Err(err) => {
-            tracing::error!("Client error: {}", err);
-            std::process::exit(1);
+            tracing::error!("Doctor error: {}", err);
+            2
         }
     }
 }
 
-fn init_logging() {
-    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+fn build_runtime() -> Runtime {
+    Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .expect("Failed to build Tokio runtime")
+}
+
+fn init_logging(default_level: &str) {
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level));
     let _ = tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_target(false)
         .without_time()
+        .with_writer(std::io::stderr)
         .try_init();
 }
 
+/// Where `-l` takes local clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ListenAddr {
+    Tcp(u16),
+    Unix(String),
+}
+
+fn parse_listen_addr(input: &str) -> Result<ListenAddr, String> {
+    if let Some(path) = parse_unix_path(input).map_err(|err| err.to_string())? {
+        return Ok(ListenAddr::Unix(path.to_string()));
+    }
+    input.parse::<u16>().map(ListenAddr::Tcp).map_err(|_| {
+        format!(
+            "Invalid listen port (expected a port or unix:/path): {}",
+            input
+        )
+    })
+}
+
 fn parse_domain(input: &str) -> Result<String, String> {
     normalize_domain(input).map_err(|err| err.to_string())
 }
@@ -109,16 +442,70 @@ fn parse_resolver(input: &str) -> Result<HostPort, String> {
     parse_host_port(input, 53, AddressKind::Resolver).map_err(|err| err.to_string())
 }
 
+fn parse_forward(input: &str) -> Result<ForwardSpec, String> {
+    parse_forward_spec(input).map_err(|err| err.to_string())
+}
+
+fn parse_dot(input: &str) -> Result<HostPort, String> {
+    parse_host_port(input, 853, AddressKind::Resolver).map_err(|err| err.to_string())
+}
+
+fn parse_doh(input: &str) -> Result<String, String> {
+    transport::DohEndpoint::parse(input).map(|_| input.to_string())
+}
+
+fn parse_qname_encoding(input: &str) -> Result<QnameEncodingPreference, String> {
+    match input {
+        "base32" => Ok(QnameEncodingPreference::Base32),
+        "base64" => Ok(QnameEncodingPreference::Base64),
+        "raw" => Ok(QnameEncodingPreference::Raw),
+        "auto" => Ok(QnameEncodingPreference::Auto),
+        _ => Err("expected one of base32, base64, raw, auto".to_string()),
+    }
+}
+
 fn build_resolvers(matches: &clap::ArgMatches) -> Result<Vec<ResolverSpec>, String> {
     let mut ordered = Vec::new();
-    collect_resolvers(matches, "resolver", ResolverMode::Recursive, &mut ordered)?;
+    collect_resolvers(
+        matches,
+        "resolver",
+        ResolverMode::Recursive,
+        |_| ResolverTransport::Udp,
+        &mut ordered,
+    )?;
     collect_resolvers(
         matches,
         "authoritative",
         ResolverMode::Authoritative,
+        |_| ResolverTransport::Udp,
+        &mut ordered,
+    )?;
+    collect_resolvers(
+        matches,
+        "dot",
+        ResolverMode::Recursive,
+        resolver_list::dot_transport,
         &mut ordered,
     )?;
-    if ordered.is_empty() {
+    collect_resolvers(
+        matches,
+        "tcp_resolver",
+        ResolverMode::Recursive,
+        |_| ResolverTransport::Tcp,
+        &mut ordered,
+    )?;
+    let doh_indices: Vec<usize> = matches.indices_of("doh").into_iter().flatten().collect();
+    let doh_urls: Vec<&String> = matches
+        .get_many::<String>("doh")
+        .into_iter()
+        .flatten()
+        .collect();
+    for (idx, url) in doh_indices.into_iter().zip(doh_urls) {
+        ordered.push((idx, resolver_list::doh_resolver_spec(url)?));
+    }
+    let has_other_sources =
+        matches.contains_id("resolvers_file") || matches.get_flag("system_resolvers");
+    if ordered.is_empty() && !has_other_sources {
         return Err("At least one resolver is required".to_string());
     }
     ordered.sort_by_key(|(idx, _)| *idx);
@@ -129,6 +516,7 @@ fn collect_resolvers(
     matches: &clap::ArgMatches,
     name: &str,
     mode: ResolverMode,
+    transport: fn(&HostPort) -> ResolverTransport,
     ordered: &mut Vec<(usize, ResolverSpec)>,
 ) -> Result<(), String> {
     let indices: Vec<usize> = matches.indices_of(name).into_iter().flatten().collect();
@@ -142,7 +530,15 @@ fn collect_resolvers(
         return Err(format!("Mismatched {} arguments", name));
     }
     for (idx, resolver) in indices.into_iter().zip(values) {
-        ordered.push((idx, ResolverSpec { resolver, mode }));
+        let transport = transport(&resolver);
+        ordered.push((
+            idx,
+            ResolverSpec {
+                resolver,
+                mode,
+                transport,
+            },
+        ));
     }
     Ok(())
 }
@@ -197,4 +593,206 @@ mod tests {
         assert_eq!(resolvers[1].resolver.host, "9.9.9.9");
         assert_eq!(resolvers[1].mode, ResolverMode::Recursive);
     }
+
+    #[test]
+    fn parses_stream_resolvers_with_default_ports() {
+        let matches = Args::command()
+            .try_get_matches_from([
+                "slipstream-client",
+                "--domain",
+                "example.com",
+                "--tcp-resolver",
+                "9.9.9.9",
+                "--dot",
+                "dns.example.net",
+            ])
+            .expect("matches should parse");
+        let resolvers = build_resolvers(&matches).expect("resolvers should parse");
+        assert_eq!(resolvers.len(), 2);
+        assert_eq!(resolvers[0].resolver.port, 53);
+        assert_eq!(resolvers[0].transport, ResolverTransport::Tcp);
+        assert_eq!(resolvers[1].resolver.port, 853);
+        assert_eq!(
+            resolvers[1].transport,
+            ResolverTransport::Dot {
+                server_name: "dns.example.net".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn doctor_takes_exactly_one_resolver() {
+        let args = DoctorArgs::try_parse_from([
+            "doctor",
+            "--domain",
+            "example.com",
+            "--authoritative",
+            "192.0.2.1",
+        ])
+        .expect("doctor args should parse");
+        assert!(args.resolver.is_none());
+        assert_eq!(args.authoritative.expect("authoritative").port, 53);
+        assert!(DoctorArgs::try_parse_from(["doctor", "--domain", "example.com"]).is_err());
+        assert!(DoctorArgs::try_parse_from([
+            "doctor",
+            "--domain",
+            "example.com",
+            "--resolver",
+            "192.0.2.1",
+            "--authoritative",
+            "192.0.2.2",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn bench_takes_client_flags() {
+        let matches = BenchArgs::command()
+            .try_get_matches_from([
+                "bench",
+                "--domain",
+                "example.com",
+                "--authoritative",
+                "192.0.2.1",
+                "-c",
+                "bbr",
+                "--streams",
+                "8",
+                "--json",
+            ])
+            .expect("bench args should parse");
+        let args = BenchArgs::from_arg_matches(&matches).expect("bench args");
+        assert_eq!(args.streams, 8);
+        assert!(args.json);
+        assert_eq!(args.client.congestion_control.as_deref(), Some("bbr"));
+        let resolvers = build_resolvers(&matches).expect("resolvers should parse");
+        assert_eq!(resolvers[0].mode, ResolverMode::Authoritative);
+        assert!(BenchArgs::try_parse_from(["bench", "--domain", "example.com"]).is_err());
+    }
+
+    #[test]
+    fn stdio_replaces_the_listen_port() {
+        let args = Args::try_parse_from([
+            "slipstream-client",
+            "--stdio",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+        ])
+        .expect("stdio args should parse");
+        assert!(args.stdio);
+        assert!(Args::try_parse_from([
+            "slipstream-client",
+            "--stdio",
+            "-l",
+            "7000",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn listen_port_takes_unix_paths() {
+        let args = Args::try_parse_from([
+            "slipstream-client",
+            "-l",
+            "unix:/run/slipstream.sock",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+        ])
+        .expect("unix listen path should parse");
+        assert_eq!(
+            args.tcp_listen_port,
+            ListenAddr::Unix("/run/slipstream.sock".to_string())
+        );
+        assert!(Args::try_parse_from([
+            "slipstream-client",
+            "-l",
+            "unix:",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn local_forwards_drop_the_default_listener() {
+        let matches = Args::command()
+            .try_get_matches_from([
+                "slipstream-client",
+                "-d",
+                "t.example.com",
+                "-r",
+                "1.1.1.1",
+                "-L",
+                "2222:10.0.0.5:22",
+                "-L",
+                "*:5432:db.internal:5432",
+            ])
+            .expect("forward args should parse");
+        let args = Args::from_arg_matches(&matches).expect("args");
+        assert_eq!(args.local_forwards.len(), 2);
+        assert_eq!(args.local_forwards[1].host, "db.internal");
+        assert_ne!(
+            matches.value_source("tcp_listen_port"),
+            Some(ValueSource::CommandLine)
+        );
+    }
+
+    #[test]
+    fn remote_forwards_bind_on_server_loopback() {
+        let args = Args::try_parse_from([
+            "slipstream-client",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+            "-R",
+            "8080:localhost:3000",
+        ])
+        .expect("remote forward should parse");
+        assert_eq!(args.remote_forwards.len(), 1);
+        assert_eq!(args.remote_forwards[0].bind_host, "127.0.0.1");
+        assert_eq!(args.remote_forwards[0].bind_port, 8080);
+        assert_eq!(args.remote_forwards[0].host, "localhost");
+        assert!(Args::try_parse_from([
+            "slipstream-client",
+            "--stdio",
+            "-R",
+            "8080:localhost:3000",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn udp_forwards_parse_with_idle_timeout() {
+        let args = Args::try_parse_from([
+            "slipstream-client",
+            "-d",
+            "t.example.com",
+            "-r",
+            "1.1.1.1",
+            "-U",
+            "5353:1.1.1.1:53",
+            "--udp-idle-timeout",
+            "15",
+        ])
+        .expect("udp forward should parse");
+        assert_eq!(args.udp_forwards[0].bind_port, 5353);
+        assert_eq!(args.udp_forwards[0].host, "1.1.1.1");
+        assert_eq!(args.udp_forwards[0].port, 53);
+        assert_eq!(args.udp_idle_timeout, 15);
+    }
 }
@@ -0,0 +1,130 @@
+use rand::Rng;
+use slipstream_ffi::ClientConfig;
+
+/// Per-resolver query budget for recursive resolvers, which start rate-limiting or
+/// blocking a source well before the tunnel runs out of bandwidth.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct QueryRateLimit {
+    /// Sustained queries per second; 0 leaves the rate unlimited.
+    pub(crate) qps: u32,
+    /// Queries that may be sent back-to-back after an idle period.
+    pub(crate) burst: u32,
+    /// Upper bound of the random gap inserted after every query.
+    pub(crate) jitter_us: u64,
+}
+
+impl QueryRateLimit {
+    pub(crate) fn from_config(config: &ClientConfig<'_>) -> Option<Self> {
+        if config.max_qps == 0 && config.query_jitter_ms == 0 {
+            return None;
+        }
+        Some(Self {
+            qps: config.max_qps,
+            burst: config.qps_burst.max(1),
+            jitter_us: config.query_jitter_ms.saturating_mul(1_000),
+        })
+    }
+}
+
+/// Token bucket refilled at `qps`, holding at most `burst` tokens.
+pub(crate) struct QueryRateLimiter {
+    limit: QueryRateLimit,
+    tokens: f64,
+    last_refill_at: u64,
+    next_allowed_at: u64,
+}
+
+impl QueryRateLimiter {
+    pub(crate) fn new(limit: QueryRateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_refill_at: 0,
+            next_allowed_at: 0,
+        }
+    }
+
+    /// Takes a token if one is available and the jitter gap has passed.
+    pub(crate) fn try_acquire(&mut self, now: u64) -> bool {
+        self.refill(now);
+        if now < self.next_allowed_at || (self.limit.qps > 0 && self.tokens < 1.0) {
+            return false;
+        }
+        if self.limit.qps > 0 {
+            self.tokens -= 1.0;
+        }
+        if self.limit.jitter_us > 0 {
+            self.next_allowed_at = now + rand::thread_rng().gen_range(0..=self.limit.jitter_us);
+        }
+        true
+    }
+
+    /// Microseconds until `try_acquire` can succeed again.
+    pub(crate) fn wait_us(&mut self, now: u64) -> u64 {
+        self.refill(now);
+        let jitter_wait = self.next_allowed_at.saturating_sub(now);
+        let token_wait = if self.limit.qps == 0 || self.tokens >= 1.0 {
+            0
+        } else {
+            ((1.0 - self.tokens) * 1_000_000.0 / self.limit.qps as f64).ceil() as u64
+        };
+        jitter_wait.max(token_wait)
+    }
+
+    fn refill(&mut self, now: u64) {
+        if self.last_refill_at == 0 {
+            self.last_refill_at = now;
+            return;
+        }
+        let elapsed_us = now.saturating_sub(self.last_refill_at);
+        self.last_refill_at = now;
+        if self.limit.qps == 0 {
+            return;
+        }
+        let refill = elapsed_us as f64 * self.limit.qps as f64 / 1_000_000.0;
+        self.tokens = (self.tokens + refill).min(self.limit.burst as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueryRateLimit, QueryRateLimiter};
+
+    #[test]
+    fn spends_burst_then_refills_at_rate() {
+        let mut limiter = QueryRateLimiter::new(QueryRateLimit {
+            qps: 100,
+            burst: 3,
+            jitter_us: 0,
+        });
+        let start = 1_000_000;
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(start));
+        }
+        assert!(!limiter.try_acquire(start));
+        assert_eq!(limiter.wait_us(start), 10_000);
+        assert!(!limiter.try_acquire(start + 5_000));
+        assert!(limiter.try_acquire(start + 10_000));
+        // Long idle periods only refill up to the burst size.
+        let later = start + 10_000_000;
+        for _ in 0..3 {
+            assert!(limiter.try_acquire(later));
+        }
+        assert!(!limiter.try_acquire(later));
+    }
+
+    #[test]
+    fn jitter_spaces_queries_without_rate_limit() {
+        let mut limiter = QueryRateLimiter::new(QueryRateLimit {
+            qps: 0,
+            burst: 1,
+            jitter_us: 2_000,
+        });
+        let start = 1_000_000;
+        assert!(limiter.try_acquire(start));
+        let wait = limiter.wait_us(start);
+        assert!(wait <= 2_000);
+        assert!(limiter.try_acquire(start + wait));
+        assert!(limiter.try_acquire(start + 10_000));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::dns::{normalize_dual_stack_addr, ResolvedResolver};
+use crate::transport::DohEndpoint;
+use slipstream_core::{parse_host_port, resolve_host_port, AddressFamily, AddressKind, HostPort};
+use slipstream_ffi::{ClientConfig, ResolverMode, ResolverSpec, ResolverTransport};
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+pub(crate) const SYSTEM_RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Everything the client needs to rebuild its resolver list at runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct ResolverSources {
+    pub(crate) static_resolvers: Vec<ResolverSpec>,
+    pub(crate) resolvers_file: Option<String>,
+    pub(crate) system_resolvers: bool,
+}
+
+impl ResolverSources {
+    pub(crate) fn from_config(config: &ClientConfig<'_>) -> Self {
+        Self {
+            static_resolvers: config.resolvers.to_vec(),
+            resolvers_file: config.resolvers_file.map(str::to_string),
+            system_resolvers: config.system_resolvers,
+        }
+    }
+
+    /// Command-line resolvers first, then the resolvers file, then resolv.conf.
+    pub(crate) fn load(&self) -> Result<Vec<ResolverSpec>, String> {
+        let mut specs = self.static_resolvers.clone();
+        if let Some(path) = self.resolvers_file.as_deref() {
+            let contents = std::fs::read_to_string(path)
+                .map_err(|err| format!("Cannot read resolvers file {}: {}", path, err))?;
+            specs.extend(
+                parse_resolvers_file(&contents)
+                    .map_err(|err| format!("Invalid resolvers file {}: {}", path, err))?,
+            );
+        }
+        if self.system_resolvers {
+            let contents = std::fs::read_to_string(SYSTEM_RESOLV_CONF)
+                .map_err(|err| format!("Cannot read {}: {}", SYSTEM_RESOLV_CONF, err))?;
+            specs.extend(parse_resolv_conf(&contents));
+        }
+        if specs.is_empty() {
+            return Err("At least one resolver is required".to_string());
+        }
+        Ok(specs)
+    }
+}
+
+/// Parses one `host[:port] [recursive|authoritative]`, `tls://host[:port]` (DoT),
+/// `tcp://host[:port]` or DoH `https://` URL per line; `#` starts a comment.
+pub(crate) fn parse_resolvers_file(contents: &str) -> Result<Vec<ResolverSpec>, String> {
+    let mut specs = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let address = fields.next().unwrap_or_default();
+        if address.starts_with("https://") || address.starts_with("http://") {
+            if let Some(extra) = fields.next() {
+                return Err(format!("line {}: unexpected field {}", index + 1, extra));
+            }
+            specs.push(
+                doh_resolver_spec(address).map_err(|err| format!("line {}: {}", index + 1, err))?,
+            );
+            continue;
+        }
+        let stream = if let Some(rest) = address.strip_prefix("tls://") {
+            Some((rest, 853, true))
+        } else {
+            address.strip_prefix("tcp://").map(|rest| (rest, 53, false))
+        };
+        if let Some((rest, default_port, tls)) = stream {
+            if let Some(extra) = fields.next() {
+                return Err(format!("line {}: unexpected field {}", index + 1, extra));
+            }
+            let resolver = parse_host_port(rest, default_port, AddressKind::Resolver)
+                .map_err(|err| format!("line {}: {}", index + 1, err))?;
+            let transport = if tls {
+                dot_transport(&resolver)
+            } else {
+                ResolverTransport::Tcp
+            };
+            specs.push(ResolverSpec {
+                resolver,
+                mode: ResolverMode::Recursive,
+                transport,
+            });
+            continue;
+        }
+        let mode = match fields.next() {
+            None | Some("recursive") | Some("resolver") => ResolverMode::Recursive,
+            Some("authoritative") => ResolverMode::Authoritative,
+            Some(other) => {
+                return Err(format!(
+                    "line {}: unknown resolver mode {}",
+                    index + 1,
+                    other
+                ));
+            }
+        };
+        if let Some(extra) = fields.next() {
+            return Err(format!("line {}: unexpected field {}", index + 1, extra));
+        }
+        let resolver = parse_resolver_address(address)
+            .map_err(|err| format!("line {}: {}", index + 1, err))?;
+        specs.push(ResolverSpec {
+            resolver,
+            mode,
+            transport: ResolverTransport::Udp,
+        });
+    }
+    Ok(specs)
+}
+
+pub(crate) fn doh_resolver_spec(url: &str) -> Result<ResolverSpec, String> {
+    let endpoint = DohEndpoint::parse(url)?;
+    Ok(ResolverSpec {
+        resolver: endpoint.host_port(),
+        mode: ResolverMode::Recursive,
+        transport: ResolverTransport::Doh {
+            url: url.to_string(),
+        },
+    })
+}
+
+/// DoT certificates are verified against the host exactly as given.
+pub(crate) fn dot_transport(resolver: &HostPort) -> ResolverTransport {
+    ResolverTransport::Dot {
+        server_name: resolver.host.clone(),
+    }
+}
+
+/// Collects the `nameserver` entries of a resolv.conf as recursive resolvers.
+pub(crate) fn parse_resolv_conf(contents: &str) -> Vec<ResolverSpec> {
+    let mut specs = Vec::new();
+    for line in contents.lines() {
+        let line = line.split(['#', ';']).next().unwrap_or("").trim();
+        let mut fields = line.split_whitespace();
+        if fields.next() != Some("nameserver") {
+            continue;
+        }
+        let Some(address) = fields.next() else {
+            continue;
+        };
+        // Link-local scopes (fe80::1%eth0) cannot be carried through HostPort.
+        let address = address.split('%').next().unwrap_or(address);
+        match parse_resolver_address(address) {
+            Ok(resolver) => specs.push(ResolverSpec {
+                resolver,
+                mode: ResolverMode::Recursive,
+                transport: ResolverTransport::Udp,
+            }),
+            Err(err) => warn!("Ignoring nameserver {}: {}", address, err),
+        }
+    }
+    specs
+}
+
+fn parse_resolver_address(input: &str) -> Result<HostPort, String> {
+    if input.parse::<Ipv6Addr>().is_ok() {
+        return Ok(HostPort {
+            host: input.to_string(),
+            port: 53,
+            family: AddressFamily::V6,
+        });
+    }
+    parse_host_port(input, 53, AddressKind::Resolver).map_err(|err| err.to_string())
+}
+
+/// Resolves every spec, skipping entries that fail to resolve. Two entries with the same
+/// address are an error, as they are at startup.
+pub(crate) fn resolve_resolver_list(
+    specs: &[ResolverSpec],
+) -> Result<Vec<ResolvedResolver>, String> {
+    let mut resolved = Vec::with_capacity(specs.len());
+    let mut seen = HashMap::new();
+    for spec in specs {
+        let connect_addr = match resolve_host_port(&spec.resolver) {
+            Ok(addr) => normalize_dual_stack_addr(addr),
+            Err(err) => {
+                warn!("Skipping resolver {}: {}", spec.resolver.host, err);
+                continue;
+            }
+        };
+        let entry = ResolvedResolver::new(connect_addr, spec.mode, spec.transport.clone());
+        if let Some(existing_mode) = seen.insert(entry.addr, spec.mode) {
+            return Err(format!(
+                "Duplicate resolver address {} (modes: {:?} and {:?})",
+                entry.addr, existing_mode, spec.mode
+            ));
+        }
+        resolved.push(entry);
+    }
+    Ok(resolved)
+}
+
+/// Re-reads and re-resolves the resolver sources every `interval`, off the main loop.
+pub(crate) fn spawn_resolver_refresh(
+    sources: ResolverSources,
+    interval: Duration,
+    refresh_tx: mpsc::UnboundedSender<Vec<ResolvedResolver>>,
+) {
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            let sources = sources.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                sources
+                    .load()
+                    .and_then(|specs| resolve_resolver_list(&specs))
+            })
+            .await;
+            match result {
+                Ok(Ok(resolved)) if !resolved.is_empty() => {
+                    if refresh_tx.send(resolved).is_err() {
+                        return;
+                    }
+                }
+                Ok(Ok(_)) => warn!("Resolver refresh resolved no addresses; keeping current list"),
+                Ok(Err(err)) => warn!("Resolver refresh failed: {}", err),
+                Err(err) => warn!("Resolver refresh task failed: {}", err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_resolv_conf, parse_resolvers_file, resolve_resolver_list};
+    use slipstream_core::AddressFamily;
+    use slipstream_ffi::{ResolverMode, ResolverTransport};
+
+    #[test]
+    fn parses_resolvers_file() {
+        let contents = "\
+# public resolvers
+1.1.1.1
+8.8.8.8:5353 recursive
+ns.example.com authoritative  # our own server
+[2001:db8::1]:53
+2001:db8::2
+https://dns.example.net/dns-query
+tls://dns.example.org
+tcp://9.9.9.9:5353
+";
+        let specs = parse_resolvers_file(contents).expect("file should parse");
+        assert_eq!(specs.len(), 8);
+        assert_eq!(specs[0].resolver.host, "1.1.1.1");
+        assert_eq!(specs[0].resolver.port, 53);
+        assert_eq!(specs[0].mode, ResolverMode::Recursive);
+        assert_eq!(specs[1].resolver.port, 5353);
+        assert_eq!(specs[2].resolver.host, "ns.example.com");
+        assert_eq!(specs[2].mode, ResolverMode::Authoritative);
+        assert_eq!(specs[3].resolver.family, AddressFamily::V6);
+        assert_eq!(specs[4].resolver.host, "2001:db8::2");
+        assert_eq!(specs[4].resolver.port, 53);
+        assert_eq!(specs[5].resolver.host, "dns.example.net");
+        assert_eq!(specs[5].resolver.port, 443);
+        assert_eq!(
+            specs[5].transport,
+            ResolverTransport::Doh {
+                url: "https://dns.example.net/dns-query".to_string()
+            }
+        );
+        assert_eq!(specs[6].resolver.port, 853);
+        assert_eq!(
+            specs[6].transport,
+            ResolverTransport::Dot {
+                server_name: "dns.example.org".to_string()
+            }
+        );
+        assert_eq!(specs[7].resolver.port, 5353);
+        assert_eq!(specs[7].transport, ResolverTransport::Tcp);
+    }
+
+    #[test]
+    fn rejects_unknown_resolver_mode() {
+        let err = parse_resolvers_file("1.1.1.1\n9.9.9.9 fast\n").expect_err("mode should fail");
+        assert!(err.contains("line 2"));
+    }
+
+    #[test]
+    fn parses_resolv_conf_nameservers() {
+        let contents = "\
+; generated by resolvconf
+search example.com
+nameserver 127.0.0.53
+nameserver fe80::1%eth0
+options edns0 trust-ad
+";
+        let specs = parse_resolv_conf(contents);
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].resolver.host, "127.0.0.53");
+        assert_eq!(specs[1].resolver.host, "fe80::1");
+        assert!(specs
+            .iter()
+            .all(|spec| spec.mode == ResolverMode::Recursive));
+    }
+
+    #[test]
+    fn refresh_rejects_duplicate_addresses_like_startup() {
+        let specs = parse_resolvers_file("192.0.2.1\n192.0.2.1 authoritative\n").expect("parse");
+        let err = resolve_resolver_list(&specs).expect_err("duplicate should be rejected");
+        assert!(err.contains("Duplicate resolver address"));
+        let specs = parse_resolvers_file("192.0.2.1\n192.0.2.2\n").expect("parse");
+        assert_eq!(resolve_resolver_list(&specs).expect("distinct").len(), 2);
+    }
+}
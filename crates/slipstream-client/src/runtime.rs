@@ -5,21 +5,32 @@ use self::path::{
     apply_path_mode, drain_path_events, fetch_path_quality, find_resolver_by_addr_mut,
     loop_burst_total, path_poll_burst_max,
 };
-use self::setup::{bind_udp_socket, compute_mtu, map_io};
+pub(crate) use self::setup::map_io;
+use self::setup::{bind_udp_socket, compute_mtu};
 use crate::dns::{
-    add_paths, expire_inflight_polls, handle_dns_response, maybe_report_debug,
-    normalize_dual_stack_addr, refresh_resolver_path, resolve_resolvers, resolver_mode_to_c,
-    send_poll_queries, sockaddr_storage_to_socket_addr, DnsResponseContext,
+    add_paths, apply_resolver_list, attach_transports, evict_unusable_resolvers,
+    expire_inflight_polls, flush_deferred_queries, handle_dns_response, maybe_report_debug,
+    next_query_id, normalize_dual_stack_addr, refresh_resolver_path, resolve_resolvers,
+    resolver_mode_to_c, send_dns_query, send_encoding_probe, send_mtu_probe, send_poll_queries,
+    sockaddr_storage_to_socket_addr, update_resolver_health, DnsResponseContext, QueryEncoder,
 };
 use crate::error::ClientError;
-use crate::pacing::{cwnd_target_polls, inflight_packet_estimate};
+use crate::pacing::{cwnd_target_polls, inflight_packet_estimate, PacingBudgetSnapshot};
 use crate::pinning::configure_pinned_certificate;
+use crate::rate_limit::QueryRateLimit;
+use crate::resolver_list::{spawn_resolver_refresh, ResolverSources};
 use crate::streams::{
-    client_callback, drain_commands, drain_stream_data, handle_command, spawn_acceptor, ClientState,
+    client_callback, drain_commands, drain_stream_data, handle_command, spawn_acceptor,
+    AcceptTarget, ClientState, Command, LocalStream,
 };
-use slipstream_dns::{build_qname, encode_query, QueryParams, CLASS_IN, RR_TXT};
+use crate::transport::{DohMethod, TransportContext, UdpSocketPool};
+use crate::tun::{open_tun, run_tun_bridge};
+use crate::udp::spawn_udp_forward;
+use crate::unix::spawn_unix_acceptor;
+use slipstream_core::stream_header::{encode_stream_header, StreamTarget};
+use slipstream_dns::{QnameEncoding, QueryControl};
 use slipstream_ffi::{
-    configure_quic_with_custom,
+    allow_path_send_mtu_up_to, configure_quic_with_custom,
     picoquic::{
         picoquic_close, picoquic_cnx_t, picoquic_connection_id_t, picoquic_create,
         picoquic_create_client_cnx, picoquic_current_time, picoquic_disable_keep_alive,
@@ -34,10 +45,11 @@ use slipstream_ffi::{
     socket_addr_to_storage, ClientConfig, QuicGuard, ResolverMode,
 };
 use std::ffi::CString;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::TcpListener as TokioTcpListener;
-use tokio::sync::{mpsc, Notify};
+use tokio::net::{TcpListener as TokioTcpListener, UdpSocket as TokioUdpSocket};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
 use tokio::time::sleep;
 use tracing::{debug, info, warn};
 
@@ -46,26 +58,154 @@ const SLIPSTREAM_ALPN: &str = "picoquic_sample";
 const SLIPSTREAM_SNI: &str = "test.example.com";
 const DNS_WAKE_DELAY_MAX_US: i64 = 10_000_000;
 const DNS_POLL_SLICE_US: u64 = 50_000;
+const TUNNEL_SAMPLE_INTERVAL_US: u64 = 250_000;
+
+/// Runs the tunnel inside another tool, such as the bench, instead of serving a TCP port.
+pub(crate) struct TunnelHooks {
+    /// Receives the command sender for opening streams once the connection is ready.
+    pub(crate) ready_tx: Option<oneshot::Sender<mpsc::UnboundedSender<Command>>>,
+    /// Receives a sample of every path about every `TUNNEL_SAMPLE_INTERVAL_US`.
+    pub(crate) sample_tx: mpsc::UnboundedSender<PathSample>,
+    /// Closes the tunnel once it flips to true.
+    pub(crate) stop_rx: watch::Receiver<bool>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PathSample {
+    pub(crate) resolver: SocketAddr,
+    pub(crate) mode: ResolverMode,
+    pub(crate) at_us: u64,
+    pub(crate) cwnd: u64,
+    pub(crate) rtt_us: u64,
+    pub(crate) bytes_in_transit: u64,
+    pub(crate) sent: u64,
+    pub(crate) lost: u64,
+    pub(crate) pacing: Option<PacingBudgetSnapshot>,
+}
 
 pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
+    run_tunnel(config, None).await
+}
+
+pub(crate) async fn run_tunnel(
+    config: &ClientConfig<'_>,
+    mut hooks: Option<TunnelHooks>,
+) -> Result<i32, ClientError> {
     let domain_len = config.domain.len();
     let mtu = compute_mtu(domain_len)?;
-    let mut resolvers = resolve_resolvers(config.resolvers, mtu, config.debug_poll)?;
+    let resolver_sources = ResolverSources::from_config(config);
+    let resolver_specs = resolver_sources.load().map_err(ClientError::new)?;
+    let rate_limit = QueryRateLimit::from_config(config);
+    let encoder = QueryEncoder::new(config.domain, config.dns_0x20, config.qname_encoding);
+    let mut resolvers = resolve_resolvers(&resolver_specs, mtu, config.debug_poll, rate_limit)?;
     if resolvers.is_empty() {
         return Err(ClientError::new("At least one resolver is required"));
     }
+    let doh_method = if config.doh_get {
+        DohMethod::Get
+    } else {
+        DohMethod::Post
+    };
+    let (response_tx, mut transport_response_rx) = mpsc::unbounded_channel();
+    let (refresh_tx, mut refresh_rx) = mpsc::unbounded_channel();
+    if config.resolver_refresh_interval > 0 {
+        spawn_resolver_refresh(
+            resolver_sources,
+            Duration::from_secs(config.resolver_refresh_interval),
+            refresh_tx,
+        );
+    }
 
-    let udp = bind_udp_socket().await?;
+    let mut sockets = Vec::with_capacity(config.udp_sockets);
+    for _ in 0..config.udp_sockets.max(1) {
+        sockets.push(bind_udp_socket().await?);
+    }
+    let udp = Arc::new(UdpSocketPool::new(sockets));
     let mut local_addr_storage = socket_addr_to_storage(udp.local_addr().map_err(map_io)?);
+    let transport_ctx = TransportContext {
+        udp: udp.clone(),
+        doh_method,
+        response_tx,
+    };
+    attach_transports(&mut resolvers, &transport_ctx)?;
 
     let (command_tx, mut command_rx) = mpsc::unbounded_channel();
     let data_notify = Arc::new(Notify::new());
     let debug_streams = config.debug_streams;
-    let listener = TokioTcpListener::bind(("0.0.0.0", config.tcp_listen_port))
-        .await
-        .map_err(map_io)?;
-    spawn_acceptor(listener, command_tx.clone());
-    info!("Listening on TCP port {}", config.tcp_listen_port);
+    let hooks_command_tx = command_tx.clone();
+    // Local listeners and reverse tunnels only exist in the plain client mode.
+    let serves_clients = !config.handshake_only && !config.stdio && hooks.is_none();
+    let mut tun_device = match config.tun {
+        Some(name) if serves_clients => Some(open_tun(name)?),
+        _ => None,
+    };
+    let mut tun_closed_rx = None;
+    if config.transparent && !cfg!(target_os = "linux") {
+        return Err(ClientError::new("--transparent is only supported on Linux"));
+    }
+    // Held so the socket file is removed when the client returns.
+    let _unix_listener = match config.unix_listen_path {
+        Some(path) if serves_clients => {
+            let guard = spawn_unix_acceptor(path, command_tx.clone())?;
+            info!("Listening on Unix socket {}", path);
+            Some(guard)
+        }
+        _ => None,
+    };
+    if serves_clients {
+        if let Some(port) = config.tcp_listen_port {
+            if config.transparent {
+                // Dual-stack, so ip6tables can redirect here as well.
+                let listener = match TokioTcpListener::bind(("::", port)).await {
+                    Ok(listener) => listener,
+                    Err(_) => TokioTcpListener::bind(("0.0.0.0", port))
+                        .await
+                        .map_err(map_io)?,
+                };
+                spawn_acceptor(listener, AcceptTarget::Original, command_tx.clone());
+                info!("Accepting redirected connections on TCP port {}", port);
+            } else {
+                let listener = TokioTcpListener::bind(("0.0.0.0", port))
+                    .await
+                    .map_err(map_io)?;
+                spawn_acceptor(listener, AcceptTarget::Default, command_tx.clone());
+                info!("Listening on TCP port {}", port);
+            }
+        }
+        for forward in config.local_forwards {
+            let listener = TokioTcpListener::bind((forward.bind_host.as_str(), forward.bind_port))
+                .await
+                .map_err(map_io)?;
+            let header = encode_stream_header(&StreamTarget::Connect {
+                host: forward.host.clone(),
+                port: forward.port,
+            });
+            spawn_acceptor(listener, AcceptTarget::Header(header), command_tx.clone());
+            info!(
+                "Forwarding {}:{} to {}:{}",
+                forward.bind_host, forward.bind_port, forward.host, forward.port
+            );
+        }
+        for forward in config.udp_forwards {
+            let socket = TokioUdpSocket::bind((forward.bind_host.as_str(), forward.bind_port))
+                .await
+                .map_err(map_io)?;
+            let header = encode_stream_header(&StreamTarget::Udp {
+                host: forward.host.clone(),
+                port: forward.port,
+            });
+            spawn_udp_forward(
+                socket,
+                header,
+                command_tx.clone(),
+                Duration::from_secs(config.udp_idle_timeout),
+            );
+            info!(
+                "Forwarding UDP {}:{} to {}:{}",
+                forward.bind_host, forward.bind_port, forward.host, forward.port
+            );
+        }
+    }
 
     let alpn = CString::new(SLIPSTREAM_ALPN)
         .map_err(|_| ClientError::new("ALPN contains an unexpected null byte"))?;
@@ -82,6 +222,7 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
         command_tx,
         data_notify.clone(),
         debug_streams,
+        config.remote_forwards.into(),
     ));
     let state_ptr: *mut ClientState = &mut *state;
     let _state = state;
@@ -116,6 +257,15 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
     }
     unsafe {
         configure_quic_with_custom(quic, mixed_cc, mtu);
+        if !encoder.candidates().is_empty() {
+            // Paths start at the base32 MTU and are raised as denser encodings are accepted.
+            let mut mtu_max = mtu;
+            for encoding in encoder.candidates() {
+                let max_payload = encoder.max_payload(*encoding, QueryControl::None, usize::MAX)?;
+                mtu_max = mtu_max.max(max_payload as u32);
+            }
+            allow_path_send_mtu_up_to(quic, mtu_max);
+        }
         picoquic_enable_path_callbacks_default(quic, 1);
         let override_ptr = cc_override
             .as_ref()
@@ -163,13 +313,15 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
         warn!("GSO is not implemented in the Rust client loop yet.");
     }
 
-    let mut dns_id = 1u16;
     let mut recv_buf = vec![0u8; 4096];
     let mut send_buf = vec![0u8; PICOQUIC_MAX_PACKET_SIZE];
-    let packet_loop_send_max = loop_burst_total(&resolvers, PICOQUIC_PACKET_LOOP_SEND_MAX);
-    let packet_loop_recv_max = loop_burst_total(&resolvers, PICOQUIC_PACKET_LOOP_RECV_MAX);
+    let mut packet_loop_send_max = loop_burst_total(&resolvers, PICOQUIC_PACKET_LOOP_SEND_MAX);
+    let mut packet_loop_recv_max = loop_burst_total(&resolvers, PICOQUIC_PACKET_LOOP_RECV_MAX);
     let mut zero_send_loops = 0u64;
     let mut zero_send_with_streams = 0u64;
+    let mut next_sample_at = 0u64;
+    let mut stdio_opened = false;
+    let mut reverse_opened = false;
 
     loop {
         let current_time = unsafe { picoquic_current_time() };
@@ -181,6 +333,67 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
         }
 
         let ready = unsafe { (*state_ptr).is_ready() };
+        if ready && config.handshake_only {
+            break;
+        }
+        if config.stdio && ready {
+            if !stdio_opened {
+                handle_command(cnx, state_ptr, Command::NewStream(LocalStream::stdio()));
+                stdio_opened = true;
+            } else if unsafe { (*state_ptr).streams_len() } == 0 {
+                // The stream is dropped once the server's side is written out, or on reset.
+                break;
+            }
+        }
+        if serves_clients && ready && !reverse_opened {
+            for forward in config.remote_forwards {
+                handle_command(
+                    cnx,
+                    state_ptr,
+                    Command::NewStream(LocalStream::reverse_control(forward)),
+                );
+                info!(
+                    "Requested reverse tunnel {}:{} to {}:{}",
+                    forward.bind_host, forward.bind_port, forward.host, forward.port
+                );
+            }
+            reverse_opened = true;
+        }
+        if ready {
+            if let Some(device) = tun_device.take() {
+                let (stream_end, bridge_end) = tokio::io::duplex(256 * 1024);
+                let (closed_tx, closed_rx) = oneshot::channel::<()>();
+                tokio::spawn(async move {
+                    run_tun_bridge(device, bridge_end).await;
+                    drop(closed_tx);
+                });
+                tun_closed_rx = Some(closed_rx);
+                handle_command(
+                    cnx,
+                    state_ptr,
+                    Command::NewStream(LocalStream::tun(stream_end)),
+                );
+            }
+        }
+        if let Some(closed_rx) = tun_closed_rx.as_mut() {
+            if !matches!(
+                closed_rx.try_recv(),
+                Err(oneshot::error::TryRecvError::Empty)
+            ) {
+                warn!("TUN stream closed");
+                break;
+            }
+        }
+        if let Some(hooks) = hooks.as_mut() {
+            if *hooks.stop_rx.borrow() {
+                break;
+            }
+            if ready {
+                if let Some(ready_tx) = hooks.ready_tx.take() {
+                    let _ = ready_tx.send(hooks_command_tx.clone());
+                }
+            }
+        }
         if ready {
             add_paths(cnx, &mut resolvers)?;
             for resolver in resolvers.iter_mut() {
@@ -190,6 +403,10 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
             }
         }
         drain_path_events(cnx, &mut resolvers, state_ptr);
+        if ready {
+            update_resolver_health(cnx, &mut resolvers, current_time);
+            evict_unusable_resolvers(cnx, &mut resolvers, current_time);
+        }
 
         for resolver in resolvers.iter_mut() {
             if resolver.mode == ResolverMode::Authoritative {
@@ -232,11 +449,30 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
             }
         }
         // Avoid a tight poll loop when idle, but keep the short slice during active transfers.
-        let timeout_us = if has_work {
+        let mut timeout_us = if has_work {
             delay_us.clamp(1, DNS_POLL_SLICE_US)
         } else {
             delay_us.max(1)
         };
+        for resolver in resolvers.iter_mut() {
+            if let Some(wait_us) = resolver.rate_limit_wait_us(current_time) {
+                timeout_us = timeout_us.min(wait_us.max(1));
+            }
+            if let Some(wait_us) = resolver
+                .encoding_negotiation
+                .as_ref()
+                .and_then(|negotiation| negotiation.wait_us(current_time))
+            {
+                timeout_us = timeout_us.min(wait_us.max(1));
+            }
+            if let Some(wait_us) = resolver
+                .mtu_discovery
+                .as_ref()
+                .and_then(|discovery| discovery.wait_us(current_time))
+            {
+                timeout_us = timeout_us.min(wait_us.max(1));
+            }
+        }
         let timeout = Duration::from_micros(timeout_us);
 
         tokio::select! {
@@ -246,6 +482,51 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                 }
             }
             _ = data_notify.notified() => {}
+            _ = async {
+                match hooks.as_mut() {
+                    Some(hooks) => {
+                        let _ = hooks.stop_rx.changed().await;
+                    }
+                    None => std::future::pending().await,
+                }
+            } => {}
+            resolved = refresh_rx.recv() => {
+                if let Some(resolved) = resolved {
+                    if apply_resolver_list(
+                        cnx,
+                        &mut resolvers,
+                        &resolved,
+                        mtu,
+                        config.debug_poll,
+                        rate_limit,
+                    ) {
+                        attach_transports(&mut resolvers, &transport_ctx)?;
+                        packet_loop_send_max =
+                            loop_burst_total(&resolvers, PICOQUIC_PACKET_LOOP_SEND_MAX);
+                        packet_loop_recv_max =
+                            loop_burst_total(&resolvers, PICOQUIC_PACKET_LOOP_RECV_MAX);
+                    }
+                }
+            }
+            transport_response = transport_response_rx.recv() => {
+                if let Some((peer, response)) = transport_response {
+                    let mut response_ctx = DnsResponseContext {
+                        quic,
+                        local_addr_storage: &local_addr_storage,
+                        resolvers: &mut resolvers,
+                        encoder: &encoder,
+                    };
+                    handle_dns_response(&response, peer, &mut response_ctx)?;
+                    for _ in 1..packet_loop_recv_max {
+                        match transport_response_rx.try_recv() {
+                            Ok((peer, response)) => {
+                                handle_dns_response(&response, peer, &mut response_ctx)?;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                }
+            }
             recv = udp.recv_from(&mut recv_buf) => {
                 match recv {
                     Ok((size, peer)) => {
@@ -253,6 +534,7 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                             quic,
                             local_addr_storage: &local_addr_storage,
                             resolvers: &mut resolvers,
+                            encoder: &encoder,
                         };
                         handle_dns_response(&recv_buf[..size], peer, &mut response_ctx)?;
                         for _ in 1..packet_loop_recv_max {
@@ -283,6 +565,13 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
         drain_stream_data(cnx, state_ptr);
         drain_path_events(cnx, &mut resolvers, state_ptr);
 
+        let flush_time = unsafe { picoquic_current_time() };
+        for resolver in resolvers.iter_mut() {
+            flush_deferred_queries(&udp, resolver, flush_time).await?;
+            send_mtu_probe(&udp, &encoder, resolver, flush_time).await?;
+            send_encoding_probe(&udp, &encoder, resolver, flush_time).await?;
+        }
+
         for _ in 0..packet_loop_send_max {
             let current_time = unsafe { picoquic_current_time() };
             let mut send_length: libc::size_t = 0;
@@ -333,6 +622,7 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
             if addr_to.ss_family == 0 {
                 break;
             }
+            let mut packet = None;
             if let Ok(dest) = sockaddr_storage_to_socket_addr(&addr_to) {
                 let dest = normalize_dual_stack_addr(dest);
                 if let Some(resolver) = find_resolver_by_addr_mut(&mut resolvers, dest) {
@@ -340,28 +630,40 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                     resolver.debug.send_packets = resolver.debug.send_packets.saturating_add(1);
                     resolver.debug.send_bytes =
                         resolver.debug.send_bytes.saturating_add(send_length as u64);
+                    resolver.health.note_query();
+                    let query_id = next_query_id(&resolver.inflight_poll_ids);
+                    packet = Some(resolver.encode_query(
+                        &encoder,
+                        query_id,
+                        &send_buf[..send_length],
+                    )?);
                 }
             }
-
-            let qname = build_qname(&send_buf[..send_length], config.domain)
-                .map_err(|err| ClientError::new(err.to_string()))?;
-            let params = QueryParams {
-                id: dns_id,
-                qname: &qname,
-                qtype: RR_TXT,
-                qclass: CLASS_IN,
-                rd: true,
-                cd: false,
-                qdcount: 1,
-                is_query: true,
+            let packet = match packet {
+                Some(packet) => packet,
+                None => encoder.encode(
+                    rand::random(),
+                    &send_buf[..send_length],
+                    QnameEncoding::Base32,
+                    QueryControl::None,
+                )?,
             };
-            dns_id = dns_id.wrapping_add(1);
-            let packet = encode_query(&params).map_err(|err| ClientError::new(err.to_string()))?;
 
             let dest = sockaddr_storage_to_socket_addr(&addr_to)?;
             let dest = normalize_dual_stack_addr(dest);
             local_addr_storage = addr_from;
-            udp.send_to(&packet, dest).await.map_err(map_io)?;
+            let Some(resolver) = find_resolver_by_addr_mut(&mut resolvers, dest) else {
+                send_dns_query(&udp, None, dest, packet).await?;
+                continue;
+            };
+            if resolver.must_defer(current_time) {
+                // Stop pulling packets once the queue is full; QUIC recovers the drop.
+                if !resolver.defer_query(packet) {
+                    break;
+                }
+                continue;
+            }
+            send_dns_query(&udp, resolver.transport.as_deref(), dest, packet).await?;
         }
 
         let has_ready_stream = unsafe { slipstream_has_ready_stream(cnx) != 0 };
@@ -399,9 +701,8 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                         send_poll_queries(
                             cnx,
                             &udp,
-                            config,
+                            &encoder,
                             &mut local_addr_storage,
-                            &mut dns_id,
                             resolver,
                             &mut to_send,
                             &mut send_buf,
@@ -418,9 +719,8 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                             send_poll_queries(
                                 cnx,
                                 &udp,
-                                config,
+                                &encoder,
                                 &mut local_addr_storage,
-                                &mut dns_id,
                                 resolver,
                                 &mut to_send,
                                 &mut send_buf,
@@ -435,9 +735,8 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                             send_poll_queries(
                                 cnx,
                                 &udp,
-                                config,
+                                &encoder,
                                 &mut local_addr_storage,
-                                &mut dns_id,
                                 resolver,
                                 &mut pending,
                                 &mut send_buf,
@@ -482,11 +781,37 @@ pub async fn run_client(config: &ClientConfig<'_>) -> Result<i32, ClientError> {
                 resolver.last_pacing_snapshot,
             );
         }
+        if let Some(hooks) = hooks.as_ref() {
+            if report_time >= next_sample_at {
+                next_sample_at = report_time.saturating_add(TUNNEL_SAMPLE_INTERVAL_US);
+                for resolver in resolvers.iter_mut() {
+                    if !refresh_resolver_path(cnx, resolver) {
+                        continue;
+                    }
+                    let quality = fetch_path_quality(cnx, resolver);
+                    let _ = hooks.sample_tx.send(PathSample {
+                        resolver: resolver.addr,
+                        mode: resolver.mode,
+                        at_us: report_time,
+                        cwnd: quality.cwin,
+                        rtt_us: quality.rtt,
+                        bytes_in_transit: quality.bytes_in_transit,
+                        sent: quality.sent,
+                        lost: quality.lost,
+                        pacing: resolver.last_pacing_snapshot,
+                    });
+                }
+            }
+        }
     }
 
+    let handshake_failed = config.handshake_only && unsafe { !(*state_ptr).is_ready() };
     unsafe {
         picoquic_close(cnx, 0);
     }
+    if handshake_failed {
+        return Err(ClientError::new("QUIC handshake failed"));
+    }
 
     Ok(0)
 }
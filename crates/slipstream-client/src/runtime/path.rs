@@ -7,7 +7,7 @@ use crate::streams::{ClientState, PathEvent};
 use slipstream_ffi::picoquic::{
     picoquic_cnx_t, picoquic_get_default_path_quality, picoquic_get_path_addr,
     picoquic_get_path_quality, slipstream_get_path_id_from_unique, slipstream_set_path_ack_delay,
-    slipstream_set_path_mode, PICOQUIC_PACKET_LOOP_SEND_MAX,
+    slipstream_set_path_mode, slipstream_set_path_send_mtu, PICOQUIC_PACKET_LOOP_SEND_MAX,
 };
 use slipstream_ffi::runtime::sockaddr_storage;
 use slipstream_ffi::ResolverMode;
@@ -26,6 +26,7 @@ pub(crate) fn apply_path_mode(
         slipstream_set_path_mode(cnx, resolver.path_id, resolver_mode_to_c(resolver.mode));
         let disable_ack_delay = matches!(resolver.mode, ResolverMode::Authoritative) as libc::c_int;
         slipstream_set_path_ack_delay(cnx, resolver.path_id, disable_ack_delay);
+        slipstream_set_path_send_mtu(cnx, resolver.path_id, resolver.upstream_mtu);
     }
     Ok(())
 }
@@ -101,6 +102,9 @@ pub(crate) fn loop_burst_total(resolvers: &[ResolverState], base: usize) -> usiz
 }
 
 pub(crate) fn path_poll_burst_max(resolver: &ResolverState) -> usize {
+    if resolver.health.is_demoted() {
+        return 1;
+    }
     PICOQUIC_PACKET_LOOP_SEND_MAX.saturating_mul(path_loop_multiplier(resolver.mode))
 }
 
@@ -1,15 +1,22 @@
-use slipstream_core::tcp::{stream_read_limit_chunks, tcp_send_buffer_bytes};
+mod reverse;
+
+use slipstream_core::datagram::{decode_datagram, encode_datagram, DatagramQueue};
+use slipstream_core::forward::ForwardSpec;
+use slipstream_core::stream_error::{StreamError, StreamErrorCounts};
+use slipstream_core::stream_header::{encode_stream_header, StreamTarget};
+use slipstream_core::tcp::{original_destination, stream_read_limit_chunks, tcp_send_buffer_bytes};
 use slipstream_ffi::picoquic::{
     picoquic_add_to_stream, picoquic_call_back_event_t, picoquic_cnx_t, picoquic_current_time,
-    picoquic_get_next_local_stream_id, picoquic_mark_active_stream,
-    picoquic_provide_stream_data_buffer, picoquic_reset_stream, picoquic_stream_data_consumed,
+    picoquic_get_next_local_stream_id, picoquic_get_remote_stream_error,
+    picoquic_mark_active_stream, picoquic_mark_datagram_ready, picoquic_provide_stream_data_buffer,
+    picoquic_reset_stream, picoquic_stream_data_consumed,
 };
-use slipstream_ffi::{SLIPSTREAM_FILE_CANCEL_ERROR, SLIPSTREAM_INTERNAL_ERROR};
+use slipstream_ffi::{provide_datagram, SLIPSTREAM_FILE_CANCEL_ERROR, SLIPSTREAM_INTERNAL_ERROR};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
 use tokio::net::{TcpListener as TokioTcpListener, TcpStream as TokioTcpStream};
-use tokio::sync::{mpsc, Notify};
+use tokio::sync::{mpsc, oneshot, Notify};
 use tracing::{debug, info, warn};
 
 const STREAM_READ_CHUNK_BYTES: usize = 4096;
@@ -26,6 +33,15 @@ pub(crate) struct ClientState {
     debug_streams: bool,
     debug_enqueued_bytes: u64,
     debug_last_enqueue_at: u64,
+    /// `-R` forwards; their listeners' connections arrive as server-initiated streams.
+    remote_forwards: Arc<[ForwardSpec]>,
+    /// Lowest server-initiated stream ID not seen yet, so late data never reopens a stream.
+    reverse_next_id: u64,
+    /// UDP flows by the ID of the stream that opened them.
+    datagram_flows: HashMap<u64, mpsc::Sender<Vec<u8>>>,
+    datagrams: DatagramQueue,
+    /// Resets the server sent for each target or policy failure.
+    stream_errors: StreamErrorCounts,
 }
 
 impl ClientState {
@@ -33,6 +49,7 @@ impl ClientState {
         command_tx: mpsc::UnboundedSender<Command>,
         data_notify: Arc<Notify>,
         debug_streams: bool,
+        remote_forwards: Arc<[ForwardSpec]>,
     ) -> Self {
         Self {
             ready: false,
@@ -44,6 +61,11 @@ impl ClientState {
             debug_streams,
             debug_enqueued_bytes: 0,
             debug_last_enqueue_at: 0,
+            remote_forwards,
+            reverse_next_id: 1,
+            datagram_flows: HashMap::new(),
+            datagrams: DatagramQueue::default(),
+            stream_errors: StreamErrorCounts::default(),
         }
     }
 
@@ -82,15 +104,172 @@ struct ClientStream {
 enum StreamWrite {
     Data(Vec<u8>),
     Fin,
+    /// The server reset the stream; close the local end as abruptly as it allows.
+    Reset,
+}
+
+/// Where a stream's data goes locally.
+pub(crate) trait LocalWriter: AsyncWrite + Send + Unpin {
+    /// Arranges for the local peer to see a reset rather than an orderly close once this end
+    /// is dropped. Ends without such a distinction close normally.
+    fn abort(&mut self) {}
+}
+
+impl LocalWriter for tokio::net::tcp::OwnedWriteHalf {
+    fn abort(&mut self) {
+        let stream: &TokioTcpStream = self.as_ref();
+        let _ = stream.set_zero_linger();
+        // Wake the reader half too: the socket only closes, with an RST, once both drop.
+        #[cfg(unix)]
+        unsafe {
+            use std::os::unix::io::AsRawFd;
+            libc::shutdown(stream.as_raw_fd(), libc::SHUT_RD);
+        }
+    }
+}
+
+#[cfg(unix)]
+impl LocalWriter for tokio::net::unix::OwnedWriteHalf {}
+impl LocalWriter for tokio::io::Stdout {}
+impl LocalWriter for tokio::io::Sink {}
+impl LocalWriter for tokio::io::WriteHalf<DuplexStream> {}
+
+/// Whether the local peer should see an RST for `error`. Hard failures mirror the refused or
+/// unreachable connection the peer would have seen connecting directly; the rest end in a FIN.
+fn resets_local_peer(error: StreamError) -> bool {
+    matches!(
+        error,
+        StreamError::Refused | StreamError::Unreachable | StreamError::Denied
+    )
+}
+
+/// The local end of a tunnel stream: an accepted TCP connection, or stdin/stdout in `--stdio`
+/// mode.
+pub(crate) struct LocalStream {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn LocalWriter>,
+    read_limit: usize,
+    send_buffer_bytes: usize,
+    kind: &'static str,
+    /// Sent ahead of the local data, such as a stream header naming the destination.
+    prefix: Option<Vec<u8>>,
+    /// Set for a UDP flow, whose payloads travel as datagrams beside the stream.
+    datagrams: Option<DatagramFlow>,
+}
+
+/// The datagram side of a UDP flow.
+pub(crate) struct DatagramFlow {
+    /// Receives the flow's payloads from the server.
+    pub(crate) to_local: mpsc::Sender<Vec<u8>>,
+    /// Told the stream ID, which tags the flow's datagrams.
+    pub(crate) opened: oneshot::Sender<u64>,
+}
+
+impl LocalStream {
+    pub(crate) fn tcp(stream: TokioTcpStream) -> Self {
+        let _ = stream.set_nodelay(true);
+        let read_limit =
+            stream_read_limit_chunks(&stream, DEFAULT_TCP_RCVBUF_BYTES, STREAM_READ_CHUNK_BYTES);
+        let send_buffer_bytes = tcp_send_buffer_bytes(&stream)
+            .filter(|bytes| *bytes > 0)
+            .unwrap_or(CLIENT_WRITE_COALESCE_DEFAULT_BYTES);
+        let (read_half, write_half) = stream.into_split();
+        Self {
+            reader: Box::new(read_half),
+            writer: Box::new(write_half),
+            read_limit,
+            send_buffer_bytes,
+            kind: "TCP",
+            prefix: None,
+            datagrams: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn unix(stream: tokio::net::UnixStream) -> Self {
+        let read_limit =
+            stream_read_limit_chunks(&stream, DEFAULT_TCP_RCVBUF_BYTES, STREAM_READ_CHUNK_BYTES);
+        let send_buffer_bytes = tcp_send_buffer_bytes(&stream)
+            .filter(|bytes| *bytes > 0)
+            .unwrap_or(CLIENT_WRITE_COALESCE_DEFAULT_BYTES);
+        let (read_half, write_half) = stream.into_split();
+        Self {
+            reader: Box::new(read_half),
+            writer: Box::new(write_half),
+            read_limit,
+            send_buffer_bytes,
+            kind: "Unix",
+            prefix: None,
+            datagrams: None,
+        }
+    }
+
+    pub(crate) fn stdio() -> Self {
+        Self {
+            reader: Box::new(tokio::io::stdin()),
+            writer: Box::new(tokio::io::stdout()),
+            read_limit: DEFAULT_TCP_RCVBUF_BYTES / STREAM_READ_CHUNK_BYTES,
+            send_buffer_bytes: CLIENT_WRITE_COALESCE_DEFAULT_BYTES,
+            kind: "stdio",
+            prefix: None,
+            datagrams: None,
+        }
+    }
+
+    /// The `--tun` stream, whose other end carries framed IP packets.
+    pub(crate) fn tun(stream: DuplexStream) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            read_limit: DEFAULT_TCP_RCVBUF_BYTES / STREAM_READ_CHUNK_BYTES,
+            send_buffer_bytes: CLIENT_WRITE_COALESCE_DEFAULT_BYTES,
+            kind: "TUN",
+            prefix: Some(encode_stream_header(&StreamTarget::Tun)),
+            datagrams: None,
+        }
+    }
+
+    /// A UDP flow's stream. It carries only `header`; the flow closes its end of `stream` to
+    /// end the flow, and sees it closed when the server ends it.
+    pub(crate) fn udp_flow(stream: DuplexStream, header: Vec<u8>, flow: DatagramFlow) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            read_limit: 1,
+            send_buffer_bytes: CLIENT_WRITE_COALESCE_DEFAULT_BYTES,
+            kind: "UDP",
+            prefix: Some(header),
+            datagrams: Some(flow),
+        }
+    }
 }
 
 pub(crate) enum Command {
-    NewStream(TokioTcpStream),
-    StreamData { stream_id: u64, data: Vec<u8> },
-    StreamClosed { stream_id: u64 },
-    StreamReadError { stream_id: u64 },
-    StreamWriteError { stream_id: u64 },
-    StreamWriteDrained { stream_id: u64, bytes: usize },
+    NewStream(LocalStream),
+    StreamData {
+        stream_id: u64,
+        data: Vec<u8>,
+    },
+    StreamClosed {
+        stream_id: u64,
+    },
+    StreamReadError {
+        stream_id: u64,
+    },
+    StreamWriteError {
+        stream_id: u64,
+    },
+    StreamWriteDrained {
+        stream_id: u64,
+        bytes: usize,
+    },
+    /// A UDP payload for the flow opened by `stream_id`.
+    Datagram {
+        stream_id: u64,
+        payload: Vec<u8>,
+    },
 }
 
 pub(crate) enum PathEvent {
@@ -137,7 +316,23 @@ pub(crate) unsafe extern "C" fn client_callback(
                 picoquic_call_back_event_t::picoquic_callback_stop_sending => "stop_sending",
                 _ => "unknown",
             };
+            let error = match fin_or_event {
+                picoquic_call_back_event_t::picoquic_callback_stream_reset => {
+                    StreamError::from_code(picoquic_get_remote_stream_error(cnx, stream_id))
+                }
+                _ => None,
+            };
+            if let Some(error) = error {
+                state.stream_errors.note(error);
+                warn!(
+                    "stream {}: server could not open it reason={} ({})",
+                    stream_id, error, state.stream_errors
+                );
+            }
             if let Some(stream) = state.streams.remove(&stream_id) {
+                if error.is_some_and(resets_local_peer) {
+                    let _ = stream.write_tx.send(StreamWrite::Reset);
+                }
                 warn!(
                     "stream {}: reset event={} rx_bytes={} tx_bytes={} queued={} consumed_offset={} fin_offset={:?} fin_enqueued={}",
                     stream_id,
@@ -163,10 +358,19 @@ pub(crate) unsafe extern "C" fn client_callback(
             state.closing = true;
             info!("Connection closed");
         }
-        picoquic_call_back_event_t::picoquic_callback_prepare_to_send => {
-            if !bytes.is_null() {
-                let _ = picoquic_provide_stream_data_buffer(bytes as *mut _, 0, 0, 0);
-            }
+        picoquic_call_back_event_t::picoquic_callback_prepare_to_send if !bytes.is_null() => {
+            let _ = picoquic_provide_stream_data_buffer(bytes as *mut _, 0, 0, 0);
+        }
+        picoquic_call_back_event_t::picoquic_callback_datagram => {
+            let data = if length > 0 && !bytes.is_null() {
+                unsafe { std::slice::from_raw_parts(bytes as *const u8, length) }
+            } else {
+                &[]
+            };
+            handle_datagram(state, data);
+        }
+        picoquic_call_back_event_t::picoquic_callback_prepare_datagram if !bytes.is_null() => {
+            provide_datagram(&mut state.datagrams, bytes as *mut _, length);
         }
         picoquic_call_back_event_t::picoquic_callback_path_available => {
             state.path_events.push(PathEvent::Available(stream_id));
@@ -180,6 +384,22 @@ pub(crate) unsafe extern "C" fn client_callback(
     0
 }
 
+fn handle_datagram(state: &mut ClientState, data: &[u8]) {
+    let Some((stream_id, payload)) = decode_datagram(data) else {
+        return;
+    };
+    let Some(flow) = state.datagram_flows.get(&stream_id) else {
+        if state.debug_streams {
+            debug!("stream {}: datagram for unknown flow", stream_id);
+        }
+        return;
+    };
+    // UDP is lossy anyway; a flow that cannot keep up drops what it cannot take.
+    if let Err(mpsc::error::TrySendError::Closed(_)) = flow.try_send(payload.to_vec()) {
+        state.datagram_flows.remove(&stream_id);
+    }
+}
+
 fn handle_stream_data(
     cnx: *mut picoquic_cnx_t,
     state: &mut ClientState,
@@ -191,6 +411,9 @@ fn handle_stream_data(
     let mut reset_stream = false;
     let mut remove_stream = false;
 
+    if reverse::is_new_reverse_stream(state, stream_id) {
+        reverse::accept_reverse_stream(state, stream_id);
+    }
     {
         let Some(stream) = state.streams.get_mut(&stream_id) else {
             warn!(
@@ -265,14 +488,42 @@ fn handle_stream_data(
     }
 }
 
+/// What a listener's streams ask the server to connect to.
+pub(crate) enum AcceptTarget {
+    /// The server's `--target-address`.
+    Default,
+    /// One `-L` destination, as its encoded stream header.
+    Header(Vec<u8>),
+    /// Wherever each connection was headed before iptables redirected it (`--transparent`).
+    Original,
+}
+
+/// Accepts TCP clients and opens a stream for each, headed for `target`.
 pub(crate) fn spawn_acceptor(
     listener: TokioTcpListener,
+    target: AcceptTarget,
     command_tx: mpsc::UnboundedSender<Command>,
 ) {
     tokio::spawn(async move {
         loop {
             match listener.accept().await {
-                Ok((stream, _)) => {
+                Ok((stream, peer)) => {
+                    let prefix = match &target {
+                        AcceptTarget::Default => None,
+                        AcceptTarget::Header(header) => Some(header.clone()),
+                        AcceptTarget::Original => match transparent_header(&stream) {
+                            Some(header) => Some(header),
+                            None => {
+                                warn!(
+                                    "Dropping connection from {} with no original destination",
+                                    peer
+                                );
+                                continue;
+                            }
+                        },
+                    };
+                    let mut stream = LocalStream::tcp(stream);
+                    stream.prefix = prefix;
                     if command_tx.send(Command::NewStream(stream)).is_err() {
                         break;
                     }
@@ -284,6 +535,26 @@ pub(crate) fn spawn_acceptor(
     });
 }
 
+/// Connect header for a redirected connection, or `None` when it was not redirected: its
+/// original destination is then this listener, which the server cannot reach.
+fn transparent_header(stream: &TokioTcpStream) -> Option<Vec<u8>> {
+    let local = stream.local_addr().ok()?;
+    let original = match original_destination(stream, local) {
+        Ok(original) => original,
+        Err(err) => {
+            debug!("original destination lookup failed err={}", err);
+            return None;
+        }
+    };
+    if original.ip() == local.ip().to_canonical() && original.port() == local.port() {
+        return None;
+    }
+    Some(encode_stream_header(&StreamTarget::Connect {
+        host: original.ip().to_string(),
+        port: original.port(),
+    }))
+}
+
 pub(crate) fn drain_commands(
     cnx: *mut picoquic_cnx_t,
     state_ptr: *mut ClientState,
@@ -317,34 +588,42 @@ pub(crate) fn handle_command(
     let state = unsafe { &mut *state_ptr };
     match command {
         Command::NewStream(stream) => {
-            let _ = stream.set_nodelay(true);
-            let read_limit = stream_read_limit_chunks(
-                &stream,
-                DEFAULT_TCP_RCVBUF_BYTES,
-                STREAM_READ_CHUNK_BYTES,
-            );
-            let (data_tx, data_rx) = mpsc::channel(read_limit);
+            let (data_tx, data_rx) = mpsc::channel(stream.read_limit);
             let data_notify = state.data_notify.clone();
             let stream_id = unsafe { picoquic_get_next_local_stream_id(cnx, 0) };
-            let send_buffer_bytes = tcp_send_buffer_bytes(&stream)
-                .filter(|bytes| *bytes > 0)
-                .unwrap_or(CLIENT_WRITE_COALESCE_DEFAULT_BYTES);
-            let (read_half, write_half) = stream.into_split();
+            let mut tx_bytes = 0;
+            if let Some(prefix) = stream.prefix.as_ref() {
+                let ret = unsafe {
+                    picoquic_add_to_stream(cnx, stream_id, prefix.as_ptr(), prefix.len(), 0)
+                };
+                if ret < 0 {
+                    // Without its header the stream would reach the server's default target,
+                    // not the one asked for; dropping `stream` closes the local connection.
+                    warn!(
+                        "stream {}: add_to_stream(prefix) failed ret={}; dropping the {} connection",
+                        stream_id, ret, stream.kind
+                    );
+                    let _ =
+                        unsafe { picoquic_reset_stream(cnx, stream_id, SLIPSTREAM_INTERNAL_ERROR) };
+                    return;
+                }
+                tx_bytes = prefix.len() as u64;
+            }
             let (write_tx, write_rx) = mpsc::unbounded_channel();
             let command_tx = state.command_tx.clone();
             spawn_client_reader(
                 stream_id,
-                read_half,
+                stream.reader,
                 command_tx.clone(),
                 data_tx,
                 data_notify,
             );
             spawn_client_writer(
                 stream_id,
-                write_half,
+                stream.writer,
                 write_rx,
                 command_tx,
-                send_buffer_bytes,
+                stream.send_buffer_bytes,
             );
             state.streams.insert(
                 stream_id,
@@ -353,17 +632,24 @@ pub(crate) fn handle_command(
                     data_rx: Some(data_rx),
                     queued_bytes: 0,
                     rx_bytes: 0,
-                    tx_bytes: 0,
+                    tx_bytes,
                     consumed_offset: 0,
                     fin_offset: None,
                     fin_enqueued: false,
                 },
             );
+            if let Some(flow) = stream.datagrams {
+                state
+                    .datagram_flows
+                    .retain(|_, to_local| !to_local.is_closed());
+                state.datagram_flows.insert(stream_id, flow.to_local);
+                let _ = flow.opened.send(stream_id);
+            }
             let _ = unsafe { picoquic_mark_active_stream(cnx, stream_id, 1, std::ptr::null_mut()) };
             if state.debug_streams {
                 debug!("stream {}: accepted", stream_id);
             } else {
-                info!("Accepted TCP stream {}", stream_id);
+                info!("Accepted {} stream {}", stream.kind, stream_id);
             }
         }
         Command::StreamData { stream_id, data } => {
@@ -459,12 +745,26 @@ pub(crate) fn handle_command(
                 state.streams.remove(&stream_id);
             }
         }
+        Command::Datagram { stream_id, payload } => {
+            if !state.streams.contains_key(&stream_id) {
+                return;
+            }
+            if state.datagrams.push(encode_datagram(stream_id, &payload)) {
+                let _ = unsafe { picoquic_mark_datagram_ready(cnx, 1) };
+            } else if state.debug_streams {
+                debug!(
+                    "stream {}: datagram queue full dropped={}",
+                    stream_id,
+                    state.datagrams.dropped()
+                );
+            }
+        }
     }
 }
 
 fn spawn_client_reader(
     stream_id: u64,
-    mut read_half: tokio::net::tcp::OwnedReadHalf,
+    mut read_half: Box<dyn AsyncRead + Send + Unpin>,
     command_tx: mpsc::UnboundedSender<Command>,
     data_tx: mpsc::Sender<Vec<u8>>,
     data_notify: Arc<Notify>,
@@ -499,7 +799,7 @@ fn spawn_client_reader(
 
 fn spawn_client_writer(
     stream_id: u64,
-    mut write_half: tokio::net::tcp::OwnedWriteHalf,
+    mut write_half: Box<dyn LocalWriter>,
     mut write_rx: mpsc::UnboundedReceiver<StreamWrite>,
     command_tx: mpsc::UnboundedSender<Command>,
     coalesce_max_bytes: usize,
@@ -511,6 +811,7 @@ fn spawn_client_writer(
                 StreamWrite::Data(data) => {
                     let mut buffer = data;
                     let mut saw_fin = false;
+                    let mut saw_reset = false;
                     while buffer.len() < coalesce_max_bytes {
                         match write_rx.try_recv() {
                             Ok(StreamWrite::Data(more)) => {
@@ -523,6 +824,10 @@ fn spawn_client_writer(
                                 saw_fin = true;
                                 break;
                             }
+                            Ok(StreamWrite::Reset) => {
+                                saw_reset = true;
+                                break;
+                            }
                             Err(mpsc::error::TryRecvError::Empty) => break,
                             Err(mpsc::error::TryRecvError::Disconnected) => {
                                 saw_fin = true;
@@ -539,6 +844,10 @@ fn spawn_client_writer(
                         stream_id,
                         bytes: len,
                     });
+                    if saw_reset {
+                        write_half.abort();
+                        return;
+                    }
                     if saw_fin {
                         let _ = write_half.shutdown().await;
                         return;
@@ -548,8 +857,28 @@ fn spawn_client_writer(
                     let _ = write_half.shutdown().await;
                     return;
                 }
+                StreamWrite::Reset => {
+                    write_half.abort();
+                    return;
+                }
             }
         }
         let _ = write_half.shutdown().await;
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::transparent_header;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn transparent_header_rejects_unredirected_connections() {
+        // A connection that iptables did not redirect has no destination of its own to name.
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let _client = TcpStream::connect(addr).await.expect("connect");
+        let (accepted, _) = listener.accept().await.expect("accept");
+        assert!(transparent_header(&accepted).is_none());
+    }
+}
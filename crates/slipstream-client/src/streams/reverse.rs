@@ -0,0 +1,155 @@
+//! Reverse tunnels (`-R`): a control stream asks the server to listen, and each connection it
+//! accepts arrives as a server-initiated stream whose header names that listener, which picks
+//! the local destination.
+
+use super::{
+    spawn_client_reader, spawn_client_writer, ClientState, ClientStream, Command, LocalStream,
+    StreamWrite, CLIENT_WRITE_COALESCE_DEFAULT_BYTES, DEFAULT_TCP_RCVBUF_BYTES,
+    STREAM_READ_CHUNK_BYTES,
+};
+use slipstream_core::forward::ForwardSpec;
+use slipstream_core::stream_header::{
+    encode_stream_header, parse_stream_header, StreamHeader, StreamTarget, MAX_STREAM_HEADER_LEN,
+};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio::sync::{mpsc, Notify};
+use tracing::{info, warn};
+
+impl LocalStream {
+    /// A control stream asking the server to listen for `forward`. It carries only the
+    /// header and lives until the connection closes or the server refuses it.
+    pub(crate) fn reverse_control(forward: &ForwardSpec) -> Self {
+        Self {
+            reader: Box::new(tokio::io::empty()),
+            writer: Box::new(tokio::io::sink()),
+            read_limit: 1,
+            send_buffer_bytes: CLIENT_WRITE_COALESCE_DEFAULT_BYTES,
+            kind: "reverse control",
+            prefix: Some(encode_stream_header(&StreamTarget::Listen {
+                host: forward.bind_host.clone(),
+                port: forward.bind_port,
+            })),
+            datagrams: None,
+        }
+    }
+}
+
+/// Server-initiated bidirectional stream IDs end in 0b01.
+pub(super) fn is_new_reverse_stream(state: &ClientState, stream_id: u64) -> bool {
+    stream_id & 0b11 == 0b01
+        && stream_id >= state.reverse_next_id
+        && !state.remote_forwards.is_empty()
+}
+
+pub(super) fn accept_reverse_stream(state: &mut ClientState, stream_id: u64) {
+    state.reverse_next_id = stream_id + 4;
+    let (data_tx, data_rx) = mpsc::channel(DEFAULT_TCP_RCVBUF_BYTES / STREAM_READ_CHUNK_BYTES);
+    let (write_tx, write_rx) = mpsc::unbounded_channel();
+    state.streams.insert(
+        stream_id,
+        ClientStream {
+            write_tx,
+            data_rx: Some(data_rx),
+            queued_bytes: 0,
+            rx_bytes: 0,
+            tx_bytes: 0,
+            consumed_offset: 0,
+            fin_offset: None,
+            fin_enqueued: false,
+        },
+    );
+    spawn_reverse_connector(
+        stream_id,
+        state.remote_forwards.clone(),
+        write_rx,
+        data_tx,
+        state.command_tx.clone(),
+        state.data_notify.clone(),
+    );
+}
+
+fn spawn_reverse_connector(
+    stream_id: u64,
+    forwards: Arc<[ForwardSpec]>,
+    mut write_rx: mpsc::UnboundedReceiver<StreamWrite>,
+    data_tx: mpsc::Sender<Vec<u8>>,
+    command_tx: mpsc::UnboundedSender<Command>,
+    data_notify: Arc<Notify>,
+) {
+    tokio::spawn(async move {
+        let mut head = Vec::new();
+        let (target, len) = loop {
+            let header = match write_rx.recv().await {
+                Some(StreamWrite::Data(data)) => {
+                    head.extend_from_slice(&data);
+                    parse_stream_header(&head)
+                }
+                Some(StreamWrite::Fin | StreamWrite::Reset) | None => StreamHeader::Invalid,
+            };
+            match header {
+                StreamHeader::Present { target, len } => break (target, len),
+                StreamHeader::Incomplete if head.len() < MAX_STREAM_HEADER_LEN => {}
+                _ => {
+                    warn!("stream {}: reverse stream without a header", stream_id);
+                    let _ = command_tx.send(Command::StreamWriteError { stream_id });
+                    return;
+                }
+            }
+        };
+        let forward = match &target {
+            StreamTarget::Accepted { host, port } => forwards
+                .iter()
+                .find(|forward| forward.bind_host == *host && forward.bind_port == *port),
+            _ => None,
+        };
+        let Some(forward) = forward else {
+            warn!(
+                "stream {}: reverse stream for an unknown listener {:?}",
+                stream_id, target
+            );
+            let _ = command_tx.send(Command::StreamWriteError { stream_id });
+            return;
+        };
+        let stream = match TokioTcpStream::connect((forward.host.as_str(), forward.port)).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!(
+                    "stream {}: reverse tunnel connect to {}:{} failed err={}",
+                    stream_id, forward.host, forward.port, err
+                );
+                let _ = command_tx.send(Command::StreamWriteError { stream_id });
+                return;
+            }
+        };
+        let LocalStream {
+            reader,
+            mut writer,
+            send_buffer_bytes,
+            ..
+        } = LocalStream::tcp(stream);
+        // The header is consumed here, and anything after it goes out first.
+        let rest = head.split_off(len);
+        let _ = command_tx.send(Command::StreamWriteDrained {
+            stream_id,
+            bytes: len,
+        });
+        if !rest.is_empty() {
+            if writer.write_all(&rest).await.is_err() {
+                let _ = command_tx.send(Command::StreamWriteError { stream_id });
+                return;
+            }
+            let _ = command_tx.send(Command::StreamWriteDrained {
+                stream_id,
+                bytes: rest.len(),
+            });
+        }
+        info!(
+            "Reverse stream {} connected to {}:{}",
+            stream_id, forward.host, forward.port
+        );
+        spawn_client_reader(stream_id, reader, command_tx.clone(), data_tx, data_notify);
+        spawn_client_writer(stream_id, writer, write_rx, command_tx, send_buffer_bytes);
+    });
+}
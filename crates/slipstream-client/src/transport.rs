@@ -0,0 +1,133 @@
+mod doh;
+mod stream;
+mod udp;
+
+pub(crate) use doh::{DohClient, DohEndpoint, DohMethod};
+pub(crate) use stream::{StreamClient, StreamSecurity};
+pub(crate) use udp::{UdpSocketPool, UdpTransport};
+
+use crate::error::ClientError;
+use slipstream_ffi::ResolverTransport;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Responses from connection-oriented transports, tagged with the resolver's path address.
+pub(crate) type ResponseSender = mpsc::UnboundedSender<(SocketAddr, Vec<u8>)>;
+
+pub(crate) type SendFuture<'a> = Pin<Box<dyn Future<Output = Result<(), ClientError>> + 'a>>;
+
+/// Carries encoded DNS queries from the client to one resolver.
+pub(crate) trait DnsTransport {
+    fn send(&self, packet: Vec<u8>) -> SendFuture<'_>;
+}
+
+/// Shared pieces every transport is built from.
+pub(crate) struct TransportContext {
+    pub(crate) udp: Arc<UdpSocketPool>,
+    pub(crate) doh_method: DohMethod,
+    pub(crate) response_tx: ResponseSender,
+}
+
+/// Builds the transport for a resolver. `connect_addr` is where packets really go and
+/// `peer` is the address picoquic knows the path by.
+pub(crate) fn open_transport(
+    transport: &ResolverTransport,
+    connect_addr: SocketAddr,
+    peer: SocketAddr,
+    ctx: &TransportContext,
+) -> Result<Box<dyn DnsTransport>, ClientError> {
+    Ok(match transport {
+        ResolverTransport::Udp => Box::new(UdpTransport::new(ctx.udp.clone(), connect_addr)),
+        ResolverTransport::Doh { url } => Box::new(DohClient::spawn(
+            DohEndpoint::parse(url).map_err(ClientError::new)?,
+            connect_addr,
+            peer,
+            ctx.doh_method,
+            ctx.response_tx.clone(),
+        )),
+        ResolverTransport::Dot { server_name } => Box::new(StreamClient::spawn(
+            StreamSecurity::Tls {
+                server_name: server_name.clone(),
+            },
+            connect_addr,
+            peer,
+            ctx.response_tx.clone(),
+        )),
+        ResolverTransport::Tcp => Box::new(StreamClient::spawn(
+            StreamSecurity::Plain,
+            connect_addr,
+            peer,
+            ctx.response_tx.clone(),
+        )),
+    })
+}
+
+/// Path address picoquic uses for a resolver. UDP resolvers use their real address; the
+/// others get a stable address in the discard-only prefix 100::/64 so that, for example,
+/// 1.1.1.1 over UDP and over TLS remain distinct paths.
+pub(crate) fn path_peer_addr(
+    transport: &ResolverTransport,
+    connect_addr: SocketAddr,
+) -> SocketAddr {
+    let kind: u16 = match transport {
+        ResolverTransport::Udp => return connect_addr,
+        ResolverTransport::Doh { .. } => 1,
+        ResolverTransport::Dot { .. } => 2,
+        ResolverTransport::Tcp => 3,
+    };
+    let mut hasher = DefaultHasher::new();
+    transport.hash(&mut hasher);
+    connect_addr.hash(&mut hasher);
+    let hash = hasher.finish();
+    let ip = Ipv6Addr::new(
+        0x0100,
+        0,
+        0,
+        0,
+        kind,
+        (hash >> 32) as u16,
+        (hash >> 16) as u16,
+        hash as u16,
+    );
+    SocketAddr::V6(SocketAddrV6::new(ip, connect_addr.port(), 0, 0))
+}
+
+/// Resolver addresses are kept in dual-stack form; sockets connect to the plain IPv4 address.
+fn unmap_dual_stack_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(v4.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::path_peer_addr;
+    use slipstream_ffi::ResolverTransport;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn path_peer_addrs_are_stable_and_distinct() {
+        let addr: SocketAddr = "[::ffff:1.1.1.1]:853".parse().unwrap();
+        let dot = ResolverTransport::Dot {
+            server_name: "one.one.one.one".to_string(),
+        };
+        assert_eq!(path_peer_addr(&ResolverTransport::Udp, addr), addr);
+        let synthetic = path_peer_addr(&dot, addr);
+        assert_eq!(synthetic, path_peer_addr(&dot, addr));
+        assert_ne!(synthetic, path_peer_addr(&ResolverTransport::Tcp, addr));
+        assert_eq!(synthetic.port(), 853);
+        match synthetic {
+            SocketAddr::V6(v6) => assert_eq!(v6.ip().segments()[..4], [0x0100, 0, 0, 0]),
+            SocketAddr::V4(_) => panic!("expected an IPv6 path address"),
+        }
+    }
+}
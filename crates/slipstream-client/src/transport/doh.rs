@@ -0,0 +1,470 @@
+use crate::error::ClientError;
+use bytes::Bytes;
+use h2::client::SendRequest;
+use http::{Method, Request, StatusCode, Uri};
+use openssl::ssl::{SslConnector, SslMethod};
+use slipstream_core::{AddressFamily, HostPort};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio::sync::mpsc;
+use tokio_openssl::SslStream;
+use tracing::{debug, warn};
+
+use super::{unmap_dual_stack_addr, DnsTransport, ResponseSender, SendFuture};
+
+const DOH_CONTENT_TYPE: &str = "application/dns-message";
+const DOH_MAX_RESPONSE_BYTES: usize = 65_535;
+const DOH_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+/// How long the TCP connect to a DoH server may take before the attempt counts as failed.
+const DOH_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum DohMethod {
+    Post,
+    Get,
+}
+
+/// A parsed RFC 8484 endpoint. `http://` URLs speak cleartext HTTP/2 (prior knowledge),
+/// which is only meant for local testing and TLS-terminating proxies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DohEndpoint {
+    secure: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl DohEndpoint {
+    pub(crate) fn parse(url: &str) -> Result<Self, String> {
+        let (secure, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(format!(
+                "DoH URL must start with https:// or http://: {}",
+                url
+            ));
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, "/dns-query"),
+        };
+        let default_port = if secure { 443 } else { 80 };
+        let (host, port) = if let Some(bracketed) = authority.strip_prefix('[') {
+            let end = bracketed
+                .find(']')
+                .ok_or_else(|| format!("Invalid IPv6 host in DoH URL: {}", url))?;
+            let port = match &bracketed[end + 1..] {
+                "" => default_port,
+                port => parse_port(port.strip_prefix(':').unwrap_or(port), url)?,
+            };
+            (&bracketed[..end], port)
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, parse_port(port, url)?),
+                None => (authority, default_port),
+            }
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in DoH URL: {}", url));
+        }
+        let endpoint = Self {
+            secure,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        };
+        // Requests are built from these URIs later; reject what they cannot carry up front.
+        for uri in [endpoint.uri(None), endpoint.uri(Some(&[]))] {
+            uri.parse::<Uri>()
+                .map_err(|err| format!("Invalid DoH URL {}: {}", url, err))?;
+        }
+        Ok(endpoint)
+    }
+
+    pub(crate) fn host_port(&self) -> HostPort {
+        let family = if self.host.parse::<Ipv6Addr>().is_ok() {
+            AddressFamily::V6
+        } else {
+            AddressFamily::V4
+        };
+        HostPort {
+            host: self.host.clone(),
+            port: self.port,
+            family,
+        }
+    }
+
+    fn uri(&self, query: Option<&[u8]>) -> String {
+        let scheme = if self.secure { "https" } else { "http" };
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match query {
+            Some(query) => {
+                let separator = if self.path.contains('?') { '&' } else { '?' };
+                format!(
+                    "{}://{}:{}{}{}dns={}",
+                    scheme,
+                    host,
+                    self.port,
+                    self.path,
+                    separator,
+                    base64url_encode(query)
+                )
+            }
+            None => format!("{}://{}:{}{}", scheme, host, self.port, self.path),
+        }
+    }
+}
+
+fn parse_port(input: &str, url: &str) -> Result<u16, String> {
+    input
+        .parse::<u16>()
+        .ok()
+        .filter(|port| *port != 0)
+        .ok_or_else(|| format!("Invalid port in DoH URL: {}", url))
+}
+
+/// Handle to a background task that multiplexes queries over one reused HTTP/2 connection.
+pub(crate) struct DohClient {
+    peer: SocketAddr,
+    query_tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl DohClient {
+    /// Connects to `connect_addr`; responses are reported as coming from `peer`, the
+    /// address the resolver path uses.
+    pub(crate) fn spawn(
+        endpoint: DohEndpoint,
+        connect_addr: SocketAddr,
+        peer: SocketAddr,
+        method: DohMethod,
+        response_tx: ResponseSender,
+    ) -> Self {
+        let (query_tx, query_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_doh_client(
+            endpoint,
+            connect_addr,
+            peer,
+            method,
+            query_rx,
+            response_tx,
+        ));
+        Self { peer, query_tx }
+    }
+}
+
+impl DnsTransport for DohClient {
+    fn send(&self, packet: Vec<u8>) -> SendFuture<'_> {
+        let result = self
+            .query_tx
+            .send(packet)
+            .map_err(|_| ClientError::new(format!("DoH client for {} stopped", self.peer)));
+        Box::pin(async move { result })
+    }
+}
+
+async fn run_doh_client(
+    endpoint: DohEndpoint,
+    connect_addr: SocketAddr,
+    peer: SocketAddr,
+    method: DohMethod,
+    mut query_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    response_tx: ResponseSender,
+) {
+    let mut sender: Option<SendRequest<Bytes>> = None;
+    let mut retry_at: Option<Instant> = None;
+    while let Some(query) = query_rx.recv().await {
+        if sender.is_none() {
+            // Queries arriving during the backoff are dropped; QUIC retransmits them.
+            if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                continue;
+            }
+            match connect(&endpoint, connect_addr).await {
+                Ok(connected) => {
+                    debug!("DoH connection to {} established", peer);
+                    sender = Some(connected);
+                    retry_at = None;
+                }
+                Err(err) => {
+                    warn!("DoH connect to {} failed: {}", endpoint.host, err);
+                    retry_at = Some(Instant::now() + DOH_RECONNECT_BACKOFF);
+                    continue;
+                }
+            }
+        }
+        let Some(current) = sender.clone() else {
+            continue;
+        };
+        let ready = match current.ready().await {
+            Ok(ready) => ready,
+            Err(err) => {
+                debug!("DoH connection to {} closed: {}", peer, err);
+                sender = None;
+                continue;
+            }
+        };
+        if let Err(err) = send_query(ready, &endpoint, method, query, peer, &response_tx) {
+            debug!("DoH request to {} failed: {}", peer, err);
+            sender = None;
+        }
+    }
+}
+
+fn send_query(
+    mut sender: SendRequest<Bytes>,
+    endpoint: &DohEndpoint,
+    method: DohMethod,
+    query: Vec<u8>,
+    peer: SocketAddr,
+    response_tx: &ResponseSender,
+) -> Result<(), String> {
+    let builder = Request::builder().header("accept", DOH_CONTENT_TYPE);
+    let request = match method {
+        DohMethod::Post => builder
+            .method(Method::POST)
+            .uri(endpoint.uri(None))
+            .header("content-type", DOH_CONTENT_TYPE),
+        DohMethod::Get => builder.method(Method::GET).uri(endpoint.uri(Some(&query))),
+    }
+    .body(())
+    .map_err(|err| err.to_string())?;
+    let (response, mut body_tx) = sender
+        .send_request(request, method == DohMethod::Get)
+        .map_err(|err| err.to_string())?;
+    if method == DohMethod::Post {
+        body_tx
+            .send_data(Bytes::from(query), true)
+            .map_err(|err| err.to_string())?;
+    }
+    let response_tx = response_tx.clone();
+    tokio::spawn(async move {
+        let response = match response.await {
+            Ok(response) => response,
+            Err(err) => {
+                debug!("DoH response from {} failed: {}", peer, err);
+                return;
+            }
+        };
+        if response.status() != StatusCode::OK {
+            debug!(
+                "DoH response from {} had status {}",
+                peer,
+                response.status()
+            );
+            return;
+        }
+        let mut body = response.into_body();
+        let mut out = Vec::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    debug!("DoH body from {} failed: {}", peer, err);
+                    return;
+                }
+            };
+            let _ = body.flow_control().release_capacity(chunk.len());
+            if out.len() + chunk.len() > DOH_MAX_RESPONSE_BYTES {
+                debug!("DoH response from {} exceeds DNS message size", peer);
+                return;
+            }
+            out.extend_from_slice(&chunk);
+        }
+        let _ = response_tx.send((peer, out));
+    });
+    Ok(())
+}
+
+async fn connect(endpoint: &DohEndpoint, addr: SocketAddr) -> Result<SendRequest<Bytes>, String> {
+    let tcp = tokio::time::timeout(
+        DOH_CONNECT_TIMEOUT,
+        TokioTcpStream::connect(unmap_dual_stack_addr(addr)),
+    )
+    .await
+    .map_err(|_| "connect timed out".to_string())?
+    .map_err(|err| err.to_string())?;
+    let _ = tcp.set_nodelay(true);
+    if !endpoint.secure {
+        return handshake(tcp).await;
+    }
+    let mut builder =
+        SslConnector::builder(SslMethod::tls_client()).map_err(|err| err.to_string())?;
+    builder
+        .set_alpn_protos(b"\x02h2")
+        .map_err(|err| err.to_string())?;
+    let ssl = builder
+        .build()
+        .configure()
+        .and_then(|config| config.into_ssl(&endpoint.host))
+        .map_err(|err| err.to_string())?;
+    let mut stream = SslStream::new(ssl, tcp).map_err(|err| err.to_string())?;
+    Pin::new(&mut stream)
+        .connect()
+        .await
+        .map_err(|err| err.to_string())?;
+    if stream.ssl().selected_alpn_protocol() != Some(b"h2") {
+        return Err("server did not negotiate HTTP/2".to_string());
+    }
+    handshake(stream).await
+}
+
+async fn handshake<T>(io: T) -> Result<SendRequest<Bytes>, String>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (sender, connection) = h2::client::handshake(io)
+        .await
+        .map_err(|err| err.to_string())?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("DoH connection ended: {}", err);
+        }
+    });
+    Ok(sender)
+}
+
+fn base64url_encode(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let b0 = chunk[0] as u32;
+        let b1 = chunk.get(1).copied().unwrap_or(0) as u32;
+        let b2 = chunk.get(2).copied().unwrap_or(0) as u32;
+        let triple = (b0 << 16) | (b1 << 8) | b2;
+        let chars = chunk.len() + 1;
+        for index in 0..chars {
+            let shift = 18 - index * 6;
+            out.push(ALPHABET[((triple >> shift) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64url_encode, DohClient, DohEndpoint, DohMethod};
+    use crate::transport::DnsTransport;
+    use bytes::Bytes;
+    use http::{Method, Response, StatusCode};
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn parses_doh_urls() {
+        let endpoint = DohEndpoint::parse("https://dns.example.com/dns-query").expect("url");
+        assert!(endpoint.secure);
+        assert_eq!(endpoint.host, "dns.example.com");
+        assert_eq!(endpoint.port, 443);
+        assert_eq!(endpoint.path, "/dns-query");
+
+        let endpoint = DohEndpoint::parse("http://[::1]:8053").expect("url");
+        assert!(!endpoint.secure);
+        assert_eq!(endpoint.host, "::1");
+        assert_eq!(endpoint.port, 8053);
+        assert_eq!(endpoint.path, "/dns-query");
+        assert_eq!(endpoint.uri(None), "http://[::1]:8053/dns-query");
+
+        assert!(DohEndpoint::parse("dns.example.com/dns-query").is_err());
+        assert!(DohEndpoint::parse("https://:443/").is_err());
+        // Characters a request URI cannot carry fail here rather than when sending.
+        assert!(DohEndpoint::parse("https://dns.example.com/dns query").is_err());
+        assert!(DohEndpoint::parse("https://dns.example.com/q?x=\"1\"").is_err());
+    }
+
+    #[test]
+    fn encodes_base64url_without_padding() {
+        assert_eq!(base64url_encode(b""), "");
+        assert_eq!(base64url_encode(b"f"), "Zg");
+        assert_eq!(base64url_encode(b"fo"), "Zm8");
+        assert_eq!(base64url_encode(b"foo"), "Zm9v");
+        assert_eq!(base64url_encode(&[0xfb, 0xff]), "-_8");
+    }
+
+    /// Stand-in DoH server: answers each request with its DNS message, reversed.
+    async fn spawn_stand_in_server() -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.expect("accept");
+            let mut connection = h2::server::handshake(socket).await.expect("handshake");
+            while let Some(request) = connection.accept().await {
+                let (request, mut respond) = request.expect("request");
+                tokio::spawn(async move {
+                    let (parts, mut body) = request.into_parts();
+                    let mut message = Vec::new();
+                    if parts.method == Method::POST {
+                        assert_eq!(
+                            parts.headers.get("content-type").unwrap(),
+                            "application/dns-message"
+                        );
+                        while let Some(chunk) = body.data().await {
+                            let chunk = chunk.expect("chunk");
+                            let _ = body.flow_control().release_capacity(chunk.len());
+                            message.extend_from_slice(&chunk);
+                        }
+                    } else {
+                        let query = parts.uri.query().expect("query");
+                        let encoded = query.strip_prefix("dns=").expect("dns param");
+                        assert_eq!(encoded, base64url_encode(b"get-query"));
+                        message.extend_from_slice(b"get-query");
+                    }
+                    message.reverse();
+                    let response = Response::builder()
+                        .status(StatusCode::OK)
+                        .header("content-type", "application/dns-message")
+                        .body(())
+                        .expect("response");
+                    let mut send = respond.send_response(response, false).expect("send");
+                    send.send_data(Bytes::from(message), true).expect("data");
+                });
+            }
+        });
+        addr
+    }
+
+    async fn round_trip(method: DohMethod, queries: &[&[u8]]) -> Vec<Vec<u8>> {
+        let addr = spawn_stand_in_server().await;
+        let endpoint =
+            DohEndpoint::parse(&format!("http://127.0.0.1:{}/dns-query", addr.port())).unwrap();
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let client = DohClient::spawn(endpoint, addr, addr, method, response_tx);
+        for query in queries {
+            client.send(query.to_vec()).await.expect("send");
+        }
+        let mut responses = Vec::new();
+        for _ in queries {
+            let (peer, response) = tokio::time::timeout(Duration::from_secs(5), response_rx.recv())
+                .await
+                .expect("response in time")
+                .expect("response");
+            assert_eq!(peer, addr);
+            responses.push(response);
+        }
+        responses.sort();
+        responses
+    }
+
+    #[tokio::test]
+    async fn posts_queries_over_one_connection() {
+        let responses = round_trip(DohMethod::Post, &[b"abc", b"defg", b"hi"]).await;
+        assert_eq!(
+            responses,
+            vec![b"cba".to_vec(), b"gfed".to_vec(), b"ih".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn gets_queries_with_base64url_parameter() {
+        let responses = round_trip(DohMethod::Get, &[b"get-query"]).await;
+        assert_eq!(responses, vec![b"yreuq-teg".to_vec()]);
+    }
+}
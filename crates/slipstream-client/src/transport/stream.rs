@@ -0,0 +1,330 @@
+use crate::error::ClientError;
+use openssl::ssl::{SslConnector, SslMethod};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpStream as TokioTcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_openssl::SslStream;
+use tracing::{debug, warn};
+
+use super::{unmap_dual_stack_addr, DnsTransport, ResponseSender, SendFuture};
+
+const STREAM_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const STREAM_QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+const STREAM_OUTSTANDING_PRUNE_AT: usize = 1024;
+
+/// DNS over TCP (RFC 7766) or over TLS (RFC 7858).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum StreamSecurity {
+    Plain,
+    Tls { server_name: String },
+}
+
+impl StreamSecurity {
+    fn name(&self) -> &'static str {
+        match self {
+            StreamSecurity::Plain => "TCP",
+            StreamSecurity::Tls { .. } => "DoT",
+        }
+    }
+}
+
+/// Handle to a background task that pipelines length-prefixed queries over one persistent
+/// connection, reconnecting when it drops.
+pub(crate) struct StreamClient {
+    peer: SocketAddr,
+    kind: &'static str,
+    query_tx: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+impl StreamClient {
+    /// Connects to `connect_addr`; responses are reported as coming from `peer`, the
+    /// address the resolver path uses.
+    pub(crate) fn spawn(
+        security: StreamSecurity,
+        connect_addr: SocketAddr,
+        peer: SocketAddr,
+        response_tx: ResponseSender,
+    ) -> Self {
+        let kind = security.name();
+        let (query_tx, query_rx) = mpsc::unbounded_channel();
+        tokio::spawn(run_stream_client(
+            security,
+            connect_addr,
+            peer,
+            query_rx,
+            response_tx,
+        ));
+        Self {
+            peer,
+            kind,
+            query_tx,
+        }
+    }
+}
+
+impl DnsTransport for StreamClient {
+    fn send(&self, packet: Vec<u8>) -> SendFuture<'_> {
+        let result = self.query_tx.send(packet).map_err(|_| {
+            ClientError::new(format!("{} client for {} stopped", self.kind, self.peer))
+        });
+        Box::pin(async move { result })
+    }
+}
+
+trait DnsStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> DnsStream for T {}
+
+struct StreamConnection {
+    writer: WriteHalf<Box<dyn DnsStream>>,
+    frames: mpsc::UnboundedReceiver<Vec<u8>>,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for StreamConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn run_stream_client(
+    security: StreamSecurity,
+    connect_addr: SocketAddr,
+    peer: SocketAddr,
+    mut query_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    response_tx: ResponseSender,
+) {
+    let kind = security.name();
+    let mut connection: Option<StreamConnection> = None;
+    // Queries written on the current connection, by DNS ID, so late or unsolicited
+    // responses are not fed to QUIC.
+    let mut outstanding: HashMap<u16, Instant> = HashMap::new();
+    let mut retry_at: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            query = query_rx.recv() => {
+                let Some(query) = query else {
+                    return;
+                };
+                if connection.is_none() {
+                    // Queries arriving during the backoff are dropped; QUIC retransmits them.
+                    if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+                        continue;
+                    }
+                    match connect(&security, connect_addr).await {
+                        Ok(connected) => {
+                            debug!("{} connection to {} established", kind, connect_addr);
+                            connection = Some(connected);
+                            retry_at = None;
+                        }
+                        Err(err) => {
+                            warn!("{} connect to {} failed: {}", kind, connect_addr, err);
+                            retry_at = Some(Instant::now() + STREAM_RECONNECT_BACKOFF);
+                            continue;
+                        }
+                    }
+                }
+                let Some(current) = connection.as_mut() else {
+                    continue;
+                };
+                if let Err(err) = write_frame(&mut current.writer, &query).await {
+                    debug!("{} connection to {} closed: {}", kind, connect_addr, err);
+                    connection = None;
+                    outstanding.clear();
+                    continue;
+                }
+                if let Some(id) = dns_id(&query) {
+                    let now = Instant::now();
+                    if outstanding.len() >= STREAM_OUTSTANDING_PRUNE_AT {
+                        outstanding.retain(|_, sent_at| now - *sent_at < STREAM_QUERY_TIMEOUT);
+                    }
+                    outstanding.insert(id, now);
+                }
+            }
+            frame = next_frame(&mut connection) => match frame {
+                Some(frame) => {
+                    // Pipelined responses may arrive in any order; match them by DNS ID.
+                    if dns_id(&frame).and_then(|id| outstanding.remove(&id)).is_none() {
+                        debug!("{} response from {} matches no query", kind, connect_addr);
+                        continue;
+                    }
+                    if response_tx.send((peer, frame)).is_err() {
+                        return;
+                    }
+                }
+                None => {
+                    debug!("{} connection to {} closed by peer", kind, connect_addr);
+                    connection = None;
+                    outstanding.clear();
+                }
+            }
+        }
+    }
+}
+
+async fn next_frame(connection: &mut Option<StreamConnection>) -> Option<Vec<u8>> {
+    match connection {
+        Some(connection) => connection.frames.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn connect(security: &StreamSecurity, addr: SocketAddr) -> Result<StreamConnection, String> {
+    let tcp = TokioTcpStream::connect(unmap_dual_stack_addr(addr))
+        .await
+        .map_err(|err| err.to_string())?;
+    let _ = tcp.set_nodelay(true);
+    let stream: Box<dyn DnsStream> = match security {
+        StreamSecurity::Plain => Box::new(tcp),
+        StreamSecurity::Tls { server_name } => {
+            let ssl = SslConnector::builder(SslMethod::tls_client())
+                .map_err(|err| err.to_string())?
+                .build()
+                .configure()
+                .and_then(|config| config.into_ssl(server_name))
+                .map_err(|err| err.to_string())?;
+            let mut stream = SslStream::new(ssl, tcp).map_err(|err| err.to_string())?;
+            Pin::new(&mut stream)
+                .connect()
+                .await
+                .map_err(|err| err.to_string())?;
+            Box::new(stream)
+        }
+    };
+    let (reader, writer) = tokio::io::split(stream);
+    let (frame_tx, frames) = mpsc::unbounded_channel();
+    let reader = tokio::spawn(read_frames(reader, frame_tx));
+    Ok(StreamConnection {
+        writer,
+        frames,
+        reader,
+    })
+}
+
+async fn read_frames(
+    mut reader: ReadHalf<Box<dyn DnsStream>>,
+    frame_tx: mpsc::UnboundedSender<Vec<u8>>,
+) {
+    loop {
+        let mut len = [0u8; 2];
+        if reader.read_exact(&mut len).await.is_err() {
+            return;
+        }
+        let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+        if reader.read_exact(&mut frame).await.is_err() {
+            return;
+        }
+        if frame_tx.send(frame).is_err() {
+            return;
+        }
+    }
+}
+
+async fn write_frame(
+    writer: &mut WriteHalf<Box<dyn DnsStream>>,
+    message: &[u8],
+) -> std::io::Result<()> {
+    let len = u16::try_from(message.len()).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "DNS message exceeds 65535 bytes",
+        )
+    })?;
+    let mut frame = Vec::with_capacity(message.len() + 2);
+    frame.extend_from_slice(&len.to_be_bytes());
+    frame.extend_from_slice(message);
+    writer.write_all(&frame).await?;
+    writer.flush().await
+}
+
+fn dns_id(message: &[u8]) -> Option<u16> {
+    (message.len() >= 2).then(|| u16::from_be_bytes([message[0], message[1]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamClient, StreamSecurity};
+    use crate::transport::DnsTransport;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::sync::mpsc;
+
+    async fn read_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await.ok()?;
+        let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut message).await.ok()?;
+        Some(message)
+    }
+
+    async fn write_message(stream: &mut TcpStream, message: &[u8]) {
+        stream
+            .write_all(&(message.len() as u16).to_be_bytes())
+            .await
+            .unwrap();
+        stream.write_all(message).await.unwrap();
+    }
+
+    async fn recv(
+        response_rx: &mut mpsc::UnboundedReceiver<(std::net::SocketAddr, Vec<u8>)>,
+    ) -> Vec<u8> {
+        tokio::time::timeout(Duration::from_secs(5), response_rx.recv())
+            .await
+            .expect("response in time")
+            .expect("response")
+            .1
+    }
+
+    #[tokio::test]
+    async fn matches_pipelined_responses_by_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut queries = Vec::new();
+            for _ in 0..3 {
+                queries.push(read_message(&mut stream).await.unwrap());
+            }
+            write_message(&mut stream, &[0x99, 0x99, 0xff]).await;
+            for query in queries.iter().rev() {
+                write_message(&mut stream, query).await;
+            }
+            let _ = read_message(&mut stream).await;
+        });
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let client = StreamClient::spawn(StreamSecurity::Plain, addr, addr, response_tx);
+        for id in 1u8..=3 {
+            client.send(vec![0, id, 0xaa]).await.expect("send");
+        }
+        for id in (1u8..=3).rev() {
+            assert_eq!(recv(&mut response_rx).await, vec![0, id, 0xaa]);
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_peer_closes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                if let Some(query) = read_message(&mut stream).await {
+                    write_message(&mut stream, &query).await;
+                }
+            }
+        });
+        let (response_tx, mut response_rx) = mpsc::unbounded_channel();
+        let client = StreamClient::spawn(StreamSecurity::Plain, addr, addr, response_tx);
+        client.send(vec![0, 1]).await.expect("send");
+        assert_eq!(recv(&mut response_rx).await, vec![0, 1]);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.send(vec![0, 2]).await.expect("send");
+        assert_eq!(recv(&mut response_rx).await, vec![0, 2]);
+    }
+}
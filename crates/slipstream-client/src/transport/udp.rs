@@ -0,0 +1,127 @@
+use crate::error::ClientError;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket as TokioUdpSocket;
+
+use super::{DnsTransport, SendFuture};
+
+/// The client's UDP sockets. Queries rotate across them so resolvers see several source
+/// ports; QUIC only ever sees the first socket's local address.
+pub(crate) struct UdpSocketPool {
+    sockets: Vec<TokioUdpSocket>,
+    next_send: AtomicUsize,
+    next_recv: AtomicUsize,
+}
+
+impl UdpSocketPool {
+    pub(crate) fn new(sockets: Vec<TokioUdpSocket>) -> Self {
+        assert!(
+            !sockets.is_empty(),
+            "UdpSocketPool needs at least one socket"
+        );
+        Self {
+            sockets,
+            next_send: AtomicUsize::new(0),
+            next_recv: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.sockets[0].local_addr()
+    }
+
+    pub(crate) async fn send_to(&self, packet: &[u8], dest: SocketAddr) -> std::io::Result<usize> {
+        let index = self.next_send.fetch_add(1, Ordering::Relaxed) % self.sockets.len();
+        self.sockets[index].send_to(packet, dest).await
+    }
+
+    /// Waits for a datagram on any socket, starting the scan at a rotating index so a busy
+    /// socket cannot starve the others.
+    pub(crate) async fn recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        std::future::poll_fn(|cx| {
+            let start = self.next_recv.fetch_add(1, Ordering::Relaxed);
+            for offset in 0..self.sockets.len() {
+                let socket = &self.sockets[(start + offset) % self.sockets.len()];
+                let mut read_buf = ReadBuf::new(buf);
+                match socket.poll_recv_from(cx, &mut read_buf) {
+                    Poll::Ready(Ok(peer)) => {
+                        return Poll::Ready(Ok((read_buf.filled().len(), peer)))
+                    }
+                    Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                    Poll::Pending => {}
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Non-blocking receive from whichever socket has a datagram queued.
+    pub(crate) fn try_recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let start = self.next_recv.fetch_add(1, Ordering::Relaxed);
+        for offset in 0..self.sockets.len() {
+            let socket = &self.sockets[(start + offset) % self.sockets.len()];
+            match socket.try_recv_from(buf) {
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
+        Err(std::io::ErrorKind::WouldBlock.into())
+    }
+}
+
+/// Plain DNS over the client's UDP sockets; responses arrive on the main loop.
+pub(crate) struct UdpTransport {
+    sockets: Arc<UdpSocketPool>,
+    dest: SocketAddr,
+}
+
+impl UdpTransport {
+    pub(crate) fn new(sockets: Arc<UdpSocketPool>, dest: SocketAddr) -> Self {
+        Self { sockets, dest }
+    }
+}
+
+impl DnsTransport for UdpTransport {
+    fn send(&self, packet: Vec<u8>) -> SendFuture<'_> {
+        Box::pin(async move {
+            self.sockets
+                .send_to(&packet, self.dest)
+                .await
+                .map_err(|err| ClientError::new(err.to_string()))?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UdpSocketPool;
+    use std::collections::HashSet;
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn spreads_queries_and_reads_every_socket() {
+        let resolver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut sockets = Vec::new();
+        for _ in 0..3 {
+            sockets.push(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        }
+        let pool = UdpSocketPool::new(sockets);
+        let resolver_addr = resolver.local_addr().unwrap();
+        let mut sources = HashSet::new();
+        let mut buf = [0u8; 16];
+        for _ in 0..3 {
+            pool.send_to(b"q", resolver_addr).await.unwrap();
+            let (_, source) = resolver.recv_from(&mut buf).await.unwrap();
+            sources.insert(source);
+            resolver.send_to(b"r", source).await.unwrap();
+            let (size, peer) = pool.recv_from(&mut buf).await.unwrap();
+            assert_eq!((&buf[..size], peer), (&b"r"[..], resolver_addr));
+        }
+        assert_eq!(sources.len(), 3);
+    }
+}
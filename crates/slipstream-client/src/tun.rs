@@ -0,0 +1,80 @@
+//! `--tun` full-tunnel mode (Linux only): every IP packet of a TUN device crosses one stream,
+//! framed by `slipstream_core::tun`. Addresses and routes are set up outside the client.
+
+use crate::error::ClientError;
+use tokio::io::DuplexStream;
+
+#[cfg(target_os = "linux")]
+pub(crate) use slipstream_core::tun::TunDevice;
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) enum TunDevice {}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn open_tun(name: &str) -> Result<TunDevice, ClientError> {
+    TunDevice::open(name)
+        .map_err(|err| ClientError::new(format!("Failed to open TUN device {}: {}", name, err)))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn open_tun(_name: &str) -> Result<TunDevice, ClientError> {
+    Err(ClientError::new("TUN mode is only supported on Linux"))
+}
+
+/// Moves packets between `device` and the tunnel stream's local end until either closes.
+#[cfg(target_os = "linux")]
+pub(crate) async fn run_tun_bridge(device: TunDevice, stream: DuplexStream) {
+    use slipstream_core::tun::{frame_packet, PacketDeframer, TUN_PACKET_MAX_BYTES};
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tracing::warn;
+
+    let device = match AsyncFd::new(device) {
+        Ok(device) => device,
+        Err(err) => {
+            warn!("TUN device cannot be polled err={}", err);
+            return;
+        }
+    };
+    let (mut from_tunnel, mut to_tunnel) = tokio::io::split(stream);
+    let mut deframer = PacketDeframer::default();
+    let mut packet = vec![0u8; TUN_PACKET_MAX_BYTES];
+    let mut data = vec![0u8; 16 * 1024];
+    loop {
+        tokio::select! {
+            guard = device.readable() => {
+                let Ok(mut guard) = guard else {
+                    break;
+                };
+                match guard.try_io(|device| device.get_ref().recv(&mut packet)) {
+                    Ok(Ok(len)) => {
+                        if to_tunnel.write_all(&frame_packet(&packet[..len])).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(Err(err)) => {
+                        warn!("TUN device {} read failed err={}", device.get_ref().name(), err);
+                        break;
+                    }
+                    Err(_would_block) => {}
+                }
+            }
+            read = from_tunnel.read(&mut data) => {
+                let len = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                deframer.push(&data[..len]);
+                while let Some(packet) = deframer.next_packet() {
+                    // Like any link, the device drops what it cannot queue.
+                    let _ = device.get_ref().send(&packet);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) async fn run_tun_bridge(device: TunDevice, _stream: DuplexStream) {
+    match device {}
+}
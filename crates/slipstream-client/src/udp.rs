@@ -0,0 +1,119 @@
+//! `-U` UDP forwards. Each local peer of a forward's socket gets its own flow: a stream whose
+//! header names the destination, with the payloads carried as QUIC datagrams tagged by the
+//! stream's ID. A flow ends after its idle timeout, or when the server ends the stream.
+
+use crate::streams::{Command, DatagramFlow, LocalStream};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+
+/// Largest UDP payload read from a local peer; bigger datagrams never fit the tunnel anyway.
+const UDP_PAYLOAD_MAX_BYTES: usize = 1500;
+/// Payloads a flow buffers in each direction before dropping more.
+const FLOW_QUEUE_DATAGRAMS: usize = 64;
+
+pub(crate) fn spawn_udp_forward(
+    socket: UdpSocket,
+    header: Vec<u8>,
+    command_tx: mpsc::UnboundedSender<Command>,
+    idle_timeout: Duration,
+) {
+    tokio::spawn(async move {
+        let socket = Arc::new(socket);
+        let mut flows: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+        let mut buf = vec![0u8; UDP_PAYLOAD_MAX_BYTES];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                // ICMP errors from earlier sends surface here on some platforms.
+                Err(err) if err.kind() == std::io::ErrorKind::ConnectionReset => continue,
+                Err(err) => {
+                    warn!("UDP forward stopped err={}", err);
+                    break;
+                }
+            };
+            let payload = buf[..len].to_vec();
+            if let Some(from_local) = flows.get(&peer).filter(|flow| !flow.is_closed()) {
+                let _ = from_local.try_send(payload);
+                continue;
+            }
+            flows.retain(|_, flow| !flow.is_closed());
+            let (from_local, from_local_rx) = mpsc::channel(FLOW_QUEUE_DATAGRAMS);
+            let _ = from_local.try_send(payload);
+            flows.insert(peer, from_local);
+            spawn_udp_flow(
+                peer,
+                socket.clone(),
+                header.clone(),
+                from_local_rx,
+                command_tx.clone(),
+                idle_timeout,
+            );
+        }
+    });
+}
+
+fn spawn_udp_flow(
+    peer: SocketAddr,
+    socket: Arc<UdpSocket>,
+    header: Vec<u8>,
+    mut from_local: mpsc::Receiver<Vec<u8>>,
+    command_tx: mpsc::UnboundedSender<Command>,
+    idle_timeout: Duration,
+) {
+    tokio::spawn(async move {
+        let (stream_end, mut flow_end) = tokio::io::duplex(64);
+        let (to_local_tx, mut to_local) = mpsc::channel(FLOW_QUEUE_DATAGRAMS);
+        let (opened_tx, opened_rx) = oneshot::channel();
+        let flow = DatagramFlow {
+            to_local: to_local_tx,
+            opened: opened_tx,
+        };
+        let stream = LocalStream::udp_flow(stream_end, header, flow);
+        if command_tx.send(Command::NewStream(stream)).is_err() {
+            return;
+        }
+        let Ok(stream_id) = opened_rx.await else {
+            return;
+        };
+        let mut closed = [0u8; 1];
+        let mut deadline = Instant::now() + idle_timeout;
+        loop {
+            tokio::select! {
+                payload = from_local.recv() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    deadline = Instant::now() + idle_timeout;
+                    if command_tx.send(Command::Datagram { stream_id, payload }).is_err() {
+                        break;
+                    }
+                }
+                payload = to_local.recv() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    deadline = Instant::now() + idle_timeout;
+                    let _ = socket.send_to(&payload, peer).await;
+                }
+                // The stream carries nothing, so any read means the server ended the flow.
+                _ = flow_end.read(&mut closed) => {
+                    debug!("stream {}: UDP flow for {} closed by the server", stream_id, peer);
+                    break;
+                }
+                _ = sleep_until(deadline) => {
+                    debug!("stream {}: UDP flow for {} idle", stream_id, peer);
+                    break;
+                }
+            }
+        }
+        // Dropping our end of the stream sends its FIN, which ends the flow on the server.
+    });
+}
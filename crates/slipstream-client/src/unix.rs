@@ -0,0 +1,94 @@
+//! `-l unix:/path`: take local clients on a Unix socket instead of a TCP port, so only users
+//! the socket's permissions admit can reach the tunnel.
+
+use crate::error::ClientError;
+use crate::streams::Command;
+use tokio::sync::mpsc;
+
+/// Removes the socket file when the client exits.
+pub(crate) struct UnixSocketGuard {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    path: std::path::PathBuf,
+}
+
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Binds `path` with mode `0600`, replacing a stale socket but never another kind of file,
+/// and hands each connection to the tunnel as a new stream.
+#[cfg(unix)]
+pub(crate) fn spawn_unix_acceptor(
+    path: &str,
+    command_tx: mpsc::UnboundedSender<Command>,
+) -> Result<UnixSocketGuard, ClientError> {
+    use crate::runtime::map_io;
+    use crate::streams::LocalStream;
+    use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+    use tokio::net::UnixListener;
+
+    let path = std::path::PathBuf::from(path);
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            return Err(ClientError::new(format!(
+                "Listen path {} exists and is not a socket",
+                path.display()
+            )));
+        }
+        std::fs::remove_file(&path).map_err(map_io)?;
+    }
+    // Bind inside a private directory and move the socket into place once it is 0600, so it
+    // is never reachable with the umask's permissions.
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| ClientError::new(format!("Invalid listen path {}", path.display())))?;
+    let staging = path.with_file_name(format!(
+        ".{}.{}",
+        file_name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .map_err(map_io)?;
+    let staged = staging.join("socket");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, &path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&staging);
+    let listener = bound.map_err(map_io)?;
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    if command_tx
+                        .send(Command::NewStream(LocalStream::unix(stream)))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            }
+        }
+    });
+    Ok(UnixSocketGuard { path })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn spawn_unix_acceptor(
+    _path: &str,
+    _command_tx: mpsc::UnboundedSender<Command>,
+) -> Result<UnixSocketGuard, ClientError> {
+    Err(ClientError::new(
+        "Unix socket listeners are only supported on Unix platforms",
+    ))
+}
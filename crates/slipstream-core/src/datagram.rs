@@ -0,0 +1,127 @@
+//! QUIC DATAGRAM payloads for UDP flows. Each carries one UDP payload behind a QUIC varint
+//! flow ID, which is the ID of the stream that opened the flow.
+
+use std::collections::VecDeque;
+
+/// Outgoing datagrams a connection holds while the tunnel is busy; more are dropped.
+pub const DATAGRAM_QUEUE_MAX: usize = 256;
+
+/// Largest DATAGRAM frame either side accepts; the tunnel's packets are far smaller anyway.
+pub const MAX_DATAGRAM_FRAME_SIZE: u32 = 1536;
+
+pub fn encode_datagram(flow_id: u64, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + payload.len());
+    match flow_id {
+        0..=0x3f => out.push(flow_id as u8),
+        0x40..=0x3fff => out.extend_from_slice(&(flow_id as u16 | 0x4000).to_be_bytes()),
+        0x4000..=0x3fff_ffff => {
+            out.extend_from_slice(&(flow_id as u32 | 0x8000_0000).to_be_bytes())
+        }
+        _ => out.extend_from_slice(&(flow_id | 0xc000_0000_0000_0000).to_be_bytes()),
+    }
+    out.extend_from_slice(payload);
+    out
+}
+
+/// Splits a datagram into its flow ID and payload.
+pub fn decode_datagram(bytes: &[u8]) -> Option<(u64, &[u8])> {
+    let first = *bytes.first()?;
+    let len = 1usize << (first >> 6);
+    let id_bytes = bytes.get(..len)?;
+    let flow_id = id_bytes[1..]
+        .iter()
+        .fold(u64::from(first & 0x3f), |id, byte| {
+            (id << 8) | u64::from(*byte)
+        });
+    Some((flow_id, &bytes[len..]))
+}
+
+/// Datagrams waiting for room in a packet. The tunnel only offers what is left once stream
+/// data is written, so a datagram that does not fit is given one more, fresh packet before
+/// it is dropped.
+#[derive(Debug, Default)]
+pub struct DatagramQueue {
+    queue: VecDeque<Vec<u8>>,
+    deferred: bool,
+    dropped: u64,
+}
+
+impl DatagramQueue {
+    /// Queues a datagram, or drops it and returns false when the queue is full.
+    pub fn push(&mut self, datagram: Vec<u8>) -> bool {
+        if self.queue.len() >= DATAGRAM_QUEUE_MAX {
+            self.dropped = self.dropped.saturating_add(1);
+            return false;
+        }
+        self.queue.push_back(datagram);
+        true
+    }
+
+    /// Takes the next datagram that fits in `space` bytes. None with datagrams still queued
+    /// means the caller should ask again for the next packet.
+    pub fn take(&mut self, space: usize) -> Option<Vec<u8>> {
+        while let Some(front) = self.queue.front() {
+            if front.len() <= space {
+                self.deferred = false;
+                return self.queue.pop_front();
+            }
+            if !self.deferred {
+                self.deferred = true;
+                return None;
+            }
+            self.queue.pop_front();
+            self.deferred = false;
+            self.dropped = self.dropped.saturating_add(1);
+        }
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_datagram, encode_datagram, DatagramQueue, DATAGRAM_QUEUE_MAX};
+
+    #[test]
+    fn flow_ids_round_trip() {
+        for (flow_id, id_len) in [
+            (0, 1),
+            (63, 1),
+            (64, 2),
+            (16_383, 2),
+            (16_384, 4),
+            (1 << 40, 8),
+        ] {
+            let datagram = encode_datagram(flow_id, b"query");
+            assert_eq!(datagram.len(), id_len + 5);
+            assert_eq!(decode_datagram(&datagram), Some((flow_id, &b"query"[..])));
+        }
+        assert_eq!(decode_datagram(&[]), None);
+        assert_eq!(decode_datagram(&[0x80, 1]), None);
+    }
+
+    #[test]
+    fn oversized_datagrams_get_one_more_packet() {
+        let mut queue = DatagramQueue::default();
+        assert!(queue.push(vec![0; 100]));
+        assert!(queue.push(vec![1; 10]));
+        assert_eq!(queue.take(50), None);
+        assert!(!queue.is_empty());
+        assert_eq!(queue.take(50), Some(vec![1; 10]));
+        assert_eq!(queue.dropped(), 1);
+        assert!(queue.is_empty());
+
+        for _ in 0..DATAGRAM_QUEUE_MAX {
+            assert!(queue.push(Vec::new()));
+        }
+        assert!(!queue.push(Vec::new()));
+        assert_eq!(queue.dropped(), 2);
+    }
+}
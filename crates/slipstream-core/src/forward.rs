@@ -0,0 +1,111 @@
+//! ssh-style `[bind:]port:host:hostport` port-forward specs.
+
+use crate::ConfigError;
+
+/// Address a forward listens on when the spec leaves the bind address out.
+pub const DEFAULT_FORWARD_BIND: &str = "127.0.0.1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardSpec {
+    pub bind_host: String,
+    pub bind_port: u16,
+    /// Destination as written in the spec; it is resolved by the side that dials it.
+    pub host: String,
+    pub port: u16,
+}
+
+/// Parses `[bind:]port:host:hostport`. IPv6 addresses go in brackets; an empty or `*` bind
+/// address listens on all interfaces.
+pub fn parse_forward_spec(input: &str) -> Result<ForwardSpec, ConfigError> {
+    let invalid = || {
+        ConfigError::new(format!(
+            "Invalid forward (expected [bind:]port:host:hostport): {}",
+            input
+        ))
+    };
+    let fields = split_fields(input).ok_or_else(invalid)?;
+    let (bind_host, rest) = match fields.len() {
+        3 => (DEFAULT_FORWARD_BIND.to_string(), &fields[..]),
+        4 => {
+            let bind = match fields[0].as_str() {
+                "" | "*" => "0.0.0.0".to_string(),
+                bind => bind.to_string(),
+            };
+            (bind, &fields[1..])
+        }
+        _ => return Err(invalid()),
+    };
+    let parse_port = |field: &str| field.parse::<u16>().ok().filter(|port| *port != 0);
+    let bind_port = parse_port(&rest[0]).ok_or_else(invalid)?;
+    let port = parse_port(&rest[2]).ok_or_else(invalid)?;
+    let host = rest[1].clone();
+    if host.is_empty() || host.len() > u8::MAX as usize {
+        return Err(invalid());
+    }
+    Ok(ForwardSpec {
+        bind_host,
+        bind_port,
+        host,
+        port,
+    })
+}
+
+/// Splits on colons outside brackets and strips the brackets.
+fn split_fields(input: &str) -> Option<Vec<String>> {
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut bracketed = false;
+    for c in input.chars() {
+        match c {
+            '[' if !bracketed && current.is_empty() => bracketed = true,
+            ']' if bracketed => bracketed = false,
+            ':' if !bracketed => fields.push(std::mem::take(&mut current)),
+            _ => current.push(c),
+        }
+    }
+    if bracketed {
+        return None;
+    }
+    fields.push(current);
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_forward_spec, ForwardSpec};
+
+    #[test]
+    fn parses_forward_specs() {
+        assert_eq!(
+            parse_forward_spec("2222:10.0.0.5:22").expect("short spec"),
+            ForwardSpec {
+                bind_host: "127.0.0.1".to_string(),
+                bind_port: 2222,
+                host: "10.0.0.5".to_string(),
+                port: 22,
+            }
+        );
+        let spec = parse_forward_spec("*:5432:db.internal:5432").expect("wildcard bind");
+        assert_eq!(spec.bind_host, "0.0.0.0");
+        assert_eq!(spec.host, "db.internal");
+        let spec = parse_forward_spec("[::1]:8080:[fd00::7]:80").expect("ipv6 spec");
+        assert_eq!(spec.bind_host, "::1");
+        assert_eq!(spec.host, "fd00::7");
+        assert_eq!(spec.port, 80);
+    }
+
+    #[test]
+    fn rejects_malformed_forward_specs() {
+        for spec in [
+            "2222",
+            "2222:host",
+            "0:host:22",
+            "2222:host:65536",
+            "2222::22",
+            "a:b:2222:host:22",
+            "[::1:8080:host:80",
+        ] {
+            assert!(parse_forward_spec(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -1,8 +1,14 @@
 use std::fmt;
 
+pub mod datagram;
+pub mod forward;
 mod macros;
+pub mod proxy_protocol;
 pub mod stream;
+pub mod stream_error;
+pub mod stream_header;
 pub mod tcp;
+pub mod tun;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -155,6 +161,17 @@ pub fn parse_host_port(
     })
 }
 
+/// Path of a `unix:/path` address, or `None` when `input` is a host and port.
+pub fn parse_unix_path(input: &str) -> Result<Option<&str>, ConfigError> {
+    match input.strip_prefix("unix:") {
+        Some("") => Err(ConfigError::new(format!(
+            "Unix socket address is missing a path: {}",
+            input
+        ))),
+        path => Ok(path),
+    }
+}
+
 pub fn resolve_host_port(address: &HostPort) -> Result<SocketAddr, ConfigError> {
     match address.family {
         AddressFamily::V4 => {
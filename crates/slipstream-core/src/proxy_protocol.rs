@@ -0,0 +1,159 @@
+//! PROXY protocol headers (HAProxy's v1 text and v2 binary formats), written ahead of the data
+//! on a target connection so the target can tell tunnel connections apart.
+
+use std::net::{IpAddr, SocketAddr};
+
+const V2_SIGNATURE: [u8; 12] = [
+    0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+];
+/// Version 2, PROXY command.
+const V2_PROXY: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+/// `PP2_TYPE_UNIQUE_ID`: an opaque identifier of up to 128 bytes.
+pub const PP2_TYPE_UNIQUE_ID: u8 = 0x05;
+const UNIQUE_ID_MAX_LEN: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProxyVersion {
+    V1,
+    V2,
+}
+
+impl ProxyVersion {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        match input {
+            "v1" | "1" => Ok(ProxyVersion::V1),
+            "v2" | "2" => Ok(ProxyVersion::V2),
+            _ => Err(format!(
+                "Invalid PROXY protocol version (expected v1 or v2): {}",
+                input
+            )),
+        }
+    }
+}
+
+/// What a header says about one connection. Missing addresses are sent as unknown.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: Option<SocketAddr>,
+    pub destination: Option<SocketAddr>,
+    /// Sent as a `PP2_TYPE_UNIQUE_ID` TLV in v2, truncated to 128 bytes; v1 cannot carry it.
+    pub unique_id: Option<Vec<u8>>,
+}
+
+pub fn encode_proxy_header(version: ProxyVersion, header: &ProxyHeader) -> Vec<u8> {
+    let addresses = header
+        .source
+        .zip(header.destination)
+        .map(|(source, destination)| same_family(source, destination));
+    match version {
+        ProxyVersion::V1 => match addresses {
+            Some((source, destination)) => format!(
+                "PROXY {} {} {} {} {}\r\n",
+                if source.is_ipv4() { "TCP4" } else { "TCP6" },
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes(),
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyVersion::V2 => {
+            let mut body = Vec::new();
+            let family = match addresses {
+                Some((SocketAddr::V4(source), SocketAddr::V4(destination))) => {
+                    body.extend_from_slice(&source.ip().octets());
+                    body.extend_from_slice(&destination.ip().octets());
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    V2_TCP4
+                }
+                Some((SocketAddr::V6(source), SocketAddr::V6(destination))) => {
+                    body.extend_from_slice(&source.ip().octets());
+                    body.extend_from_slice(&destination.ip().octets());
+                    body.extend_from_slice(&source.port().to_be_bytes());
+                    body.extend_from_slice(&destination.port().to_be_bytes());
+                    V2_TCP6
+                }
+                _ => V2_UNSPEC,
+            };
+            if let Some(unique_id) = &header.unique_id {
+                let unique_id = &unique_id[..unique_id.len().min(UNIQUE_ID_MAX_LEN)];
+                body.push(PP2_TYPE_UNIQUE_ID);
+                body.extend_from_slice(&(unique_id.len() as u16).to_be_bytes());
+                body.extend_from_slice(unique_id);
+            }
+            let mut out = Vec::with_capacity(16 + body.len());
+            out.extend_from_slice(&V2_SIGNATURE);
+            out.push(V2_PROXY);
+            out.push(family);
+            out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+            out.extend_from_slice(&body);
+            out
+        }
+    }
+}
+
+/// Both addresses in one family, as the header requires; IPv4 is mapped into IPv6 if needed.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    if source.is_ipv4() == destination.is_ipv4() {
+        return (source, destination);
+    }
+    let to_v6 = |addr: SocketAddr| match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), addr.port()),
+        IpAddr::V6(_) => addr,
+    };
+    (to_v6(source), to_v6(destination))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_proxy_header, ProxyHeader, ProxyVersion, PP2_TYPE_UNIQUE_ID};
+
+    fn header() -> ProxyHeader {
+        ProxyHeader {
+            source: Some("198.51.100.7:53124".parse().expect("source")),
+            destination: Some("127.0.0.1:22".parse().expect("destination")),
+            unique_id: Some(b"7".to_vec()),
+        }
+    }
+
+    #[test]
+    fn v1_names_both_addresses() {
+        assert_eq!(
+            encode_proxy_header(ProxyVersion::V1, &header()),
+            b"PROXY TCP4 198.51.100.7 127.0.0.1 53124 22\r\n".to_vec()
+        );
+        let mixed = ProxyHeader {
+            destination: Some("[::1]:22".parse().expect("destination")),
+            ..header()
+        };
+        assert_eq!(
+            encode_proxy_header(ProxyVersion::V1, &mixed),
+            b"PROXY TCP6 ::ffff:198.51.100.7 ::1 53124 22\r\n".to_vec()
+        );
+        assert_eq!(
+            encode_proxy_header(ProxyVersion::V1, &ProxyHeader::default()),
+            b"PROXY UNKNOWN\r\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn v2_carries_addresses_and_unique_id() {
+        let encoded = encode_proxy_header(ProxyVersion::V2, &header());
+        assert_eq!(&encoded[12..14], &[0x21, 0x11]);
+        let len = u16::from_be_bytes([encoded[14], encoded[15]]) as usize;
+        assert_eq!(encoded.len(), 16 + len);
+        assert_eq!(&encoded[16..20], &[198, 51, 100, 7]);
+        assert_eq!(&encoded[20..24], &[127, 0, 0, 1]);
+        assert_eq!(&encoded[24..26], &53124u16.to_be_bytes());
+        assert_eq!(&encoded[26..28], &22u16.to_be_bytes());
+        assert_eq!(&encoded[28..], &[PP2_TYPE_UNIQUE_ID, 0, 1, b'7']);
+
+        let unknown = encode_proxy_header(ProxyVersion::V2, &ProxyHeader::default());
+        assert_eq!(&unknown[12..], &[0x21, 0x00, 0, 0]);
+    }
+}
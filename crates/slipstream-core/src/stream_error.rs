@@ -0,0 +1,141 @@
+//! Reasons the server resets a stream instead of relaying it, carried as the QUIC application
+//! error code of its RESET_STREAM so the client can tell its local peer what happened.
+
+use std::fmt;
+use std::io;
+
+/// A target or policy failure, as seen by the side that could not open the stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StreamError {
+    /// The target refused the connection, or a reverse listener could not bind.
+    Refused,
+    /// The target did not answer within the connect timeout.
+    TimedOut,
+    /// The target's host or network could not be reached or resolved.
+    Unreachable,
+    /// The server's configuration does not allow what the stream asked for.
+    Denied,
+    /// A server-side limit was reached; the same request may succeed later.
+    Quota,
+}
+
+impl StreamError {
+    pub const ALL: [StreamError; 5] = [
+        StreamError::Refused,
+        StreamError::TimedOut,
+        StreamError::Unreachable,
+        StreamError::Denied,
+        StreamError::Quota,
+    ];
+
+    pub fn code(self) -> u64 {
+        match self {
+            StreamError::Refused => 0x110,
+            StreamError::TimedOut => 0x111,
+            StreamError::Unreachable => 0x112,
+            StreamError::Denied => 0x113,
+            StreamError::Quota => 0x114,
+        }
+    }
+
+    pub fn from_code(code: u64) -> Option<Self> {
+        Self::ALL.into_iter().find(|error| error.code() == code)
+    }
+
+    /// Classifies a failed connect or bind.
+    pub fn from_io(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::AddrInUse => StreamError::Refused,
+            io::ErrorKind::TimedOut => StreamError::TimedOut,
+            io::ErrorKind::PermissionDenied => StreamError::Denied,
+            _ => StreamError::Unreachable,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StreamError::Refused => "refused",
+            StreamError::TimedOut => "timeout",
+            StreamError::Unreachable => "unreachable",
+            StreamError::Denied => "denied",
+            StreamError::Quota => "quota",
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Running totals of stream errors, one per kind.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StreamErrorCounts {
+    counts: [u64; StreamError::ALL.len()],
+}
+
+impl StreamErrorCounts {
+    pub fn note(&mut self, error: StreamError) {
+        if let Some(index) = StreamError::ALL.iter().position(|known| *known == error) {
+            self.counts[index] += 1;
+        }
+    }
+
+    pub fn get(&self, error: StreamError) -> u64 {
+        StreamError::ALL
+            .iter()
+            .position(|known| *known == error)
+            .map_or(0, |index| self.counts[index])
+    }
+}
+
+impl fmt::Display for StreamErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, error) in StreamError::ALL.iter().enumerate() {
+            if index > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{}={}", error, self.counts[index])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{StreamError, StreamErrorCounts};
+    use std::io;
+
+    #[test]
+    fn codes_round_trip() {
+        for error in StreamError::ALL {
+            assert_eq!(StreamError::from_code(error.code()), Some(error));
+        }
+        assert_eq!(StreamError::from_code(0x101), None);
+    }
+
+    #[test]
+    fn io_errors_classify() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(StreamError::from_io(&refused), StreamError::Refused);
+        let timed_out = io::Error::from(io::ErrorKind::TimedOut);
+        assert_eq!(StreamError::from_io(&timed_out), StreamError::TimedOut);
+        let unreachable = io::Error::from(io::ErrorKind::HostUnreachable);
+        assert_eq!(StreamError::from_io(&unreachable), StreamError::Unreachable);
+    }
+
+    #[test]
+    fn counts_track_each_kind() {
+        let mut counts = StreamErrorCounts::default();
+        counts.note(StreamError::Refused);
+        counts.note(StreamError::Refused);
+        counts.note(StreamError::Denied);
+        assert_eq!(counts.get(StreamError::Refused), 2);
+        assert_eq!(counts.get(StreamError::Quota), 0);
+        assert_eq!(
+            counts.to_string(),
+            "refused=2 timeout=0 unreachable=0 denied=1 quota=0"
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! An optional header at the start of a tunnel stream that asks the server for a built-in
+//! target or a named destination instead of its configured one. Streams without it are
+//! relayed to the target as before; the magic opens with a NUL byte, which none of the
+//! protocols we tunnel start with.
+
+/// Marks a stream header; the trailing digit is the header version.
+pub const STREAM_HEADER_MAGIC: &[u8] = b"\0slip1";
+
+const KIND_SINK: u8 = b's';
+const KIND_SOURCE: u8 = b'o';
+const KIND_ECHO: u8 = b'e';
+const KIND_CONNECT: u8 = b'c';
+const KIND_LISTEN: u8 = b'l';
+const KIND_ACCEPTED: u8 = b'a';
+const KIND_UDP: u8 = b'u';
+const KIND_TUN: u8 = b'n';
+
+/// Longest header: magic, kind, port, host length and a 255-byte host.
+pub const MAX_STREAM_HEADER_LEN: usize = 6 + 1 + 2 + 1 + 255;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamTarget {
+    /// Discards the stream, then answers its FIN with the byte count as a big-endian u64.
+    Sink,
+    /// Sends this many bytes, then FIN.
+    Source(u64),
+    /// Sends everything back.
+    Echo,
+    /// Relays the stream to `host:port`, if the server allows it. Hosts longer than 255
+    /// bytes cannot be encoded.
+    Connect { host: String, port: u16 },
+    /// Asks the server to listen on `host:port` for a reverse tunnel, for as long as the
+    /// stream lives.
+    Listen { host: String, port: u16 },
+    /// Opens a server-initiated stream for a connection accepted by the `Listen` on
+    /// `host:port`.
+    Accepted { host: String, port: u16 },
+    /// Opens a UDP flow to `host:port`, if the server allows it. The stream carries no data;
+    /// the flow's datagrams are tagged with its stream ID and it ends with the stream.
+    Udp { host: String, port: u16 },
+    /// Carries framed IP packets between the client's TUN device and the server's.
+    Tun,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StreamHeader {
+    /// Too few bytes to tell yet.
+    Incomplete,
+    /// The stream does not start with a header.
+    Absent,
+    /// A header of `len` bytes asking for `target`.
+    Present { target: StreamTarget, len: usize },
+    /// The magic matched, but what follows is not a header this build knows.
+    Invalid,
+}
+
+pub fn encode_stream_header(target: &StreamTarget) -> Vec<u8> {
+    let mut out = STREAM_HEADER_MAGIC.to_vec();
+    match target {
+        StreamTarget::Sink => out.push(KIND_SINK),
+        StreamTarget::Source(len) => {
+            out.push(KIND_SOURCE);
+            out.extend_from_slice(&len.to_be_bytes());
+        }
+        StreamTarget::Echo => out.push(KIND_ECHO),
+        StreamTarget::Connect { host, port } => encode_address(&mut out, KIND_CONNECT, host, *port),
+        StreamTarget::Listen { host, port } => encode_address(&mut out, KIND_LISTEN, host, *port),
+        StreamTarget::Accepted { host, port } => {
+            encode_address(&mut out, KIND_ACCEPTED, host, *port)
+        }
+        StreamTarget::Udp { host, port } => encode_address(&mut out, KIND_UDP, host, *port),
+        StreamTarget::Tun => out.push(KIND_TUN),
+    }
+    out
+}
+
+fn encode_address(out: &mut Vec<u8>, kind: u8, host: &str, port: u16) {
+    let host = &host.as_bytes()[..host.len().min(u8::MAX as usize)];
+    out.push(kind);
+    out.extend_from_slice(&port.to_be_bytes());
+    out.push(host.len() as u8);
+    out.extend_from_slice(host);
+}
+
+/// Looks for a header at the start of a stream's first bytes.
+pub fn parse_stream_header(data: &[u8]) -> StreamHeader {
+    let magic_len = STREAM_HEADER_MAGIC.len();
+    let compared = data.len().min(magic_len);
+    if data[..compared] != STREAM_HEADER_MAGIC[..compared] {
+        return StreamHeader::Absent;
+    }
+    let Some(&kind) = data.get(magic_len) else {
+        return StreamHeader::Incomplete;
+    };
+    let body = &data[magic_len + 1..];
+    let (target, body_len) = match kind {
+        KIND_SINK => (StreamTarget::Sink, 0),
+        KIND_ECHO => (StreamTarget::Echo, 0),
+        KIND_TUN => (StreamTarget::Tun, 0),
+        KIND_SOURCE => {
+            let Some(len) = body.get(..8) else {
+                return StreamHeader::Incomplete;
+            };
+            let mut bytes = [0u8; 8];
+            bytes.copy_from_slice(len);
+            (StreamTarget::Source(u64::from_be_bytes(bytes)), 8)
+        }
+        KIND_CONNECT | KIND_LISTEN | KIND_ACCEPTED | KIND_UDP => {
+            let Some(&[port_hi, port_lo, host_len]) = body.get(..3) else {
+                return StreamHeader::Incomplete;
+            };
+            let Some(host) = body.get(3..3 + host_len as usize) else {
+                return StreamHeader::Incomplete;
+            };
+            let Ok(host) = std::str::from_utf8(host) else {
+                return StreamHeader::Invalid;
+            };
+            if host.is_empty() {
+                return StreamHeader::Invalid;
+            }
+            let host = host.to_string();
+            let port = u16::from_be_bytes([port_hi, port_lo]);
+            let target = match kind {
+                KIND_CONNECT => StreamTarget::Connect { host, port },
+                KIND_LISTEN => StreamTarget::Listen { host, port },
+                KIND_UDP => StreamTarget::Udp { host, port },
+                _ => StreamTarget::Accepted { host, port },
+            };
+            (target, 3 + host_len as usize)
+        }
+        _ => return StreamHeader::Invalid,
+    };
+    StreamHeader::Present {
+        target,
+        len: magic_len + 1 + body_len,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_stream_header, parse_stream_header, StreamHeader, StreamTarget};
+
+    #[test]
+    fn headers_round_trip_at_any_split() {
+        for target in [
+            StreamTarget::Sink,
+            StreamTarget::Source(1 << 40),
+            StreamTarget::Echo,
+            StreamTarget::Connect {
+                host: "db.internal".to_string(),
+                port: 5432,
+            },
+            StreamTarget::Listen {
+                host: "0.0.0.0".to_string(),
+                port: 8022,
+            },
+            StreamTarget::Accepted {
+                host: "0.0.0.0".to_string(),
+                port: 8022,
+            },
+            StreamTarget::Udp {
+                host: "1.1.1.1".to_string(),
+                port: 53,
+            },
+            StreamTarget::Tun,
+        ] {
+            let mut stream = encode_stream_header(&target);
+            let len = stream.len();
+            for split in 0..len {
+                assert_eq!(
+                    parse_stream_header(&stream[..split]),
+                    StreamHeader::Incomplete
+                );
+            }
+            stream.extend_from_slice(b"payload");
+            assert_eq!(
+                parse_stream_header(&stream),
+                StreamHeader::Present { target, len }
+            );
+        }
+    }
+
+    #[test]
+    fn ordinary_streams_have_no_header() {
+        assert_eq!(
+            parse_stream_header(b"SSH-2.0-OpenSSH"),
+            StreamHeader::Absent
+        );
+        assert_eq!(parse_stream_header(&[5, 1, 0]), StreamHeader::Absent);
+        assert_eq!(parse_stream_header(b"\0s"), StreamHeader::Incomplete);
+        assert_eq!(parse_stream_header(b"\0slip1?"), StreamHeader::Invalid);
+        assert_eq!(
+            parse_stream_header(b"\0slip1c\0\x16\0"),
+            StreamHeader::Invalid
+        );
+    }
+}
@@ -116,6 +116,71 @@ pub fn tcp_send_buffer_bytes<T>(_stream: &T) -> Option<usize> {
     None
 }
 
+/// Destination a connection had before an iptables `REDIRECT` or `DNAT` sent it to this socket.
+/// `local` is the socket's own address; IPv4 connections accepted on a dual-stack socket are
+/// looked up in the IPv4 table.
+#[cfg(target_os = "linux")]
+pub fn original_destination<T: AsRawFd>(
+    stream: &T,
+    local: std::net::SocketAddr,
+) -> std::io::Result<std::net::SocketAddr> {
+    use std::mem::{size_of, zeroed};
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+    let mapped_v4 = match local.ip() {
+        IpAddr::V4(_) => true,
+        IpAddr::V6(ip) => ip.to_ipv4_mapped().is_some(),
+    };
+    if mapped_v4 {
+        let mut addr: libc::sockaddr_in = unsafe { zeroed() };
+        let mut len = size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_IP,
+                libc::SO_ORIGINAL_DST,
+                &mut addr as *mut _ as *mut _,
+                &mut len as *mut _,
+            )
+        };
+        if ret != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let ip = u32::from_be(addr.sin_addr.s_addr);
+        return Ok(SocketAddr::from((
+            ip.to_be_bytes(),
+            u16::from_be(addr.sin_port),
+        )));
+    }
+    let mut addr: libc::sockaddr_in6 = unsafe { zeroed() };
+    let mut len = size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_IPV6,
+            libc::IP6T_SO_ORIGINAL_DST,
+            &mut addr as *mut _ as *mut _,
+            &mut len as *mut _,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+    Ok(SocketAddr::from((ip, u16::from_be(addr.sin6_port))))
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_destination<T>(
+    _stream: &T,
+    _local: std::net::SocketAddr,
+) -> std::io::Result<std::net::SocketAddr> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "original destinations are only available on Linux",
+    ))
+}
+
 #[cfg(test)]
 mod tests {
     use super::{stream_write_buffer_bytes, within_stream_buffer};
@@ -133,4 +198,25 @@ mod tests {
         assert!(!within_stream_buffer(limit, 1));
         assert!(!within_stream_buffer(limit - 1, 2));
     }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn original_destination_of_direct_connection_is_local() {
+        use std::net::{TcpListener, TcpStream};
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let _client = TcpStream::connect(addr).expect("connect");
+        let (accepted, _) = listener.accept().expect("accept");
+        // With conntrack an unredirected connection reports the address it was accepted on;
+        // without it the lookup finds no entry or the option does not exist.
+        match super::original_destination(&accepted, addr) {
+            Ok(original) => assert_eq!(original, addr),
+            Err(err) => assert!(
+                matches!(err.raw_os_error(), Some(libc::ENOENT | libc::ENOPROTOOPT)),
+                "unexpected lookup error: {}",
+                err
+            ),
+        }
+    }
 }
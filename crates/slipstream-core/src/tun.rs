@@ -0,0 +1,196 @@
+//! Full-tunnel mode: IP packets from a TUN device ride one tunnel stream, each behind its
+//! length as a big-endian u16. A stream suits the tunnel better than DATAGRAM frames here,
+//! since its QUIC packets are usually smaller than the IP packets they carry.
+
+/// Largest IP packet a TUN device hands over with the MTUs full-tunnel setups use.
+pub const TUN_PACKET_MAX_BYTES: usize = u16::MAX as usize;
+
+pub fn frame_packet(packet: &[u8]) -> Vec<u8> {
+    let len = packet.len().min(TUN_PACKET_MAX_BYTES);
+    let mut out = Vec::with_capacity(2 + len);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    out.extend_from_slice(&packet[..len]);
+    out
+}
+
+/// Reassembles framed packets from stream data split at arbitrary points.
+#[derive(Debug, Default)]
+pub struct PacketDeframer {
+    buf: Vec<u8>,
+}
+
+impl PacketDeframer {
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        let header = self.buf.get(..2)?;
+        let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+        if self.buf.len() < 2 + len {
+            return None;
+        }
+        let packet = self.buf[2..2 + len].to_vec();
+        self.buf.drain(..2 + len);
+        Some(packet)
+    }
+}
+
+/// Destination address of an IPv4 or IPv6 packet.
+pub fn packet_destination(packet: &[u8]) -> Option<std::net::IpAddr> {
+    packet_address(packet, 16, 24)
+}
+
+/// Source address of an IPv4 or IPv6 packet.
+pub fn packet_source(packet: &[u8]) -> Option<std::net::IpAddr> {
+    packet_address(packet, 12, 8)
+}
+
+fn packet_address(packet: &[u8], v4_offset: usize, v6_offset: usize) -> Option<std::net::IpAddr> {
+    match packet.first()? >> 4 {
+        4 => {
+            let bytes: [u8; 4] = packet.get(v4_offset..v4_offset + 4)?.try_into().ok()?;
+            Some(std::net::Ipv4Addr::from(bytes).into())
+        }
+        6 => {
+            let bytes: [u8; 16] = packet.get(v6_offset..v6_offset + 16)?.try_into().ok()?;
+            Some(std::net::Ipv6Addr::from(bytes).into())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub use linux::TunDevice;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+    /// A TUN device opened without packet information, in non-blocking mode. Addresses,
+    /// routes and NAT are left to the system's own configuration.
+    #[derive(Debug)]
+    pub struct TunDevice {
+        fd: OwnedFd,
+        name: String,
+    }
+
+    impl TunDevice {
+        /// Opens `name`, creating the device if it does not exist (which needs CAP_NET_ADMIN).
+        pub fn open(name: &str) -> io::Result<Self> {
+            if name.is_empty() || name.len() >= libc::IFNAMSIZ {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid TUN device name: {}", name),
+                ));
+            }
+            // SAFETY: the path is a valid null-terminated string.
+            let fd = unsafe {
+                libc::open(
+                    c"/dev/net/tun".as_ptr(),
+                    libc::O_RDWR | libc::O_NONBLOCK | libc::O_CLOEXEC,
+                )
+            };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: fd was just opened and is owned by nothing else.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+            // SAFETY: ifreq is plain-old-data; zeroing is valid.
+            let mut request: libc::ifreq = unsafe { std::mem::zeroed() };
+            for (dst, src) in request.ifr_name.iter_mut().zip(name.bytes()) {
+                *dst = src as libc::c_char;
+            }
+            request.ifr_ifru.ifru_flags = (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short;
+            // SAFETY: TUNSETIFF reads and writes an ifreq, which outlives the call.
+            if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut request) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Self {
+                fd,
+                name: name.to_string(),
+            })
+        }
+
+        pub fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// Reads one packet; fails with `WouldBlock` when none is waiting.
+        pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            // SAFETY: buf is valid for writes of buf.len() bytes.
+            let len =
+                unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(len as usize)
+        }
+
+        /// Writes one packet; fails with `WouldBlock` when the device queue is full.
+        pub fn send(&self, packet: &[u8]) -> io::Result<usize> {
+            // SAFETY: packet is valid for reads of packet.len() bytes.
+            let len =
+                unsafe { libc::write(self.fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(len as usize)
+        }
+    }
+
+    impl AsRawFd for TunDevice {
+        fn as_raw_fd(&self) -> RawFd {
+            self.fd.as_raw_fd()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_packet, packet_destination, packet_source, PacketDeframer};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn packets_survive_any_split() {
+        let packets = [vec![0x45; 20], Vec::new(), vec![0x60; 300]];
+        let stream: Vec<u8> = packets.iter().flat_map(|p| frame_packet(p)).collect();
+        for chunk in [1, 2, 7, stream.len()] {
+            let mut deframer = PacketDeframer::default();
+            let mut out = Vec::new();
+            for data in stream.chunks(chunk) {
+                deframer.push(data);
+                while let Some(packet) = deframer.next_packet() {
+                    out.push(packet);
+                }
+            }
+            assert_eq!(out, packets);
+        }
+    }
+
+    #[test]
+    fn reads_packet_addresses() {
+        let mut v4 = vec![0u8; 20];
+        v4[0] = 0x45;
+        v4[12..16].copy_from_slice(&[10, 8, 0, 2]);
+        v4[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        assert_eq!(
+            packet_source(&v4),
+            Some(IpAddr::V4(Ipv4Addr::new(10, 8, 0, 2)))
+        );
+        assert_eq!(
+            packet_destination(&v4),
+            Some(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)))
+        );
+        let mut v6 = vec![0u8; 40];
+        v6[0] = 0x60;
+        v6[8..24].copy_from_slice(&Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2).octets());
+        assert_eq!(
+            packet_source(&v6),
+            Some(IpAddr::V6(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)))
+        );
+        assert_eq!(packet_destination(&v6[..30]), None);
+        assert_eq!(packet_source(&[0x45, 0]), None);
+    }
+}
@@ -0,0 +1,78 @@
+//! Unpadded base64 with the URL-safe alphabet, which only uses characters that are valid
+//! in DNS labels. Unlike base32 it is case-sensitive.
+
+const ENCODE_TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+pub(crate) fn encode(input: &[u8]) -> String {
+    let mut out = String::with_capacity(encoded_len(input.len()));
+    let mut buffer: u32 = 0;
+    let mut bits: u8 = 0;
+
+    for &byte in input {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 6 {
+            bits -= 6;
+            out.push(ENCODE_TABLE[((buffer >> bits) & 0x3f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ENCODE_TABLE[((buffer << (6 - bits)) & 0x3f) as usize] as char);
+    }
+    out
+}
+
+pub(crate) fn decode(input: &str) -> Option<Vec<u8>> {
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(input.len() * 6 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits: u8 = 0;
+
+    for &ch in input.as_bytes() {
+        buffer = (buffer << 6) | decode_value(ch)? as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    // Leftover bits are padding and must be zero.
+    if buffer & ((1 << bits) - 1) != 0 {
+        return None;
+    }
+    Some(out)
+}
+
+pub(crate) fn encoded_len(payload_len: usize) -> usize {
+    (payload_len * 8).div_ceil(6)
+}
+
+fn decode_value(ch: u8) -> Option<u8> {
+    match ch {
+        b'A'..=b'Z' => Some(ch - b'A'),
+        b'a'..=b'z' => Some(ch - b'a' + 26),
+        b'0'..=b'9' => Some(ch - b'0' + 52),
+        b'-' => Some(62),
+        b'_' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode};
+
+    #[test]
+    fn round_trips_every_length() {
+        let data: Vec<u8> = (0u8..=255).collect();
+        for len in 0..16 {
+            let encoded = encode(&data[200..200 + len]);
+            assert_eq!(decode(&encoded).as_deref(), Some(&data[200..200 + len]));
+        }
+        assert_eq!(encode(&[0xfb, 0xff]), "-_8");
+        assert!(decode("-_9").is_none());
+        assert!(decode("ab.c").is_none());
+    }
+}
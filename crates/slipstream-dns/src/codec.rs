@@ -1,14 +1,13 @@
-use crate::base32;
 use crate::dots;
-
+use crate::encoding::decode_subdomain;
 use crate::name::{encode_name, extract_subdomain_multi, parse_name};
 use crate::types::{
-    DecodeQueryError, DecodedQuery, DnsError, QueryParams, Rcode, ResponseParams, EDNS_UDP_PAYLOAD,
-    RR_OPT, RR_TXT,
+    DecodeQueryError, DecodedQuery, DnsError, QueryParams, Question, Rcode, ResponseParams,
+    EDNS_UDP_PAYLOAD, RR_OPT, RR_TXT,
 };
 use crate::wire::{
-    parse_header, parse_question, parse_question_for_reply, read_u16, read_u32, write_u16,
-    write_u32,
+    parse_edns_udp_payload, parse_header, parse_question, parse_question_for_reply, read_u16,
+    read_u32, write_u16, write_u32,
 };
 
 pub fn decode_query(packet: &[u8], domain: &str) -> Result<DecodedQuery, DecodeQueryError> {
@@ -49,8 +48,8 @@ pub fn decode_query_with_domains(
         });
     }
 
-    let question = match parse_question(packet, header.offset) {
-        Ok((question, _)) => question,
+    let (question, question_end) = match parse_question(packet, header.offset) {
+        Ok(parsed) => parsed,
         Err(_) => return Err(DecodeQueryError::Drop),
     };
 
@@ -77,8 +76,7 @@ pub fn decode_query_with_domains(
         }
     };
 
-    let undotted = dots::undotify(&subdomain_raw);
-    if undotted.is_empty() {
+    if dots::undotify(&subdomain_raw).is_empty() {
         return Err(DecodeQueryError::Reply {
             id: header.id,
             rd,
@@ -88,9 +86,9 @@ pub fn decode_query_with_domains(
         });
     }
 
-    let payload = match base32::decode(&undotted) {
-        Ok(payload) => payload,
-        Err(_) => {
+    let decoded = match decode_subdomain(&subdomain_raw) {
+        Some(decoded) => decoded,
+        None => {
             return Err(DecodeQueryError::Reply {
                 id: header.id,
                 rd,
@@ -106,7 +104,10 @@ pub fn decode_query_with_domains(
         rd,
         cd,
         question,
-        payload,
+        payload: decoded.payload,
+        encoding: decoded.encoding,
+        control: decoded.control,
+        edns_udp_payload: parse_edns_udp_payload(packet, &header, question_end),
     })
 }
 
@@ -142,17 +143,34 @@ pub fn encode_query(params: &QueryParams<'_>) -> Result<Vec<u8>, DnsError> {
 }
 
 pub fn encode_response(params: &ResponseParams<'_>) -> Result<Vec<u8>, DnsError> {
-    let payload_len = params.payload.map(|payload| payload.len()).unwrap_or(0);
+    encode_answers(params, params.payload.as_slice())
+}
+
+/// Like `encode_response`, but answers with one TXT record per entry of `payloads`, so a
+/// single response can carry several QUIC packets. `params.payload` is not used.
+pub fn encode_coalesced_response(
+    params: &ResponseParams<'_>,
+    payloads: &[&[u8]],
+) -> Result<Vec<u8>, DnsError> {
+    encode_answers(params, payloads)
+}
+
+fn encode_answers(params: &ResponseParams<'_>, payloads: &[&[u8]]) -> Result<Vec<u8>, DnsError> {
+    let payloads: Vec<&[u8]> = payloads
+        .iter()
+        .copied()
+        .filter(|payload| !payload.is_empty())
+        .collect();
 
-    let mut rcode = params.rcode.unwrap_or(if payload_len > 0 {
+    let mut rcode = params.rcode.unwrap_or(if !payloads.is_empty() {
         Rcode::Ok
     } else {
         Rcode::NameError
     });
 
     let mut ancount = 0u16;
-    if payload_len > 0 && rcode == Rcode::Ok {
-        ancount = 1;
+    if !payloads.is_empty() && rcode == Rcode::Ok {
+        ancount = u16::try_from(payloads.len()).map_err(|_| DnsError::new("too many answers"))?;
     } else if params.rcode.is_some() {
         rcode = params.rcode.unwrap_or(Rcode::Ok);
     }
@@ -178,27 +196,19 @@ pub fn encode_response(params: &ResponseParams<'_>) -> Result<Vec<u8>, DnsError>
     write_u16(&mut out, params.question.qtype);
     write_u16(&mut out, params.question.qclass);
 
-    if ancount == 1 {
+    for payload in payloads.iter().take(ancount as usize) {
         out.extend_from_slice(&[0xC0, 0x0C]);
         write_u16(&mut out, params.question.qtype);
         write_u16(&mut out, params.question.qclass);
         write_u32(&mut out, 60);
-        let chunk_count = payload_len.div_ceil(255);
-        let rdata_len = payload_len + chunk_count;
+        let rdata_len = payload.len() + payload.len().div_ceil(255);
         if rdata_len > u16::MAX as usize {
             return Err(DnsError::new("payload too long"));
         }
         write_u16(&mut out, rdata_len as u16);
-        if let Some(payload) = params.payload {
-            let mut remaining = payload_len;
-            let mut cursor = 0;
-            while remaining > 0 {
-                let chunk_len = remaining.min(255);
-                out.push(chunk_len as u8);
-                out.extend_from_slice(&payload[cursor..cursor + chunk_len]);
-                cursor += chunk_len;
-                remaining -= chunk_len;
-            }
+        for chunk in payload.chunks(255) {
+            out.push(chunk.len() as u8);
+            out.extend_from_slice(chunk);
         }
     }
 
@@ -207,7 +217,51 @@ pub fn encode_response(params: &ResponseParams<'_>) -> Result<Vec<u8>, DnsError>
     Ok(out)
 }
 
+/// Largest payload `encode_response` can answer `question` with while keeping the whole
+/// response within `udp_payload` bytes.
+pub fn max_response_payload_len(question: &Question, udp_payload: u16) -> usize {
+    max_next_answer_len(question, udp_payload, &[])
+}
+
+/// Largest payload one more answer record can carry in a coalesced response that already
+/// holds answers with the `answered` payload lengths, within `udp_payload` bytes.
+pub fn max_next_answer_len(question: &Question, udp_payload: u16, answered: &[usize]) -> usize {
+    let mut name = Vec::with_capacity(question.name.len() + 2);
+    if encode_name(&question.name, &mut name).is_err() {
+        return 0;
+    }
+    // Header, question and our OPT record, then each answer RR (compressed name).
+    let overhead = 12 + name.len() + 4 + 11;
+    let used = answered
+        .iter()
+        .fold(overhead, |used, len| used + answer_record_len(*len));
+    let rdata_max = (udp_payload as usize).saturating_sub(used + ANSWER_RR_HEADER_LEN);
+    // Each 255-byte TXT string costs one length byte.
+    let mut payload = rdata_max.saturating_sub(rdata_max.div_ceil(256));
+    while payload > 0 && payload + payload.div_ceil(255) > rdata_max {
+        payload -= 1;
+    }
+    payload
+}
+
+const ANSWER_RR_HEADER_LEN: usize = 12;
+
+fn answer_record_len(payload_len: usize) -> usize {
+    ANSWER_RR_HEADER_LEN + payload_len + payload_len.div_ceil(255)
+}
+
+/// The payload of a response with a single TXT answer.
 pub fn decode_response(packet: &[u8]) -> Option<Vec<u8>> {
+    let mut payloads = decode_response_payloads(packet)?;
+    if payloads.len() != 1 {
+        return None;
+    }
+    payloads.pop()
+}
+
+/// The payloads of every TXT answer in a response, in order; coalesced responses carry
+/// one QUIC packet per answer.
+pub fn decode_response_payloads(packet: &[u8]) -> Option<Vec<Vec<u8>>> {
     let header = parse_header(packet)?;
     if !header.is_response {
         return None;
@@ -216,7 +270,7 @@ pub fn decode_response(packet: &[u8]) -> Option<Vec<u8>> {
     if rcode != Rcode::Ok {
         return None;
     }
-    if header.ancount != 1 {
+    if header.ancount == 0 {
         return None;
     }
 
@@ -230,8 +284,17 @@ pub fn decode_response(packet: &[u8]) -> Option<Vec<u8>> {
         offset += 4;
     }
 
-    let (_, new_offset) = parse_name(packet, offset).ok()?;
-    offset = new_offset;
+    let mut payloads = Vec::with_capacity(header.ancount as usize);
+    for _ in 0..header.ancount {
+        let (payload, next) = parse_txt_answer(packet, offset)?;
+        payloads.push(payload);
+        offset = next;
+    }
+    Some(payloads)
+}
+
+fn parse_txt_answer(packet: &[u8], offset: usize) -> Option<(Vec<u8>, usize)> {
+    let (_, mut offset) = parse_name(packet, offset).ok()?;
     if offset + 10 > packet.len() {
         return None;
     }
@@ -267,7 +330,31 @@ pub fn decode_response(packet: &[u8]) -> Option<Vec<u8>> {
     if out.is_empty() {
         return None;
     }
-    Some(out)
+    Some((out, offset + rdlen))
+}
+
+/// Returns the raw RCODE of a DNS response, so callers can tell resolver errors
+/// apart from empty answers (`decode_response` yields `None` for both).
+pub fn response_rcode(packet: &[u8]) -> Option<u8> {
+    if packet.len() < 12 {
+        return None;
+    }
+    let flags = read_u16(packet, 2)?;
+    if flags & 0x8000 == 0 {
+        return None;
+    }
+    Some((flags & 0x000f) as u8)
+}
+
+/// Returns the first question name of a response exactly as the server wrote it,
+/// letter case included, for DNS 0x20 checks.
+pub fn response_question_name(packet: &[u8]) -> Option<String> {
+    let header = parse_header(packet)?;
+    if !header.is_response || header.qdcount == 0 {
+        return None;
+    }
+    let (name, _) = parse_name(packet, header.offset).ok()?;
+    Some(name)
 }
 
 pub fn is_response(packet: &[u8]) -> bool {
@@ -287,8 +374,15 @@ fn encode_opt_record(out: &mut Vec<u8>) -> Result<(), DnsError> {
 
 #[cfg(test)]
 mod tests {
-    use super::encode_response;
-    use crate::types::{Question, ResponseParams, CLASS_IN, RR_TXT};
+    use super::{
+        decode_query, decode_response, decode_response_payloads, encode_coalesced_response,
+        encode_query, encode_response, max_next_answer_len, max_response_payload_len,
+        response_question_name, response_rcode,
+    };
+    use crate::control::QueryControl;
+    use crate::encoding::QnameEncoding;
+    use crate::types::QueryParams;
+    use crate::types::{Question, Rcode, ResponseParams, CLASS_IN, EDNS_UDP_PAYLOAD, RR_TXT};
 
     #[test]
     fn encode_response_rejects_large_payload() {
@@ -308,4 +402,163 @@ mod tests {
         };
         assert!(encode_response(&params).is_err());
     }
+
+    #[test]
+    fn response_rcode_reports_errors() {
+        let question = Question {
+            name: "a.test.com.".to_string(),
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+        };
+        let params = ResponseParams {
+            id: 0x1234,
+            rd: true,
+            cd: false,
+            question: &question,
+            payload: None,
+            rcode: Some(Rcode::ServerFailure),
+        };
+        let response = encode_response(&params).expect("encode response");
+        assert_eq!(response_rcode(&response), Some(2));
+
+        let mut refused = response.clone();
+        refused[3] = (refused[3] & 0xf0) | 5;
+        assert_eq!(response_rcode(&refused), Some(5));
+
+        let mut query = response;
+        query[2] &= 0x7f;
+        assert_eq!(response_rcode(&query), None);
+    }
+
+    #[test]
+    fn decodes_raw_probe_queries() {
+        let payload: Vec<u8> = (0u8..=255).step_by(3).collect();
+        let control = QueryControl::Probe(700);
+        let qname = crate::build_encoded_qname(&payload, "test.com", QnameEncoding::Raw, control)
+            .expect("qname");
+        let query = encode_query(&QueryParams {
+            id: 9,
+            qname: &qname,
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+            rd: true,
+            cd: false,
+            qdcount: 1,
+            is_query: true,
+        })
+        .expect("encode query");
+        let decoded = decode_query(&query, "test.com").expect("decode raw query");
+        assert_eq!(decoded.payload, payload);
+        assert_eq!(decoded.encoding, QnameEncoding::Raw);
+        assert_eq!(decoded.control, control);
+        assert_eq!(decoded.question.name, qname);
+    }
+
+    #[test]
+    fn sizes_responses_to_advertised_edns_payload() {
+        let qname = crate::build_qname(&[0x5a; 120], "test.com").expect("qname");
+        let query = encode_query(&QueryParams {
+            id: 3,
+            qname: &qname,
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+            rd: true,
+            cd: false,
+            qdcount: 1,
+            is_query: true,
+        })
+        .expect("encode query");
+        let decoded = decode_query(&query, "test.com").expect("decode query");
+        assert_eq!(decoded.edns_udp_payload, Some(EDNS_UDP_PAYLOAD));
+
+        for limit in [512u16, EDNS_UDP_PAYLOAD, 4096] {
+            let max = max_response_payload_len(&decoded.question, limit);
+            let response_len = |len: usize| {
+                encode_response(&ResponseParams {
+                    id: 3,
+                    rd: true,
+                    cd: false,
+                    question: &decoded.question,
+                    payload: Some(&vec![0u8; len]),
+                    rcode: None,
+                })
+                .expect("encode response")
+                .len()
+            };
+            assert!(response_len(max) <= limit as usize);
+            assert!(response_len(max + 1) > limit as usize);
+        }
+    }
+
+    #[test]
+    fn decodes_mixed_case_queries() {
+        let upper = "AEBAGBA.Test.COM.";
+        let params = QueryParams {
+            id: 7,
+            qname: upper,
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+            rd: true,
+            cd: false,
+            qdcount: 1,
+            is_query: true,
+        };
+        let query = encode_query(&params).expect("encode query");
+        let decoded = decode_query(&query, "test.com").expect("mixed case decodes");
+        assert_eq!(decoded.payload, vec![1, 2, 3, 4]);
+        assert_eq!(decoded.question.name, upper);
+
+        let mixed = "aEbAgbA.tEsT.com.";
+        let query = encode_query(&QueryParams {
+            qname: mixed,
+            ..params
+        })
+        .expect("encode query");
+        let decoded = decode_query(&query, "test.com").expect("mixed case decodes");
+        assert_eq!(decoded.payload, vec![1, 2, 3, 4]);
+
+        let response = encode_response(&ResponseParams {
+            id: 7,
+            rd: true,
+            cd: false,
+            question: &decoded.question,
+            payload: Some(b"ok"),
+            rcode: None,
+        })
+        .expect("encode response");
+        assert_eq!(response_question_name(&response).as_deref(), Some(mixed));
+    }
+
+    #[test]
+    fn coalesced_responses_fill_the_budget_and_split_back() {
+        let question = Question {
+            name: "abcdefgh.test.com.".to_string(),
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+        };
+        let limit = 1232u16;
+        let first = vec![1u8; 300];
+        let second_len = max_next_answer_len(&question, limit, &[first.len()]);
+        let second = vec![2u8; second_len];
+        let params = ResponseParams {
+            id: 5,
+            rd: true,
+            cd: false,
+            question: &question,
+            payload: None,
+            rcode: None,
+        };
+        let response =
+            encode_coalesced_response(&params, &[&first, &second]).expect("coalesced response");
+        assert!(response.len() <= limit as usize);
+        assert!(response.len() + 3 > limit as usize);
+        assert_eq!(
+            decode_response_payloads(&response),
+            Some(vec![first.clone(), second])
+        );
+        assert_eq!(decode_response(&response), None);
+
+        let single = encode_coalesced_response(&params, &[&first]).expect("single");
+        assert_eq!(decode_response(&single), Some(first));
+    }
 }
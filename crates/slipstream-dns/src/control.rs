@@ -0,0 +1,173 @@
+//! An optional first label that tells the server how to answer a query. It starts with
+//! `_`, which no payload encoding produces, followed by a case-insensitive letter and a
+//! decimal DNS message size.
+
+/// Length of the payload digest that opens every probe answer.
+pub const PROBE_DIGEST_LEN: usize = 8;
+
+const CONTROL_PREFIX: u8 = b'_';
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QueryControl {
+    #[default]
+    None,
+    /// Keep the answer's DNS message within this many bytes; the path drops larger ones.
+    AnswerLimit(u16),
+    /// Like `AnswerLimit`, and the client accepts answers that carry several QUIC packets,
+    /// one TXT record each. Servers answer with a single record to queries without it.
+    Coalesce(u16),
+    /// A path probe: answer with a DNS message of about this many bytes holding
+    /// [`probe_answer`] instead of handing the payload to QUIC.
+    Probe(u16),
+    /// Answer with a [`QueryDiagnostic`] describing the query as the server received it.
+    Diagnostic,
+}
+
+impl QueryControl {
+    pub(crate) fn label(self) -> Option<String> {
+        match self {
+            QueryControl::None => None,
+            QueryControl::AnswerLimit(size) => Some(format!("_l{}", size)),
+            QueryControl::Coalesce(size) => Some(format!("_c{}", size)),
+            QueryControl::Probe(size) => Some(format!("_p{}", size)),
+            QueryControl::Diagnostic => Some("_d0".to_string()),
+        }
+    }
+
+    /// Name characters the label takes, including its separating dot.
+    pub(crate) fn label_len(self) -> usize {
+        self.label().map_or(0, |label| label.len() + 1)
+    }
+
+    pub(crate) fn parse_label(label: &str) -> Option<Self> {
+        let bytes = label.as_bytes();
+        if bytes.len() < 3 || bytes[0] != CONTROL_PREFIX {
+            return None;
+        }
+        let size: u16 = label[2..].parse().ok()?;
+        match bytes[1].to_ascii_lowercase() {
+            b'l' => Some(QueryControl::AnswerLimit(size)),
+            b'c' => Some(QueryControl::Coalesce(size)),
+            b'p' => Some(QueryControl::Probe(size)),
+            b'd' => Some(QueryControl::Diagnostic),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_control_label(label: &str) -> bool {
+        label.as_bytes().first() == Some(&CONTROL_PREFIX)
+    }
+}
+
+/// What the server saw of a diagnostic query, sent back as its answer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryDiagnostic {
+    /// The QNAME exactly as it arrived, letter case included.
+    pub qname: String,
+    /// The EDNS UDP payload size the sender advertised, if it used EDNS.
+    pub edns_udp_payload: Option<u16>,
+    /// The address the query came from: the resolver's egress, not the client.
+    pub peer: String,
+}
+
+impl QueryDiagnostic {
+    pub fn encode(&self) -> Vec<u8> {
+        let edns = self
+            .edns_udp_payload
+            .map_or_else(|| "none".to_string(), |size| size.to_string());
+        format!("qname={} edns={} peer={}", self.qname, edns, self.peer).into_bytes()
+    }
+
+    pub fn decode(answer: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(answer).ok()?;
+        let mut qname = None;
+        let mut edns_udp_payload = None;
+        let mut peer = None;
+        for field in text.split(' ') {
+            match field.split_once('=')? {
+                ("qname", value) => qname = Some(value.to_string()),
+                ("edns", "none") => edns_udp_payload = Some(None),
+                ("edns", value) => edns_udp_payload = Some(Some(value.parse().ok()?)),
+                ("peer", value) => peer = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        Some(Self {
+            qname: qname?,
+            edns_udp_payload: edns_udp_payload?,
+            peer: peer?,
+        })
+    }
+}
+
+/// The answer payload for a probe that carried `payload`: a digest of it, so the client can
+/// tell the query arrived intact, padded to `len` bytes.
+pub fn probe_answer(payload: &[u8], len: usize) -> Vec<u8> {
+    // FNV-1a; it only has to catch mangled names, not resist forgery.
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for &byte in payload {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    let mut out = Vec::with_capacity(len.max(PROBE_DIGEST_LEN));
+    out.extend_from_slice(&hash.to_be_bytes());
+    out.extend((PROBE_DIGEST_LEN..len).map(|index| index as u8));
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{probe_answer, QueryControl, QueryDiagnostic, PROBE_DIGEST_LEN};
+
+    #[test]
+    fn labels_round_trip_case_insensitively() {
+        for control in [
+            QueryControl::AnswerLimit(512),
+            QueryControl::Coalesce(1232),
+            QueryControl::Probe(1232),
+            QueryControl::Diagnostic,
+        ] {
+            let label = control.label().expect("label");
+            assert_eq!(QueryControl::parse_label(&label), Some(control));
+            assert_eq!(
+                QueryControl::parse_label(&label.to_ascii_uppercase()),
+                Some(control)
+            );
+            assert_eq!(control.label_len(), label.len() + 1);
+        }
+        assert_eq!(QueryControl::None.label_len(), 0);
+        assert_eq!(QueryControl::parse_label("_x12"), None);
+        assert_eq!(QueryControl::parse_label("_p99999"), None);
+    }
+
+    #[test]
+    fn probe_answers_depend_on_the_payload() {
+        let answer = probe_answer(b"payload", 40);
+        assert_eq!(answer.len(), 40);
+        assert_eq!(answer, probe_answer(b"payload", 40));
+        assert_ne!(
+            answer[..PROBE_DIGEST_LEN],
+            probe_answer(b"paylaod", 40)[..8]
+        );
+        assert_eq!(probe_answer(b"", 0).len(), PROBE_DIGEST_LEN);
+    }
+
+    #[test]
+    fn diagnostics_round_trip() {
+        let diagnostic = QueryDiagnostic {
+            qname: "_d0.AbC.tunnel.example.com.".to_string(),
+            edns_udp_payload: Some(1232),
+            peer: "192.0.2.1:53000".to_string(),
+        };
+        assert_eq!(
+            QueryDiagnostic::decode(&diagnostic.encode()),
+            Some(diagnostic.clone())
+        );
+        let stripped = QueryDiagnostic {
+            edns_udp_payload: None,
+            ..diagnostic
+        };
+        assert_eq!(QueryDiagnostic::decode(&stripped.encode()), Some(stripped));
+        assert_eq!(QueryDiagnostic::decode(b"qname=x"), None);
+    }
+}
@@ -0,0 +1,277 @@
+use crate::base32;
+use crate::base64;
+use crate::control::QueryControl;
+use crate::dots;
+use crate::name::{escape_label, split_labels, unescape_label, MAX_DNS_NAME_LEN};
+use crate::types::DnsError;
+use std::fmt;
+
+const MAX_LABEL_LEN: usize = 63;
+const BASE32_LABEL_LEN: usize = 57;
+
+/// How payload bytes are spelled in the QNAME.
+///
+/// Base32 is the default and survives resolvers that fold or randomise letter case. The
+/// denser encodings only work on paths that deliver the QNAME byte for byte, so the client
+/// probes for that first. They are told apart by a leading tag character that base32 never
+/// produces. A control label, if any, comes before the tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QnameEncoding {
+    Base32,
+    /// URL-safe base64, 6 bits per character.
+    Base64,
+    /// Payload bytes used directly as label bytes.
+    Raw,
+}
+
+impl QnameEncoding {
+    /// The encoding a tunnel QNAME was built with, judged by its tag.
+    pub fn from_qname(qname: &str) -> Self {
+        strip_control_label(qname)
+            .1
+            .as_bytes()
+            .first()
+            .and_then(|tag| Self::from_tag(*tag))
+            .unwrap_or(QnameEncoding::Base32)
+    }
+
+    /// Whether a resolver that changes letter case corrupts the payload.
+    pub fn is_case_sensitive(self) -> bool {
+        !matches!(self, QnameEncoding::Base32)
+    }
+
+    fn data_tag(self) -> Option<u8> {
+        match self {
+            QnameEncoding::Base32 => None,
+            QnameEncoding::Base64 => Some(b'0'),
+            QnameEncoding::Raw => Some(b'1'),
+        }
+    }
+
+    fn from_tag(tag: u8) -> Option<Self> {
+        [QnameEncoding::Base64, QnameEncoding::Raw]
+            .into_iter()
+            .find(|encoding| encoding.data_tag() == Some(tag))
+    }
+
+    fn label_len(self) -> usize {
+        match self {
+            QnameEncoding::Base32 => BASE32_LABEL_LEN,
+            QnameEncoding::Base64 | QnameEncoding::Raw => MAX_LABEL_LEN,
+        }
+    }
+
+    /// Label bytes needed for `payload_len` bytes of payload, tag included.
+    fn wire_len(self, payload_len: usize) -> usize {
+        let tag_len = self.data_tag().map_or(0, |_| 1);
+        tag_len
+            + match self {
+                QnameEncoding::Base32 => (payload_len * 8).div_ceil(5),
+                QnameEncoding::Base64 => base64::encoded_len(payload_len),
+                QnameEncoding::Raw => payload_len,
+            }
+    }
+}
+
+impl fmt::Display for QnameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            QnameEncoding::Base32 => "base32",
+            QnameEncoding::Base64 => "base64",
+            QnameEncoding::Raw => "raw",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The payload carried by a query's subdomain labels.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SubdomainPayload {
+    pub(crate) encoding: QnameEncoding,
+    pub(crate) control: QueryControl,
+    pub(crate) payload: Vec<u8>,
+}
+
+pub(crate) fn encode_subdomain(
+    payload: &[u8],
+    encoding: QnameEncoding,
+    control: QueryControl,
+) -> Result<String, DnsError> {
+    let data = encode_data(payload, encoding);
+    Ok(match control.label() {
+        Some(label) => format!("{}.{}", label, data),
+        None => data,
+    })
+}
+
+fn encode_data(payload: &[u8], encoding: QnameEncoding) -> String {
+    let tag = encoding.data_tag();
+    match encoding {
+        QnameEncoding::Base32 => dots::dotify(&base32::encode(payload)),
+        QnameEncoding::Base64 => {
+            let mut data = String::with_capacity(encoding.wire_len(payload.len()));
+            data.extend(tag.map(char::from));
+            data.push_str(&base64::encode(payload));
+            let labels: Vec<&str> = data
+                .as_bytes()
+                .chunks(MAX_LABEL_LEN)
+                .map(|label| std::str::from_utf8(label).unwrap_or_default())
+                .collect();
+            labels.join(".")
+        }
+        QnameEncoding::Raw => {
+            let data: Vec<u8> = tag.into_iter().chain(payload.iter().copied()).collect();
+            let mut out = String::with_capacity(data.len() * 2);
+            for (index, label) in data.chunks(MAX_LABEL_LEN).enumerate() {
+                if index > 0 {
+                    out.push('.');
+                }
+                escape_label(label, &mut out);
+            }
+            out
+        }
+    }
+}
+
+/// Splits off a leading control label, if there is one.
+fn strip_control_label(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('.') {
+        Some((label, rest)) if QueryControl::is_control_label(label) => (Some(label), rest),
+        _ => (None, name),
+    }
+}
+
+/// Decodes the labels in front of the tunnel domain; `None` means they are not valid for
+/// the encoding their tag announces, or the control label is malformed.
+pub(crate) fn decode_subdomain(subdomain: &str) -> Option<SubdomainPayload> {
+    let (control, subdomain) = match strip_control_label(subdomain) {
+        (Some(label), rest) => (QueryControl::parse_label(label)?, rest),
+        (None, rest) => (QueryControl::None, rest),
+    };
+    let Some(encoding) = subdomain
+        .as_bytes()
+        .first()
+        .and_then(|tag| QnameEncoding::from_tag(*tag))
+    else {
+        return base32::decode(&dots::undotify(subdomain))
+            .ok()
+            .map(|payload| SubdomainPayload {
+                encoding: QnameEncoding::Base32,
+                control,
+                payload,
+            });
+    };
+    // Tags are single ASCII characters, so the remainder starts on a char boundary.
+    let data = &subdomain[1..];
+    let payload = match encoding {
+        QnameEncoding::Base64 => base64::decode(&dots::undotify(data))?,
+        QnameEncoding::Raw => {
+            let mut payload = Vec::with_capacity(data.len());
+            for label in split_labels(data) {
+                payload.extend(unescape_label(label).ok()?);
+            }
+            payload
+        }
+        QnameEncoding::Base32 => return None,
+    };
+    Some(SubdomainPayload {
+        encoding,
+        control,
+        payload,
+    })
+}
+
+/// Largest payload that fits in one QNAME under `domain` with the given encoding.
+pub fn max_payload_len(domain: &str, encoding: QnameEncoding) -> Result<usize, DnsError> {
+    max_payload_len_within(domain, encoding, QueryControl::None, MAX_DNS_NAME_LEN)
+}
+
+/// Largest payload that fits under `domain`, after the control label, in a QNAME of at
+/// most `max_name_len` characters.
+pub fn max_payload_len_within(
+    domain: &str,
+    encoding: QnameEncoding,
+    control: QueryControl,
+    max_name_len: usize,
+) -> Result<usize, DnsError> {
+    let domain = domain.trim_end_matches('.');
+    if domain.is_empty() {
+        return Err(DnsError::new("domain must not be empty"));
+    }
+    if domain.len() > MAX_DNS_NAME_LEN {
+        return Err(DnsError::new("domain too long"));
+    }
+    let max_wire_len = max_name_len
+        .min(MAX_DNS_NAME_LEN)
+        .saturating_sub(domain.len() + 1 + control.label_len());
+    let label_len = encoding.label_len();
+    let mut max_data_len = 0usize;
+    for len in 1..=max_wire_len {
+        let dots = (len - 1) / label_len;
+        if len + dots > max_wire_len {
+            break;
+        }
+        max_data_len = len;
+    }
+
+    let mut max_payload = max_data_len;
+    while max_payload > 0 && encoding.wire_len(max_payload) > max_data_len {
+        max_payload -= 1;
+    }
+    Ok(max_payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_subdomain, encode_subdomain, max_payload_len, max_payload_len_within, QnameEncoding,
+    };
+    use crate::control::QueryControl;
+
+    #[test]
+    fn dense_encodings_round_trip_at_capacity() {
+        let domain = "tunnel.example.com";
+        let base32 = max_payload_len(domain, QnameEncoding::Base32).expect("base32");
+        for encoding in [QnameEncoding::Base64, QnameEncoding::Raw] {
+            let max = max_payload_len(domain, encoding).expect("max payload");
+            assert!(max > base32, "{} should beat base32", encoding);
+            let payload: Vec<u8> = (0..max).map(|i| (i * 37 + 11) as u8).collect();
+            let subdomain =
+                encode_subdomain(&payload, encoding, QueryControl::None).expect("encode");
+            let decoded = decode_subdomain(&subdomain).expect("decode");
+            assert_eq!(decoded.encoding, encoding);
+            assert_eq!(decoded.payload, payload);
+        }
+    }
+
+    #[test]
+    fn control_label_precedes_the_payload() {
+        let domain = "tunnel.example.com";
+        let control = QueryControl::Probe(512);
+        for encoding in [QnameEncoding::Base32, QnameEncoding::Raw] {
+            let max = max_payload_len_within(domain, encoding, control, 160).expect("max");
+            assert!(max < max_payload_len(domain, encoding).expect("full"));
+            let payload = vec![0x5a; max];
+            let subdomain = encode_subdomain(&payload, encoding, control).expect("encode");
+            assert!(subdomain.len() + 1 + domain.len() <= 160);
+            assert_eq!(QnameEncoding::from_qname(&subdomain), encoding);
+            let decoded = decode_subdomain(&subdomain).expect("decode");
+            assert_eq!(decoded.control, control);
+            assert_eq!(decoded.payload, payload);
+        }
+    }
+
+    #[test]
+    fn case_folding_breaks_base64_but_not_base32() {
+        let payload = b"Mixed case payload".to_vec();
+        let base32 =
+            encode_subdomain(&payload, QnameEncoding::Base32, QueryControl::None).expect("base32");
+        let decoded = decode_subdomain(&base32.to_ascii_lowercase()).expect("base32 decodes");
+        assert_eq!(decoded.payload, payload);
+
+        let base64 =
+            encode_subdomain(&payload, QnameEncoding::Base64, QueryControl::None).expect("base64");
+        let folded = decode_subdomain(&base64.to_ascii_lowercase());
+        assert_ne!(folded.map(|decoded| decoded.payload), Some(payload));
+    }
+}
@@ -1,69 +1,52 @@
 mod base32;
+mod base64;
 mod codec;
+mod control;
 mod dots;
+mod encoding;
 mod name;
 mod types;
 mod wire;
 
 pub use base32::{decode as base32_decode, encode as base32_encode, Base32Error};
 pub use codec::{
-    decode_query, decode_query_with_domains, decode_response, encode_query, encode_response,
-    is_response,
+    decode_query, decode_query_with_domains, decode_response, decode_response_payloads,
+    encode_coalesced_response, encode_query, encode_response, is_response, max_next_answer_len,
+    max_response_payload_len, response_question_name, response_rcode,
 };
+pub use control::{probe_answer, QueryControl, QueryDiagnostic, PROBE_DIGEST_LEN};
 pub use dots::{dotify, undotify};
+pub use encoding::{max_payload_len, max_payload_len_within, QnameEncoding};
 pub use types::{
     DecodeQueryError, DecodedQuery, DnsError, QueryParams, Question, Rcode, ResponseParams,
-    CLASS_IN, EDNS_UDP_PAYLOAD, RR_A, RR_OPT, RR_TXT,
+    CLASS_IN, DNS_UDP_PAYLOAD_MIN, EDNS_UDP_PAYLOAD, RR_A, RR_OPT, RR_TXT,
 };
 
 pub fn build_qname(payload: &[u8], domain: &str) -> Result<String, DnsError> {
+    build_encoded_qname(payload, domain, QnameEncoding::Base32, QueryControl::None)
+}
+
+/// Builds a QNAME carrying `payload` with the given encoding, behind `control`'s label.
+pub fn build_encoded_qname(
+    payload: &[u8],
+    domain: &str,
+    encoding: QnameEncoding,
+    control: QueryControl,
+) -> Result<String, DnsError> {
     let domain = domain.trim_end_matches('.');
     if domain.is_empty() {
         return Err(DnsError::new("domain must not be empty"));
     }
-    let max_payload = max_payload_len_for_domain(domain)?;
+    let max_payload = max_payload_len_within(domain, encoding, control, name::MAX_DNS_NAME_LEN)?;
     if payload.len() > max_payload {
         return Err(DnsError::new("payload too large for domain"));
     }
-    let base32 = base32_encode(payload);
-    let dotted = dotify(&base32);
-    Ok(format!("{}.{}.", dotted, domain))
+    let subdomain = encoding::encode_subdomain(payload, encoding, control)?;
+    Ok(format!("{}.{}.", subdomain, domain))
 }
 
 pub fn max_payload_len_for_domain(domain: &str) -> Result<usize, DnsError> {
-    let domain = domain.trim_end_matches('.');
-    if domain.is_empty() {
-        return Err(DnsError::new("domain must not be empty"));
-    }
-    if domain.len() > name::MAX_DNS_NAME_LEN {
-        return Err(DnsError::new("domain too long"));
-    }
-    let max_name_len = name::MAX_DNS_NAME_LEN;
-    let max_dotted_len = max_name_len.saturating_sub(domain.len() + 1);
-    if max_dotted_len == 0 {
-        return Ok(0);
-    }
-    let mut max_base32_len = 0usize;
-    for len in 1..=max_dotted_len {
-        let dots = (len - 1) / 57;
-        if len + dots > max_dotted_len {
-            break;
-        }
-        max_base32_len = len;
-    }
-
-    let mut max_payload = (max_base32_len * 5) / 8;
-    while max_payload > 0 && base32_len(max_payload) > max_base32_len {
-        max_payload -= 1;
-    }
-    Ok(max_payload)
-}
-
-fn base32_len(payload_len: usize) -> usize {
-    if payload_len == 0 {
-        return 0;
-    }
-    (payload_len * 8).div_ceil(5)
+    max_payload_len(domain, QnameEncoding::Base32)
 }
 
 #[cfg(test)]
@@ -1,4 +1,5 @@
 use crate::types::{DnsError, Rcode};
+use std::fmt::Write;
 
 pub(crate) const MAX_DNS_NAME_LEN: usize = 253;
 
@@ -133,9 +134,9 @@ pub(crate) fn parse_name(packet: &[u8], start: usize) -> Result<(String, usize),
         if name_len > MAX_DNS_NAME_LEN {
             return Err(DnsError::new("name too long"));
         }
-        let label = std::str::from_utf8(&packet[offset..end])
-            .map_err(|_| DnsError::new("label not utf-8"))?;
-        labels.push(label.to_string());
+        let mut label = String::with_capacity(len as usize);
+        escape_label(&packet[offset..end], &mut label);
+        labels.push(label);
         offset = end;
         if !jumped {
             end_offset = offset;
@@ -162,10 +163,11 @@ pub(crate) fn encode_name(name: &str, out: &mut Vec<u8>) -> Result<(), DnsError>
     let trimmed = name.trim_end_matches('.');
     let mut name_len = 0usize;
     let mut first = true;
-    for label in trimmed.split('.') {
+    for label in split_labels(trimmed) {
         if label.is_empty() {
             return Err(DnsError::new("empty label"));
         }
+        let label = unescape_label(label)?;
         if label.len() > 63 {
             return Err(DnsError::new("label too long"));
         }
@@ -177,18 +179,100 @@ pub(crate) fn encode_name(name: &str, out: &mut Vec<u8>) -> Result<(), DnsError>
             return Err(DnsError::new("name too long"));
         }
         out.push(label.len() as u8);
-        out.extend_from_slice(label.as_bytes());
+        out.extend_from_slice(&label);
         first = false;
     }
     out.push(0);
     Ok(())
 }
 
+/// Writes a wire label in presentation format: printable ASCII stays as is, while dots,
+/// backslashes and any other byte become `\DDD` escapes.
+pub(crate) fn escape_label(label: &[u8], out: &mut String) {
+    for &byte in label {
+        if byte.is_ascii_graphic() && byte != b'.' && byte != b'\\' {
+            out.push(byte as char);
+        } else {
+            let _ = write!(out, "\\{:03}", byte);
+        }
+    }
+}
+
+/// Reverses `escape_label`, also accepting the `\X` form for a literal character.
+pub(crate) fn unescape_label(label: &str) -> Result<Vec<u8>, DnsError> {
+    let bytes = label.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != b'\\' {
+            out.push(bytes[index]);
+            index += 1;
+            continue;
+        }
+        let digits = bytes.get(index + 1..index + 4);
+        match digits {
+            Some(digits) if digits.iter().all(u8::is_ascii_digit) => {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |acc, digit| acc * 10 + (digit - b'0') as u32);
+                let value =
+                    u8::try_from(value).map_err(|_| DnsError::new("escape out of range"))?;
+                out.push(value);
+                index += 4;
+            }
+            _ => {
+                let escaped = bytes
+                    .get(index + 1)
+                    .ok_or_else(|| DnsError::new("dangling escape"))?;
+                out.push(*escaped);
+                index += 2;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Splits a presentation-format name on the dots that are not escaped.
+pub(crate) fn split_labels(name: &str) -> Vec<&str> {
+    let bytes = name.as_bytes();
+    let mut labels = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'\\' => index += 2,
+            b'.' => {
+                labels.push(&name[start..index]);
+                index += 1;
+                start = index;
+            }
+            _ => index += 1,
+        }
+    }
+    labels.push(&name[start.min(name.len())..]);
+    labels
+}
+
 #[cfg(test)]
 mod tests {
     use super::MAX_DNS_NAME_LEN;
     use super::{encode_name, parse_name};
 
+    #[test]
+    fn binary_labels_round_trip_through_escapes() {
+        let label: Vec<u8> = vec![0x00, b'.', b'\\', b'A', b'z', b' ', 0x7f, 0xff];
+        let mut packet = vec![label.len() as u8];
+        packet.extend_from_slice(&label);
+        packet.extend_from_slice(b"\x04test\x03com\x00");
+        let (name, end) = parse_name(&packet, 0).expect("parse binary label");
+        assert_eq!(end, packet.len());
+        assert_eq!(name, "\\000\\046\\092Az\\032\\127\\255.test.com.");
+
+        let mut out = Vec::new();
+        encode_name(&name, &mut out).expect("encode escaped name");
+        assert_eq!(out, packet);
+    }
+
     fn build_name(last_label_len: usize) -> String {
         format!(
             "{}.{}.{}.{}.",
@@ -1,3 +1,5 @@
+use crate::control::QueryControl;
+use crate::encoding::QnameEncoding;
 use std::fmt;
 
 pub const RR_A: u16 = 1;
@@ -5,6 +7,8 @@ pub const RR_TXT: u16 = 16;
 pub const RR_OPT: u16 = 41;
 pub const CLASS_IN: u16 = 1;
 pub const EDNS_UDP_PAYLOAD: u16 = 1232;
+/// Response size limit for queries without EDNS (RFC 1035).
+pub const DNS_UDP_PAYLOAD_MIN: u16 = 512;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Rcode {
@@ -49,6 +53,11 @@ pub struct DecodedQuery {
     pub cd: bool,
     pub question: Question,
     pub payload: Vec<u8>,
+    pub encoding: QnameEncoding,
+    /// Instructions from the query's control label, if it had one.
+    pub control: QueryControl,
+    /// The UDP payload size the sender advertised with EDNS.
+    pub edns_udp_payload: Option<u16>,
 }
 
 #[derive(Debug, Clone)]
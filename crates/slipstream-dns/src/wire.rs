@@ -1,5 +1,5 @@
 use crate::name::parse_name;
-use crate::types::{DecodeQueryError, DnsError, Question, Rcode};
+use crate::types::{DecodeQueryError, DnsError, Question, Rcode, RR_OPT};
 
 #[derive(Debug, Clone, Copy)]
 pub(crate) struct Header {
@@ -9,6 +9,8 @@ pub(crate) struct Header {
     pub(crate) cd: bool,
     pub(crate) qdcount: u16,
     pub(crate) ancount: u16,
+    pub(crate) nscount: u16,
+    pub(crate) arcount: u16,
     pub(crate) rcode: Option<Rcode>,
     pub(crate) offset: usize,
 }
@@ -21,8 +23,8 @@ pub(crate) fn parse_header(packet: &[u8]) -> Option<Header> {
     let flags = read_u16(packet, 2)?;
     let qdcount = read_u16(packet, 4)?;
     let ancount = read_u16(packet, 6)?;
-    let _nscount = read_u16(packet, 8)?;
-    let _arcount = read_u16(packet, 10)?;
+    let nscount = read_u16(packet, 8)?;
+    let arcount = read_u16(packet, 10)?;
 
     let is_response = flags & 0x8000 != 0;
     let rd = flags & 0x0100 != 0;
@@ -36,6 +38,8 @@ pub(crate) fn parse_header(packet: &[u8]) -> Option<Header> {
         cd,
         qdcount,
         ancount,
+        nscount,
+        arcount,
         rcode,
         offset: 12,
     })
@@ -90,6 +94,25 @@ pub(crate) fn parse_question(packet: &[u8], offset: usize) -> Result<(Question,
     ))
 }
 
+/// Reads the UDP payload size from the OPT record among the records that follow the
+/// question, if the sender advertised one (RFC 6891).
+pub(crate) fn parse_edns_udp_payload(packet: &[u8], header: &Header, offset: usize) -> Option<u16> {
+    let records = header.ancount as usize + header.nscount as usize + header.arcount as usize;
+    let mut offset = offset;
+    for _ in 0..records {
+        let (_, next) = parse_name(packet, offset).ok()?;
+        offset = next;
+        let rtype = read_u16(packet, offset)?;
+        let class = read_u16(packet, offset + 2)?;
+        let rdlen = read_u16(packet, offset + 8)? as usize;
+        if rtype == RR_OPT {
+            return Some(class);
+        }
+        offset += 10 + rdlen;
+    }
+    None
+}
+
 pub(crate) fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
     if offset + 2 > packet.len() {
         return None;
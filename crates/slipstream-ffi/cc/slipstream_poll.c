@@ -67,3 +67,46 @@ int slipstream_get_path_id_from_unique(picoquic_cnx_t *cnx, uint64_t unique_path
     }
     return path_id;
 }
+
+int slipstream_get_path_count(picoquic_cnx_t *cnx) {
+    if (cnx == NULL) {
+        return 0;
+    }
+    return cnx->nb_paths;
+}
+
+int slipstream_get_unique_path_id(picoquic_cnx_t *cnx, int path_id, uint64_t *unique_path_id) {
+    if (cnx == NULL || unique_path_id == NULL || path_id < 0 || path_id >= cnx->nb_paths) {
+        return -1;
+    }
+    picoquic_path_t* path_x = cnx->path[path_id];
+    if (path_x == NULL) {
+        return -1;
+    }
+    *unique_path_id = path_x->unique_path_id;
+    if (path_x->path_is_demoted || path_x->path_abandon_received || path_x->path_abandon_sent) {
+        return 1;
+    }
+    return 0;
+}
+
+void slipstream_set_max_datagram_frame_size(picoquic_quic_t *quic, uint32_t max_size) {
+    if (quic == NULL) {
+        return;
+    }
+    quic->default_tp.max_datagram_frame_size = max_size;
+}
+
+void slipstream_set_path_send_mtu(picoquic_cnx_t *cnx, int path_id, uint32_t mtu) {
+    if (cnx == NULL || path_id < 0 || path_id >= cnx->nb_paths || mtu == 0) {
+        return;
+    }
+    picoquic_path_t* path_x = cnx->path[path_id];
+    if (path_x == NULL) {
+        return;
+    }
+    if (cnx->quic->mtu_max > 0 && mtu > cnx->quic->mtu_max) {
+        mtu = cnx->quic->mtu_max;
+    }
+    path_x->send_mtu = mtu;
+}
@@ -1,4 +1,4 @@
-use slipstream_core::HostPort;
+use slipstream_core::{forward::ForwardSpec, HostPort};
 
 pub mod picoquic;
 pub mod runtime;
@@ -6,34 +6,100 @@ pub mod runtime;
 pub use picoquic::get_pacing_rate;
 pub use picoquic::get_rtt;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum ResolverMode {
     Recursive = 1,
     Authoritative = 2,
 }
 
+/// How DNS queries reach a resolver.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ResolverTransport {
+    Udp,
+    /// RFC 8484 DNS-over-HTTPS; `resolver` holds the URL's host and port.
+    Doh {
+        url: String,
+    },
+    /// RFC 7858 DNS-over-TLS; the certificate is checked against `server_name`.
+    Dot {
+        server_name: String,
+    },
+    /// RFC 7766 DNS over a persistent TCP connection.
+    Tcp,
+}
+
+/// Which QNAME encodings the client tries to negotiate on each resolver path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QnameEncodingPreference {
+    /// Base32 only; works through any resolver.
+    Base32,
+    /// Base64 where the path preserves letter case, base32 elsewhere.
+    Base64,
+    /// Raw 8-bit labels where the path carries them intact, base32 elsewhere.
+    Raw,
+    /// The densest encoding each path carries: raw, then base64, then base32.
+    Auto,
+}
+
 #[derive(Debug, Clone)]
 pub struct ResolverSpec {
     pub resolver: HostPort,
     pub mode: ResolverMode,
+    pub transport: ResolverTransport,
 }
 
 #[derive(Debug)]
 pub struct ClientConfig<'a> {
-    pub tcp_listen_port: u16,
+    /// Port for TCP clients relayed to the server's default target; None skips the listener.
+    pub tcp_listen_port: Option<u16>,
+    /// Unix socket path taking the same clients instead of a TCP port (`-l unix:/path`).
+    pub unix_listen_path: Option<&'a str>,
+    /// `-L` forwards: each listens locally and names its destination to the server.
+    pub local_forwards: &'a [ForwardSpec],
+    /// `-R` forwards: the server listens on the bind address and streams each connection back.
+    pub remote_forwards: &'a [ForwardSpec],
     pub resolvers: &'a [ResolverSpec],
+    pub resolvers_file: Option<&'a str>,
+    pub system_resolvers: bool,
+    /// Seconds between re-reading and re-resolving resolver sources; 0 disables.
+    pub resolver_refresh_interval: u64,
     pub domain: &'a str,
     pub cert: Option<&'a str>,
     pub congestion_control: Option<&'a str>,
     pub gso: bool,
     pub keep_alive_interval: usize,
     pub debug_poll: bool,
+    /// Send DoH queries as GET requests instead of POST.
+    pub doh_get: bool,
+    /// Per-resolver query ceiling for recursive resolvers; 0 disables.
+    pub max_qps: u32,
+    pub qps_burst: u32,
+    /// Upper bound of the random delay between queries to one recursive resolver.
+    pub query_jitter_ms: u64,
+    /// Number of UDP sockets queries are spread across.
+    pub udp_sockets: usize,
+    /// Randomise QNAME letter case (DNS 0x20) and require responses to echo it.
+    pub dns_0x20: bool,
+    pub qname_encoding: QnameEncodingPreference,
     pub debug_streams: bool,
+    /// Return once the QUIC handshake completes instead of accepting TCP clients.
+    pub handshake_only: bool,
+    /// Bridge one stream to stdin/stdout instead of accepting TCP clients, and return once
+    /// it closes.
+    pub stdio: bool,
+    /// `-U` forwards: UDP on the bind address, relayed to `host:hostport` in DATAGRAM frames.
+    pub udp_forwards: &'a [ForwardSpec],
+    /// Seconds without traffic after which a UDP flow is closed.
+    pub udp_idle_timeout: u64,
+    /// TUN device whose IP packets are carried to the server's (`--tun`, Linux only).
+    pub tun: Option<&'a str>,
+    /// Send each connection on the listen port to its pre-redirect destination (Linux only).
+    pub transparent: bool,
 }
 
 pub use runtime::{
-    configure_quic, configure_quic_with_custom, sockaddr_storage_to_socket_addr,
-    socket_addr_to_storage, write_stream_or_reset, QuicGuard, SLIPSTREAM_FILE_CANCEL_ERROR,
-    SLIPSTREAM_INTERNAL_ERROR,
+    allow_path_send_mtu_up_to, configure_quic, configure_quic_with_custom, provide_datagram,
+    sockaddr_storage_to_socket_addr, socket_addr_to_storage, write_stream_or_reset, QuicGuard,
+    SLIPSTREAM_FILE_CANCEL_ERROR, SLIPSTREAM_INTERNAL_ERROR,
 };
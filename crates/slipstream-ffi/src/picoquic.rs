@@ -107,6 +107,22 @@ pub enum picoquic_state_enum {
     picoquic_state_disconnected = 19,
 }
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum picoquic_path_status_enum {
+    picoquic_path_status_available = 0,
+    picoquic_path_status_standby = 1,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum picoquic_datagram_active_enum {
+    picoquic_datagram_not_active = 0,
+    picoquic_datagram_active_any_path = 1,
+    picoquic_datagram_active_this_path_only = 2,
+    picoquic_datagram_active_this_path_and_others = 3,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum picoquic_call_back_event_t {
@@ -222,6 +238,10 @@ extern "C" {
 
     pub fn picoquic_set_cookie_mode(quic: *mut picoquic_quic_t, cookie_mode: c_int);
     pub fn picoquic_set_default_priority(quic: *mut picoquic_quic_t, default_stream_priority: u8);
+    pub fn picoquic_set_default_datagram_priority(
+        quic: *mut picoquic_quic_t,
+        default_datagram_priority: u8,
+    );
     pub fn picoquic_set_default_direct_receive_callback(
         quic: *mut picoquic_quic_t,
         direct_receive_fn: picoquic_stream_direct_receive_fn,
@@ -256,6 +276,7 @@ extern "C" {
         initial_mtu_ipv6: u32,
     );
     pub fn picoquic_set_key_log_file_from_env(quic: *mut picoquic_quic_t);
+    pub fn picoquic_set_default_pmtud_policy(quic: *mut picoquic_quic_t, pmtud_policy: c_int);
     pub fn picoquic_enable_path_callbacks_default(quic: *mut picoquic_quic_t, are_enabled: c_int);
 
     pub fn picoquic_set_verify_certificate_callback(
@@ -311,6 +332,9 @@ extern "C" {
         unique_path_id: u64,
         quality: *mut picoquic_path_quality_t,
     ) -> c_int;
+    pub fn picoquic_get_data_sent(cnx: *mut picoquic_cnx_t) -> u64;
+    pub fn picoquic_get_data_received(cnx: *mut picoquic_cnx_t) -> u64;
+    pub fn picoquic_get_logging_cnxid(cnx: *mut picoquic_cnx_t) -> picoquic_connection_id_t;
 
     pub fn slipstream_request_poll(cnx: *mut picoquic_cnx_t);
     pub fn slipstream_is_flow_blocked(cnx: *mut picoquic_cnx_t) -> c_int;
@@ -324,10 +348,21 @@ extern "C" {
         cnx: *mut picoquic_cnx_t,
         unique_path_id: u64,
     ) -> c_int;
+    pub fn slipstream_get_path_count(cnx: *mut picoquic_cnx_t) -> c_int;
+    /// Returns 0 for an active path, 1 for a demoted/abandoned path, -1 if invalid.
+    pub fn slipstream_get_unique_path_id(
+        cnx: *mut picoquic_cnx_t,
+        path_id: c_int,
+        unique_path_id: *mut u64,
+    ) -> c_int;
     pub fn slipstream_set_cc_override(alg_name: *const c_char);
     pub fn slipstream_set_default_path_mode(mode: c_int);
     pub fn slipstream_set_path_mode(cnx: *mut picoquic_cnx_t, path_id: c_int, mode: c_int);
     pub fn slipstream_set_path_ack_delay(cnx: *mut picoquic_cnx_t, path_id: c_int, disable: c_int);
+    /// Sets the largest packet picoquic builds for the path, capped at the context's MTU max.
+    pub fn slipstream_set_path_send_mtu(cnx: *mut picoquic_cnx_t, path_id: c_int, mtu: u32);
+    /// Advertises DATAGRAM support (RFC 9221) on connections created from now on.
+    pub fn slipstream_set_max_datagram_frame_size(quic: *mut picoquic_quic_t, max_size: u32);
 
     pub fn picoquic_get_first_cnx(quic: *mut picoquic_quic_t) -> *mut picoquic_cnx_t;
     pub fn picoquic_get_next_cnx(cnx: *mut picoquic_cnx_t) -> *mut picoquic_cnx_t;
@@ -394,11 +429,18 @@ extern "C" {
         stream_id: u64,
         local_stream_error: u64,
     ) -> c_int;
+    pub fn picoquic_get_remote_stream_error(cnx: *mut picoquic_cnx_t, stream_id: u64) -> u64;
     pub fn picoquic_stream_data_consumed(
         cnx: *mut picoquic_cnx_t,
         stream_id: u64,
         new_offset: u64,
     ) -> c_int;
+    pub fn picoquic_mark_datagram_ready(cnx: *mut picoquic_cnx_t, is_ready: c_int) -> c_int;
+    pub fn picoquic_provide_datagram_buffer_ex(
+        context: *mut c_void,
+        length: size_t,
+        is_active: picoquic_datagram_active_enum,
+    ) -> *mut u8;
     pub fn picoquic_get_next_local_stream_id(cnx: *mut picoquic_cnx_t, is_unidir: c_int) -> u64;
 
     pub fn picoquic_set_app_stream_ctx(
@@ -424,6 +466,20 @@ extern "C" {
         path_id_p: *mut c_int,
     ) -> c_int;
 
+    pub fn picoquic_abandon_path(
+        cnx: *mut picoquic_cnx_t,
+        unique_path_id: u64,
+        reason: u64,
+        phrase: *const c_char,
+        current_time: u64,
+    ) -> c_int;
+
+    pub fn picoquic_set_path_status(
+        cnx: *mut picoquic_cnx_t,
+        unique_path_id: u64,
+        status: picoquic_path_status_enum,
+    ) -> c_int;
+
     pub fn picoquic_get_path_addr(
         cnx: *mut picoquic_cnx_t,
         unique_path_id: u64,
@@ -1,13 +1,16 @@
 use crate::picoquic::{
-    picoquic_cnx_t, picoquic_congestion_algorithm_t, picoquic_disable_port_blocking, picoquic_free,
+    picoquic_cnx_t, picoquic_congestion_algorithm_t, picoquic_datagram_active_enum,
+    picoquic_disable_port_blocking, picoquic_free, picoquic_provide_datagram_buffer_ex,
     picoquic_quic_t, picoquic_reset_stream, picoquic_set_cookie_mode,
     picoquic_set_default_congestion_algorithm, picoquic_set_default_congestion_algorithm_by_name,
-    picoquic_set_default_multipath_option, picoquic_set_default_priority,
+    picoquic_set_default_datagram_priority, picoquic_set_default_multipath_option,
+    picoquic_set_default_pmtud_policy, picoquic_set_default_priority,
     picoquic_set_initial_send_mtu, picoquic_set_key_log_file_from_env,
     picoquic_set_max_data_control, picoquic_set_mtu_max, picoquic_set_preemptive_repeat_policy,
-    picoquic_set_stream_data_consumption_mode,
+    picoquic_set_stream_data_consumption_mode, slipstream_set_max_datagram_frame_size,
 };
-use libc::c_char;
+use libc::{c_char, c_void};
+use slipstream_core::datagram::{DatagramQueue, MAX_DATAGRAM_FRAME_SIZE};
 use slipstream_core::tcp::stream_write_buffer_bytes;
 use std::io::Write;
 use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, SocketAddrV6, TcpStream};
@@ -35,6 +38,8 @@ impl Default for sockaddr_storage {
 
 pub const SLIPSTREAM_INTERNAL_ERROR: u64 = 0x101;
 pub const SLIPSTREAM_FILE_CANCEL_ERROR: u64 = 0x105;
+/// `picoquic_pmtud_blocked` from picoquic.h.
+const PICOQUIC_PMTUD_BLOCKED: libc::c_int = 3;
 
 pub struct QuicGuard {
     quic: *mut picoquic_quic_t,
@@ -75,6 +80,9 @@ pub unsafe fn configure_quic_with_custom(
     picoquic_set_default_congestion_algorithm(quic, algo);
 }
 
+/// picoquic serves lower values first.
+const STREAM_PRIORITY: u8 = 2;
+
 /// Configure shared QUIC defaults.
 /// Backpressure is enforced via a connection-level `max_data` cap (shared across streams),
 /// rather than per-stream buffer limits/reset.
@@ -83,7 +91,10 @@ pub unsafe fn configure_quic_with_custom(
 /// `quic` must be a valid picoquic context and `mtu` must be non-zero.
 unsafe fn configure_quic_common(quic: *mut picoquic_quic_t, mtu: u32) {
     picoquic_set_cookie_mode(quic, 0);
-    picoquic_set_default_priority(quic, 2);
+    picoquic_set_default_priority(quic, STREAM_PRIORITY);
+    // UDP flows ride in DATAGRAM frames, sent only when no stream has data waiting.
+    slipstream_set_max_datagram_frame_size(quic, MAX_DATAGRAM_FRAME_SIZE);
+    picoquic_set_default_datagram_priority(quic, STREAM_PRIORITY + 1);
     picoquic_set_default_multipath_option(quic, 1);
     picoquic_set_preemptive_repeat_policy(quic, 1);
     picoquic_disable_port_blocking(quic, 1);
@@ -94,6 +105,38 @@ unsafe fn configure_quic_common(quic: *mut picoquic_quic_t, mtu: u32) {
     picoquic_set_key_log_file_from_env(quic);
 }
 
+/// Raises the context's MTU ceiling so individual paths can be given a larger send MTU
+/// with `slipstream_set_path_send_mtu`. Path MTU discovery stays off: only the caller
+/// knows which paths can carry the larger packets.
+///
+/// # Safety
+/// `quic` must be a valid picoquic context.
+pub unsafe fn allow_path_send_mtu_up_to(quic: *mut picoquic_quic_t, mtu_max: u32) {
+    picoquic_set_default_pmtud_policy(quic, PICOQUIC_PMTUD_BLOCKED);
+    picoquic_set_mtu_max(quic, mtu_max);
+}
+
+/// Answers `picoquic_callback_prepare_datagram` with the next queued datagram that fits,
+/// and keeps the connection polling for datagrams while any are left.
+///
+/// # Safety
+/// `context` and `space` must be the `bytes` and `length` of that callback.
+pub unsafe fn provide_datagram(queue: &mut DatagramQueue, context: *mut c_void, space: usize) {
+    let datagram = queue.take(space);
+    let is_active = if queue.is_empty() {
+        picoquic_datagram_active_enum::picoquic_datagram_not_active
+    } else {
+        picoquic_datagram_active_enum::picoquic_datagram_active_any_path
+    };
+    let len = datagram.as_ref().map_or(0, Vec::len);
+    let buffer = picoquic_provide_datagram_buffer_ex(context, len, is_active);
+    if let Some(datagram) = datagram {
+        if !buffer.is_null() {
+            std::ptr::copy_nonoverlapping(datagram.as_ptr(), buffer, len);
+        }
+    }
+}
+
 // Windows AF_* constants
 #[cfg(windows)]
 const AF_INET: i32 = 2;
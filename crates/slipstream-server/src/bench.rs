@@ -0,0 +1,138 @@
+//! Built-in stream targets for `slipstream-client bench`, so a tunnel can be measured without
+//! an external target. They plug into the same channels as a TCP target connection.
+
+use crate::server::{
+    Command, StreamKey, StreamWrite, DEFAULT_TCP_RCVBUF_BYTES, STREAM_READ_CHUNK_BYTES,
+};
+use slipstream_core::stream_header::StreamTarget;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+
+struct Output {
+    key: StreamKey,
+    data_tx: Option<mpsc::Sender<Vec<u8>>>,
+    command_tx: mpsc::UnboundedSender<Command>,
+    send_pending: Arc<AtomicBool>,
+}
+
+impl Output {
+    async fn send(&self, data: Vec<u8>) -> bool {
+        let Some(data_tx) = self.data_tx.as_ref() else {
+            return false;
+        };
+        if data_tx.send(data).await.is_err() {
+            return false;
+        }
+        self.readable();
+        true
+    }
+
+    fn readable(&self) {
+        if !self.send_pending.swap(true, Ordering::SeqCst) {
+            let _ = self.command_tx.send(Command::StreamReadable {
+                cnx_id: self.key.cnx,
+                stream_id: self.key.stream_id,
+            });
+        }
+    }
+
+    fn close(&mut self) {
+        if self.data_tx.take().is_some() {
+            let _ = self.command_tx.send(Command::StreamClosed {
+                cnx_id: self.key.cnx,
+                stream_id: self.key.stream_id,
+            });
+        }
+    }
+}
+
+pub(crate) fn spawn_builtin_target(
+    key: StreamKey,
+    target: StreamTarget,
+    command_tx: mpsc::UnboundedSender<Command>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let (data_tx, data_rx) = mpsc::channel(DEFAULT_TCP_RCVBUF_BYTES / STREAM_READ_CHUNK_BYTES);
+    let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+    let send_pending = Arc::new(AtomicBool::new(false));
+    let _ = command_tx.send(Command::StreamConnected {
+        cnx_id: key.cnx,
+        stream_id: key.stream_id,
+        write_tx,
+        data_rx,
+        send_pending: send_pending.clone(),
+    });
+    tokio::spawn(async move {
+        let mut output = Output {
+            key,
+            data_tx: Some(data_tx),
+            command_tx: command_tx.clone(),
+            send_pending,
+        };
+        let mut remaining = match target {
+            StreamTarget::Source(len) => len,
+            _ => 0,
+        };
+        if matches!(target, StreamTarget::Source(0)) {
+            output.close();
+        }
+        let chunk = vec![0u8; STREAM_READ_CHUNK_BYTES];
+        let mut received = 0u64;
+        let mut input_open = true;
+        // Keep reading until the client's FIN even once our side is done, as a TCP target
+        // would, so late data does not hit a closed channel.
+        while input_open || output.data_tx.is_some() {
+            let source_tx = output
+                .data_tx
+                .clone()
+                .filter(|_| matches!(target, StreamTarget::Source(_)));
+            let sourcing = source_tx.is_some();
+            tokio::select! {
+                changed = shutdown_rx.changed() => {
+                    if changed.is_err() || *shutdown_rx.borrow() {
+                        return;
+                    }
+                }
+                msg = write_rx.recv(), if input_open => match msg {
+                    Some(StreamWrite::Data(data)) => {
+                        let len = data.len();
+                        received = received.saturating_add(len as u64);
+                        if target == StreamTarget::Echo && !output.send(data).await {
+                            return;
+                        }
+                        let _ = command_tx.send(Command::StreamWriteDrained {
+                            cnx_id: key.cnx,
+                            stream_id: key.stream_id,
+                            bytes: len,
+                        });
+                    }
+                    Some(StreamWrite::Fin) | None => {
+                        input_open = false;
+                        if target == StreamTarget::Sink
+                            && !output.send(received.to_be_bytes().to_vec()).await
+                        {
+                            return;
+                        }
+                        // A source closes its side once it has sent everything.
+                        if !matches!(target, StreamTarget::Source(_)) {
+                            output.close();
+                        }
+                    }
+                },
+                permit = async { source_tx?.reserve_owned().await.ok() }, if sourcing => {
+                    let Some(permit) = permit else {
+                        return;
+                    };
+                    let len = remaining.min(chunk.len() as u64) as usize;
+                    permit.send(chunk[..len].to_vec());
+                    output.readable();
+                    remaining -= len as u64;
+                    if remaining == 0 {
+                        output.close();
+                    }
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,325 @@
+use crate::server::{Command, ServerError};
+use crate::streams::ServerState;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::mpsc;
+
+/// Requests accepted on the control socket, one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub(crate) enum ControlRequest {
+    ListConnections,
+    ListStreams {
+        connection: u64,
+    },
+    CloseConnection {
+        connection: u64,
+    },
+    ResetStream {
+        connection: u64,
+        stream: u64,
+    },
+    SetDebug {
+        #[serde(default)]
+        debug_streams: Option<bool>,
+        #[serde(default)]
+        debug_commands: Option<bool>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ControlResponse {
+    pub(crate) ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) result: Option<Value>,
+}
+
+impl ControlResponse {
+    fn ok(result: impl Serialize) -> Self {
+        match serde_json::to_value(result) {
+            Ok(result) => Self {
+                ok: true,
+                error: None,
+                result: Some(result),
+            },
+            Err(err) => Self::error(err.to_string()),
+        }
+    }
+
+    fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(message.into()),
+            result: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ConnectionSnapshot {
+    pub(crate) id: u64,
+    pub(crate) cid: String,
+    pub(crate) state: String,
+    pub(crate) age_secs: u64,
+    pub(crate) idle_ms: u64,
+    pub(crate) queries: u64,
+    pub(crate) resolvers: Vec<ResolverSnapshot>,
+    pub(crate) paths: Vec<PathSnapshot>,
+    pub(crate) rtt_us: u64,
+    pub(crate) cwin: u64,
+    pub(crate) bytes_sent: u64,
+    pub(crate) bytes_received: u64,
+    pub(crate) streams: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ResolverSnapshot {
+    pub(crate) addr: String,
+    pub(crate) queries: u64,
+    pub(crate) idle_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PathSnapshot {
+    pub(crate) path_id: i32,
+    pub(crate) unique_path_id: u64,
+    pub(crate) active: bool,
+    pub(crate) rtt_us: u64,
+    pub(crate) rtt_min_us: u64,
+    pub(crate) cwin: u64,
+    pub(crate) pacing_rate: u64,
+    pub(crate) bytes_in_transit: u64,
+    pub(crate) sent: u64,
+    pub(crate) lost: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StreamSnapshot {
+    pub(crate) stream_id: u64,
+    pub(crate) target_connected: bool,
+    pub(crate) rx_bytes: u64,
+    pub(crate) tx_bytes: u64,
+    pub(crate) queued_bytes: usize,
+    pub(crate) consumed_offset: u64,
+    pub(crate) fin_offset: Option<u64>,
+    pub(crate) pending_chunks: usize,
+    pub(crate) pending_fin: bool,
+    pub(crate) fin_enqueued: bool,
+    pub(crate) target_fin_pending: bool,
+    pub(crate) close_after_flush: bool,
+    pub(crate) send_stash_bytes: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DebugFlags {
+    pub(crate) debug_streams: bool,
+    pub(crate) debug_commands: bool,
+}
+
+/// Runs on the server loop, so it may touch picoquic state directly.
+pub(crate) fn handle_control_request(
+    state: &mut ServerState,
+    request: ControlRequest,
+) -> ControlResponse {
+    match request {
+        ControlRequest::ListConnections => ControlResponse::ok(state.connection_snapshots()),
+        ControlRequest::ListStreams { connection } => match state.stream_snapshots(connection) {
+            Ok(streams) => ControlResponse::ok(streams),
+            Err(err) => ControlResponse::error(err),
+        },
+        ControlRequest::CloseConnection { connection } => {
+            match state.close_connection(connection) {
+                Ok(()) => ControlResponse::ok(Value::Null),
+                Err(err) => ControlResponse::error(err),
+            }
+        }
+        ControlRequest::ResetStream { connection, stream } => {
+            match state.reset_stream(connection, stream) {
+                Ok(()) => ControlResponse::ok(Value::Null),
+                Err(err) => ControlResponse::error(err),
+            }
+        }
+        ControlRequest::SetDebug {
+            debug_streams,
+            debug_commands,
+        } => ControlResponse::ok(state.set_debug(debug_streams, debug_commands)),
+    }
+}
+
+/// Removes the socket file when the server exits.
+pub(crate) struct ControlSocketGuard {
+    #[cfg_attr(not(unix), allow(dead_code))]
+    path: std::path::PathBuf,
+}
+
+impl Drop for ControlSocketGuard {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn spawn_control_listener(
+    path: &str,
+    command_tx: mpsc::UnboundedSender<Command>,
+) -> Result<ControlSocketGuard, ServerError> {
+    use crate::server::map_io;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::net::UnixListener;
+    use tracing::{info, warn};
+
+    let path = std::path::PathBuf::from(path);
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        use std::os::unix::fs::FileTypeExt;
+        if !metadata.file_type().is_socket() {
+            return Err(ServerError::new(format!(
+                "Control socket path {} exists and is not a socket",
+                path.display()
+            )));
+        }
+        std::fs::remove_file(&path).map_err(map_io)?;
+    }
+    let listener = UnixListener::bind(&path).map_err(map_io)?;
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).map_err(map_io)?;
+    info!("Control socket listening on {}", path.display());
+
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(serve_control_client(stream, command_tx.clone()));
+                }
+                Err(err) => {
+                    warn!("control socket accept failed: {}", err);
+                    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                }
+            }
+        }
+    });
+
+    Ok(ControlSocketGuard { path })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn spawn_control_listener(
+    _path: &str,
+    _command_tx: mpsc::UnboundedSender<Command>,
+) -> Result<ControlSocketGuard, ServerError> {
+    Err(ServerError::new(
+        "Control sockets are only supported on Unix platforms",
+    ))
+}
+
+#[cfg(unix)]
+async fn serve_control_client(
+    stream: tokio::net::UnixStream,
+    command_tx: mpsc::UnboundedSender<Command>,
+) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::sync::oneshot;
+
+    let (read_half, mut write_half) = stream.into_split();
+    let mut lines = BufReader::new(read_half).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => {
+                let (reply, reply_rx) = oneshot::channel();
+                if command_tx
+                    .send(Command::Control { request, reply })
+                    .is_err()
+                {
+                    return;
+                }
+                match reply_rx.await {
+                    Ok(response) => response,
+                    Err(_) => return,
+                }
+            }
+            Err(err) => ControlResponse::error(format!("invalid request: {}", err)),
+        };
+        let mut encoded = match serde_json::to_vec(&response) {
+            Ok(encoded) => encoded,
+            Err(_) => return,
+        };
+        encoded.push(b'\n');
+        if write_half.write_all(&encoded).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Sends one request to a running server and returns its response.
+#[cfg(unix)]
+pub(crate) fn send_control_request(
+    path: &str,
+    request: &ControlRequest,
+) -> Result<ControlResponse, String> {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    let mut stream =
+        UnixStream::connect(path).map_err(|err| format!("connect {}: {}", path, err))?;
+    let mut encoded = serde_json::to_vec(request).map_err(|err| err.to_string())?;
+    encoded.push(b'\n');
+    stream
+        .write_all(&encoded)
+        .map_err(|err| format!("write {}: {}", path, err))?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|err| format!("read {}: {}", path, err))?;
+    if line.is_empty() {
+        return Err(format!("{}: connection closed without a response", path));
+    }
+    serde_json::from_str(&line).map_err(|err| format!("invalid response: {}", err))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn send_control_request(
+    _path: &str,
+    _request: &ControlRequest,
+) -> Result<ControlResponse, String> {
+    Err("Control sockets are only supported on Unix platforms".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ControlRequest;
+
+    #[test]
+    fn parses_control_requests() {
+        let request: ControlRequest = serde_json::from_str(r#"{"cmd":"list_connections"}"#)
+            .expect("list_connections should parse");
+        assert_eq!(request, ControlRequest::ListConnections);
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"cmd":"reset_stream","connection":3,"stream":8}"#)
+                .expect("reset_stream should parse");
+        assert_eq!(
+            request,
+            ControlRequest::ResetStream {
+                connection: 3,
+                stream: 8
+            }
+        );
+
+        let request: ControlRequest =
+            serde_json::from_str(r#"{"cmd":"set_debug","debug_streams":true}"#)
+                .expect("set_debug should parse");
+        assert_eq!(
+            request,
+            ControlRequest::SetDebug {
+                debug_streams: Some(true),
+                debug_commands: None
+            }
+        );
+
+        assert!(serde_json::from_str::<ControlRequest>(r#"{"cmd":"reboot"}"#).is_err());
+    }
+}
@@ -0,0 +1,343 @@
+//! `--target-exec`: runs a command per stream, inetd-style, and bridges its stdin and stdout
+//! to the stream through the same reader and writer tasks as a TCP target.
+
+use crate::server::{Command, ServerError, StreamKey};
+use crate::target::{spawn_target_io, TargetSocket};
+use slipstream_core::stream_error::StreamError;
+use std::net::SocketAddr;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::process::{Child, Command as ProcessCommand};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, info, warn};
+
+/// Time a command gets to exit after SIGTERM before its process group is killed.
+#[cfg(unix)]
+const EXEC_TERMINATE_GRACE: std::time::Duration = std::time::Duration::from_secs(2);
+
+pub(crate) struct ExecTarget {
+    command: String,
+    slots: Arc<Semaphore>,
+}
+
+impl ExecTarget {
+    pub(crate) fn new(command: String, max_processes: usize) -> Result<Self, ServerError> {
+        if cfg!(not(unix)) {
+            return Err(ServerError::new(
+                "--target-exec is only supported on Unix platforms",
+            ));
+        }
+        if command.trim().is_empty() {
+            return Err(ServerError::new("--target-exec needs a command"));
+        }
+        Ok(Self {
+            command,
+            slots: Arc::new(Semaphore::new(max_processes)),
+        })
+    }
+
+    /// Starts the command for `key`, or resets the stream with `Quota` when every slot is
+    /// taken. The process is stopped when the stream shuts down.
+    pub(crate) fn spawn(
+        &self,
+        key: StreamKey,
+        identity: (u64, Option<SocketAddr>),
+        command_tx: mpsc::UnboundedSender<Command>,
+        debug_streams: bool,
+        shutdown_rx: watch::Receiver<bool>,
+    ) {
+        let connect_error = |error| Command::StreamConnectError {
+            cnx_id: key.cnx,
+            stream_id: key.stream_id,
+            error,
+        };
+        let Ok(permit) = self.slots.clone().try_acquire_owned() else {
+            warn!(
+                "stream {:?}: --target-exec process limit reached",
+                key.stream_id
+            );
+            let _ = command_tx.send(connect_error(StreamError::Quota));
+            return;
+        };
+        let (connection_id, resolver) = identity;
+        let mut command = ProcessCommand::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .env("SLIPSTREAM_CONNECTION_ID", connection_id.to_string())
+            .env("SLIPSTREAM_STREAM_ID", key.stream_id.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true);
+        // Its own process group, so cleanup reaches whatever the shell started.
+        #[cfg(unix)]
+        command.process_group(0);
+        if let Some(resolver) = resolver {
+            command.env("SLIPSTREAM_RESOLVER", resolver.to_string());
+        }
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(err) => {
+                warn!(
+                    "stream {:?}: --target-exec failed to start err={}",
+                    key.stream_id, err
+                );
+                let _ = command_tx.send(connect_error(StreamError::from_io(&err)));
+                return;
+            }
+        };
+        let (Some(stdin), Some(stdout), Some(stderr)) =
+            (child.stdin.take(), child.stdout.take(), child.stderr.take())
+        else {
+            let _ = command_tx.send(connect_error(StreamError::Unreachable));
+            return;
+        };
+        if debug_streams {
+            debug!(
+                "stream {:?}: started --target-exec pid={:?}",
+                key.stream_id,
+                child.id()
+            );
+        }
+        tokio::spawn(log_stderr(key, stderr));
+        tokio::spawn(supervise(key, child, permit, shutdown_rx.clone()));
+        let (write_tx, data_rx, send_pending) = spawn_target_io(
+            key,
+            TargetSocket::process(stdout, stdin),
+            command_tx.clone(),
+            debug_streams,
+            shutdown_rx,
+        );
+        let _ = command_tx.send(Command::StreamConnected {
+            cnx_id: key.cnx,
+            stream_id: key.stream_id,
+            write_tx,
+            data_rx,
+            send_pending,
+        });
+    }
+}
+
+async fn log_stderr(key: StreamKey, stderr: tokio::process::ChildStderr) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("stream {:?}: target-exec: {}", key.stream_id, line);
+    }
+}
+
+/// Waits for the process, holding its slot, and stops it if the stream goes away first.
+async fn supervise(
+    key: StreamKey,
+    mut child: Child,
+    _permit: OwnedSemaphorePermit,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status,
+            changed = shutdown_rx.changed() => {
+                if changed.is_err() || *shutdown_rx.borrow() {
+                    break terminate(&mut child).await;
+                }
+            }
+        }
+    };
+    match status {
+        Ok(status) if status.success() => {
+            debug!("stream {:?}: target-exec exited", key.stream_id);
+        }
+        Ok(status) => {
+            info!(
+                "stream {:?}: target-exec exited status={}",
+                key.stream_id, status
+            );
+        }
+        Err(err) => warn!(
+            "stream {:?}: target-exec wait failed err={}",
+            key.stream_id, err
+        ),
+    }
+}
+
+/// SIGTERM to the process group, then SIGKILL if it is still running after the grace period.
+#[cfg(unix)]
+async fn terminate(child: &mut Child) -> std::io::Result<std::process::ExitStatus> {
+    let Some(pid) = child.id() else {
+        return child.wait().await;
+    };
+    let group = -(pid as libc::pid_t);
+    unsafe {
+        libc::kill(group, libc::SIGTERM);
+    }
+    if let Ok(status) = tokio::time::timeout(EXEC_TERMINATE_GRACE, child.wait()).await {
+        return status;
+    }
+    unsafe {
+        libc::kill(group, libc::SIGKILL);
+    }
+    child.wait().await
+}
+
+#[cfg(not(unix))]
+async fn terminate(child: &mut Child) -> std::io::Result<std::process::ExitStatus> {
+    child.kill().await?;
+    child.wait().await
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::ExecTarget;
+    use crate::server::{Command, StreamKey, StreamWrite};
+    use slipstream_core::stream_error::StreamError;
+    use std::time::Duration;
+    use tokio::sync::{mpsc, watch};
+    use tokio::time::timeout;
+
+    const KEY: StreamKey = StreamKey {
+        cnx: 1,
+        stream_id: 4,
+    };
+
+    /// Waits for the `StreamConnected` or `StreamConnectError` that answers a spawn.
+    async fn next_connect(command_rx: &mut mpsc::UnboundedReceiver<Command>) -> Command {
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let command = command_rx.recv().await.expect("command channel open");
+                if matches!(
+                    command,
+                    Command::StreamConnected { .. } | Command::StreamConnectError { .. }
+                ) {
+                    break command;
+                }
+            }
+        })
+        .await
+        .expect("spawn answered")
+    }
+
+    /// Reads from the process's stdout until `len` bytes arrived.
+    async fn read_output(data_rx: &mut mpsc::Receiver<Vec<u8>>, len: usize) -> Vec<u8> {
+        let mut output = Vec::new();
+        while output.len() < len {
+            let chunk = timeout(Duration::from_secs(5), data_rx.recv())
+                .await
+                .expect("process output")
+                .expect("output channel open");
+            output.extend_from_slice(&chunk);
+        }
+        output
+    }
+
+    #[tokio::test]
+    async fn bytes_round_trip_through_the_process() {
+        let exec = ExecTarget::new("cat".to_string(), 4).expect("exec target");
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        exec.spawn(KEY, (7, None), command_tx, false, shutdown_rx);
+        let Command::StreamConnected {
+            write_tx,
+            mut data_rx,
+            ..
+        } = next_connect(&mut command_rx).await
+        else {
+            panic!("cat did not start");
+        };
+        let message = b"hello through cat".to_vec();
+        write_tx
+            .send(StreamWrite::Data(message.clone()))
+            .expect("write");
+        assert_eq!(read_output(&mut data_rx, message.len()).await, message);
+    }
+
+    #[tokio::test]
+    async fn spawns_past_the_limit_are_refused_with_quota() {
+        let exec = ExecTarget::new("cat".to_string(), 1).expect("exec target");
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        exec.spawn(
+            KEY,
+            (7, None),
+            command_tx.clone(),
+            false,
+            shutdown_rx.clone(),
+        );
+        assert!(matches!(
+            next_connect(&mut command_rx).await,
+            Command::StreamConnected { .. }
+        ));
+        let second = StreamKey {
+            cnx: 1,
+            stream_id: 8,
+        };
+        exec.spawn(second, (7, None), command_tx, false, shutdown_rx);
+        assert!(matches!(
+            next_connect(&mut command_rx).await,
+            Command::StreamConnectError {
+                stream_id: 8,
+                error: StreamError::Quota,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_the_process_group_and_frees_the_slot() {
+        let exec = ExecTarget::new("sleep 30 & echo $!; wait".to_string(), 1).expect("exec");
+        let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        exec.spawn(KEY, (7, None), command_tx.clone(), false, shutdown_rx);
+        let Command::StreamConnected { mut data_rx, .. } = next_connect(&mut command_rx).await
+        else {
+            panic!("command did not start");
+        };
+        let mut output = Vec::new();
+        while !output.ends_with(b"\n") {
+            output.extend(read_output(&mut data_rx, 1).await);
+        }
+        let sleep_pid: u32 = String::from_utf8(output)
+            .expect("pid text")
+            .trim()
+            .parse()
+            .expect("pid");
+
+        shutdown_tx.send(true).expect("shutdown");
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        timeout(Duration::from_secs(5), async {
+            loop {
+                exec.spawn(
+                    KEY,
+                    (7, None),
+                    command_tx.clone(),
+                    false,
+                    shutdown_rx.clone(),
+                );
+                if let Command::StreamConnected { .. } = next_connect(&mut command_rx).await {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("slot freed");
+
+        // The shell's background job was in its process group, so it got the signal too;
+        // once gone it is either reaped or a zombie left for init.
+        timeout(Duration::from_secs(5), async {
+            loop {
+                let stat = std::process::Command::new("ps")
+                    .args(["-o", "stat=", "-p", &sleep_pid.to_string()])
+                    .output()
+                    .expect("run ps");
+                let stat = String::from_utf8_lossy(&stat.stdout);
+                if stat.trim().is_empty() || stat.trim_start().starts_with('Z') {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("background job stopped");
+    }
+}
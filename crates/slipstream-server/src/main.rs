@@ -1,52 +1,179 @@
+mod bench;
+mod control;
+mod exec;
+mod reverse;
 mod server;
 mod streams;
 mod target;
+mod tun;
+mod udp;
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand};
+use control::{send_control_request, ControlRequest};
 use server::{run_server, ServerConfig};
-use slipstream_core::{normalize_domain, parse_host_port, AddressKind, HostPort};
+use slipstream_core::normalize_domain;
+use slipstream_core::proxy_protocol::ProxyVersion;
+use target::{AllowedTarget, TargetAddress, TargetPolicy};
 use tokio::runtime::Builder;
 use tracing_subscriber::EnvFilter;
 
 #[derive(Parser, Debug)]
 #[command(
     name = "slipstream-server",
-    about = "slipstream-server - A high-performance covert channel over DNS (server)"
+    about = "slipstream-server - A high-performance covert channel over DNS (server)",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
 )]
-struct Args {
+struct Cli {
+    #[command(subcommand)]
+    command: Option<ServerCommand>,
+    /// Without a subcommand, the server runs with these options.
+    #[command(flatten)]
+    server: Option<ServerArgs>,
+}
+
+#[derive(Subcommand, Debug)]
+enum ServerCommand {
+    /// Inspect and manage a running slipstream-server through its control socket.
+    Ctl(CtlArgs),
+}
+
+#[derive(Args, Debug)]
+struct ServerArgs {
     #[arg(long = "dns-listen-port", short = 'l', default_value_t = 53)]
     dns_listen_port: u16,
+    /// Target for streams without a header, host:port or unix:/path; repeat for failover.
     #[arg(
         long = "target-address",
         short = 'a',
         default_value = "127.0.0.1:5201",
-        value_parser = parse_target_address
+        value_parser = TargetAddress::parse
     )]
-    target_address: HostPort,
+    target_addresses: Vec<TargetAddress>,
+    /// Order streams try the targets in.
+    #[arg(long = "target-policy", value_enum, default_value = "ordered")]
+    target_policy: TargetPolicy,
+    /// Seconds one target may take to connect before the next is tried.
+    #[arg(
+        long = "target-connect-timeout",
+        value_name = "SECS",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    target_connect_timeout: u64,
+    /// Seconds between re-resolving target host names; 0 disables.
+    #[arg(
+        long = "target-resolve-interval",
+        value_name = "SECS",
+        default_value_t = 60
+    )]
+    target_resolve_interval: u64,
+    #[arg(long = "allow-target", value_name = "HOST:PORT", value_parser = AllowedTarget::parse)]
+    allowed_targets: Vec<AllowedTarget>,
+    #[arg(long = "allow-listen", value_name = "HOST:PORT", value_parser = AllowedTarget::parse)]
+    allowed_listeners: Vec<AllowedTarget>,
     #[arg(long = "cert", short = 'c', value_name = "PATH")]
     cert: String,
     #[arg(long = "key", short = 'k', value_name = "PATH")]
     key: String,
     #[arg(long = "domain", short = 'd', value_parser = parse_domain, required = true)]
     domains: Vec<String>,
+    #[arg(
+        long = "max-downstream-mtu",
+        value_name = "BYTES",
+        default_value_t = 1200,
+        value_parser = clap::value_parser!(u32).range(256..=1452)
+    )]
+    max_downstream_mtu: u32,
     #[arg(long = "debug-streams")]
     debug_streams: bool,
     #[arg(long = "debug-commands")]
     debug_commands: bool,
+    #[arg(long = "control-socket", value_name = "PATH")]
+    control_socket: Option<String>,
+    #[arg(long = "bench")]
+    bench: bool,
+    #[arg(long = "tun", value_name = "NAME")]
+    tun: Option<String>,
+    /// Send a PROXY protocol header (v1 or v2) to targets ahead of each stream's data.
+    #[arg(long = "proxy-protocol", value_name = "VERSION", value_parser = ProxyVersion::parse)]
+    proxy_protocol: Option<ProxyVersion>,
+    /// Run this shell command per stream and bridge its stdin/stdout, instead of connecting
+    /// to a target address.
+    #[arg(
+        long = "target-exec",
+        value_name = "COMMAND",
+        conflicts_with = "target_addresses"
+    )]
+    target_exec: Option<String>,
+    /// Most --target-exec processes running at once.
+    #[arg(
+        long = "target-exec-max",
+        value_name = "COUNT",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    target_exec_max: u64,
+}
+
+#[derive(Args, Debug)]
+struct CtlArgs {
+    #[arg(long = "socket", short = 's', value_name = "PATH")]
+    socket: String,
+    #[command(subcommand)]
+    command: CtlCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// List live QUIC connections with their resolvers, paths and counters.
+    Connections,
+    /// List the streams of one connection.
+    Streams { connection: u64 },
+    /// Close a connection and all of its streams.
+    Close { connection: u64 },
+    /// Reset one stream and drop its target connection.
+    Reset { connection: u64, stream: u64 },
+    /// Toggle debug logging at runtime.
+    Debug {
+        #[arg(long = "streams", value_parser = clap::builder::BoolishValueParser::new())]
+        streams: Option<bool>,
+        #[arg(long = "commands", value_parser = clap::builder::BoolishValueParser::new())]
+        commands: Option<bool>,
+    },
 }
 
 fn main() {
+    let cli = Cli::parse();
+    let args = match (cli.command, cli.server) {
+        (Some(ServerCommand::Ctl(args)), _) => std::process::exit(run_ctl(args)),
+        (None, Some(args)) => args,
+        // Clap requires the server options whenever no subcommand is given.
+        (None, None) => unreachable!("server options missing"),
+    };
+
     init_logging();
-    let args = Args::parse();
 
     let config = ServerConfig {
         dns_listen_port: args.dns_listen_port,
-        target_address: args.target_address,
+        target_addresses: args.target_addresses,
+        target_policy: args.target_policy,
+        target_connect_timeout: args.target_connect_timeout,
+        target_resolve_interval: args.target_resolve_interval,
+        allowed_targets: args.allowed_targets,
+        allowed_listeners: args.allowed_listeners,
         cert: args.cert,
         key: args.key,
         domains: args.domains,
+        max_downstream_mtu: args.max_downstream_mtu,
         debug_streams: args.debug_streams,
         debug_commands: args.debug_commands,
+        control_socket: args.control_socket,
+        bench: args.bench,
+        tun: args.tun,
+        proxy_protocol: args.proxy_protocol,
+        target_exec: args.target_exec,
+        target_exec_max: args.target_exec_max as usize,
     };
 
     let runtime = Builder::new_current_thread()
@@ -63,6 +190,43 @@ fn main() {
     }
 }
 
+fn run_ctl(args: CtlArgs) -> i32 {
+    let request = match args.command {
+        CtlCommand::Connections => ControlRequest::ListConnections,
+        CtlCommand::Streams { connection } => ControlRequest::ListStreams { connection },
+        CtlCommand::Close { connection } => ControlRequest::CloseConnection { connection },
+        CtlCommand::Reset { connection, stream } => {
+            ControlRequest::ResetStream { connection, stream }
+        }
+        CtlCommand::Debug { streams, commands } => ControlRequest::SetDebug {
+            debug_streams: streams,
+            debug_commands: commands,
+        },
+    };
+    match send_control_request(&args.socket, &request) {
+        Ok(response) => {
+            if let Some(error) = response.error.as_deref() {
+                eprintln!("error: {}", error);
+            }
+            if let Some(result) = response.result.as_ref().filter(|result| !result.is_null()) {
+                match serde_json::to_string_pretty(result) {
+                    Ok(text) => println!("{}", text),
+                    Err(err) => eprintln!("error: {}", err),
+                }
+            }
+            if response.ok {
+                0
+            } else {
+                1
+            }
+        }
+        Err(err) => {
+            eprintln!("error: {}", err);
+            1
+        }
+    }
+}
+
 fn init_logging() {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     let _ = tracing_subscriber::fmt()
@@ -76,6 +240,47 @@ fn parse_domain(input: &str) -> Result<String, String> {
     normalize_domain(input).map_err(|err| err.to_string())
 }
 
-fn parse_target_address(input: &str) -> Result<HostPort, String> {
-    parse_host_port(input, 5201, AddressKind::Target).map_err(|err| err.to_string())
+#[cfg(test)]
+mod tests {
+    use super::{Cli, CtlCommand, ServerCommand};
+    use clap::Parser;
+
+    #[test]
+    fn ctl_is_a_subcommand_without_server_options() {
+        let cli =
+            Cli::try_parse_from(["slipstream-server", "ctl", "-s", "/run/ctl", "streams", "3"])
+                .expect("ctl parses");
+        match cli.command {
+            Some(ServerCommand::Ctl(args)) => {
+                assert_eq!(args.socket, "/run/ctl");
+                assert!(matches!(
+                    args.command,
+                    CtlCommand::Streams { connection: 3 }
+                ));
+            }
+            None => panic!("expected the ctl subcommand"),
+        }
+        assert!(cli.server.is_none());
+        assert!(
+            Cli::try_parse_from(["slipstream-server", "--bench", "ctl", "connections"]).is_err()
+        );
+    }
+
+    #[test]
+    fn server_options_are_the_default_command() {
+        let cli = Cli::try_parse_from([
+            "slipstream-server",
+            "--cert",
+            "cert.pem",
+            "--key",
+            "key.pem",
+            "--domain",
+            "t.example.com",
+        ])
+        .expect("server options parse");
+        assert!(cli.command.is_none());
+        let server = cli.server.expect("server options");
+        assert_eq!(server.dns_listen_port, 53);
+        assert!(Cli::try_parse_from(["slipstream-server", "--cert", "cert.pem"]).is_err());
+    }
 }
@@ -0,0 +1,73 @@
+//! Server-side listeners for reverse tunnels (`slipstream-client -R`). Each lives as long as
+//! the client's control stream that asked for it; accepted connections are handed to the
+//! server loop, which opens a server-initiated stream for each.
+
+use crate::server::{Command, StreamKey};
+use slipstream_core::stream_error::StreamError;
+use tokio::net::TcpListener as TokioTcpListener;
+use tokio::sync::{mpsc, watch};
+use tracing::{info, warn};
+
+pub(crate) fn spawn_reverse_listener(
+    key: StreamKey,
+    host: String,
+    port: u16,
+    command_tx: mpsc::UnboundedSender<Command>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
+    tokio::spawn(async move {
+        let listener = match TokioTcpListener::bind((host.as_str(), port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!(
+                    "stream {:?}: reverse listener on {}:{} failed err={}",
+                    key.stream_id, host, port, err
+                );
+                let _ = command_tx.send(Command::StreamConnectError {
+                    cnx_id: key.cnx,
+                    stream_id: key.stream_id,
+                    error: StreamError::from_io(&err),
+                });
+                return;
+            }
+        };
+        info!(
+            "stream {:?}: listening on {}:{} for a reverse tunnel",
+            key.stream_id, host, port
+        );
+        loop {
+            tokio::select! {
+                changed = shutdown_rx.changed() => {
+                    if changed.is_err() || *shutdown_rx.borrow() {
+                        break;
+                    }
+                }
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => {
+                        let accepted = Command::ReverseAccepted {
+                            cnx_id: key.cnx,
+                            control_stream_id: key.stream_id,
+                            listener: (host.clone(), port),
+                            stream,
+                        };
+                        if command_tx.send(accepted).is_err() {
+                            break;
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        warn!(
+                            "stream {:?}: reverse listener on {}:{} stopped err={}",
+                            key.stream_id, host, port, err
+                        );
+                        break;
+                    }
+                },
+            }
+        }
+        info!(
+            "stream {:?}: closed reverse listener on {}:{}",
+            key.stream_id, host, port
+        );
+    });
+}
@@ -1,36 +1,51 @@
-use slipstream_core::{resolve_host_port, HostPort};
+use slipstream_core::proxy_protocol::ProxyVersion;
+use slipstream_core::stream_error::StreamError;
 use slipstream_dns::{
-    decode_query_with_domains, encode_response, DecodeQueryError, Question, Rcode, ResponseParams,
+    decode_query_with_domains, encode_coalesced_response, max_next_answer_len,
+    max_response_payload_len, probe_answer, DecodeQueryError, DecodedQuery, QueryControl,
+    QueryDiagnostic, Question, Rcode, ResponseParams, DNS_UDP_PAYLOAD_MIN,
 };
 use slipstream_ffi::picoquic::{
     picoquic_cnx_t, picoquic_create, picoquic_current_time, picoquic_incoming_packet_ex,
     picoquic_prepare_packet_ex, picoquic_quic_t, slipstream_disable_ack_delay,
-    slipstream_server_cc_algorithm, sockaddr, PICOQUIC_MAX_PACKET_SIZE,
-    PICOQUIC_PACKET_LOOP_RECV_MAX,
+    slipstream_server_cc_algorithm, slipstream_set_path_send_mtu, sockaddr,
+    PICOQUIC_MAX_PACKET_SIZE, PICOQUIC_PACKET_LOOP_RECV_MAX,
 };
 use slipstream_ffi::runtime::sockaddr_storage;
-use slipstream_ffi::{configure_quic_with_custom, socket_addr_to_storage, QuicGuard};
+use slipstream_ffi::{
+    allow_path_send_mtu_up_to, configure_quic_with_custom, socket_addr_to_storage, QuicGuard,
+};
 use std::ffi::CString;
 use std::fmt;
 use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::net::UdpSocket as TokioUdpSocket;
-use tokio::sync::mpsc;
+use tokio::net::{TcpStream as TokioTcpStream, UdpSocket as TokioUdpSocket};
+use tokio::sync::{mpsc, oneshot};
 use tokio::time::sleep;
 
+use crate::control::{spawn_control_listener, ControlRequest, ControlResponse};
+use crate::exec::ExecTarget;
 use crate::streams::{
     drain_commands, handle_command, handle_shutdown, maybe_report_command_stats, server_callback,
     ServerState,
 };
+use crate::target::{
+    spawn_target_resolver, AllowedTarget, TargetAddress, TargetPolicy, TargetPool,
+};
+use crate::tun::TunHub;
 
 // Protocol defaults; see docs/config.md for details.
 const SLIPSTREAM_ALPN: &str = "picoquic_sample";
 const DNS_MAX_QUERY_SIZE: usize = 512;
 const IDLE_SLEEP_MS: u64 = 10;
-// Default QUIC MTU for server packets; see docs/config.md for details.
+// Initial QUIC MTU for server packets; each answer is then sized to the query's EDNS limit.
 const QUIC_MTU: u32 = 900;
+// Answers to clients that ask for it carry up to this many QUIC packets, each at least this
+// large after the first.
+const MAX_COALESCED_PACKETS: usize = 4;
+const MIN_COALESCED_MTU: u32 = 128;
 pub(crate) const STREAM_READ_CHUNK_BYTES: usize = 4096;
 pub(crate) const DEFAULT_TCP_RCVBUF_BYTES: usize = 256 * 1024;
 pub(crate) const TARGET_WRITE_COALESCE_DEFAULT_BYTES: usize = 256 * 1024;
@@ -48,7 +63,7 @@ pub struct ServerError {
 }
 
 impl ServerError {
-    fn new(message: impl Into<String>) -> Self {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
         Self {
             message: message.into(),
         }
@@ -65,12 +80,35 @@ impl std::error::Error for ServerError {}
 
 pub struct ServerConfig {
     pub dns_listen_port: u16,
-    pub target_address: HostPort,
+    /// Targets for streams without a header, tried in `target_policy` order.
+    pub target_addresses: Vec<TargetAddress>,
+    pub target_policy: TargetPolicy,
+    /// Seconds one target may take to connect before the next is tried.
+    pub target_connect_timeout: u64,
+    /// Seconds between re-resolving target host names; 0 disables.
+    pub target_resolve_interval: u64,
+    /// Destinations clients may name per stream; others are reset.
+    pub allowed_targets: Vec<AllowedTarget>,
+    /// Addresses clients may ask the server to listen on for reverse tunnels.
+    pub allowed_listeners: Vec<AllowedTarget>,
     pub cert: String,
     pub key: String,
     pub domains: Vec<String>,
+    /// Ceiling for QUIC packets sent in answers, whatever size resolvers advertise.
+    pub max_downstream_mtu: u32,
     pub debug_streams: bool,
     pub debug_commands: bool,
+    pub control_socket: Option<String>,
+    /// Serve the built-in sink, source and echo targets to streams that ask for them.
+    pub bench: bool,
+    /// TUN device that carries the IP packets of `--tun` clients (Linux only).
+    pub tun: Option<String>,
+    /// Write a PROXY header of this version ahead of each target connection's data.
+    pub proxy_protocol: Option<ProxyVersion>,
+    /// Shell command run per stream instead of connecting to `target_addresses`.
+    pub target_exec: Option<String>,
+    /// Most `target_exec` processes running at once; streams past it are reset.
+    pub target_exec_max: usize,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -96,6 +134,7 @@ pub(crate) enum Command {
     StreamConnectError {
         cnx_id: usize,
         stream_id: u64,
+        error: StreamError,
     },
     StreamClosed {
         cnx_id: usize,
@@ -118,6 +157,23 @@ pub(crate) enum Command {
         stream_id: u64,
         bytes: usize,
     },
+    Control {
+        request: ControlRequest,
+        reply: oneshot::Sender<ControlResponse>,
+    },
+    /// A UDP payload for the client, on the flow opened by `stream_id`.
+    Datagram {
+        cnx_id: usize,
+        stream_id: u64,
+        payload: Vec<u8>,
+    },
+    /// A reverse-tunnel listener, opened by `control_stream_id`, accepted a connection.
+    ReverseAccepted {
+        cnx_id: usize,
+        control_stream_id: u64,
+        listener: (String, u16),
+        stream: TokioTcpStream,
+    },
 }
 
 struct Slot {
@@ -127,13 +183,35 @@ struct Slot {
     cd: bool,
     question: Question,
     rcode: Option<Rcode>,
+    /// DNS message size the answer to this query has to fit in.
+    answer_size: u16,
+    /// QUIC packets the answer may carry; more than one only if the query asked for it.
+    max_packets: usize,
+    /// Answer to a path probe, sent instead of QUIC data.
+    echo: Option<Vec<u8>>,
     cnx: *mut picoquic_cnx_t,
     path_id: libc::c_int,
 }
 
 pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
-    let target_addr = resolve_host_port(&config.target_address)
-        .map_err(|err| ServerError::new(err.to_string()))?;
+    let targets = Arc::new(TargetPool::new(
+        &config.target_addresses,
+        config.target_policy,
+        Duration::from_secs(config.target_connect_timeout),
+    ));
+    let exec = match config.target_exec.clone() {
+        Some(command) => Some(ExecTarget::new(command, config.target_exec_max)?),
+        None => {
+            if targets.resolve().await == 0 {
+                return Err(ServerError::new("No target address could be resolved"));
+            }
+            spawn_target_resolver(
+                targets.clone(),
+                Duration::from_secs(config.target_resolve_interval),
+            );
+            None
+        }
+    };
 
     let alpn = CString::new(SLIPSTREAM_ALPN)
         .map_err(|_| ServerError::new("ALPN contains an unexpected null byte"))?;
@@ -142,14 +220,27 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
     let key = CString::new(config.key.clone())
         .map_err(|_| ServerError::new("Key path contains an unexpected null byte"))?;
     let (command_tx, mut command_rx) = mpsc::unbounded_channel();
+    let control_tx = command_tx.clone();
     let debug_streams = config.debug_streams;
     let debug_commands = config.debug_commands;
     let mut state = Box::new(ServerState::new(
-        target_addr,
+        targets,
+        config.allowed_targets.clone(),
+        config.allowed_listeners.clone(),
         command_tx,
         debug_streams,
         debug_commands,
+        config.bench,
     ));
+    if let Some(version) = config.proxy_protocol {
+        state.set_proxy_protocol(version);
+    }
+    if let Some(exec) = exec {
+        state.set_exec(exec);
+    }
+    if let Some(name) = config.tun.as_deref() {
+        state.set_tun(TunHub::open(name)?);
+    }
     let state_ptr: *mut ServerState = &mut *state;
     let _state = state;
 
@@ -177,13 +268,21 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
         return Err(ServerError::new("Could not create QUIC context"));
     }
     let _quic_guard = QuicGuard::new(quic);
+    unsafe {
+        (*state_ptr).set_quic(quic);
+    }
     unsafe {
         if slipstream_server_cc_algorithm.is_null() {
             return Err(ServerError::new(
                 "Slipstream server congestion algorithm is unavailable",
             ));
         }
-        configure_quic_with_custom(quic, slipstream_server_cc_algorithm, QUIC_MTU);
+        configure_quic_with_custom(
+            quic,
+            slipstream_server_cc_algorithm,
+            QUIC_MTU.min(config.max_downstream_mtu),
+        );
+        allow_path_send_mtu_up_to(quic, config.max_downstream_mtu);
     }
 
     let udp = bind_udp_socket(config.dns_listen_port).await?;
@@ -194,13 +293,18 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
         return Err(ServerError::new("At least one domain must be configured"));
     }
 
+    let _control_guard = match config.control_socket.as_deref() {
+        Some(path) => Some(spawn_control_listener(path, control_tx)?),
+        None => None,
+    };
+
     #[cfg(not(windows))]
     unsafe {
-        libc::signal(libc::SIGTERM, handle_sigterm as usize);
+        libc::signal(libc::SIGTERM, handle_sigterm as *const () as usize);
     }
 
     let mut recv_buf = vec![0u8; DNS_MAX_QUERY_SIZE];
-    let mut send_buf = vec![0u8; PICOQUIC_MAX_PACKET_SIZE];
+    let mut send_buf = vec![0u8; PICOQUIC_MAX_PACKET_SIZE * MAX_COALESCED_PACKETS];
 
     loop {
         drain_commands(state_ptr, &mut command_rx);
@@ -230,6 +334,7 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
                     quic,
                     loop_time,
                     &local_addr_storage,
+                    config.max_downstream_mtu,
                 )? {
                     slots.push(slot);
                 }
@@ -243,6 +348,7 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
                                 quic,
                                 loop_time,
                                 &local_addr_storage,
+                                config.max_downstream_mtu,
                             )? {
                                 slots.push(slot);
                             }
@@ -263,51 +369,49 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
             continue;
         }
 
+        {
+            let state = unsafe { &mut *state_ptr };
+            for slot in slots.iter() {
+                state.note_query(slot.cnx, slot.peer);
+            }
+        }
+
         let loop_time = unsafe { picoquic_current_time() };
 
         for slot in slots.iter_mut() {
-            let mut send_length = 0usize;
             let mut addr_to: sockaddr_storage = unsafe { std::mem::zeroed() };
             let mut addr_from: sockaddr_storage = unsafe { std::mem::zeroed() };
             let mut if_index: libc::c_int = 0;
 
-            if slot.rcode.is_none() && !slot.cnx.is_null() {
-                let ret = unsafe {
-                    picoquic_prepare_packet_ex(
-                        slot.cnx,
-                        slot.path_id,
-                        loop_time,
-                        send_buf.as_mut_ptr(),
-                        send_buf.len(),
-                        &mut send_length,
-                        &mut addr_to,
-                        &mut addr_from,
-                        &mut if_index,
-                        std::ptr::null_mut(),
-                    )
-                };
-                if ret < 0 {
-                    return Err(ServerError::new("Failed to prepare QUIC packet"));
-                }
-            }
-
-            let (payload, rcode) = if send_length > 0 {
-                (Some(&send_buf[..send_length]), slot.rcode)
-            } else if slot.rcode.is_none() {
-                // No QUIC payload ready; still answer the poll with NOERROR and empty payload to clear it.
-                (None, Some(slipstream_dns::Rcode::Ok))
-            } else {
-                (None, slot.rcode)
-            };
-            let response = encode_response(&ResponseParams {
-                id: slot.id,
-                rd: slot.rd,
-                cd: slot.cd,
-                question: &slot.question,
-                payload,
-                rcode,
-            })
-            .map_err(|err| ServerError::new(err.to_string()))?;
+            let (cnx, path_id) = (slot.cnx, slot.path_id);
+            let packet_lens = prepare_answer_packets(
+                slot,
+                config.max_downstream_mtu,
+                &mut send_buf,
+                |mtu, buf| {
+                    let mut send_length = 0usize;
+                    let ret = unsafe {
+                        slipstream_set_path_send_mtu(cnx, path_id, mtu);
+                        picoquic_prepare_packet_ex(
+                            cnx,
+                            path_id,
+                            loop_time,
+                            buf.as_mut_ptr(),
+                            buf.len(),
+                            &mut send_length,
+                            &mut addr_to,
+                            &mut addr_from,
+                            &mut if_index,
+                            std::ptr::null_mut(),
+                        )
+                    };
+                    if ret < 0 {
+                        return Err(ServerError::new("Failed to prepare QUIC packet"));
+                    }
+                    Ok(send_length)
+                },
+            )?;
+            let response = encode_answer(slot, &send_buf, &packet_lens)?;
             let peer = normalize_dual_stack_addr(slot.peer);
             udp.send_to(&response, peer).await.map_err(map_io)?;
         }
@@ -316,6 +420,96 @@ pub async fn run_server(config: &ServerConfig) -> Result<i32, ServerError> {
     Ok(0)
 }
 
+/// Prepares the QUIC packets answering `slot` back to back in `send_buf`, and returns their
+/// lengths. `prepare` fills a buffer with one packet of at most the given MTU and returns its
+/// length, or 0 when there is nothing to send.
+fn prepare_answer_packets(
+    slot: &Slot,
+    max_downstream_mtu: u32,
+    send_buf: &mut [u8],
+    mut prepare: impl FnMut(u32, &mut [u8]) -> Result<usize, ServerError>,
+) -> Result<Vec<usize>, ServerError> {
+    let mut packet_lens: Vec<usize> = Vec::new();
+    if slot.rcode.is_some() || slot.cnx.is_null() {
+        return Ok(packet_lens);
+    }
+    // Keep preparing packets while the answer has room for a useful one, so a client that
+    // accepts coalesced answers gets several per round trip.
+    let mut offset = 0usize;
+    while packet_lens.len() < slot.max_packets {
+        let mtu = (max_next_answer_len(&slot.question, slot.answer_size, &packet_lens) as u32)
+            .min(max_downstream_mtu);
+        if !packet_lens.is_empty() && mtu < MIN_COALESCED_MTU {
+            break;
+        }
+        let send_length = prepare(
+            mtu,
+            &mut send_buf[offset..offset + PICOQUIC_MAX_PACKET_SIZE],
+        )?;
+        if send_length == 0 {
+            break;
+        }
+        packet_lens.push(send_length);
+        offset += send_length;
+    }
+    Ok(packet_lens)
+}
+
+/// The DNS response to `slot`, carrying the packets `prepare_answer_packets` left in
+/// `send_buf`, or the slot's probe answer.
+fn encode_answer(
+    slot: &Slot,
+    send_buf: &[u8],
+    packet_lens: &[usize],
+) -> Result<Vec<u8>, ServerError> {
+    let mut packets: Vec<&[u8]> = Vec::with_capacity(packet_lens.len());
+    let mut offset = 0usize;
+    for len in packet_lens {
+        packets.push(&send_buf[offset..offset + len]);
+        offset += len;
+    }
+    let rcode = if let Some(echo) = slot.echo.as_deref() {
+        packets = vec![echo];
+        slot.rcode
+    } else if !packets.is_empty() {
+        slot.rcode
+    } else if slot.rcode.is_none() {
+        // No QUIC payload ready; still answer the poll with NOERROR and empty payload to clear it.
+        Some(slipstream_dns::Rcode::Ok)
+    } else {
+        slot.rcode
+    };
+    encode_coalesced_response(
+        &ResponseParams {
+            id: slot.id,
+            rd: slot.rd,
+            cd: slot.cd,
+            question: &slot.question,
+            payload: None,
+            rcode,
+        },
+        &packets,
+    )
+    .map_err(|err| ServerError::new(err.to_string()))
+}
+
+/// The DNS message size the answer to `query` has to fit in, and how many QUIC packets it
+/// may carry.
+fn answer_budget(query: &DecodedQuery) -> (u16, usize) {
+    // Resolvers advertise how large an answer they accept; QUIC packets sent on the path are
+    // sized per query to fit it, and within what the client found the path to deliver.
+    let udp_payload = query
+        .edns_udp_payload
+        .unwrap_or(DNS_UDP_PAYLOAD_MIN)
+        .max(DNS_UDP_PAYLOAD_MIN);
+    match query.control {
+        QueryControl::AnswerLimit(limit) => (udp_payload.min(limit), 1),
+        QueryControl::Coalesce(limit) => (udp_payload.min(limit), MAX_COALESCED_PACKETS),
+        // Clients that predate coalescing only read answers with a single record.
+        _ => (udp_payload, 1),
+    }
+}
+
 fn decode_slot(
     packet: &[u8],
     peer: SocketAddr,
@@ -323,9 +517,44 @@ fn decode_slot(
     quic: *mut picoquic_quic_t,
     current_time: u64,
     local_addr_storage: &sockaddr_storage,
+    max_downstream_mtu: u32,
 ) -> Result<Option<Slot>, ServerError> {
     match decode_query_with_domains(packet, domains) {
         Ok(query) => {
+            let echo = match query.control {
+                QueryControl::Probe(answer_size) => {
+                    let answer_len = max_response_payload_len(&query.question, answer_size)
+                        .min(max_downstream_mtu as usize);
+                    Some(probe_answer(&query.payload, answer_len))
+                }
+                QueryControl::Diagnostic => Some(
+                    QueryDiagnostic {
+                        qname: query.question.name.clone(),
+                        edns_udp_payload: query.edns_udp_payload,
+                        peer: normalize_dual_stack_addr(peer).to_string(),
+                    }
+                    .encode(),
+                ),
+                QueryControl::AnswerLimit(_) | QueryControl::Coalesce(_) | QueryControl::None => {
+                    None
+                }
+            };
+            if echo.is_some() {
+                return Ok(Some(Slot {
+                    peer: normalize_dual_stack_addr(peer),
+                    id: query.id,
+                    rd: query.rd,
+                    cd: query.cd,
+                    question: query.question,
+                    rcode: Some(Rcode::Ok),
+                    answer_size: 0,
+                    max_packets: 1,
+                    echo,
+                    cnx: std::ptr::null_mut(),
+                    path_id: -1,
+                }));
+            }
+            let (answer_size, max_packets) = answer_budget(&query);
             let mut peer_storage = dummy_sockaddr_storage();
             let mut local_storage = unsafe { std::ptr::read(local_addr_storage) };
             let mut first_cnx: *mut picoquic_cnx_t = std::ptr::null_mut();
@@ -360,6 +589,9 @@ fn decode_slot(
                 cd: query.cd,
                 question: query.question,
                 rcode: None,
+                answer_size,
+                max_packets,
+                echo: None,
                 cnx: first_cnx,
                 path_id: first_path,
             }))
@@ -383,6 +615,9 @@ fn decode_slot(
                 cd,
                 question,
                 rcode: Some(rcode),
+                answer_size: 0,
+                max_packets: 1,
+                echo: None,
                 cnx: std::ptr::null_mut(),
                 path_id: -1,
             }))
@@ -457,7 +692,7 @@ fn dummy_sockaddr_storage() -> sockaddr_storage {
     storage
 }
 
-fn map_io(err: std::io::Error) -> ServerError {
+pub(crate) fn map_io(err: std::io::Error) -> ServerError {
     ServerError::new(err.to_string())
 }
 
@@ -505,3 +740,72 @@ fn is_label_suffix(domain: &str, suffix: &str) -> bool {
     }
     domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.'
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{answer_budget, encode_answer, prepare_answer_packets, Slot};
+    use slipstream_dns::{
+        build_encoded_qname, decode_query, encode_query, QnameEncoding, QueryControl, QueryParams,
+        CLASS_IN, RR_TXT,
+    };
+    use slipstream_ffi::picoquic::PICOQUIC_MAX_PACKET_SIZE;
+    use std::net::{Ipv6Addr, SocketAddr};
+
+    const MAX_DOWNSTREAM_MTU: u32 = 1200;
+
+    /// The number of answer records the server sends for a query with `control`, when QUIC
+    /// always has more packets to send.
+    fn answer_count(control: QueryControl) -> u16 {
+        let qname = build_encoded_qname(&[7; 40], "test.com", QnameEncoding::Base32, control)
+            .expect("qname");
+        let packet = encode_query(&QueryParams {
+            id: 11,
+            qname: &qname,
+            qtype: RR_TXT,
+            qclass: CLASS_IN,
+            rd: true,
+            cd: false,
+            qdcount: 1,
+            is_query: true,
+        })
+        .expect("encode query");
+        let query = decode_query(&packet, "test.com").expect("decode query");
+        let (answer_size, max_packets) = answer_budget(&query);
+        let slot = Slot {
+            peer: SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 53),
+            id: query.id,
+            rd: query.rd,
+            cd: query.cd,
+            question: query.question,
+            rcode: None,
+            answer_size,
+            max_packets,
+            echo: None,
+            // Only checked for null; the fake `prepare` below never uses it.
+            cnx: std::ptr::NonNull::dangling().as_ptr(),
+            path_id: 0,
+        };
+        let mut send_buf = vec![0u8; PICOQUIC_MAX_PACKET_SIZE * 4];
+        let packet_lens =
+            prepare_answer_packets(&slot, MAX_DOWNSTREAM_MTU, &mut send_buf, |mtu, buf| {
+                let len = (mtu as usize).min(200);
+                buf[..len].fill(0x42);
+                Ok(len)
+            })
+            .expect("prepare packets");
+        let response = encode_answer(&slot, &send_buf, &packet_lens).expect("encode answer");
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    #[test]
+    fn queries_without_the_coalesce_signal_get_one_answer() {
+        assert_eq!(answer_count(QueryControl::None), 1);
+        assert_eq!(answer_count(QueryControl::AnswerLimit(1232)), 1);
+    }
+
+    #[test]
+    fn queries_with_the_coalesce_signal_get_several_answers() {
+        assert_eq!(answer_count(QueryControl::Coalesce(1232)), 4);
+        assert_eq!(answer_count(QueryControl::Coalesce(512)), 2);
+    }
+}
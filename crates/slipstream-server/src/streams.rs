@@ -1,13 +1,34 @@
+use crate::bench::spawn_builtin_target;
+use crate::control::{
+    handle_control_request, ConnectionSnapshot, DebugFlags, PathSnapshot, ResolverSnapshot,
+    StreamSnapshot,
+};
+use crate::exec::ExecTarget;
+use crate::reverse::spawn_reverse_listener;
 use crate::server::{Command, StreamKey, StreamWrite};
-use crate::target::spawn_target_connector;
+use crate::target::{
+    spawn_target_connector, spawn_target_io, AllowedTarget, ProxyIdentity, TargetAddr, TargetPool,
+    TargetSocket,
+};
+use crate::tun::TunHub;
+use crate::udp::spawn_udp_target;
+use slipstream_core::datagram::{decode_datagram, encode_datagram, DatagramQueue};
+use slipstream_core::proxy_protocol::ProxyVersion;
+use slipstream_core::stream_error::{StreamError, StreamErrorCounts};
+use slipstream_core::stream_header::{
+    encode_stream_header, parse_stream_header, StreamHeader, StreamTarget, MAX_STREAM_HEADER_LEN,
+};
 use slipstream_ffi::picoquic::{
     picoquic_call_back_event_t, picoquic_close, picoquic_close_immediate, picoquic_cnx_t,
-    picoquic_get_first_cnx, picoquic_get_next_cnx, picoquic_mark_active_stream,
+    picoquic_get_cnx_state, picoquic_get_cwin, picoquic_get_data_received, picoquic_get_data_sent,
+    picoquic_get_first_cnx, picoquic_get_logging_cnxid, picoquic_get_next_cnx,
+    picoquic_get_next_local_stream_id, picoquic_get_path_quality, picoquic_get_rtt,
+    picoquic_mark_active_stream, picoquic_mark_datagram_ready, picoquic_path_quality_t,
     picoquic_provide_stream_data_buffer, picoquic_quic_t, picoquic_reset_stream,
-    picoquic_stream_data_consumed,
+    picoquic_stream_data_consumed, slipstream_get_path_count, slipstream_get_unique_path_id,
 };
-use slipstream_ffi::{SLIPSTREAM_FILE_CANCEL_ERROR, SLIPSTREAM_INTERNAL_ERROR};
-use std::collections::{HashMap, VecDeque};
+use slipstream_ffi::{provide_datagram, SLIPSTREAM_FILE_CANCEL_ERROR, SLIPSTREAM_INTERNAL_ERROR};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
@@ -16,32 +37,344 @@ use tokio::sync::{mpsc, watch};
 use tracing::{debug, error, warn};
 
 pub(crate) struct ServerState {
-    target_addr: SocketAddr,
+    quic: *mut picoquic_quic_t,
+    targets: Arc<TargetPool>,
+    allowed_targets: Vec<AllowedTarget>,
+    allowed_listeners: Vec<AllowedTarget>,
     streams: HashMap<StreamKey, ServerStream>,
+    /// UDP flows by the stream that opened them.
+    datagram_flows: HashMap<StreamKey, mpsc::Sender<Vec<u8>>>,
+    /// Datagrams waiting to be sent, per connection.
+    datagrams: HashMap<usize, DatagramQueue>,
+    connections: HashMap<usize, ConnectionInfo>,
+    next_connection_id: u64,
     command_tx: mpsc::UnboundedSender<Command>,
     debug_streams: bool,
     debug_commands: bool,
+    bench: bool,
+    tun: Option<TunHub>,
+    /// `--proxy-protocol`: the PROXY header version written to target connections.
+    proxy_protocol: Option<ProxyVersion>,
+    /// `--target-exec`, which replaces `targets` for streams without a header.
+    exec: Option<ExecTarget>,
+    /// Streams reset for each target or policy failure, reported with the command counts.
+    stream_errors: StreamErrorCounts,
     command_counts: CommandCounts,
     last_command_report: Instant,
 }
 
 impl ServerState {
     pub(crate) fn new(
-        target_addr: SocketAddr,
+        targets: Arc<TargetPool>,
+        allowed_targets: Vec<AllowedTarget>,
+        allowed_listeners: Vec<AllowedTarget>,
         command_tx: mpsc::UnboundedSender<Command>,
         debug_streams: bool,
         debug_commands: bool,
+        bench: bool,
     ) -> Self {
         Self {
-            target_addr,
+            quic: std::ptr::null_mut(),
+            targets,
+            allowed_targets,
+            allowed_listeners,
             streams: HashMap::new(),
+            datagram_flows: HashMap::new(),
+            datagrams: HashMap::new(),
+            connections: HashMap::new(),
+            next_connection_id: 1,
             command_tx,
             debug_streams,
             debug_commands,
+            bench,
+            tun: None,
+            proxy_protocol: None,
+            exec: None,
+            stream_errors: StreamErrorCounts::default(),
             command_counts: CommandCounts::default(),
             last_command_report: Instant::now(),
         }
     }
+
+    pub(crate) fn set_quic(&mut self, quic: *mut picoquic_quic_t) {
+        self.quic = quic;
+    }
+
+    pub(crate) fn set_tun(&mut self, tun: TunHub) {
+        self.tun = Some(tun);
+    }
+
+    pub(crate) fn set_proxy_protocol(&mut self, version: ProxyVersion) {
+        self.proxy_protocol = Some(version);
+    }
+
+    pub(crate) fn set_exec(&mut self, exec: ExecTarget) {
+        self.exec = Some(exec);
+    }
+
+    /// The connection's ID and the resolver of its first query.
+    fn connection_identity(&self, cnx: usize) -> (u64, Option<SocketAddr>) {
+        let info = self.connections.get(&cnx);
+        (
+            info.map_or(0, |info| info.id),
+            info.and_then(|info| info.first_resolver),
+        )
+    }
+
+    fn proxy_identity(&self, cnx: usize) -> Option<ProxyIdentity> {
+        let version = self.proxy_protocol?;
+        let (connection_id, source) = self.connection_identity(cnx);
+        Some(ProxyIdentity {
+            version,
+            source,
+            connection_id,
+        })
+    }
+
+    pub(crate) fn note_query(&mut self, cnx: *mut picoquic_cnx_t, peer: SocketAddr) {
+        if cnx.is_null() {
+            return;
+        }
+        let info = self.connection_info(cnx);
+        let now = Instant::now();
+        info.last_seen = now;
+        info.queries = info.queries.saturating_add(1);
+        info.first_resolver.get_or_insert(peer);
+        let resolver = info.resolvers.entry(peer).or_insert(ResolverSeen {
+            queries: 0,
+            last_seen: now,
+        });
+        resolver.queries = resolver.queries.saturating_add(1);
+        resolver.last_seen = now;
+    }
+
+    fn connection_info(&mut self, cnx: *mut picoquic_cnx_t) -> &mut ConnectionInfo {
+        let next_id = &mut self.next_connection_id;
+        self.connections.entry(cnx as usize).or_insert_with(|| {
+            let id = *next_id;
+            *next_id = next_id.saturating_add(1);
+            let now = Instant::now();
+            ConnectionInfo {
+                id,
+                first_seen: now,
+                last_seen: now,
+                queries: 0,
+                resolvers: HashMap::new(),
+                first_resolver: None,
+            }
+        })
+    }
+
+    /// Walks picoquic's connection list, dropping bookkeeping for connections it has freed.
+    fn live_connections(&mut self) -> Vec<*mut picoquic_cnx_t> {
+        let mut live = Vec::new();
+        if self.quic.is_null() {
+            return live;
+        }
+        let mut cnx = unsafe { picoquic_get_first_cnx(self.quic) };
+        while !cnx.is_null() {
+            live.push(cnx);
+            cnx = unsafe { picoquic_get_next_cnx(cnx) };
+        }
+        let live_keys: HashSet<usize> = live.iter().map(|cnx| *cnx as usize).collect();
+        self.connections.retain(|key, _| live_keys.contains(key));
+        self.datagrams.retain(|key, _| live_keys.contains(key));
+        live
+    }
+
+    fn find_connection(&mut self, id: u64) -> Result<*mut picoquic_cnx_t, String> {
+        self.live_connections()
+            .into_iter()
+            .find(|cnx| {
+                self.connections
+                    .get(&(*cnx as usize))
+                    .is_some_and(|info| info.id == id)
+            })
+            .ok_or_else(|| format!("unknown connection {}", id))
+    }
+
+    pub(crate) fn connection_snapshots(&mut self) -> Vec<ConnectionSnapshot> {
+        let live = self.live_connections();
+        let mut stream_counts: HashMap<usize, usize> = HashMap::new();
+        for key in self.streams.keys() {
+            *stream_counts.entry(key.cnx).or_insert(0) += 1;
+        }
+        let now = Instant::now();
+        let mut snapshots = Vec::with_capacity(live.len());
+        for cnx in live {
+            let streams = stream_counts.get(&(cnx as usize)).copied().unwrap_or(0);
+            let info = self.connection_info(cnx);
+            let mut resolvers: Vec<ResolverSnapshot> = info
+                .resolvers
+                .iter()
+                .map(|(addr, seen)| ResolverSnapshot {
+                    addr: addr.to_string(),
+                    queries: seen.queries,
+                    idle_ms: duration_ms(now.duration_since(seen.last_seen)),
+                })
+                .collect();
+            resolvers.sort_by_key(|resolver| std::cmp::Reverse(resolver.queries));
+            let cid = unsafe { picoquic_get_logging_cnxid(cnx) };
+            let cid_len = (cid.id_len as usize).min(cid.id.len());
+            let state = unsafe { picoquic_get_cnx_state(cnx) };
+            snapshots.push(ConnectionSnapshot {
+                id: info.id,
+                cid: cid.id[..cid_len]
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect(),
+                state: format!("{:?}", state)
+                    .trim_start_matches("picoquic_state_")
+                    .to_string(),
+                age_secs: now.duration_since(info.first_seen).as_secs(),
+                idle_ms: duration_ms(now.duration_since(info.last_seen)),
+                queries: info.queries,
+                resolvers,
+                paths: path_snapshots(cnx),
+                rtt_us: unsafe { picoquic_get_rtt(cnx) },
+                cwin: unsafe { picoquic_get_cwin(cnx) },
+                bytes_sent: unsafe { picoquic_get_data_sent(cnx) },
+                bytes_received: unsafe { picoquic_get_data_received(cnx) },
+                streams,
+            });
+        }
+        snapshots.sort_by_key(|snapshot| snapshot.id);
+        snapshots
+    }
+
+    pub(crate) fn stream_snapshots(
+        &mut self,
+        connection: u64,
+    ) -> Result<Vec<StreamSnapshot>, String> {
+        let cnx = self.find_connection(connection)? as usize;
+        let ordered: BTreeMap<u64, &ServerStream> = self
+            .streams
+            .iter()
+            .filter(|(key, _)| key.cnx == cnx)
+            .map(|(key, stream)| (key.stream_id, stream))
+            .collect();
+        Ok(ordered
+            .into_iter()
+            .map(|(stream_id, stream)| StreamSnapshot {
+                stream_id,
+                target_connected: stream.write_tx.is_some(),
+                rx_bytes: stream.rx_bytes,
+                tx_bytes: stream.tx_bytes,
+                queued_bytes: stream.queued_bytes,
+                consumed_offset: stream.consumed_offset,
+                fin_offset: stream.fin_offset,
+                pending_chunks: stream.pending_data.len(),
+                pending_fin: stream.pending_fin,
+                fin_enqueued: stream.fin_enqueued,
+                target_fin_pending: stream.target_fin_pending,
+                close_after_flush: stream.close_after_flush,
+                send_stash_bytes: stream.send_stash.as_ref().map_or(0, Vec::len),
+            })
+            .collect())
+    }
+
+    pub(crate) fn close_connection(&mut self, connection: u64) -> Result<(), String> {
+        let cnx = self.find_connection(connection)?;
+        remove_connection_streams(self, cnx as usize);
+        self.connections.remove(&(cnx as usize));
+        self.datagrams.remove(&(cnx as usize));
+        unsafe {
+            let _ = picoquic_close(cnx, 0);
+        }
+        warn!("connection {}: closed via control socket", connection);
+        Ok(())
+    }
+
+    pub(crate) fn reset_stream(&mut self, connection: u64, stream_id: u64) -> Result<(), String> {
+        let cnx = self.find_connection(connection)?;
+        let key = StreamKey {
+            cnx: cnx as usize,
+            stream_id,
+        };
+        if shutdown_stream(self, key).is_none() {
+            return Err(format!(
+                "unknown stream {} on connection {}",
+                stream_id, connection
+            ));
+        }
+        unsafe {
+            let _ = picoquic_reset_stream(cnx, stream_id, SLIPSTREAM_FILE_CANCEL_ERROR);
+        }
+        warn!(
+            "stream {:?}: reset via control socket (connection {})",
+            stream_id, connection
+        );
+        Ok(())
+    }
+
+    pub(crate) fn set_debug(
+        &mut self,
+        debug_streams: Option<bool>,
+        debug_commands: Option<bool>,
+    ) -> DebugFlags {
+        if let Some(enabled) = debug_streams {
+            self.debug_streams = enabled;
+        }
+        if let Some(enabled) = debug_commands {
+            if enabled && !self.debug_commands {
+                self.command_counts.reset();
+                self.last_command_report = Instant::now();
+            }
+            self.debug_commands = enabled;
+        }
+        DebugFlags {
+            debug_streams: self.debug_streams,
+            debug_commands: self.debug_commands,
+        }
+    }
+}
+
+struct ConnectionInfo {
+    id: u64,
+    first_seen: Instant,
+    last_seen: Instant,
+    queries: u64,
+    resolvers: HashMap<SocketAddr, ResolverSeen>,
+    /// The resolver of the connection's first query, its source in PROXY headers.
+    first_resolver: Option<SocketAddr>,
+}
+
+struct ResolverSeen {
+    queries: u64,
+    last_seen: Instant,
+}
+
+fn path_snapshots(cnx: *mut picoquic_cnx_t) -> Vec<PathSnapshot> {
+    let count = unsafe { slipstream_get_path_count(cnx) };
+    let mut paths = Vec::with_capacity(count.max(0) as usize);
+    for path_id in 0..count {
+        let mut unique_path_id = 0u64;
+        let ret = unsafe { slipstream_get_unique_path_id(cnx, path_id, &mut unique_path_id) };
+        if ret < 0 {
+            continue;
+        }
+        let mut quality = picoquic_path_quality_t::default();
+        if unsafe { picoquic_get_path_quality(cnx, unique_path_id, &mut quality) } != 0 {
+            continue;
+        }
+        paths.push(PathSnapshot {
+            path_id,
+            unique_path_id,
+            active: ret == 0,
+            rtt_us: quality.rtt,
+            rtt_min_us: quality.rtt_min,
+            cwin: quality.cwin,
+            pacing_rate: quality.pacing_rate,
+            bytes_in_transit: quality.bytes_in_transit,
+            sent: quality.sent,
+            lost: quality.lost,
+        });
+    }
+    paths
+}
+
+fn duration_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
 }
 
 #[derive(Default)]
@@ -53,6 +386,9 @@ struct CommandCounts {
     stream_read_error: u64,
     stream_write_error: u64,
     stream_write_drained: u64,
+    control: u64,
+    reverse_accepted: u64,
+    datagram: u64,
 }
 
 impl CommandCounts {
@@ -65,6 +401,9 @@ impl CommandCounts {
             Command::StreamReadError { .. } => self.stream_read_error += 1,
             Command::StreamWriteError { .. } => self.stream_write_error += 1,
             Command::StreamWriteDrained { .. } => self.stream_write_drained += 1,
+            Command::Control { .. } => self.control += 1,
+            Command::ReverseAccepted { .. } => self.reverse_accepted += 1,
+            Command::Datagram { .. } => self.datagram += 1,
         }
     }
 
@@ -76,6 +415,9 @@ impl CommandCounts {
             + self.stream_read_error
             + self.stream_write_error
             + self.stream_write_drained
+            + self.control
+            + self.reverse_accepted
+            + self.datagram
     }
 
     fn reset(&mut self) {
@@ -99,6 +441,96 @@ struct ServerStream {
     pending_data: VecDeque<Vec<u8>>,
     pending_fin: bool,
     fin_enqueued: bool,
+    /// A target was picked; until then data waits for a possible stream header.
+    target_started: bool,
+}
+
+enum TargetChoice {
+    /// Not enough bytes yet to tell whether the stream opens with a header.
+    Wait,
+    Configured,
+    Builtin(StreamTarget),
+    Forward {
+        host: String,
+        port: u16,
+    },
+    Listen {
+        host: String,
+        port: u16,
+    },
+    Udp {
+        host: String,
+        port: u16,
+    },
+    Tun,
+    Invalid,
+}
+
+impl ServerStream {
+    fn new(shutdown_tx: watch::Sender<bool>) -> Self {
+        Self {
+            write_tx: None,
+            data_rx: None,
+            send_pending: None,
+            send_stash: None,
+            queued_bytes: 0,
+            shutdown_tx,
+            rx_bytes: 0,
+            consumed_offset: 0,
+            fin_offset: None,
+            tx_bytes: 0,
+            target_fin_pending: false,
+            close_after_flush: false,
+            pending_data: VecDeque::new(),
+            pending_fin: false,
+            fin_enqueued: false,
+            target_started: false,
+        }
+    }
+
+    /// Picks the stream's target once its first bytes show whether it opens with a
+    /// header, which is then dropped from the pending data. Clients send a header with the
+    /// stream's first bytes, so a stream that opens without data goes to the configured
+    /// target right away; targets that speak first would otherwise never be dialed.
+    fn choose_target(&mut self) -> TargetChoice {
+        let head: Vec<u8> = self
+            .pending_data
+            .iter()
+            .flatten()
+            .copied()
+            .take(MAX_STREAM_HEADER_LEN)
+            .collect();
+        match parse_stream_header(&head) {
+            StreamHeader::Incomplete if !head.is_empty() && self.fin_offset.is_none() => {
+                TargetChoice::Wait
+            }
+            StreamHeader::Incomplete | StreamHeader::Absent => TargetChoice::Configured,
+            StreamHeader::Invalid => TargetChoice::Invalid,
+            StreamHeader::Present { target, len } => {
+                let mut skip = len;
+                while let Some(front) = self.pending_data.front_mut() {
+                    if front.len() > skip {
+                        front.drain(..skip);
+                        break;
+                    }
+                    skip -= front.len();
+                    self.pending_data.pop_front();
+                }
+                // The header never reaches a target, so count it as consumed here.
+                self.queued_bytes = self.queued_bytes.saturating_sub(len);
+                self.consumed_offset = len as u64;
+                match target {
+                    StreamTarget::Connect { host, port } => TargetChoice::Forward { host, port },
+                    StreamTarget::Listen { host, port } => TargetChoice::Listen { host, port },
+                    StreamTarget::Udp { host, port } => TargetChoice::Udp { host, port },
+                    StreamTarget::Tun => TargetChoice::Tun,
+                    // Only the server opens streams for accepted connections.
+                    StreamTarget::Accepted { .. } => TargetChoice::Invalid,
+                    target => TargetChoice::Builtin(target),
+                }
+            }
+        }
+    }
 }
 
 pub(crate) unsafe extern "C" fn server_callback(
@@ -168,8 +600,22 @@ pub(crate) unsafe extern "C" fn server_callback(
         | picoquic_call_back_event_t::picoquic_callback_application_close
         | picoquic_call_back_event_t::picoquic_callback_stateless_reset => {
             remove_connection_streams(state, cnx as usize);
+            state.connections.remove(&(cnx as usize));
+            state.datagrams.remove(&(cnx as usize));
             let _ = picoquic_close(cnx, 0);
         }
+        picoquic_call_back_event_t::picoquic_callback_datagram => {
+            let data = if length > 0 && !bytes.is_null() {
+                unsafe { std::slice::from_raw_parts(bytes as *const u8, length) }
+            } else {
+                &[]
+            };
+            handle_datagram(cnx, state, data);
+        }
+        picoquic_call_back_event_t::picoquic_callback_prepare_datagram if !bytes.is_null() => {
+            let queue = state.datagrams.entry(cnx as usize).or_default();
+            provide_datagram(queue, bytes as *mut _, length);
+        }
         picoquic_call_back_event_t::picoquic_callback_prepare_to_send => {
             if bytes.is_null() {
                 return 0;
@@ -288,6 +734,26 @@ pub(crate) unsafe extern "C" fn server_callback(
     0
 }
 
+fn handle_datagram(cnx: *mut picoquic_cnx_t, state: &mut ServerState, data: &[u8]) {
+    let Some((stream_id, payload)) = decode_datagram(data) else {
+        return;
+    };
+    let key = StreamKey {
+        cnx: cnx as usize,
+        stream_id,
+    };
+    let Some(flow) = state.datagram_flows.get(&key) else {
+        if state.debug_streams {
+            debug!("stream {:?}: datagram for unknown flow", stream_id);
+        }
+        return;
+    };
+    // UDP is lossy anyway; a flow that cannot keep up drops what it cannot take.
+    if let Err(mpsc::error::TrySendError::Closed(_)) = flow.try_send(payload.to_vec()) {
+        state.datagram_flows.remove(&key);
+    }
+}
+
 fn handle_stream_data(
     cnx: *mut picoquic_cnx_t,
     state: &mut ServerState,
@@ -301,38 +767,14 @@ fn handle_stream_data(
     };
     let debug_streams = state.debug_streams;
     let mut reset_stream = false;
+    let mut denied = false;
+    let mut target_choice = None;
 
     {
-        let stream = state.streams.entry(key).or_insert_with(|| {
-            let (shutdown_tx, shutdown_rx) = watch::channel(false);
-            if debug_streams {
-                debug!("stream {:?}: connecting", key.stream_id);
-            }
-            spawn_target_connector(
-                key,
-                state.target_addr,
-                state.command_tx.clone(),
-                debug_streams,
-                shutdown_rx,
-            );
-            ServerStream {
-                write_tx: None,
-                data_rx: None,
-                send_pending: None,
-                send_stash: None,
-                queued_bytes: 0,
-                shutdown_tx,
-                rx_bytes: 0,
-                consumed_offset: 0,
-                fin_offset: None,
-                tx_bytes: 0,
-                target_fin_pending: false,
-                close_after_flush: false,
-                pending_data: VecDeque::new(),
-                pending_fin: false,
-                fin_enqueued: false,
-            }
-        });
+        let stream = state
+            .streams
+            .entry(key)
+            .or_insert_with(|| ServerStream::new(watch::channel(false).0));
 
         if !data.is_empty() {
             // Backpressure is enforced via connection-level max_data, not per-stream buffer caps.
@@ -368,9 +810,149 @@ fn handle_stream_data(
                 }
             }
         }
+
+        if !stream.target_started {
+            let choice = stream.choose_target();
+            if !matches!(choice, TargetChoice::Wait) {
+                stream.target_started = true;
+                target_choice = Some((choice, stream.shutdown_tx.subscribe()));
+            }
+        }
     }
 
-    if reset_stream {
+    if let Some((choice, shutdown_rx)) = target_choice {
+        match choice {
+            TargetChoice::Wait => {}
+            TargetChoice::Configured => {
+                if let Some(exec) = &state.exec {
+                    exec.spawn(
+                        key,
+                        state.connection_identity(key.cnx),
+                        state.command_tx.clone(),
+                        debug_streams,
+                        shutdown_rx,
+                    );
+                } else {
+                    if debug_streams {
+                        debug!("stream {:?}: connecting", key.stream_id);
+                    }
+                    spawn_target_connector(
+                        key,
+                        TargetAddr::Configured(state.targets.clone()),
+                        state.command_tx.clone(),
+                        debug_streams,
+                        state.targets.connect_timeout(),
+                        state.proxy_identity(key.cnx),
+                        shutdown_rx,
+                    );
+                }
+            }
+            TargetChoice::Forward { host, port }
+                if state
+                    .allowed_targets
+                    .iter()
+                    .any(|allowed| allowed.allows(&host, port)) =>
+            {
+                if debug_streams {
+                    debug!(
+                        "stream {:?}: connecting to {}:{}",
+                        key.stream_id, host, port
+                    );
+                }
+                spawn_target_connector(
+                    key,
+                    TargetAddr::Forward { host, port },
+                    state.command_tx.clone(),
+                    debug_streams,
+                    state.targets.connect_timeout(),
+                    state.proxy_identity(key.cnx),
+                    shutdown_rx,
+                );
+            }
+            TargetChoice::Forward { host, port } => {
+                warn!(
+                    "stream {:?}: client asked for {}:{}, which no --allow-target admits",
+                    key.stream_id, host, port
+                );
+                denied = true;
+            }
+            TargetChoice::Listen { host, port }
+                if state
+                    .allowed_listeners
+                    .iter()
+                    .any(|allowed| allowed.allows(&host, port)) =>
+            {
+                spawn_reverse_listener(key, host, port, state.command_tx.clone(), shutdown_rx);
+            }
+            TargetChoice::Listen { host, port } => {
+                warn!(
+                    "stream {:?}: client asked to listen on {}:{}, which no --allow-listen admits",
+                    key.stream_id, host, port
+                );
+                denied = true;
+            }
+            TargetChoice::Udp { host, port }
+                if state
+                    .allowed_targets
+                    .iter()
+                    .any(|allowed| allowed.allows(&host, port)) =>
+            {
+                if debug_streams {
+                    debug!("stream {:?}: UDP flow to {}:{}", key.stream_id, host, port);
+                }
+                let to_target =
+                    spawn_udp_target(key, host, port, state.command_tx.clone(), shutdown_rx);
+                state.datagram_flows.insert(key, to_target);
+            }
+            TargetChoice::Udp { host, port } => {
+                warn!(
+                    "stream {:?}: client asked for UDP to {}:{}, which no --allow-target admits",
+                    key.stream_id, host, port
+                );
+                denied = true;
+            }
+            TargetChoice::Tun => match state.tun.as_ref() {
+                Some(tun) => {
+                    if debug_streams {
+                        debug!("stream {:?}: TUN session", key.stream_id);
+                    }
+                    tun.spawn_session(key, state.command_tx.clone(), shutdown_rx);
+                }
+                None => {
+                    warn!(
+                        "stream {:?}: client asked for TUN mode, which needs --tun",
+                        key.stream_id
+                    );
+                    denied = true;
+                }
+            },
+            TargetChoice::Builtin(target) if state.bench => {
+                if debug_streams {
+                    debug!("stream {:?}: built-in target {:?}", key.stream_id, target);
+                }
+                spawn_builtin_target(key, target, state.command_tx.clone(), shutdown_rx);
+            }
+            TargetChoice::Builtin(target) => {
+                warn!(
+                    "stream {:?}: client asked for built-in target {:?}, which needs --bench",
+                    key.stream_id, target
+                );
+                denied = true;
+            }
+            TargetChoice::Invalid => {
+                warn!("stream {:?}: unknown stream header", key.stream_id);
+                reset_stream = true;
+            }
+        }
+    }
+
+    if denied {
+        shutdown_stream(state, key);
+        state.stream_errors.note(StreamError::Denied);
+        unsafe {
+            let _ = picoquic_reset_stream(cnx, stream_id, StreamError::Denied.code());
+        }
+    } else if reset_stream {
         if debug_streams {
             debug!("stream {:?}: resetting", stream_id);
         }
@@ -394,6 +976,7 @@ fn remove_connection_streams(state: &mut ServerState, cnx: usize) {
 }
 
 fn shutdown_stream(state: &mut ServerState, key: StreamKey) -> Option<ServerStream> {
+    state.datagram_flows.remove(&key);
     if let Some(stream) = state.streams.remove(&key) {
         let _ = stream.shutdown_tx.send(true);
         return Some(stream);
@@ -475,15 +1058,23 @@ pub(crate) fn handle_command(state_ptr: *mut ServerState, command: Command) {
                 let _ = unsafe { picoquic_reset_stream(cnx, stream_id, SLIPSTREAM_INTERNAL_ERROR) };
             }
         }
-        Command::StreamConnectError { cnx_id, stream_id } => {
+        Command::StreamConnectError {
+            cnx_id,
+            stream_id,
+            error,
+        } => {
             let cnx = cnx_id as *mut picoquic_cnx_t;
             let key = StreamKey {
                 cnx: cnx_id,
                 stream_id,
             };
             if shutdown_stream(state, key).is_some() {
-                let _ = unsafe { picoquic_reset_stream(cnx, stream_id, SLIPSTREAM_INTERNAL_ERROR) };
-                warn!("stream {:?}: target connect failed", stream_id);
+                state.stream_errors.note(error);
+                let _ = unsafe { picoquic_reset_stream(cnx, stream_id, error.code()) };
+                warn!(
+                    "stream {:?}: target connect failed reason={}",
+                    stream_id, error
+                );
             }
         }
         Command::StreamClosed { cnx_id, stream_id } => {
@@ -566,6 +1157,84 @@ pub(crate) fn handle_command(state_ptr: *mut ServerState, command: Command) {
                 let _ = unsafe { picoquic_reset_stream(cnx, stream_id, SLIPSTREAM_INTERNAL_ERROR) };
             }
         }
+        Command::Datagram {
+            cnx_id,
+            stream_id,
+            payload,
+        } => {
+            let key = StreamKey {
+                cnx: cnx_id,
+                stream_id,
+            };
+            if !state.streams.contains_key(&key) {
+                return;
+            }
+            let queue = state.datagrams.entry(cnx_id).or_default();
+            if queue.push(encode_datagram(stream_id, &payload)) {
+                let cnx = cnx_id as *mut picoquic_cnx_t;
+                let _ = unsafe { picoquic_mark_datagram_ready(cnx, 1) };
+            } else if state.debug_streams {
+                debug!(
+                    "stream {:?}: datagram queue full dropped={}",
+                    stream_id,
+                    queue.dropped()
+                );
+            }
+        }
+        Command::Control { request, reply } => {
+            let _ = reply.send(handle_control_request(state, request));
+        }
+        Command::ReverseAccepted {
+            cnx_id,
+            control_stream_id,
+            listener: (host, port),
+            stream,
+        } => {
+            let control_key = StreamKey {
+                cnx: cnx_id,
+                stream_id: control_stream_id,
+            };
+            // The connection may be gone; its control stream is dropped with it.
+            if !state.streams.contains_key(&control_key) {
+                return;
+            }
+            let cnx = cnx_id as *mut picoquic_cnx_t;
+            let stream_id = unsafe { picoquic_get_next_local_stream_id(cnx, 0) };
+            let key = StreamKey {
+                cnx: cnx_id,
+                stream_id,
+            };
+            let (shutdown_tx, shutdown_rx) = watch::channel(false);
+            let (write_tx, data_rx, send_pending) = spawn_target_io(
+                key,
+                TargetSocket::tcp(stream),
+                state.command_tx.clone(),
+                state.debug_streams,
+                shutdown_rx,
+            );
+            let mut server_stream = ServerStream::new(shutdown_tx);
+            server_stream.write_tx = Some(write_tx);
+            server_stream.data_rx = Some(data_rx);
+            server_stream.send_pending = Some(send_pending);
+            server_stream.send_stash =
+                Some(encode_stream_header(&StreamTarget::Accepted { host, port }));
+            server_stream.target_started = true;
+            state.streams.insert(key, server_stream);
+            let ret =
+                unsafe { picoquic_mark_active_stream(cnx, stream_id, 1, std::ptr::null_mut()) };
+            if ret != 0 {
+                warn!(
+                    "stream {:?}: mark_active_stream for reverse tunnel failed ret={}",
+                    stream_id, ret
+                );
+                shutdown_stream(state, key);
+            } else if state.debug_streams {
+                debug!(
+                    "stream {:?}: opened for reverse tunnel stream {:?}",
+                    stream_id, control_stream_id
+                );
+            }
+        }
         Command::StreamWriteDrained {
             cnx_id,
             stream_id,
@@ -625,7 +1294,7 @@ pub(crate) fn maybe_report_command_stats(state_ptr: *mut ServerState) {
     let total = state.command_counts.total();
     if total > 0 {
         debug!(
-            "debug: commands total={} connected={} connect_err={} closed={} readable={} read_err={} write_err={} write_drained={}",
+            "debug: commands total={} connected={} connect_err={} closed={} readable={} read_err={} write_err={} write_drained={} control={} reverse_accepted={} datagram={} stream_errors=[{}]",
             total,
             state.command_counts.stream_connected,
             state.command_counts.stream_connect_error,
@@ -633,7 +1302,11 @@ pub(crate) fn maybe_report_command_stats(state_ptr: *mut ServerState) {
             state.command_counts.stream_readable,
             state.command_counts.stream_read_error,
             state.command_counts.stream_write_error,
-            state.command_counts.stream_write_drained
+            state.command_counts.stream_write_drained,
+            state.command_counts.control,
+            state.command_counts.reverse_accepted,
+            state.command_counts.datagram,
+            state.stream_errors
         );
     }
     state.command_counts.reset();
@@ -649,5 +1322,41 @@ pub(crate) fn handle_shutdown(quic: *mut picoquic_quic_t, state: &mut ServerStat
         cnx = next;
     }
     state.streams.clear();
+    state.connections.clear();
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ServerStream, TargetChoice};
+    use tokio::sync::watch;
+
+    fn stream_with(data: &[u8]) -> ServerStream {
+        let mut stream = ServerStream::new(watch::channel(false).0);
+        if !data.is_empty() {
+            stream.pending_data.push_back(data.to_vec());
+        }
+        stream
+    }
+
+    #[test]
+    fn stream_without_data_dials_the_configured_target() {
+        // Servers that speak first (SSH, SMTP) must be dialed before the client sends a byte.
+        assert!(matches!(
+            stream_with(b"").choose_target(),
+            TargetChoice::Configured
+        ));
+        assert!(matches!(
+            stream_with(b"SSH-2.0").choose_target(),
+            TargetChoice::Configured
+        ));
+    }
+
+    #[test]
+    fn partial_header_waits_for_more_bytes() {
+        assert!(matches!(
+            stream_with(b"\0sl").choose_target(),
+            TargetChoice::Wait
+        ));
+    }
+}
@@ -1,28 +1,152 @@
+mod pool;
+
+pub use pool::TargetPolicy;
+pub(crate) use pool::{spawn_target_resolver, TargetPool};
+
 use crate::server::{
     Command, StreamKey, StreamWrite, DEFAULT_TCP_RCVBUF_BYTES, STREAM_READ_CHUNK_BYTES,
     TARGET_WRITE_COALESCE_DEFAULT_BYTES,
 };
+use slipstream_core::proxy_protocol::{encode_proxy_header, ProxyHeader, ProxyVersion};
+use slipstream_core::stream_error::StreamError;
+#[cfg(unix)]
 use slipstream_core::tcp::{stream_read_limit_chunks, tcp_send_buffer_bytes};
+use slipstream_core::{parse_host_port, parse_unix_path, AddressKind, HostPort};
 use std::net::SocketAddr;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream as TokioTcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::sync::{mpsc, watch};
 use tracing::{debug, warn};
 
+/// `--target-address` as given: a host and port, or a `unix:/path` socket.
+#[derive(Debug, Clone)]
+pub enum TargetAddress {
+    Tcp(HostPort),
+    /// Only parsed on Unix platforms.
+    Unix(PathBuf),
+}
+
+/// Where a stream's target connection goes.
+#[derive(Debug, Clone)]
+pub(crate) enum TargetAddr {
+    /// The `--target-address` entries.
+    Configured(Arc<TargetPool>),
+    /// A destination the client named in the stream header, resolved on connect.
+    Forward { host: String, port: u16 },
+}
+
+/// Who a target connection is for, sent in a PROXY header ahead of the stream's data.
+#[derive(Debug, Clone)]
+pub(crate) struct ProxyIdentity {
+    pub(crate) version: ProxyVersion,
+    /// First resolver address the QUIC connection arrived through.
+    pub(crate) source: Option<SocketAddr>,
+    /// The connection's ID, as `slipstream-server ctl` lists it.
+    pub(crate) connection_id: u64,
+}
+
+/// One `--allow-target` entry: a host as clients name it, and a port; either may be `*` for any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedTarget {
+    host: String,
+    port: Option<u16>,
+}
+
+impl TargetAddress {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        if let Some(path) = parse_unix_path(input).map_err(|err| err.to_string())? {
+            if cfg!(not(unix)) {
+                return Err("unix: targets are only supported on Unix platforms".to_string());
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        parse_host_port(input, 5201, AddressKind::Target)
+            .map(Self::Tcp)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl AllowedTarget {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "Invalid allowed target (expected host:port, host:* or *:port): {}",
+                input
+            )
+        };
+        let (host, port) = input.rsplit_once(':').ok_or_else(invalid)?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let port = match port {
+            "*" => None,
+            port => Some(
+                port.parse::<u16>()
+                    .ok()
+                    .filter(|port| *port != 0)
+                    .ok_or_else(invalid)?,
+            ),
+        };
+        Ok(Self {
+            host: host.to_ascii_lowercase(),
+            port,
+        })
+    }
+
+    /// Hosts compare as written, so an entry for an IP does not admit names resolving to it.
+    pub(crate) fn allows(&self, host: &str, port: u16) -> bool {
+        (self.host == "*" || self.host.eq_ignore_ascii_case(host))
+            && self.port.is_none_or(|allowed| allowed == port)
+    }
+}
+
 pub(crate) fn spawn_target_connector(
     key: StreamKey,
-    target_addr: SocketAddr,
+    target: TargetAddr,
     command_tx: mpsc::UnboundedSender<Command>,
     debug_streams: bool,
+    connect_timeout: Duration,
+    proxy: Option<ProxyIdentity>,
     mut shutdown_rx: watch::Receiver<bool>,
 ) {
     tokio::spawn(async move {
         if *shutdown_rx.borrow() {
             return;
         }
-        let connect = TokioTcpStream::connect(target_addr);
+        let connect = async {
+            let mut socket = match &target {
+                TargetAddr::Configured(pool) => pool.connect(key.stream_id).await,
+                TargetAddr::Forward { host, port } => {
+                    pool::connect_host(host, *port, connect_timeout)
+                        .await
+                        .map(TargetSocket::tcp)
+                }
+            }?;
+            if let Some(proxy) = &proxy {
+                let header = encode_proxy_header(
+                    proxy.version,
+                    &ProxyHeader {
+                        source: proxy.source,
+                        destination: socket.peer,
+                        unique_id: Some(proxy.connection_id.to_string().into_bytes()),
+                    },
+                );
+                socket.writer.write_all(&header).await?;
+            }
+            Ok::<_, std::io::Error>(socket)
+        };
         let stream = tokio::select! {
             _ = shutdown_rx.changed() => {
                 return;
@@ -34,36 +158,8 @@ pub(crate) fn spawn_target_connector(
         }
         match stream {
             Ok(stream) => {
-                let _ = stream.set_nodelay(true);
-                let read_limit = stream_read_limit_chunks(
-                    &stream,
-                    DEFAULT_TCP_RCVBUF_BYTES,
-                    STREAM_READ_CHUNK_BYTES,
-                );
-                let (data_tx, data_rx) = mpsc::channel(read_limit);
-                let send_buffer_bytes = tcp_send_buffer_bytes(&stream)
-                    .filter(|bytes| *bytes > 0)
-                    .unwrap_or(TARGET_WRITE_COALESCE_DEFAULT_BYTES);
-                let (read_half, write_half) = stream.into_split();
-                let (write_tx, write_rx) = mpsc::unbounded_channel();
-                let send_pending = Arc::new(AtomicBool::new(false));
-                spawn_target_reader(
-                    key,
-                    read_half,
-                    data_tx,
-                    command_tx.clone(),
-                    send_pending.clone(),
-                    debug_streams,
-                    shutdown_rx.clone(),
-                );
-                spawn_target_writer(
-                    key,
-                    write_half,
-                    write_rx,
-                    command_tx.clone(),
-                    shutdown_rx,
-                    send_buffer_bytes,
-                );
+                let (write_tx, data_rx, send_pending) =
+                    spawn_target_io(key, stream, command_tx.clone(), debug_streams, shutdown_rx);
                 let _ = command_tx.send(Command::StreamConnected {
                     cnx_id: key.cnx,
                     stream_id: key.stream_id,
@@ -73,24 +169,149 @@ pub(crate) fn spawn_target_connector(
                 });
             }
             Err(err) => {
+                let target = match &target {
+                    TargetAddr::Configured(_) => "--target-address".to_string(),
+                    TargetAddr::Forward { host, port } => format!("{}:{}", host, port),
+                };
                 warn!(
-                    "stream {:?}: target connect failed err={} kind={:?}",
+                    "stream {:?}: target connect failed target={} err={} kind={:?}",
                     key.stream_id,
+                    target,
                     err,
                     err.kind()
                 );
                 let _ = command_tx.send(Command::StreamConnectError {
                     cnx_id: key.cnx,
                     stream_id: key.stream_id,
+                    error: StreamError::from_io(&err),
                 });
             }
         }
     });
 }
 
-pub(crate) fn spawn_target_reader(
+/// A connected target socket of either family, split for bridging to a stream.
+pub(crate) struct TargetSocket {
+    reader: Box<dyn AsyncRead + Unpin + Send>,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    /// Chunks buffered from the socket before its reader waits for QUIC.
+    read_limit: usize,
+    /// Bytes coalesced into one socket write.
+    send_buffer_bytes: usize,
+    /// The target's address, for a PROXY header; unknown for Unix sockets.
+    peer: Option<SocketAddr>,
+}
+
+impl TargetSocket {
+    pub(crate) fn tcp(stream: TokioTcpStream) -> Self {
+        let _ = stream.set_nodelay(true);
+        #[cfg(unix)]
+        let (read_limit, send_buffer_bytes) = buffer_sizes(&stream);
+        #[cfg(not(unix))]
+        let (read_limit, send_buffer_bytes) = DEFAULT_BUFFER_SIZES;
+        let peer = stream.peer_addr().ok();
+        let (read_half, write_half) = stream.into_split();
+        Self {
+            reader: Box::new(read_half),
+            writer: Box::new(write_half),
+            read_limit,
+            send_buffer_bytes,
+            peer,
+        }
+    }
+
+    /// A `--target-exec` process: the stream's data goes to its stdin and comes from its stdout.
+    pub(crate) fn process(
+        stdout: tokio::process::ChildStdout,
+        stdin: tokio::process::ChildStdin,
+    ) -> Self {
+        Self {
+            reader: Box::new(stdout),
+            writer: Box::new(stdin),
+            read_limit: DEFAULT_BUFFER_SIZES.0,
+            send_buffer_bytes: DEFAULT_BUFFER_SIZES.1,
+            peer: None,
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn unix(stream: UnixStream) -> Self {
+        let (read_limit, send_buffer_bytes) = buffer_sizes(&stream);
+        let (read_half, write_half) = stream.into_split();
+        Self {
+            reader: Box::new(read_half),
+            writer: Box::new(write_half),
+            read_limit,
+            send_buffer_bytes,
+            peer: None,
+        }
+    }
+}
+
+/// Read limit and write coalescing size for targets whose buffers cannot be queried.
+const DEFAULT_BUFFER_SIZES: (usize, usize) = (
+    DEFAULT_TCP_RCVBUF_BYTES / STREAM_READ_CHUNK_BYTES,
+    TARGET_WRITE_COALESCE_DEFAULT_BYTES,
+);
+
+/// Read limit and write coalescing size from the socket's buffers; `SO_RCVBUF` and
+/// `SO_SNDBUF` apply to Unix stream sockets as well.
+#[cfg(unix)]
+fn buffer_sizes<T: AsRawFd>(stream: &T) -> (usize, usize) {
+    let read_limit =
+        stream_read_limit_chunks(stream, DEFAULT_TCP_RCVBUF_BYTES, STREAM_READ_CHUNK_BYTES);
+    let send_buffer_bytes = tcp_send_buffer_bytes(stream)
+        .filter(|bytes| *bytes > 0)
+        .unwrap_or(TARGET_WRITE_COALESCE_DEFAULT_BYTES);
+    (read_limit, send_buffer_bytes)
+}
+
+/// Bridges a connected socket to a stream: returns the stream's write channel, its data
+/// channel and the flag its reader raises when data is waiting.
+pub(crate) fn spawn_target_io(
+    key: StreamKey,
+    socket: TargetSocket,
+    command_tx: mpsc::UnboundedSender<Command>,
+    debug_streams: bool,
+    shutdown_rx: watch::Receiver<bool>,
+) -> (
+    mpsc::UnboundedSender<StreamWrite>,
+    mpsc::Receiver<Vec<u8>>,
+    Arc<AtomicBool>,
+) {
+    let TargetSocket {
+        reader: read_half,
+        writer: write_half,
+        read_limit,
+        send_buffer_bytes,
+        ..
+    } = socket;
+    let (data_tx, data_rx) = mpsc::channel(read_limit);
+    let (write_tx, write_rx) = mpsc::unbounded_channel();
+    let send_pending = Arc::new(AtomicBool::new(false));
+    spawn_target_reader(
+        key,
+        read_half,
+        data_tx,
+        command_tx.clone(),
+        send_pending.clone(),
+        debug_streams,
+        shutdown_rx.clone(),
+    );
+    spawn_target_writer(
+        key,
+        write_half,
+        write_rx,
+        command_tx,
+        shutdown_rx,
+        send_buffer_bytes,
+    );
+    (write_tx, data_rx, send_pending)
+}
+
+fn spawn_target_reader(
     key: StreamKey,
-    mut read_half: tokio::net::tcp::OwnedReadHalf,
+    mut read_half: Box<dyn AsyncRead + Unpin + Send>,
     data_tx: mpsc::Sender<Vec<u8>>,
     command_tx: mpsc::UnboundedSender<Command>,
     send_pending: Arc<AtomicBool>,
@@ -162,9 +383,9 @@ pub(crate) fn spawn_target_reader(
     });
 }
 
-pub(crate) fn spawn_target_writer(
+fn spawn_target_writer(
     key: StreamKey,
-    mut write_half: tokio::net::tcp::OwnedWriteHalf,
+    mut write_half: Box<dyn AsyncWrite + Unpin + Send>,
     mut write_rx: mpsc::UnboundedReceiver<StreamWrite>,
     command_tx: mpsc::UnboundedSender<Command>,
     mut shutdown_rx: watch::Receiver<bool>,
@@ -235,3 +456,40 @@ pub(crate) fn spawn_target_writer(
         let _ = write_half.shutdown().await;
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{AllowedTarget, TargetAddress};
+
+    #[test]
+    fn allowed_targets_match_host_and_port() {
+        let ssh = AllowedTarget::parse("10.0.0.5:22").expect("ip entry");
+        assert!(ssh.allows("10.0.0.5", 22));
+        assert!(!ssh.allows("10.0.0.5", 2222));
+        let db = AllowedTarget::parse("DB.internal:*").expect("wildcard entry");
+        assert!(db.allows("db.internal", 5432));
+        assert!(!db.allows("db.internal.evil", 5432));
+        let v6 = AllowedTarget::parse("[fd00::7]:80").expect("ipv6 entry");
+        assert!(v6.allows("fd00::7", 80));
+        let web = AllowedTarget::parse("*:443").expect("any host entry");
+        assert!(web.allows("203.0.113.9", 443));
+        assert!(!web.allows("203.0.113.9", 80));
+        assert!(AllowedTarget::parse("db.internal").is_err());
+        assert!(AllowedTarget::parse(":22").is_err());
+        assert!(AllowedTarget::parse("db:0").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn target_addresses_take_unix_paths() {
+        match TargetAddress::parse("unix:/run/agent.sock").expect("unix target") {
+            TargetAddress::Unix(path) => assert_eq!(path.to_str(), Some("/run/agent.sock")),
+            other => panic!("expected a unix target, got {:?}", other),
+        }
+        match TargetAddress::parse("127.0.0.1:22").expect("tcp target") {
+            TargetAddress::Tcp(address) => assert_eq!(address.port, 22),
+            other => panic!("expected a tcp target, got {:?}", other),
+        }
+        assert!(TargetAddress::parse("unix:").is_err());
+    }
+}
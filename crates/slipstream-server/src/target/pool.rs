@@ -0,0 +1,281 @@
+//! `--target-address` entries: their resolved addresses, the order streams try them in, and
+//! happy-eyeballs dialing across the addresses of one host.
+
+use super::{TargetAddress, TargetSocket};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{lookup_host, TcpStream as TokioTcpStream};
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+/// Head start each connection attempt gets before the next address is tried alongside it
+/// (RFC 8305 suggests 250 ms).
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Order in which streams try the configured targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TargetPolicy {
+    /// Always start with the first target and fail over down the list.
+    Ordered,
+    /// Start each stream one target further along, then fail over from there.
+    RoundRobin,
+}
+
+#[derive(Debug)]
+struct PoolTarget {
+    address: TargetAddress,
+    /// Last successful resolution of a TCP target; kept when a refresh fails.
+    resolved: Mutex<Vec<SocketAddr>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct TargetPool {
+    targets: Vec<PoolTarget>,
+    policy: TargetPolicy,
+    next: AtomicUsize,
+    connect_timeout: Duration,
+}
+
+impl TargetPool {
+    pub(crate) fn new(
+        addresses: &[TargetAddress],
+        policy: TargetPolicy,
+        connect_timeout: Duration,
+    ) -> Self {
+        Self {
+            targets: addresses
+                .iter()
+                .map(|address| PoolTarget {
+                    address: address.clone(),
+                    resolved: Mutex::new(Vec::new()),
+                })
+                .collect(),
+            policy,
+            next: AtomicUsize::new(0),
+            connect_timeout,
+        }
+    }
+
+    pub(crate) fn connect_timeout(&self) -> Duration {
+        self.connect_timeout
+    }
+
+    /// Re-resolves every TCP target and returns how many targets can be dialed.
+    pub(crate) async fn resolve(&self) -> usize {
+        let mut usable = 0;
+        for target in &self.targets {
+            let TargetAddress::Tcp(address) = &target.address else {
+                usable += 1;
+                continue;
+            };
+            match lookup_host((address.host.as_str(), address.port)).await {
+                Ok(addrs) => {
+                    let addrs: Vec<SocketAddr> = addrs.collect();
+                    if let Ok(mut resolved) = target.resolved.lock() {
+                        if *resolved != addrs {
+                            debug!("target {} resolves to {:?}", address.host, addrs);
+                        }
+                        *resolved = addrs;
+                    }
+                }
+                Err(err) => warn!("Cannot resolve target {}: {}", address.host, err),
+            }
+            if target
+                .resolved
+                .lock()
+                .is_ok_and(|resolved| !resolved.is_empty())
+            {
+                usable += 1;
+            }
+        }
+        usable
+    }
+
+    /// Target indices in the order the next stream should try them.
+    fn order(&self) -> Vec<usize> {
+        let len = self.targets.len();
+        let start = match self.policy {
+            TargetPolicy::Ordered => 0,
+            TargetPolicy::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % len.max(1),
+        };
+        (0..len).map(|offset| (start + offset) % len).collect()
+    }
+
+    /// Connects to the first target that answers, trying each in policy order.
+    pub(crate) async fn connect(&self, stream_id: u64) -> io::Result<TargetSocket> {
+        let mut last_err = None;
+        for index in self.order() {
+            let target = &self.targets[index];
+            let attempt = match &target.address {
+                #[cfg(unix)]
+                TargetAddress::Unix(path) => {
+                    with_timeout(self.connect_timeout, tokio::net::UnixStream::connect(path))
+                        .await
+                        .map(TargetSocket::unix)
+                }
+                // `TargetAddress::parse` only yields these on Unix platforms.
+                #[cfg(not(unix))]
+                TargetAddress::Unix(_) => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "unix: targets are only supported on Unix platforms",
+                )),
+                TargetAddress::Tcp(_) => {
+                    let addrs = target
+                        .resolved
+                        .lock()
+                        .map(|resolved| resolved.clone())
+                        .unwrap_or_default();
+                    connect_tcp(&addrs, self.connect_timeout)
+                        .await
+                        .map(TargetSocket::tcp)
+                }
+            };
+            match attempt {
+                Ok(socket) => return Ok(socket),
+                Err(err) => {
+                    if self.targets.len() > 1 {
+                        warn!(
+                            "stream {:?}: target {:?} failed err={}",
+                            stream_id, target.address, err
+                        );
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::other("no target address configured")))
+    }
+}
+
+/// Keeps `pool`'s addresses current as DNS changes; an interval of zero disables it.
+pub(crate) fn spawn_target_resolver(pool: Arc<TargetPool>, interval: Duration) {
+    if interval.is_zero()
+        || !pool
+            .targets
+            .iter()
+            .any(|target| matches!(target.address, TargetAddress::Tcp(_)))
+    {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            pool.resolve().await;
+        }
+    });
+}
+
+/// Resolves `host` and dials its addresses within `timeout`.
+pub(crate) async fn connect_host(
+    host: &str,
+    port: u16,
+    timeout: Duration,
+) -> io::Result<TokioTcpStream> {
+    let addrs: Vec<SocketAddr> = with_timeout(timeout, lookup_host((host, port)))
+        .await?
+        .collect();
+    connect_tcp(&addrs, timeout).await
+}
+
+async fn connect_tcp(addrs: &[SocketAddr], timeout: Duration) -> io::Result<TokioTcpStream> {
+    with_timeout(timeout, happy_eyeballs(addrs)).await
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    future: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    tokio::time::timeout(timeout, future)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "connect timed out")))
+}
+
+/// Races connection attempts in `interleave_families` order, starting the next one when the
+/// current attempt fails or has had `CONNECTION_ATTEMPT_DELAY` to itself.
+async fn happy_eyeballs(addrs: &[SocketAddr]) -> io::Result<TokioTcpStream> {
+    let mut pending = interleave_families(addrs).into_iter().peekable();
+    let mut attempts = JoinSet::new();
+    let mut last_err = None;
+    loop {
+        if let Some(addr) = pending.next() {
+            attempts.spawn(TokioTcpStream::connect(addr));
+        }
+        if attempts.is_empty() {
+            return Err(last_err.unwrap_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, "target has no addresses")
+            }));
+        }
+        let more = pending.peek().is_some();
+        tokio::select! {
+            result = attempts.join_next() => match result {
+                Some(Ok(Ok(stream))) => return Ok(stream),
+                Some(Ok(Err(err))) => last_err = Some(err),
+                Some(Err(err)) => last_err = Some(io::Error::other(err)),
+                None => {}
+            },
+            _ = tokio::time::sleep(CONNECTION_ATTEMPT_DELAY), if more => {}
+        }
+    }
+}
+
+/// Alternates address families, starting with the family of the first address, so a broken
+/// family costs one attempt delay rather than a timeout per address.
+fn interleave_families(addrs: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return Vec::new();
+    };
+    let (mut preferred, mut other): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .iter()
+        .copied()
+        .partition(|addr| addr.is_ipv6() == first.is_ipv6());
+    preferred.reverse();
+    other.reverse();
+    let mut ordered = Vec::with_capacity(addrs.len());
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop());
+        ordered.extend(other.pop());
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{interleave_families, TargetPolicy, TargetPool};
+    use crate::target::TargetAddress;
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn families_alternate_from_the_first_address() {
+        let addrs: Vec<SocketAddr> = ["[2001:db8::1]:80", "[2001:db8::2]:80", "192.0.2.1:80"]
+            .iter()
+            .map(|addr| addr.parse().expect("addr"))
+            .collect();
+        assert_eq!(
+            interleave_families(&addrs),
+            vec![addrs[0], addrs[2], addrs[1]]
+        );
+        assert!(interleave_families(&[]).is_empty());
+    }
+
+    #[test]
+    fn round_robin_rotates_the_starting_target() {
+        let addresses: Vec<TargetAddress> = ["192.0.2.1:80", "192.0.2.2:80", "192.0.2.3:80"]
+            .iter()
+            .map(|address| TargetAddress::parse(address).expect("address"))
+            .collect();
+        let ordered = TargetPool::new(&addresses, TargetPolicy::Ordered, Duration::from_secs(1));
+        assert_eq!(ordered.order(), vec![0, 1, 2]);
+        assert_eq!(ordered.order(), vec![0, 1, 2]);
+        let rotating =
+            TargetPool::new(&addresses, TargetPolicy::RoundRobin, Duration::from_secs(1));
+        assert_eq!(rotating.order(), vec![0, 1, 2]);
+        assert_eq!(rotating.order(), vec![1, 2, 0]);
+        assert_eq!(rotating.order(), vec![2, 0, 1]);
+    }
+}
@@ -0,0 +1,288 @@
+//! `--tun` full-tunnel mode (Linux only). Clients' IP packets arrive framed on one stream
+//! each and are written to the server's TUN device; packets read from it go to the client
+//! that owns their destination address. A session owns the first IPv4 and the first IPv6
+//! source address it sends from that no other session holds, and packets from any other
+//! source are dropped. Addresses, forwarding and NAT are left to the system's configuration.
+
+#[cfg(not(target_os = "linux"))]
+use crate::server::{Command, ServerError, StreamKey};
+#[cfg(not(target_os = "linux"))]
+use tokio::sync::{mpsc, watch};
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::TunHub;
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) enum TunHub {}
+
+#[cfg(not(target_os = "linux"))]
+impl TunHub {
+    pub(crate) fn open(_name: &str) -> Result<Self, ServerError> {
+        Err(ServerError::new("TUN mode is only supported on Linux"))
+    }
+
+    pub(crate) fn spawn_session(
+        &self,
+        _key: StreamKey,
+        _command_tx: mpsc::UnboundedSender<Command>,
+        _shutdown_rx: watch::Receiver<bool>,
+    ) {
+        match *self {}
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::server::{
+        Command, ServerError, StreamKey, StreamWrite, DEFAULT_TCP_RCVBUF_BYTES,
+        STREAM_READ_CHUNK_BYTES,
+    };
+    use slipstream_core::tun::{
+        frame_packet, packet_destination, packet_source, PacketDeframer, TunDevice,
+        TUN_PACKET_MAX_BYTES,
+    };
+    use std::collections::HashMap;
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::unix::AsyncFd;
+    use tokio::sync::{mpsc, watch};
+    use tracing::{debug, info, warn};
+
+    /// Packets read from the device a session buffers before dropping more.
+    const SESSION_QUEUE_PACKETS: usize = 256;
+    /// Pause after a failed device read, so a persistent error does not spin.
+    const DEVICE_READ_RETRY: Duration = Duration::from_millis(100);
+
+    struct Route {
+        session: StreamKey,
+        to_client: mpsc::Sender<Vec<u8>>,
+    }
+
+    type Routes = Arc<Mutex<HashMap<IpAddr, Route>>>;
+
+    pub(crate) struct TunHub {
+        device: Arc<AsyncFd<TunDevice>>,
+        routes: Routes,
+    }
+
+    impl TunHub {
+        /// Opens the device and starts handing its packets to sessions.
+        pub(crate) fn open(name: &str) -> Result<Self, ServerError> {
+            let device = TunDevice::open(name)
+                .and_then(AsyncFd::new)
+                .map_err(|err| {
+                    ServerError::new(format!("Failed to open TUN device {}: {}", name, err))
+                })?;
+            let hub = Self {
+                device: Arc::new(device),
+                routes: Arc::new(Mutex::new(HashMap::new())),
+            };
+            spawn_device_reader(hub.device.clone(), hub.routes.clone());
+            info!("Carrying client IP packets through TUN device {}", name);
+            Ok(hub)
+        }
+
+        pub(crate) fn spawn_session(
+            &self,
+            key: StreamKey,
+            command_tx: mpsc::UnboundedSender<Command>,
+            mut shutdown_rx: watch::Receiver<bool>,
+        ) {
+            let device = self.device.clone();
+            let routes = self.routes.clone();
+            let (data_tx, data_rx) =
+                mpsc::channel(DEFAULT_TCP_RCVBUF_BYTES / STREAM_READ_CHUNK_BYTES);
+            let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+            let send_pending = Arc::new(AtomicBool::new(false));
+            let _ = command_tx.send(Command::StreamConnected {
+                cnx_id: key.cnx,
+                stream_id: key.stream_id,
+                write_tx,
+                data_rx,
+                send_pending: send_pending.clone(),
+            });
+            tokio::spawn(async move {
+                let (to_client, mut from_device) = mpsc::channel(SESSION_QUEUE_PACKETS);
+                let mut deframer = PacketDeframer::default();
+                let mut addresses = SessionAddresses::default();
+                loop {
+                    tokio::select! {
+                        changed = shutdown_rx.changed() => {
+                            if changed.is_err() || *shutdown_rx.borrow() {
+                                break;
+                            }
+                        }
+                        write = write_rx.recv() => {
+                            let Some(StreamWrite::Data(data)) = write else {
+                                break;
+                            };
+                            deframer.push(&data);
+                            while let Some(packet) = deframer.next_packet() {
+                                let Some(source) = packet_source(&packet) else {
+                                    continue;
+                                };
+                                let admitted = addresses.admit(source, || {
+                                    claim_route(&routes, source, key, &to_client)
+                                });
+                                if !admitted {
+                                    debug!(
+                                        "stream {:?}: dropping packet from {}, not this session's address",
+                                        key.stream_id, source
+                                    );
+                                    continue;
+                                }
+                                // Like any link, the device drops what it cannot queue.
+                                let _ = device.get_ref().send(&packet);
+                            }
+                            let _ = command_tx.send(Command::StreamWriteDrained {
+                                cnx_id: key.cnx,
+                                stream_id: key.stream_id,
+                                bytes: data.len(),
+                            });
+                        }
+                        packet = from_device.recv() => {
+                            let Some(packet) = packet else {
+                                break;
+                            };
+                            if data_tx.send(frame_packet(&packet)).await.is_err() {
+                                break;
+                            }
+                            if !send_pending.swap(true, Ordering::SeqCst) {
+                                let _ = command_tx.send(Command::StreamReadable {
+                                    cnx_id: key.cnx,
+                                    stream_id: key.stream_id,
+                                });
+                            }
+                        }
+                    }
+                }
+                if let Ok(mut routes) = routes.lock() {
+                    routes.retain(|_, route| route.session != key);
+                }
+                if !*shutdown_rx.borrow() {
+                    drop(data_tx);
+                    let _ = command_tx.send(Command::StreamClosed {
+                        cnx_id: key.cnx,
+                        stream_id: key.stream_id,
+                    });
+                }
+                debug!("stream {:?}: TUN session ended", key.stream_id);
+            });
+        }
+    }
+
+    /// The addresses a session may send from: the first of each family it claimed.
+    #[derive(Default)]
+    struct SessionAddresses {
+        v4: Option<IpAddr>,
+        v6: Option<IpAddr>,
+    }
+
+    impl SessionAddresses {
+        /// Whether the session may send from `source`. The first source of a family is bound
+        /// if `claim` takes it; later ones must match.
+        fn admit(&mut self, source: IpAddr, claim: impl FnOnce() -> bool) -> bool {
+            let bound = match source {
+                IpAddr::V4(_) => &mut self.v4,
+                IpAddr::V6(_) => &mut self.v6,
+            };
+            match bound {
+                Some(address) => *address == source,
+                None if claim() => {
+                    *bound = Some(source);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    /// Points `source` at `session`, unless another live session holds it.
+    fn claim_route(
+        routes: &Routes,
+        source: IpAddr,
+        session: StreamKey,
+        to_client: &mpsc::Sender<Vec<u8>>,
+    ) -> bool {
+        let Ok(mut routes) = routes.lock() else {
+            return false;
+        };
+        match routes.get(&source) {
+            Some(route) if route.session == session => true,
+            Some(route) if !route.to_client.is_closed() => {
+                debug!(
+                    "stream {:?}: {} already belongs to stream {:?}",
+                    session.stream_id, source, route.session.stream_id
+                );
+                false
+            }
+            _ => {
+                routes.insert(
+                    source,
+                    Route {
+                        session,
+                        to_client: to_client.clone(),
+                    },
+                );
+                true
+            }
+        }
+    }
+
+    fn spawn_device_reader(device: Arc<AsyncFd<TunDevice>>, routes: Routes) {
+        tokio::spawn(async move {
+            let mut packet = vec![0u8; TUN_PACKET_MAX_BYTES];
+            loop {
+                let Ok(mut guard) = device.readable().await else {
+                    break;
+                };
+                let len = match guard.try_io(|device| device.get_ref().recv(&mut packet)) {
+                    Ok(Ok(len)) => len,
+                    Ok(Err(err)) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Ok(Err(err)) => {
+                        // Every client's return traffic depends on this task; keep reading.
+                        warn!(
+                            "TUN device {} read failed err={}",
+                            device.get_ref().name(),
+                            err
+                        );
+                        drop(guard);
+                        tokio::time::sleep(DEVICE_READ_RETRY).await;
+                        continue;
+                    }
+                    Err(_would_block) => continue,
+                };
+                let Some(destination) = packet_destination(&packet[..len]) else {
+                    continue;
+                };
+                let Ok(routes) = routes.lock() else {
+                    break;
+                };
+                if let Some(route) = routes.get(&destination) {
+                    let _ = route.to_client.try_send(packet[..len].to_vec());
+                }
+            }
+        });
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::SessionAddresses;
+        use std::net::IpAddr;
+
+        #[test]
+        fn session_keeps_the_first_address_of_each_family() {
+            let ip = |text: &str| text.parse::<IpAddr>().expect("ip");
+            let mut addresses = SessionAddresses::default();
+            // Held by another session: not bound, so the next free address still can be.
+            assert!(!addresses.admit(ip("10.8.0.3"), || false));
+            assert!(addresses.admit(ip("10.8.0.2"), || true));
+            assert!(addresses.admit(ip("10.8.0.2"), || unreachable!()));
+            assert!(!addresses.admit(ip("10.8.0.9"), || true));
+            assert!(addresses.admit(ip("fd00::2"), || true));
+            assert!(!addresses.admit(ip("fd00::9"), || true));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! UDP flows opened by `slipstream-client -U`. Each flow's stream only names the destination
+//! and marks the flow's lifetime; payloads arrive and leave as QUIC datagrams.
+
+use crate::server::{Command, StreamKey, StreamWrite};
+use slipstream_core::stream_error::StreamError;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, Instant};
+use tracing::{debug, warn};
+
+/// Clients close idle flows themselves; this only reclaims flows whose client went away.
+const UDP_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const UDP_PAYLOAD_MAX_BYTES: usize = 1500;
+/// Payloads from the client a flow buffers before dropping more.
+const FLOW_QUEUE_DATAGRAMS: usize = 64;
+
+/// Opens the flow's socket in the background and returns where its client payloads go.
+/// Payloads sent before the socket is up wait in the flow's queue, so a flow's first datagram,
+/// often its only one for DNS, is not lost.
+pub(crate) fn spawn_udp_target(
+    key: StreamKey,
+    host: String,
+    port: u16,
+    command_tx: mpsc::UnboundedSender<Command>,
+    mut shutdown_rx: watch::Receiver<bool>,
+) -> mpsc::Sender<Vec<u8>> {
+    let (to_target, mut from_client) = mpsc::channel::<Vec<u8>>(FLOW_QUEUE_DATAGRAMS);
+    tokio::spawn(async move {
+        let socket = tokio::select! {
+            _ = shutdown_rx.changed() => return,
+            socket = connect_udp(&host, port) => socket,
+        };
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!(
+                    "stream {:?}: UDP target {}:{} failed err={}",
+                    key.stream_id, host, port, err
+                );
+                let _ = command_tx.send(Command::StreamConnectError {
+                    cnx_id: key.cnx,
+                    stream_id: key.stream_id,
+                    error: StreamError::from_io(&err),
+                });
+                return;
+            }
+        };
+        let (write_tx, mut write_rx) = mpsc::unbounded_channel();
+        // The flow never writes to its stream; dropping this sender at the end sends the FIN.
+        let (data_tx, data_rx) = mpsc::channel::<Vec<u8>>(1);
+        let _ = command_tx.send(Command::StreamConnected {
+            cnx_id: key.cnx,
+            stream_id: key.stream_id,
+            write_tx,
+            data_rx,
+            send_pending: Arc::new(AtomicBool::new(false)),
+        });
+
+        let mut buf = vec![0u8; UDP_PAYLOAD_MAX_BYTES];
+        let mut deadline = Instant::now() + UDP_FLOW_IDLE_TIMEOUT;
+        loop {
+            tokio::select! {
+                changed = shutdown_rx.changed() => {
+                    if changed.is_err() || *shutdown_rx.borrow() {
+                        return;
+                    }
+                }
+                write = write_rx.recv() => match write {
+                    Some(StreamWrite::Data(_)) => {}
+                    // The client ended the flow.
+                    Some(StreamWrite::Fin) | None => break,
+                },
+                payload = from_client.recv() => {
+                    let Some(payload) = payload else {
+                        break;
+                    };
+                    deadline = Instant::now() + UDP_FLOW_IDLE_TIMEOUT;
+                    let _ = socket.send(&payload).await;
+                }
+                received = socket.recv(&mut buf) => match received {
+                    Ok(len) => {
+                        deadline = Instant::now() + UDP_FLOW_IDLE_TIMEOUT;
+                        let _ = command_tx.send(Command::Datagram {
+                            cnx_id: key.cnx,
+                            stream_id: key.stream_id,
+                            payload: buf[..len].to_vec(),
+                        });
+                    }
+                    // ICMP errors from earlier sends; the target may still come up.
+                    Err(err) if err.kind() == std::io::ErrorKind::ConnectionRefused => {}
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+                    Err(err) => {
+                        debug!("stream {:?}: UDP target read failed err={}", key.stream_id, err);
+                        break;
+                    }
+                },
+                _ = sleep_until(deadline) => {
+                    debug!("stream {:?}: UDP flow idle", key.stream_id);
+                    break;
+                }
+            }
+        }
+        drop(data_tx);
+        let _ = command_tx.send(Command::StreamClosed {
+            cnx_id: key.cnx,
+            stream_id: key.stream_id,
+        });
+    });
+    to_target
+}
+
+async fn connect_udp(host: &str, port: u16) -> std::io::Result<UdpSocket> {
+    let target = lookup_host((host, port)).await?.next().ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "host has no addresses")
+    })?;
+    let local = match target {
+        SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(target).await?;
+    Ok(socket)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::spawn_udp_target;
+    use crate::server::StreamKey;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+    use tokio::sync::{mpsc, watch};
+
+    #[tokio::test]
+    async fn first_datagram_reaches_the_target() {
+        let target = UdpSocket::bind("127.0.0.1:0").await.expect("bind target");
+        let port = target.local_addr().expect("target addr").port();
+        let (command_tx, _command_rx) = mpsc::unbounded_channel();
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let key = StreamKey {
+            cnx: 1,
+            stream_id: 4,
+        };
+        let to_target =
+            spawn_udp_target(key, "127.0.0.1".to_string(), port, command_tx, shutdown_rx);
+        // Sent before the flow's socket can be up.
+        to_target
+            .try_send(b"first".to_vec())
+            .expect("queue payload");
+        let mut buf = [0u8; 16];
+        let len = tokio::time::timeout(Duration::from_secs(5), target.recv(&mut buf))
+            .await
+            .expect("first datagram in time")
+            .expect("recv");
+        assert_eq!(&buf[..len], b"first");
+    }
+}
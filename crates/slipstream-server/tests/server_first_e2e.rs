@@ -0,0 +1,183 @@
+//! A target that speaks first (an SMTP or SSH banner) must reach a client that has not sent
+//! anything yet.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BANNER: &[u8] = b"220 target ready\r\n";
+
+struct ChildGuard {
+    child: Child,
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn workspace_root() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join("..")
+}
+
+fn ensure_client_bin(root: &Path) -> PathBuf {
+    let status = Command::new("cargo")
+        .arg("build")
+        .arg("-p")
+        .arg("slipstream-client")
+        .current_dir(root)
+        .status()
+        .expect("failed to invoke cargo build for slipstream-client");
+    assert!(status.success(), "cargo build -p slipstream-client failed");
+    let mut path = root.join("target").join("debug").join("slipstream-client");
+    if cfg!(windows) {
+        path.set_extension("exe");
+    }
+    path
+}
+
+fn pick_udp_port() -> std::io::Result<u16> {
+    let socket = UdpSocket::bind("127.0.0.1:0")?;
+    Ok(socket.local_addr()?.port())
+}
+
+fn pick_tcp_port() -> std::io::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?.port())
+}
+
+/// Accepts connections and greets each with `BANNER`, without waiting to read anything.
+fn spawn_banner_target() -> std::io::Result<u16> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let port = listener.local_addr()?.port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(mut stream) = stream else {
+                break;
+            };
+            thread::spawn(move || {
+                let _ = stream.write_all(BANNER);
+                let mut sink = [0u8; 256];
+                while matches!(stream.read(&mut sink), Ok(n) if n > 0) {}
+            });
+        }
+    });
+    Ok(port)
+}
+
+fn wait_for_listening(child: &mut Child, timeout: Duration) -> bool {
+    let Some(stdout) = child.stdout.take() else {
+        return false;
+    };
+    let Some(stderr) = child.stderr.take() else {
+        return false;
+    };
+    let (tx, rx) = mpsc::channel();
+    for reader in [
+        Box::new(stdout) as Box<dyn Read + Send>,
+        Box::new(stderr) as Box<dyn Read + Send>,
+    ] {
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for line in BufReader::new(reader).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                let _ = tx.send(line);
+            }
+        });
+    }
+    let deadline = Instant::now() + timeout;
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        match rx.recv_timeout(remaining) {
+            Ok(line) if line.contains("Listening on TCP port") => return true,
+            Ok(_) => {}
+            Err(_) => return false,
+        }
+    }
+    false
+}
+
+#[test]
+fn server_first_target_e2e() {
+    let root = workspace_root();
+    let client_bin = ensure_client_bin(&root);
+    let server_bin = PathBuf::from(env!("CARGO_BIN_EXE_slipstream-server"));
+    let cert = root.join("fixtures/certs/cert.pem");
+    let key = root.join("fixtures/certs/key.pem");
+    assert!(cert.exists(), "missing fixtures/certs/cert.pem");
+    assert!(key.exists(), "missing fixtures/certs/key.pem");
+
+    let ports = (pick_udp_port(), pick_tcp_port(), spawn_banner_target());
+    let (dns_port, tcp_port, target_port) = match ports {
+        (Ok(dns), Ok(tcp), Ok(target)) => (dns, tcp, target),
+        _ => {
+            eprintln!("skipping server-first e2e test: cannot bind local ports");
+            return;
+        }
+    };
+    let domain = "test.example.com";
+
+    let mut server = ChildGuard {
+        child: Command::new(&server_bin)
+            .arg("--dns-listen-port")
+            .arg(dns_port.to_string())
+            .arg("--target-address")
+            .arg(format!("127.0.0.1:{}", target_port))
+            .arg("--domain")
+            .arg(domain)
+            .arg("--cert")
+            .arg(&cert)
+            .arg("--key")
+            .arg(&key)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("start slipstream-server"),
+    };
+    thread::sleep(Duration::from_millis(200));
+    if matches!(server.child.try_wait(), Ok(Some(_)) | Err(_)) {
+        eprintln!("skipping server-first e2e test: server failed to start");
+        return;
+    }
+
+    let mut client = ChildGuard {
+        child: Command::new(&client_bin)
+            .arg("--tcp-listen-port")
+            .arg(tcp_port.to_string())
+            .arg("--resolver")
+            .arg(format!("127.0.0.1:{}", dns_port))
+            .arg("--domain")
+            .arg(domain)
+            .arg("--cert")
+            .arg(&cert)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("start slipstream-client"),
+    };
+    assert!(
+        wait_for_listening(&mut client.child, Duration::from_secs(5)),
+        "client did not start listening"
+    );
+
+    let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, tcp_port));
+    let mut local = TcpStream::connect_timeout(&addr, Duration::from_secs(5))
+        .expect("connect to client TCP port");
+    local
+        .set_read_timeout(Some(Duration::from_secs(15)))
+        .expect("set read timeout");
+    // Nothing is written: the banner has to arrive on its own.
+    let mut banner = vec![0u8; BANNER.len()];
+    local
+        .read_exact(&mut banner)
+        .expect("banner from a server-first target");
+    assert_eq!(banner, BANNER);
+}